@@ -0,0 +1,280 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::warn;
+use serde_json::json;
+use tokio::sync::mpsc;
+
+use crate::config::AccessLogConfig;
+use crate::error::Error;
+use crate::relay::Traffic;
+use crate::socket5::{Proxy, Reply};
+
+// records waiting for the writer, more than this are dropped rather than slowing connections
+const QUEUE: usize = 4096;
+
+// what is known of a connection as it goes, written once it's done
+pub struct Access {
+    pub start: SystemTime,
+    pub source: Option<IpAddr>,
+    pub user: Option<String>,
+    pub proxy: Option<Proxy>,
+    pub traffic: Traffic,
+}
+
+impl Access {
+    pub fn new(source: Option<IpAddr>) -> Self {
+        Access { start: SystemTime::now(), source, user: None, proxy: None, traffic: Traffic::default() }
+    }
+}
+
+// one json record per connection that got as far as a request, a no-op unless `access_log` is configured
+#[derive(Clone, Default)]
+pub struct AccessLog {
+    sender: Option<mpsc::Sender<String>>,
+}
+
+impl AccessLog {
+    // needs a runtime when configured, the writer is spawned here
+    pub fn new(config: Option<AccessLogConfig>) -> io::Result<Self> {
+        let Some(config) = config else {
+            return Ok(AccessLog::default());
+        };
+        let mut writer = Writer::open(config)?;
+        let (sender, mut receiver) = mpsc::channel::<String>(QUEUE);
+        tokio::spawn(async move {
+            while let Some(line) = receiver.recv().await {
+                if let Err(e) = writer.write(&line, SystemTime::now()) {
+                    warn!("write access log {} fail : {}", writer.config.path.display(), e);
+                }
+            }
+        });
+        Ok(AccessLog { sender: Some(sender) })
+    }
+
+    pub fn record(&self, access: &Access, error: Option<&Error>) {
+        let (Some(sender), Some(proxy)) = (&self.sender, &access.proxy) else {
+            return;
+        };
+        let _ = sender.try_send(line(access, proxy, error, SystemTime::now()));
+    }
+}
+
+fn line(access: &Access, proxy: &Proxy, error: Option<&Error>, end: SystemTime) -> String {
+    let seconds = |time: SystemTime| time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64();
+    json!({
+        "start": seconds(access.start),
+        "end": seconds(end),
+        "user": access.user,
+        "source": access.source.map(|ip| ip.to_string()),
+        "command": proxy.command.to_string(),
+        "target": proxy.address.to_string(),
+        "bytes_up": access.traffic.up(),
+        "bytes_down": access.traffic.down(),
+        "close": access.traffic.close().map(|close| close.to_string()),
+        "reply": error.map_or(Reply::RepSuccess, |e| e.to_reply()).to_string(),
+        "rule": error.and_then(rule),
+        "error": error.map(|e| e.to_string()),
+    }).to_string()
+}
+
+// the setting that refused the request, for requests the server turned down itself
+fn rule(error: &Error) -> Option<&'static str> {
+    match error {
+        Error::Blocked(_) => Some("blocklist"),
+        Error::Forbidden(_) => Some("block_private"),
+        Error::Loop(_) => Some("hairpin"),
+        Error::TargetBusy(_) => Some("target_limit"),
+        Error::SourceBusy(_) => Some("source_limit"),
+        Error::QuotaExceeded => Some("quota"),
+        Error::TransferExceeded(_) => Some("transfer"),
+        Error::Overloaded => Some("shed"),
+        Error::Maintenance => Some("maintenance"),
+        _ => None,
+    }
+}
+
+struct Writer {
+    config: AccessLogConfig,
+    file: File,
+    size: u64,
+    opened: SystemTime,
+}
+
+impl Writer {
+    fn open(config: AccessLogConfig) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&config.path)
+            .map_err(|e| io::Error::new(e.kind(), format!("access log {} : {}", config.path.display(), e)))?;
+        let size = file.metadata()?.len();
+        Ok(Writer { config, file, size, opened: SystemTime::now() })
+    }
+
+    fn write(&mut self, line: &str, now: SystemTime) -> io::Result<()> {
+        let too_big = self.config.max_size > 0 && self.size > 0 && self.size + line.len() as u64 > self.config.max_size;
+        let too_old = self.config.max_age > 0
+            && now.duration_since(self.opened).is_ok_and(|age| age.as_secs() >= self.config.max_age);
+        if too_big || (too_old && self.size > 0) {
+            self.rotate(now)?;
+        }
+        writeln!(self.file, "{}", line)?;
+        self.size += line.len() as u64 + 1;
+        Ok(())
+    }
+
+    // access.log becomes access.log.<unix seconds>, gzipped when asked, the oldest past keep are removed
+    fn rotate(&mut self, now: SystemTime) -> io::Result<()> {
+        let path = &self.config.path;
+        let seconds = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let mut rotated = suffixed(path, &seconds.to_string());
+        let mut n = 1;
+        while rotated.exists() || suffixed(&rotated, "gz").exists() {
+            rotated = suffixed(path, &format!("{}-{}", seconds, n));
+            n += 1;
+        }
+        self.file.flush()?;
+        fs::rename(path, &rotated)?;
+        self.file = OpenOptions::new().create(true).append(true).open(path)?;
+        self.size = 0;
+        self.opened = now;
+        if self.config.gzip {
+            gzip(rotated);
+        }
+        prune(path, self.config.keep)
+    }
+}
+
+fn suffixed(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(".");
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+// the system gzip, in the background, replacing the file with file.gz
+fn gzip(path: PathBuf) {
+    match tokio::process::Command::new("gzip").arg(&path).spawn() {
+        Ok(mut child) => {
+            tokio::spawn(async move {
+                let _ = child.wait().await;
+            });
+        }
+        Err(e) => warn!("gzip {} fail : {}", path.display(), e),
+    }
+}
+
+// rotated names sort by age, the unix seconds have the same number of digits for centuries
+fn prune(path: &Path, keep: usize) -> io::Result<()> {
+    let (Some(dir), Some(name)) = (path.parent(), path.file_name().and_then(|n| n.to_str())) else {
+        return Ok(());
+    };
+    let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+    let prefix = format!("{}.", name);
+    let mut rotated: Vec<String> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .filter(|file| file.strip_prefix(&prefix).is_some_and(|rest| rest.starts_with(|c: char| c.is_ascii_digit())))
+        .collect();
+    rotated.sort();
+    let excess = rotated.len().saturating_sub(keep);
+    for file in &rotated[..excess] {
+        fs::remove_file(dir.join(file))?;
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use crate::access::{Access, line, Writer};
+    use crate::config::{AccessLogConfig, ServerConfig};
+    use crate::error::Error;
+    use crate::relay::Close;
+    use crate::socket5::{Address, Command, Proxy};
+    use crate::tcp::TcpSocksClient;
+    use crate::test_util::{assert_echo, config, echo_server, socks_server, test_state};
+    use crate::transport::Endpoint;
+
+    #[test]
+    fn line_test() {
+        let mut access = Access::new(Some("10.0.0.1".parse().unwrap()));
+        access.start = UNIX_EPOCH + Duration::from_secs(10);
+        access.user = Some("alice".to_string());
+        access.traffic.add_up(5);
+        let proxy = Proxy::new(Command::CONNECT, Address::DomainName("ads.example.com".to_string(), 443));
+        let end = UNIX_EPOCH + Duration::from_millis(12500);
+        let value: serde_json::Value = serde_json::from_str(&line(&access, &proxy, None, end)).unwrap();
+        assert_eq!((value["start"].as_f64(), value["end"].as_f64()), (Some(10.0), Some(12.5)));
+        assert_eq!((value["user"].as_str(), value["source"].as_str()), (Some("alice"), Some("10.0.0.1")));
+        assert_eq!((value["target"].as_str(), value["command"].as_str()), (Some("ads.example.com:443"), Some("connect")));
+        assert_eq!((value["bytes_up"].as_u64(), value["bytes_down"].as_u64()), (Some(5), Some(0)));
+        assert_eq!((value["reply"].as_str(), value["rule"].as_str()), (Some("success"), None));
+        assert!(value["close"].is_null());
+        access.traffic.set_close(Close::TargetEof);
+        let value: serde_json::Value = serde_json::from_str(&line(&access, &proxy, None, end)).unwrap();
+        assert_eq!(value["close"].as_str(), Some("target_eof"));
+
+        let blocked = Error::Blocked("ads.example.com".to_string());
+        let value: serde_json::Value = serde_json::from_str(&line(&access, &proxy, Some(&blocked), end)).unwrap();
+        assert_eq!((value["reply"].as_str(), value["rule"].as_str()), (Some("host-unreachable"), Some("blocklist")));
+    }
+
+    #[test]
+    fn rotate_test() {
+        let dir = std::env::temp_dir().join(format!("rust-ss5-access-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("access.log");
+        let mut writer = Writer::open(AccessLogConfig { path: path.clone(), max_size: 20, max_age: 0, keep: 2, gzip: false }).unwrap();
+        let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        for (i, line) in ["first line", "second line", "third line", "fourth line"].iter().enumerate() {
+            writer.write(line, now + Duration::from_secs(i as u64)).unwrap();
+        }
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "fourth line\n");
+        let mut rotated: Vec<String> = std::fs::read_dir(&dir).unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name != "access.log")
+            .collect();
+        rotated.sort();
+        // the first rotation was pruned, keep is 2
+        assert_eq!(rotated, vec!["access.log.1700000002", "access.log.1700000003"]);
+        assert_eq!(std::fs::read_to_string(dir.join("access.log.1700000002")).unwrap(), "second line\n");
+
+        let mut writer = Writer::open(AccessLogConfig { path: path.clone(), max_size: 0, max_age: 60, keep: 10, gzip: false }).unwrap();
+        writer.write("young", writer.opened).unwrap();
+        writer.write("old", writer.opened + Duration::from_secs(60)).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "old\n");
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn access_log_test() {
+        let path = std::env::temp_dir().join(format!("rust-ss5-access-log-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let echo = echo_server().await;
+        let state = test_state(ServerConfig {
+            access_log: Some(AccessLogConfig { path: path.clone(), ..AccessLogConfig::default() }),
+            ..config()
+        });
+        let server = socks_server(Endpoint::Tcp("127.0.0.1:0".to_string()), state).await.to_string();
+        let mut client = TcpSocksClient::client_connect(&server, Proxy::new(Command::CONNECT, Address::Address(echo))).await.unwrap();
+        assert_echo(&mut client.stream).await;
+        drop(client);
+        let mut text = String::new();
+        for _ in 0..100 {
+            text = std::fs::read_to_string(&path).unwrap();
+            if !text.is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let _ = std::fs::remove_file(&path);
+        let value: serde_json::Value = serde_json::from_str(text.trim_end()).unwrap();
+        assert_eq!(value["target"].as_str(), Some(echo.to_string().as_str()));
+        assert_eq!((value["bytes_up"].as_u64(), value["bytes_down"].as_u64()), (Some(5), Some(5)));
+        assert_eq!(value["source"].as_str(), Some("127.0.0.1"));
+    }
+}
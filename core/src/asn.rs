@@ -0,0 +1,165 @@
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use log::{info, warn};
+use tokio::sync::watch;
+
+use crate::config::AsnConfig;
+
+// the autonomous system announcing each address range, from an ip-to-asn table like iptoasn.com's
+// ip2asn-combined.tsv: "start end asn ..." a range per line, tab or space separated
+#[derive(Debug, Clone, Default)]
+pub struct AsnTable {
+    // (start, end, asn) sorted by start, ipv4 mapped into ipv6
+    ranges: Vec<(u128, u128, u32)>,
+}
+
+impl AsnTable {
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)
+            .map_err(|e| io::Error::new(e.kind(), format!("asn table {} : {}", path.display(), e)))?;
+        Ok(AsnTable::parse(&text))
+    }
+
+    // lines that aren't a range are skipped, asn 0 is iptoasn's "not routed"
+    pub fn parse(text: &str) -> Self {
+        let mut ranges: Vec<_> = text.lines().filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let start = fields.next()?.parse::<IpAddr>().ok()?;
+            let end = fields.next()?.parse::<IpAddr>().ok()?;
+            let asn = fields.next()?.trim_start_matches("AS").parse::<u32>().ok()?;
+            (asn > 0).then_some((key(start), key(end), asn))
+        }).collect();
+        ranges.sort_unstable();
+        AsnTable { ranges }
+    }
+
+    pub fn len(&self) -> usize {
+        self.ranges.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    pub fn lookup(&self, ip: IpAddr) -> Option<u32> {
+        let ip = key(ip);
+        let i = self.ranges.partition_point(|(start, _, _)| *start <= ip).checked_sub(1)?;
+        let (_, end, asn) = self.ranges[i];
+        (ip <= end).then_some(asn)
+    }
+}
+
+fn key(ip: IpAddr) -> u128 {
+    match ip {
+        IpAddr::V4(ip) => u128::from(ip.to_ipv6_mapped()),
+        IpAddr::V6(ip) => u128::from(ip),
+    }
+}
+
+// the configured table, swapped whole when its file changes; empty without `[asn]`
+#[derive(Debug, Clone, Default)]
+pub struct Asns {
+    table: Arc<RwLock<Arc<AsnTable>>>,
+}
+
+impl Asns {
+    pub fn new(config: Option<&AsnConfig>) -> io::Result<Self> {
+        let table = match config {
+            Some(config) => AsnTable::load(&config.path)?,
+            None => AsnTable::default(),
+        };
+        Ok(Asns { table: Arc::new(RwLock::new(Arc::new(table))) })
+    }
+
+    pub fn lookup(&self, ip: IpAddr) -> Option<u32> {
+        self.table.read().unwrap().lookup(ip)
+    }
+
+    pub fn replace(&self, table: AsnTable) {
+        *self.table.write().unwrap() = Arc::new(table);
+    }
+}
+
+// reload the table whenever its file was modified since the last look, every `reload` seconds
+// until shutdown; a file that fails to load keeps the last good table
+pub async fn reload(asns: Asns, config: AsnConfig, mut shutdown: watch::Receiver<bool>) {
+    let modified = |path: &Path| fs::metadata(path).and_then(|metadata| metadata.modified()).ok();
+    let mut loaded: Option<SystemTime> = modified(&config.path);
+    loop {
+        tokio::select! {
+            _ = shutdown.changed() => break,
+            _ = tokio::time::sleep(Duration::from_secs(config.reload.max(1))) => {}
+        }
+        let now = modified(&config.path);
+        if now.is_none() || now == loaded {
+            continue;
+        }
+        match AsnTable::load(&config.path) {
+            Ok(table) => {
+                info!("asn table {} reloaded, {} ranges", config.path.display(), table.len());
+                asns.replace(table);
+                loaded = now;
+            }
+            Err(e) => warn!("reload {}, keep the last good table", e),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::asn::{AsnTable, Asns, reload};
+    use crate::config::AsnConfig;
+
+    const TABLE: &str = "\
+1.0.0.0\t1.0.0.255\t13335\tUS\tCLOUDFLARENET
+1.0.4.0\t1.0.7.255\t38803\tAU\tWPL-AS-AP
+1.0.8.0\t1.0.15.255\t0\tNone\tNot routed
+2606:4700::\t2606:4700:ffff:ffff:ffff:ffff:ffff:ffff\t13335\tUS\tCLOUDFLARENET
+not a range
+";
+
+    #[test]
+    fn lookup_test() {
+        let table = AsnTable::parse(TABLE);
+        assert_eq!(table.len(), 3);
+        assert_eq!(table.lookup("1.0.0.1".parse().unwrap()), Some(13335));
+        assert_eq!(table.lookup("1.0.5.9".parse().unwrap()), Some(38803));
+        assert_eq!(table.lookup("1.0.1.0".parse().unwrap()), None);
+        assert_eq!(table.lookup("1.0.9.1".parse().unwrap()), None);
+        assert_eq!(table.lookup("2606:4700::1111".parse().unwrap()), Some(13335));
+        assert_eq!(table.lookup("2001:db8::1".parse().unwrap()), None);
+        assert_eq!(table.lookup("0.0.0.1".parse().unwrap()), None);
+    }
+
+    #[tokio::test]
+    async fn reload_test() {
+        let path = std::env::temp_dir().join(format!("rust-ss5-asn-{}", std::process::id()));
+        std::fs::write(&path, "1.0.0.0 1.0.0.255 13335\n").unwrap();
+        let config = AsnConfig { path: path.clone(), reload: 1 };
+        let asns = Asns::new(Some(&config)).unwrap();
+        assert_eq!(asns.lookup("1.0.0.1".parse().unwrap()), Some(13335));
+        let (shutdown, watcher) = tokio::sync::watch::channel(false);
+        let task = tokio::spawn(reload(asns.clone(), config, watcher));
+        // a later mtime than the first write, whatever the file system's resolution
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        std::fs::write(&path, "1.0.0.0 1.0.0.255 64500\n").unwrap();
+        let updated = tokio::time::timeout(Duration::from_secs(5), async {
+            while asns.lookup("1.0.0.1".parse().unwrap()) != Some(64500) {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        }).await;
+        let _ = shutdown.send(true);
+        task.await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(updated.is_ok());
+    }
+}
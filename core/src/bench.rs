@@ -0,0 +1,238 @@
+use std::io;
+use std::time::{Duration, Instant};
+
+use log::debug;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::error::Error;
+use crate::socket5::{Address, Command, Proxy};
+use crate::tcp::TcpSocksClient;
+use crate::transport::Endpoint;
+
+const CHUNK: usize = 16 * 1024;
+const PING_PAYLOAD: &[u8] = b"rust-ss5 ping";
+
+#[derive(Debug, Clone)]
+pub struct BenchReport {
+    pub connect: Duration,
+    pub sent: u64,
+    pub received: u64,
+    pub elapsed: Duration,
+}
+
+impl BenchReport {
+    // bytes per second in both directions together
+    pub fn throughput(&self) -> f64 {
+        (self.sent + self.received) as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+// answered by a server running with `bench` on instead of dialed, never a real host under .invalid
+pub const BENCH_HOST: &str = "rust-ss5.invalid";
+pub const ECHO_PORT: u16 = 7;
+pub const DISCARD_PORT: u16 = 9;
+pub const CHARGEN_PORT: u16 = 19;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Builtin {
+    // sends back what it reads until eof, for checking the tunnel end to end
+    Echo,
+    // reads until eof and sends nothing
+    Discard,
+    // sends until the client goes away
+    Chargen,
+}
+
+impl Builtin {
+    pub fn target(address: &Address) -> Option<Builtin> {
+        match address {
+            Address::DomainName(host, ECHO_PORT) if host == BENCH_HOST => Some(Builtin::Echo),
+            Address::DomainName(host, DISCARD_PORT) if host == BENCH_HOST => Some(Builtin::Discard),
+            Address::DomainName(host, CHARGEN_PORT) if host == BENCH_HOST => Some(Builtin::Chargen),
+            _ => None,
+        }
+    }
+
+    // echo with `diag` on, all of them with `bench`
+    pub fn enabled(self, diag: bool, bench: bool) -> bool {
+        bench || (diag && self == Builtin::Echo)
+    }
+
+    // the server side, run on the client's stream once the request was answered
+    pub async fn serve<S>(self, mut stream: S) -> Result<(), Error>
+        where S: AsyncRead + AsyncWrite + Unpin
+    {
+        let mut buf = vec![0; CHUNK];
+        match self {
+            Builtin::Echo => {
+                loop {
+                    let n = stream.read(&mut buf).await?;
+                    if n == 0 {
+                        break;
+                    }
+                    stream.write_all(&buf[..n]).await?;
+                }
+                stream.shutdown().await?;
+            }
+            Builtin::Discard => {
+                while stream.read(&mut buf).await? > 0 {}
+                stream.shutdown().await?;
+            }
+            Builtin::Chargen => {
+                for (i, b) in buf.iter_mut().enumerate() {
+                    *b = b' ' + (i % 95) as u8;
+                }
+                loop {
+                    match stream.write_all(&buf).await {
+                        Ok(()) => {}
+                        // the client read what it wanted and closed
+                        Err(e) if matches!(e.kind(), io::ErrorKind::BrokenPipe | io::ErrorKind::ConnectionReset) => break,
+                        Err(e) => return Err(e.into()),
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// push `bytes` to the target through the server while reading what it sends back,
+// until eof or, with `pull` above 0, until that much arrived
+pub async fn bench(server: &Endpoint, target: Address, bytes: u64, pull: u64) -> Result<BenchReport, Error> {
+    let proxy = Proxy::build(Command::CONNECT, target)?;
+    let start = Instant::now();
+    let client = TcpSocksClient::client_connect_endpoint(server, proxy).await?;
+    let connect = start.elapsed();
+    let (mut read, mut write) = tokio::io::split(client.stream);
+    let chunk = vec![0; CHUNK];
+    let writer = async move {
+        let mut left = bytes;
+        while left > 0 {
+            let n = left.min(CHUNK as u64) as usize;
+            write.write_all(&chunk[..n]).await?;
+            left -= n as u64;
+        }
+        write.shutdown().await?;
+        Ok::<u64, Error>(bytes)
+    };
+    let reader = async move {
+        let mut buf = vec![0; CHUNK];
+        let mut received = 0;
+        loop {
+            let n = read.read(&mut buf).await?;
+            received += n as u64;
+            if n == 0 || (pull > 0 && received >= pull) {
+                return Ok::<u64, Error>(received);
+            }
+        }
+    };
+    let (sent, received) = tokio::try_join!(writer, reader)?;
+    Ok(BenchReport {
+        connect,
+        sent,
+        received,
+        elapsed: start.elapsed() - connect,
+    })
+}
+
+#[derive(Debug, Clone)]
+pub struct PingReport {
+    // one per successful round, sorted
+    pub samples: Vec<Duration>,
+    pub failed: usize,
+}
+
+impl PingReport {
+    // nearest rank, None without a successful round
+    pub fn percentile(&self, p: f64) -> Option<Duration> {
+        if self.samples.is_empty() {
+            return None;
+        }
+        let rank = (p / 100.0 * self.samples.len() as f64).ceil() as usize;
+        Some(self.samples[rank.clamp(1, self.samples.len()) - 1])
+    }
+}
+
+// `count` rounds of a fresh connection, the socks handshake and a small echo off the target
+pub async fn ping(server: &Endpoint, target: Address, count: usize) -> Result<PingReport, Error> {
+    let proxy = Proxy::build(Command::CONNECT, target)?;
+    let mut samples = Vec::with_capacity(count);
+    let mut failed = 0;
+    for _ in 0..count {
+        match ping_once(server, proxy.clone()).await {
+            Ok(sample) => samples.push(sample),
+            Err(e) => {
+                debug!("ping {} fail : {:?}", server, e);
+                failed += 1;
+            }
+        }
+    }
+    samples.sort();
+    Ok(PingReport { samples, failed })
+}
+
+async fn ping_once(server: &Endpoint, proxy: Proxy) -> Result<Duration, Error> {
+    let start = Instant::now();
+    let mut client = TcpSocksClient::client_connect_endpoint(server, proxy).await?;
+    client.stream.write_all(PING_PAYLOAD).await?;
+    let mut buf = [0; PING_PAYLOAD.len()];
+    client.stream.read_exact(&mut buf).await?;
+    Ok(start.elapsed())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::bench::{bench, BENCH_HOST, CHARGEN_PORT, DISCARD_PORT, ECHO_PORT, ping, PingReport};
+    use crate::config::ServerConfig;
+    use crate::socket5::Address;
+    use crate::test_util::{config, echo_server, socks_server, test_state};
+    use crate::transport::Endpoint;
+
+    #[test]
+    fn percentile_test() {
+        let report = PingReport { samples: (1..=100).map(Duration::from_millis).collect(), failed: 0 };
+        assert_eq!(report.percentile(50.0), Some(Duration::from_millis(50)));
+        assert_eq!(report.percentile(99.0), Some(Duration::from_millis(99)));
+        assert_eq!(report.percentile(0.0), Some(Duration::from_millis(1)));
+        assert_eq!(PingReport { samples: Vec::new(), failed: 3 }.percentile(50.0), None);
+    }
+
+    #[tokio::test]
+    async fn builtin_test() {
+        let server = socks_server(Endpoint::Tcp("127.0.0.1:0".to_string()), test_state(ServerConfig {
+            bench: true,
+            ..config()
+        })).await;
+        let discard = bench(&server, Address::domain(BENCH_HOST, DISCARD_PORT).unwrap(), 1 << 20, 0).await.unwrap();
+        assert_eq!((discard.sent, discard.received), (1 << 20, 0));
+        let chargen = bench(&server, Address::domain(BENCH_HOST, CHARGEN_PORT).unwrap(), 0, 1 << 20).await.unwrap();
+        assert!(chargen.received >= 1 << 20);
+        let echo = bench(&server, Address::domain(BENCH_HOST, ECHO_PORT).unwrap(), 1 << 20, 0).await.unwrap();
+        assert_eq!((echo.sent, echo.received), (1 << 20, 1 << 20));
+
+        // without bench the name is looked up like any other and fails
+        let server = socks_server(Endpoint::Tcp("127.0.0.1:0".to_string()), test_state(config())).await;
+        assert!(bench(&server, Address::domain(BENCH_HOST, DISCARD_PORT).unwrap(), 1, 0).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn ping_test() {
+        let target = Address::Address(echo_server().await);
+        let server = socks_server(Endpoint::Tcp("127.0.0.1:0".to_string()), test_state(config())).await;
+        let report = ping(&server, target, 5).await.unwrap();
+        assert_eq!(report.samples.len(), 5);
+        assert_eq!(report.failed, 0);
+
+        // the server's own echo, diag alone doesn't answer discard and chargen
+        let server = socks_server(Endpoint::Tcp("127.0.0.1:0".to_string()), test_state(ServerConfig {
+            diag: true,
+            ..config()
+        })).await;
+        let report = ping(&server, "rust-ss5.invalid:7".parse().unwrap(), 3).await.unwrap();
+        assert_eq!((report.samples.len(), report.failed), (3, 0));
+        assert!(bench(&server, Address::domain(BENCH_HOST, DISCARD_PORT).unwrap(), 1, 0).await.is_err());
+    }
+}
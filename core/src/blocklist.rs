@@ -0,0 +1,294 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use ed25519_dalek::{Signature, VerifyingKey};
+use log::{info, warn};
+use tokio::sync::watch;
+
+use crate::config::{BlocklistConfig, BlockRule, RemoteListConfig};
+use crate::crypto::decode_key;
+use crate::error::Error;
+use crate::socket5::Address;
+
+// domains refused with RepHostNo, a listed domain covers everything under it
+#[derive(Debug, Clone, Default)]
+pub struct Blocklist {
+    domains: HashSet<String>,
+    rules: Vec<BlockRule>,
+    // the last good fetch of each remote list by url, swapped whole on refresh
+    remote: Arc<RwLock<HashMap<String, HashSet<String>>>>,
+}
+
+impl Blocklist {
+    pub fn new(config: &BlocklistConfig) -> io::Result<Self> {
+        let mut blocklist = Blocklist { rules: config.rules.clone(), ..Blocklist::default() };
+        for domain in &config.domains {
+            blocklist.insert(domain);
+        }
+        for path in &config.files {
+            blocklist.load(path)?;
+        }
+        Ok(blocklist)
+    }
+
+    pub fn load<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)
+            .map_err(|e| io::Error::new(e.kind(), format!("blocklist {} : {}", path.display(), e)))?;
+        for line in text.lines() {
+            if let Some(domain) = parse_line(line) {
+                self.insert(domain);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn insert(&mut self, domain: &str) {
+        if let Some(domain) = normalize(domain) {
+            self.domains.insert(domain);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.domains.len() + self.remote.read().unwrap().values().map(|domains| domains.len()).sum::<usize>()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0 && self.rules.is_empty()
+    }
+
+    pub fn replace_remote(&self, url: &str, text: &str) -> usize {
+        let domains: HashSet<String> = text.lines().filter_map(parse_line).filter_map(normalize).collect();
+        let len = domains.len();
+        self.remote.write().unwrap().insert(url.to_string(), domains);
+        len
+    }
+
+    // the first matching rule, else one lookup per label: "a.b.example.com" tries itself,
+    // "b.example.com", "example.com" and "com"
+    pub fn contains(&self, host: &str) -> bool {
+        if let Some(rule) = self.rules.iter().find(|rule| rule.pattern.matches(host)) {
+            return !rule.allow;
+        }
+        let remote = self.remote.read().unwrap();
+        if self.domains.is_empty() && remote.is_empty() {
+            return false;
+        }
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        let mut rest = host.as_str();
+        loop {
+            if self.domains.contains(rest) || remote.values().any(|domains| domains.contains(rest)) {
+                return true;
+            }
+            match rest.split_once('.') {
+                Some((_, parent)) => rest = parent,
+                None => return false,
+            }
+        }
+    }
+
+    // only domain targets are matched, a client sending an ip already resolved it
+    pub fn check(&self, address: &Address) -> Result<(), Error> {
+        match address {
+            Address::DomainName(host, _) if self.contains(host) => Err(Error::Blocked(host.clone())),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn normalize(domain: &str) -> Option<String> {
+    let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+    (!domain.is_empty()).then_some(domain)
+}
+
+// fetch the list now and every refresh until shutdown, applying it only when signed by the key
+pub async fn refresh(blocklist: Arc<Blocklist>, config: RemoteListConfig, mut shutdown: watch::Receiver<bool>) {
+    loop {
+        match fetch(&config).await {
+            Ok(text) => {
+                let len = blocklist.replace_remote(&config.url, &text);
+                info!("blocklist {} refreshed, {} domains", config.url, len);
+            }
+            Err(e) => warn!("fetch blocklist {} fail, keep the last good list : {}", config.url, e),
+        }
+        tokio::select! {
+            _ = shutdown.changed() => break,
+            _ = tokio::time::sleep(Duration::from_secs(config.refresh.max(1))) => {}
+        }
+    }
+}
+
+async fn fetch(config: &RemoteListConfig) -> Result<String, String> {
+    let key = config.public_key().ok_or("key isn't a base64 ed25519 public key")?;
+    let agent: ureq::Agent = ureq::Agent::config_builder()
+        .timeout_global(Some(Duration::from_secs(config.timeout.max(1))))
+        .build()
+        .into();
+    let (url, signature_url) = (config.url.clone(), config.signature_url());
+    let (list, signature) = tokio::task::spawn_blocking(move || {
+        let get = |url: &str| agent.get(url).call()
+            .map_err(|e| format!("{} : {}", url, e))?
+            .into_body()
+            .read_to_vec()
+            .map_err(|e| format!("{} : {}", url, e));
+        Ok::<_, String>((get(&url)?, get(&signature_url)?))
+    }).await.map_err(|e| e.to_string())??;
+    verified(&key, list, &signature)
+}
+
+// the list's text if the signature, base64 in the signature file, is over exactly its bytes
+pub fn verified(key: &[u8; 32], list: Vec<u8>, signature: &[u8]) -> Result<String, String> {
+    let signature: [u8; 64] = std::str::from_utf8(signature).ok()
+        .and_then(decode_key)
+        .and_then(|signature| signature.try_into().ok())
+        .ok_or("signature isn't 64 bytes of base64")?;
+    // verify_strict also turns down small order keys and non canonical or malleated signatures
+    VerifyingKey::from_bytes(key)
+        .and_then(|key| key.verify_strict(&list, &Signature::from_bytes(&signature)))
+        .map_err(|_| "signature doesn't match the list".to_string())?;
+    String::from_utf8(list).map_err(|_| "list isn't utf-8".to_string())
+}
+
+// "0.0.0.0 ads.example.com", "||ads.example.com^" or "ads.example.com", comments and other rules skipped
+fn parse_line(line: &str) -> Option<&str> {
+    let line = line.split('#').next().unwrap_or_default().trim();
+    if line.is_empty() || line.starts_with('!') || line.starts_with('[') {
+        return None;
+    }
+    if let Some(rule) = line.strip_prefix("||") {
+        // "||example.com^$third-party" only applies in some contexts, it isn't a plain block
+        return rule.strip_suffix('^').filter(|d| is_domain(d));
+    }
+    let mut fields = line.split_whitespace();
+    let first = fields.next()?;
+    let domain = match fields.next() {
+        Some(host) if first.parse::<std::net::IpAddr>().is_ok() => host,
+        Some(_) => return None,
+        None => first,
+    };
+    // hosts files map these to themselves, they aren't entries to block
+    if matches!(domain, "localhost" | "localhost.localdomain" | "local" | "broadcasthost") {
+        return None;
+    }
+    Some(domain).filter(|d| is_domain(d))
+}
+
+fn is_domain(domain: &str) -> bool {
+    !domain.is_empty() && domain.len() <= 255
+        && domain.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'.' || b == b'_')
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::blocklist::{Blocklist, parse_line, verified};
+    use crate::config::{BlocklistConfig, BlockRule, RemoteListConfig};
+    use crate::crypto::encode_key;
+
+    #[test]
+    fn parse_line_test() {
+        assert_eq!(parse_line("0.0.0.0 ads.example.com # tracker"), Some("ads.example.com"));
+        assert_eq!(parse_line("127.0.0.1 localhost"), None);
+        assert_eq!(parse_line("||ads.example.com^"), Some("ads.example.com"));
+        assert_eq!(parse_line("||ads.example.com^$third-party"), None);
+        assert_eq!(parse_line("! adblock comment"), None);
+        assert_eq!(parse_line("[Adblock Plus 2.0]"), None);
+        assert_eq!(parse_line("tracker.example.net"), Some("tracker.example.net"));
+        assert_eq!(parse_line("/banner/*.gif"), None);
+    }
+
+    #[test]
+    fn blocklist_test() {
+        let path = std::env::temp_dir().join(format!("rust-ss5-blocklist-{}", std::process::id()));
+        std::fs::write(&path, "0.0.0.0 ads.example.com\n||Tracker.example.net^\n").unwrap();
+        let blocklist = Blocklist::new(&BlocklistConfig {
+            files: vec![path.clone()],
+            domains: vec!["evil.org".to_string()],
+            ..BlocklistConfig::default()
+        }).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(blocklist.len(), 3);
+        assert!(blocklist.contains("ads.example.com"));
+        assert!(blocklist.contains("cdn.ads.example.com."));
+        assert!(blocklist.contains("TRACKER.example.net"));
+        assert!(blocklist.contains("www.evil.org"));
+        assert!(!blocklist.contains("example.com"));
+        assert!(!blocklist.contains("notevil.org"));
+        assert!(Blocklist::new(&BlocklistConfig { files: vec!["/nonexistent/list".into()], ..BlocklistConfig::default() }).is_err());
+    }
+
+    #[test]
+    fn block_rules_test() {
+        let rule = |pattern: &str, allow| BlockRule { pattern: pattern.parse().unwrap(), allow };
+        let blocklist = Blocklist::new(&BlocklistConfig {
+            domains: vec!["example.com".to_string()],
+            rules: vec![
+                rule("www.example.com", true),
+                rule("*.ads.example.org", false),
+                rule(r"regex:^track(er)?[0-9]*\.", false),
+            ],
+            ..BlocklistConfig::default()
+        }).unwrap();
+        // the allow rule comes first and wins over the listed parent domain
+        assert!(!blocklist.contains("www.example.com"));
+        assert!(blocklist.contains("mail.example.com"));
+        assert!(blocklist.contains("x.ads.example.org"));
+        assert!(!blocklist.contains("ads.example.org"));
+        assert!(blocklist.contains("tracker7.example.net"));
+        assert!(!blocklist.contains("www.tracker.example.net"));
+    }
+
+    #[test]
+    fn remote_test() {
+        // RFC 8032 section 7.1 test 2, whose message "r" happens to be a one domain list
+        let hex = |text: &str| -> Vec<u8> { (0..text.len()).step_by(2).map(|i| u8::from_str_radix(&text[i..i + 2], 16).unwrap()).collect() };
+        let key: [u8; 32] = hex("3d4017c3e843895a92b70aa74d1b7ebc9c982ccf2ec4968cc0cd55f12af4660c").try_into().unwrap();
+        let signature = encode_key(&hex("92a009a9f0d4cab8720e820b5f642540a2b27b5416503f8fb3762223ebdb69da085ac1e43e15996e458f3613d0f11d8c387b2eaeb4302aeeb00d291612bb0c00"));
+        assert_eq!(verified(&key, b"r".to_vec(), signature.as_bytes()), Ok("r".to_string()));
+        assert!(verified(&key, b"s".to_vec(), signature.as_bytes()).is_err());
+        assert!(verified(&key, b"r".to_vec(), b"not base64").is_err());
+        // S + L verifies under the cofactored equation too, but isn't the canonical encoding
+        let mut malleated = hex("92a009a9f0d4cab8720e820b5f642540a2b27b5416503f8fb3762223ebdb69da085ac1e43e15996e458f3613d0f11d8c387b2eaeb4302aeeb00d291612bb0c00");
+        let order = hex("edd3f55c1a631258d69cf7a2def9de1400000000000000000000000000000010");
+        let mut carry = 0;
+        for (byte, add) in malleated[32..].iter_mut().zip(order) {
+            let sum = *byte as u16 + add as u16 + carry;
+            (*byte, carry) = (sum as u8, sum >> 8);
+        }
+        assert!(verified(&key, b"r".to_vec(), encode_key(&malleated).as_bytes()).is_err());
+        // the identity as key with R = identity and S = 0 holds for any message
+        let mut identity = [0; 32];
+        identity[0] = 1;
+        let mut forged = [0; 64];
+        forged[0] = 1;
+        assert!(verified(&identity, b"r".to_vec(), encode_key(&forged).as_bytes()).is_err());
+        let remote = |key: &[u8]| RemoteListConfig {
+            url: "https://example.com/list".to_string(),
+            signature: None,
+            key: encode_key(key),
+            refresh: 1,
+            timeout: 1,
+        };
+        assert_eq!(remote(&key).public_key(), Some(key));
+        assert_eq!(remote(&identity).public_key(), None);
+        // y = 2 has no x on the curve
+        let mut invalid = [0; 32];
+        invalid[0] = 2;
+        assert_eq!(remote(&invalid).public_key(), None);
+        assert_eq!(remote(&key[..31]).public_key(), None);
+
+        let blocklist = Blocklist::new(&BlocklistConfig { domains: vec!["evil.org".to_string()], ..BlocklistConfig::default() }).unwrap();
+        assert_eq!(blocklist.replace_remote("https://example.com/list", "0.0.0.0 ads.example.com\n"), 1);
+        assert!(blocklist.contains("cdn.ads.example.com"));
+        assert!(blocklist.contains("evil.org"));
+        // a refresh replaces the list, the configured domains stay
+        blocklist.clone().replace_remote("https://example.com/list", "tracker.example.net");
+        assert!(!blocklist.contains("ads.example.com"));
+        assert!(blocklist.contains("tracker.example.net"));
+        assert_eq!(blocklist.len(), 2);
+    }
+}
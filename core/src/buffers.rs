@@ -0,0 +1,139 @@
+use std::collections::HashMap;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Mutex, OnceLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// idle buffers kept across all sizes, what's handed back past this is freed
+const MAX_IDLE_BYTES: usize = 64 * 1024 * 1024;
+
+// relay and udp buffers handed back on drop for the next connection, so a busy server isn't
+// allocating and freeing a few buffers per connection; sizes come from the config, so each
+// size is its own free list
+#[derive(Default)]
+pub struct BufferPool {
+    free: Mutex<Free>,
+    allocated: AtomicU64,
+    reused: AtomicU64,
+}
+
+#[derive(Default)]
+struct Free {
+    sizes: HashMap<usize, Vec<Box<[u8]>>>,
+    bytes: usize,
+}
+
+impl BufferPool {
+    // the one every relay and association takes from
+    pub fn global() -> &'static BufferPool {
+        static POOL: OnceLock<BufferPool> = OnceLock::new();
+        POOL.get_or_init(BufferPool::default)
+    }
+
+    // a zeroed buffer the first time, a previous user's leftovers after that
+    pub fn get(&self, size: usize) -> Buffer<'_> {
+        let reused = {
+            let mut free = self.free.lock().unwrap();
+            let buf = free.sizes.get_mut(&size).and_then(Vec::pop);
+            if buf.is_some() {
+                free.bytes -= size;
+            }
+            buf
+        };
+        let buf = match reused {
+            Some(buf) => {
+                self.reused.fetch_add(1, Ordering::Relaxed);
+                buf
+            }
+            None => {
+                self.allocated.fetch_add(1, Ordering::Relaxed);
+                vec![0; size].into_boxed_slice()
+            }
+        };
+        Buffer { buf: Some(buf), pool: self }
+    }
+
+    fn put(&self, buf: Box<[u8]>) {
+        let mut free = self.free.lock().unwrap();
+        if free.bytes + buf.len() > MAX_IDLE_BYTES {
+            return;
+        }
+        free.bytes += buf.len();
+        free.sizes.entry(buf.len()).or_default().push(buf);
+    }
+
+    pub fn stats(&self) -> BufferStats {
+        let free = self.free.lock().unwrap();
+        BufferStats {
+            allocated: self.allocated.load(Ordering::Relaxed),
+            reused: self.reused.load(Ordering::Relaxed),
+            idle: free.sizes.values().map(Vec::len).sum::<usize>() as u64,
+            idle_bytes: free.bytes as u64,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BufferStats {
+    // buffers made because none of the size was free
+    pub allocated: u64,
+    pub reused: u64,
+    pub idle: u64,
+    pub idle_bytes: u64,
+}
+
+// back to its pool when dropped
+pub struct Buffer<'a> {
+    buf: Option<Box<[u8]>>,
+    pool: &'a BufferPool,
+}
+
+impl Deref for Buffer<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.buf.as_deref().unwrap_or_default()
+    }
+}
+
+impl DerefMut for Buffer<'_> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        self.buf.as_deref_mut().unwrap_or_default()
+    }
+}
+
+impl Drop for Buffer<'_> {
+    fn drop(&mut self) {
+        if let Some(buf) = self.buf.take() {
+            self.pool.put(buf);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::buffers::{BufferPool, BufferStats, MAX_IDLE_BYTES};
+
+    #[test]
+    fn pool_test() {
+        let pool = BufferPool::default();
+        let mut first = pool.get(8192);
+        assert_eq!(first.len(), 8192);
+        first[0] = 7;
+        drop(first);
+        assert_eq!(pool.stats(), BufferStats { allocated: 1, reused: 0, idle: 1, idle_bytes: 8192 });
+
+        // the same size comes back, another one is made
+        let again = pool.get(8192);
+        assert_eq!(again[0], 7);
+        let other = pool.get(1024);
+        assert_eq!(pool.stats(), BufferStats { allocated: 2, reused: 1, idle: 0, idle_bytes: 0 });
+        drop((again, other));
+        assert_eq!(pool.stats().idle_bytes, 8192 + 1024);
+
+        // past the idle budget buffers are freed
+        let big = pool.get(MAX_IDLE_BYTES);
+        drop(big);
+        assert_eq!(pool.stats().idle, 2);
+    }
+}
@@ -0,0 +1,1698 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::de::DeserializeOwned;
+use toml::de::{DeTable, DeValue};
+use serde::{Deserialize, Serialize};
+
+use crate::crypto::{CryptoError, Keyring};
+use crate::obfs::ObfsMode;
+use crate::policy::Cidr;
+use crate::rules::Pattern;
+use crate::socket5::Address;
+use crate::transport::{Endpoint, ListenOptions, PortRange};
+
+pub const DEFAULT_HOST: &str = "127.0.0.1";
+pub const DEFAULT_SERVER_PORT: u16 = 9999;
+// the largest payload an ipv4 or ipv6 udp datagram can carry
+pub const MAX_UDP_PAYLOAD: usize = 65535;
+pub const DEFAULT_LOCAL_PORT: u16 = 1080;
+pub const DEFAULT_METHOD: &str = "chacha20-ietf-poly1305";
+// where windows keeps local named pipes
+const PIPE_NAMESPACE: &str = r"\\.\pipe\";
+// included files may include others, this deep at most, which also stops a file including itself
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+#[derive(Debug)]
+pub enum ConfigError {
+    IoError(io::Error),
+    ParseError(toml::de::Error),
+    CryptoError(CryptoError),
+    // an environment override that doesn't parse
+    EnvError(String, String),
+    // --profile names a table the file has no [profile.<name>] for
+    UnknownProfile(String),
+    // a file named by `include` that can't be read or parsed
+    IncludeError(PathBuf, String),
+    // what check() found, ServerConfigBuilder::build and server::start refuse the config for it
+    Invalid(Vec<String>),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::IoError(e) => write!(f, "read config fail : {}", e),
+            ConfigError::ParseError(e) => write!(f, "parse config fail : {}", e),
+            ConfigError::CryptoError(e) => write!(f, "{}", e),
+            ConfigError::EnvError(name, value) => write!(f, "invalid {} : {}", name, value),
+            ConfigError::UnknownProfile(name) => write!(f, "no [profile.{}] in the config", name),
+            ConfigError::IncludeError(path, e) => write!(f, "include {} : {}", path.display(), e),
+            ConfigError::Invalid(problems) => write!(f, "invalid config : {}", problems.join("; ")),
+        }
+    }
+}
+
+impl From<io::Error> for ConfigError {
+    fn from(err: io::Error) -> Self {
+        ConfigError::IoError(err)
+    }
+}
+
+impl From<CryptoError> for ConfigError {
+    fn from(err: CryptoError) -> Self {
+        ConfigError::CryptoError(err)
+    }
+}
+
+impl From<toml::de::Error> for ConfigError {
+    fn from(err: toml::de::Error) -> Self {
+        ConfigError::ParseError(err)
+    }
+}
+
+// read a toml config file, missing keys take their defaults
+pub fn load<T: DeserializeOwned, P: AsRef<Path>>(path: P) -> Result<T, ConfigError> {
+    load_profile(path, None)
+}
+
+pub fn load_profile<T: DeserializeOwned, P: AsRef<Path>>(path: P, profile: Option<&str>) -> Result<T, ConfigError> {
+    let path = path.as_ref();
+    let content = std::fs::read_to_string(path)?;
+    let mut table: toml::Table = content.parse()?;
+    if !table.contains_key("include") {
+        return from_profile(&content, profile);
+    }
+    include(&mut table, path.parent().unwrap_or(Path::new(".")), 0)?;
+    select(table, profile)
+}
+
+// the top-level settings with the `[profile.<name>]` table merged over them, its own tables key
+// by key; without a profile the [profile.*] tables are left out
+pub fn from_profile<T: DeserializeOwned>(content: &str, profile: Option<&str>) -> Result<T, ConfigError> {
+    let table: toml::Table = content.parse()?;
+    if !table.contains_key("profile") && profile.is_none() {
+        // parsed from the text, so type errors still say which line
+        return Ok(toml::from_str(content)?);
+    }
+    select(table, profile)
+}
+
+fn select<T: DeserializeOwned>(mut table: toml::Table, profile: Option<&str>) -> Result<T, ConfigError> {
+    let profiles = table.remove("profile");
+    if let Some(name) = profile {
+        let chosen = match profiles {
+            Some(toml::Value::Table(mut profiles)) => profiles.remove(name),
+            _ => None,
+        };
+        match chosen {
+            Some(toml::Value::Table(chosen)) => merge(&mut table, chosen),
+            _ => return Err(ConfigError::UnknownProfile(name.to_string())),
+        }
+    }
+    Ok(toml::Value::Table(table).try_into()?)
+}
+
+// `include = ["rules/*.toml", "users.toml"]`, paths relative to the including file, `*` and `?`
+// only in the file name; a pattern matching nothing is fine, a plain path has to exist.
+// what the file sets itself wins over its includes, arrays such as [[users]] are joined
+fn include(table: &mut toml::Table, dir: &Path, depth: usize) -> Result<(), ConfigError> {
+    let Some(patterns) = table.remove("include") else {
+        return Ok(());
+    };
+    let patterns: Vec<String> = patterns.try_into()?;
+    for pattern in patterns {
+        let pattern = dir.join(pattern);
+        if depth >= MAX_INCLUDE_DEPTH {
+            return Err(ConfigError::IncludeError(pattern, "includes nested too deep".to_string()));
+        }
+        for path in expand(&pattern).map_err(|e| ConfigError::IncludeError(pattern.clone(), e.to_string()))? {
+            let fail = |e: String| ConfigError::IncludeError(path.clone(), e);
+            let content = std::fs::read_to_string(&path).map_err(|e| fail(e.to_string()))?;
+            let mut included: toml::Table = content.parse().map_err(|e: toml::de::Error| fail(e.to_string()))?;
+            include(&mut included, path.parent().unwrap_or(Path::new(".")), depth + 1)?;
+            join(table, included);
+        }
+    }
+    Ok(())
+}
+
+// the files a pattern names, sorted so the order doesn't depend on the directory
+fn expand(pattern: &Path) -> io::Result<Vec<PathBuf>> {
+    let name = pattern.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+    if !name.contains(['*', '?']) {
+        return Ok(vec![pattern.to_path_buf()]);
+    }
+    let dir = pattern.parent().unwrap_or(Path::new("."));
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut paths = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        if entry.file_type()?.is_file() && entry.file_name().to_str().is_some_and(|file| wildcard(name.as_bytes(), file.as_bytes())) {
+            paths.push(entry.path());
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+fn wildcard(pattern: &[u8], name: &[u8]) -> bool {
+    match (pattern.first(), name.first()) {
+        (None, None) => true,
+        (Some(b'*'), _) => wildcard(&pattern[1..], name) || (!name.is_empty() && wildcard(pattern, &name[1..])),
+        (Some(b'?'), Some(_)) => wildcard(&pattern[1..], &name[1..]),
+        (Some(p), Some(n)) if p == n => wildcard(&pattern[1..], &name[1..]),
+        _ => false,
+    }
+}
+
+// an included table under the including one: keys it doesn't have are added, arrays appended
+fn join(base: &mut toml::Table, included: toml::Table) {
+    for (key, value) in included {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(included)) => join(base, included),
+            (Some(toml::Value::Array(base)), toml::Value::Array(included)) => base.extend(included),
+            (Some(_), _) => {}
+            (None, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+// tables merge, anything else in over replaces what base has
+fn merge(base: &mut toml::Table, over: toml::Table) {
+    for (key, value) in over {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(over)) => merge(base, over),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+// where a config file sets its keys, to point the problems check() finds at their line; the
+// profile's table is looked at before the top level, as it's what the config was merged from
+pub struct Spans<'a> {
+    content: &'a str,
+    table: DeTable<'a>,
+    profile: Option<&'a str>,
+}
+
+impl<'a> Spans<'a> {
+    pub fn parse(content: &'a str, profile: Option<&'a str>) -> Result<Self, ConfigError> {
+        Ok(Spans { content, table: DeTable::parse(content)?.into_inner(), profile })
+    }
+
+    // line and column, from 1, of the key a problem starts with, or of the entry it names in a
+    // list such as [[users]] or blocklist.files; None for what the file doesn't set itself, a
+    // default, an environment variable or an include
+    pub fn locate(&self, problem: &str) -> Option<(usize, usize)> {
+        let (field, message) = problem.split_once(" : ")?;
+        let profile = self.profile.and_then(|name| self.table.get("profile")?.get_ref().as_table()?.get(name)?.get_ref().as_table());
+        let span = field.split(" / ").find_map(|field| {
+            profile.and_then(|table| find_span(table, field, message)).or_else(|| find_span(&self.table, field, message))
+        })?;
+        let before = &self.content[..span.start];
+        let line = before.matches('\n').count() + 1;
+        let column = before.rsplit('\n').next().unwrap_or_default().chars().count() + 1;
+        Some((line, column))
+    }
+}
+
+// the span of a dotted key, going into the entry of a list the message is about
+fn find_span(table: &DeTable, field: &str, message: &str) -> Option<std::ops::Range<usize>> {
+    let (name, rest) = match field.split_once('.') {
+        Some((name, rest)) => (name, Some(rest)),
+        None => (field, None),
+    };
+    let (key, value) = table.get_key_value(name)?;
+    match (value.get_ref(), rest) {
+        (DeValue::Table(table), Some(rest)) => find_span(table, rest, message),
+        (DeValue::Array(items), rest) => match (named_entry(items, message).map(|item| (item.get_ref(), item)), rest) {
+            (Some((DeValue::Table(table), _)), Some(rest)) => find_span(table, rest, message),
+            (Some((_, item)), None) => Some(item.span()),
+            _ => Some(key.span()),
+        },
+        (_, None) => Some(key.span()),
+        _ => None,
+    }
+}
+
+// the last entry whose name, pattern, url or value is a word of the message, as check() quotes
+// it; else the first without one, e.g. a user with no name or a rule matching everything
+fn named_entry<'t, 'i>(items: &'t [toml::Spanned<DeValue<'i>>], message: &str) -> Option<&'t toml::Spanned<DeValue<'i>>> {
+    let identity = |item: &toml::Spanned<DeValue>| -> String {
+        match item.get_ref() {
+            DeValue::String(value) => value.to_string(),
+            DeValue::Table(table) => ["name", "pattern", "url"].iter()
+                .find_map(|key| table.get(*key)?.get_ref().as_str().map(str::to_string))
+                .unwrap_or_default(),
+            _ => String::new(),
+        }
+    };
+    let words: Vec<&str> = message.split_whitespace().collect();
+    items.iter().rev().find(|item| words.contains(&identity(item).as_str()))
+        .or_else(|| items.iter().find(|item| identity(item).is_empty()))
+}
+
+// only built by the builder, default() or a config file, and checked again by server::start
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+#[non_exhaustive]
+pub struct ServerConfig {
+    pub host: String,
+    pub port: u16,
+    pub password: String,
+    pub encrypt: String,
+    // base64 keys accepted besides the password one, for rotating keys
+    pub keys: Vec<String>,
+    // also accept socks connections on this unix domain socket
+    pub unix: Option<PathBuf>,
+    pub quota: QuotaConfig,
+    pub users: Vec<UserConfig>,
+    pub udp: UdpConfig,
+    pub pool: PoolConfig,
+    // expect the tunnel wrapped in http or tls framing
+    pub obfs: Option<ObfsMode>,
+    pub probe: ProbeConfig,
+    // export connection spans over OTLP/HTTP when set
+    pub trace: Option<TraceConfig>,
+    pub backlog: u32,
+    // one SO_REUSEPORT listener per acceptor on the tcp endpoint, linux only
+    pub reuse_port: bool,
+    // 0 is one per cpu
+    pub acceptors: usize,
+    // seconds a stopping server waits for open connections to finish before it exits, 0 exits at
+    // once; with reuse_port the upgraded binary can listen on the same port meanwhile
+    pub drain_timeout: u64,
+    // targets allowed to come back to this server, other requests looping back are refused
+    pub hairpin: Vec<Address>,
+    // refuse private, loopback and link-local destinations, ipv4 ones behind an ipv6 address included,
+    // unset blocks them unless host is loopback
+    pub block_private: Option<bool>,
+    // where relay sockets get their ports, so a firewall only has to open this range
+    pub relay_ports: Option<PortRange>,
+    // source ranges that may connect at all, others are closed before a byte is read; empty allows any
+    pub allow_sources: Vec<Cidr>,
+    pub rate_limit: RateLimitConfig,
+    pub auth_ban: AuthBanConfig,
+    // answer rust-ss5.invalid:7, :9 and :19 as echo, discard and chargen, for `bench` without a
+    // target of your own
+    pub bench: bool,
+    // answer rust-ss5.invalid:7 with an echo in the server itself, so clients and monitoring can
+    // check the tunnel end to end without a target of their own
+    pub diag: bool,
+    pub blocklist: BlocklistConfig,
+    // host:port serving /metrics in the prometheus text format
+    pub metrics: Option<String>,
+    // the bearer token POST and DELETE /maintenance on the metrics listener take, without one
+    // they're refused
+    pub metrics_token: Option<String>,
+    // open connections to one destination host across all clients, 0 is unlimited
+    pub target_limit: usize,
+    // open connections from one client ip, 0 is unlimited
+    pub source_limit: usize,
+    // answer new requests with a server failure while the server is past these, leaving open relays alone
+    pub shed: ShedConfig,
+    // log each client's method list, handshake timing and request shape
+    pub fingerprint: bool,
+    // read the tls server name or http Host of connections to ip targets, so the blocklist still sees a domain
+    pub sniff: bool,
+    // milliseconds a connection may stay silent before it's relayed unsniffed
+    pub sniff_timeout: u64,
+    // one json record per connection in a rotated file
+    pub access_log: Option<AccessLogConfig>,
+    pub relay: RelayConfig,
+    pub reject: RejectMode,
+    pub priority: PriorityConfig,
+    pub resolver: ResolverConfig,
+    // "auto" or a prefix like "64:ff9b::/96", ipv4 targets are dialed through nat64 on ipv6-only networks
+    pub nat64: Option<String>,
+    // the first rule matching a connect target picks the address or interface it leaves from,
+    // e.g. a second uplink for some destinations; the rest go out as the routing table says
+    pub outbound: Vec<OutboundRule>,
+    // the first rule matching a connect target caps the bytes each connection to it may move,
+    // both ways together, e.g. 10 GB on a shared server; other connections move as much as they like
+    pub transfer: Vec<TransferRule>,
+    // a socks5 proxy every connect is forwarded through instead of dialing targets, for networks
+    // whose only way out is e.g. the corporate proxy
+    pub parent: Option<ParentConfig>,
+    // json connection events posted in batches, for alerting or billing elsewhere
+    pub webhook: Option<WebhookConfig>,
+    // an ip-to-asn table for transfer and priority rules to match targets by `asn`
+    pub asn: Option<AsnConfig>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            host: DEFAULT_HOST.to_string(),
+            port: DEFAULT_SERVER_PORT,
+            password: "".to_string(),
+            encrypt: "".to_string(),
+            keys: Vec::new(),
+            unix: None,
+            quota: QuotaConfig::default(),
+            users: Vec::new(),
+            udp: UdpConfig::default(),
+            pool: PoolConfig::default(),
+            obfs: None,
+            probe: ProbeConfig::default(),
+            trace: None,
+            backlog: 1024,
+            reuse_port: false,
+            acceptors: 0,
+            drain_timeout: 0,
+            hairpin: Vec::new(),
+            block_private: None,
+            relay_ports: None,
+            allow_sources: Vec::new(),
+            rate_limit: RateLimitConfig::default(),
+            auth_ban: AuthBanConfig::default(),
+            bench: false,
+            diag: false,
+            blocklist: BlocklistConfig::default(),
+            metrics: None,
+            metrics_token: None,
+            target_limit: 0,
+            source_limit: 0,
+            shed: ShedConfig::default(),
+            fingerprint: false,
+            sniff: false,
+            sniff_timeout: 300,
+            access_log: None,
+            relay: RelayConfig::default(),
+            reject: RejectMode::default(),
+            priority: PriorityConfig::default(),
+            resolver: ResolverConfig::default(),
+            nat64: None,
+            outbound: Vec::new(),
+            transfer: Vec::new(),
+            parent: None,
+            webhook: None,
+            asn: None,
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn builder() -> ServerConfigBuilder {
+        ServerConfigBuilder::default()
+    }
+
+    // the config itself if check() has nothing to say about it
+    pub fn validate(self) -> Result<Self, ConfigError> {
+        let problems = self.check();
+        match problems.is_empty() {
+            true => Ok(self),
+            false => Err(ConfigError::Invalid(problems)),
+        }
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ConfigError> {
+        load(path)
+    }
+
+    pub fn load_profile<P: AsRef<Path>>(path: P, profile: Option<&str>) -> Result<Self, ConfigError> {
+        load_profile(path, profile)
+    }
+
+    // what `server --password X` runs without a file: every interface, the default port and an aead cipher
+    pub fn zero_config(password: &str) -> Self {
+        ServerConfig {
+            host: "0.0.0.0".to_string(),
+            password: password.to_string(),
+            encrypt: DEFAULT_METHOD.to_string(),
+            ..ServerConfig::default()
+        }
+    }
+
+    pub fn endpoints(&self) -> Vec<Endpoint> {
+        endpoints(&self.host, self.port, &self.unix)
+    }
+
+    pub fn outbound(&self, target: &Address) -> Option<&OutboundRule> {
+        self.outbound.iter().find(|rule| matches_target(&rule.pattern, &rule.ports, target))
+    }
+
+    // asn is the target's, when its address is known and in the asn table
+    pub fn transfer(&self, target: &Address, asn: Option<u32>) -> Option<&TransferRule> {
+        self.transfer.iter().find(|rule| matches_target(&rule.pattern, &rule.ports, target) && matches_asn(&rule.asn, asn))
+    }
+
+    pub fn listen_options(&self) -> ListenOptions {
+        ListenOptions { backlog: self.backlog, reuse_port: self.reuse_port }
+    }
+
+    // a server only reachable from this host is taken to be a personal one
+    pub fn blocks_private(&self) -> bool {
+        self.block_private.unwrap_or_else(|| {
+            !self.host.parse::<std::net::IpAddr>().is_ok_and(|ip| ip.is_loopback())
+        })
+    }
+
+    // unix socket clients have no ip and are always let in
+    pub fn allows_source(&self, ip: std::net::IpAddr) -> bool {
+        self.allow_sources.is_empty() || self.allow_sources.iter().any(|cidr| cidr.contains(ip))
+    }
+
+    // listeners to bind on the tcp endpoint
+    pub fn acceptors(&self) -> usize {
+        match (self.reuse_port, self.acceptors) {
+            (false, _) => 1,
+            (true, 0) => std::thread::available_parallelism().map_or(1, |n| n.get()),
+            (true, n) => n,
+        }
+    }
+
+    pub fn keyring(&self) -> Result<Keyring, CryptoError> {
+        let users: Vec<(String, String)> = self.users.iter()
+            .filter_map(|u| u.key.clone().map(|key| (u.name.clone(), key)))
+            .collect();
+        Keyring::with_users(&self.encrypt, &self.password, &self.keys, &users)
+    }
+
+    // quota.users with the limits from the users table on top
+    pub fn quota_config(&self) -> QuotaConfig {
+        let mut quota = self.quota.clone();
+        for user in &self.users {
+            if let Some(limit) = user.quota {
+                quota.users.insert(user.name.clone(), limit);
+            }
+        }
+        quota
+    }
+
+    // name to password of the users that log in with socks username/password auth
+    pub fn passwords(&self) -> HashMap<String, String> {
+        self.users.iter()
+            .filter_map(|u| u.password.clone().map(|p| (u.name.clone(), p)))
+            .collect()
+    }
+
+    // SS5_HOST, SS5_PORT, SS5_PASSWORD, SS5_METHOD and SS5_KEY over what the file says,
+    // SS5_KEY is accepted on top of the file's keys
+    pub fn apply_env<F: Fn(&str) -> Option<String>>(&mut self, var: F) -> Result<(), ConfigError> {
+        let env = Env(var);
+        env.string("SS5_HOST", &mut self.host);
+        env.port(&mut self.port)?;
+        env.string("SS5_PASSWORD", &mut self.password);
+        env.string("SS5_METHOD", &mut self.encrypt);
+        if let Some(key) = env.get("SS5_KEY") {
+            self.keys.push(key);
+        }
+        Ok(())
+    }
+
+    // what would fail at start or at the first request, each as a line to act on
+    pub fn check(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if let Err(e) = self.keyring() {
+            problems.push(format!("encrypt / password / keys : {}", e));
+        }
+        check_listen(&self.host, &self.unix, &mut problems);
+        let mut names = std::collections::HashSet::new();
+        for user in &self.users {
+            if user.name.is_empty() {
+                problems.push("users : a user has an empty name".to_string());
+            } else if !names.insert(&user.name) {
+                problems.push(format!("users : {} is listed twice, only one entry would apply", user.name));
+            }
+            if user.password.is_none() && user.key.is_none() {
+                problems.push(format!("users : {} has neither a password nor a key and can't log in", user.name));
+            }
+        }
+        if let Some(ports) = self.relay_ports {
+            if (ports.start..=ports.end).contains(&self.port) {
+                problems.push(format!("relay_ports : {} includes the listening port {}", ports, self.port));
+            }
+        }
+        if self.rate_limit.rate > 0.0 && self.rate_limit.burst == 0 {
+            problems.push("rate_limit : burst 0 refuses every connection, use at least 1".to_string());
+        }
+        if let Some(command) = &self.auth_ban.command {
+            if command.split_whitespace().next().is_none() {
+                problems.push("auth_ban.command : empty command".to_string());
+            }
+        }
+        if self.shed.load > 0.0 && !cfg!(target_os = "linux") {
+            problems.push("shed.load : the load average is only read on linux, it would never shed".to_string());
+        }
+        if !(1..=crate::mmsg::MAX_BATCH).contains(&self.udp.batch) {
+            problems.push(format!("udp.batch : {} isn't between 1 and {}", self.udp.batch, crate::mmsg::MAX_BATCH));
+        } else if self.udp.batch > 1 && !cfg!(target_os = "linux") {
+            problems.push("udp.batch : recvmmsg / sendmmsg are linux only, datagrams would go one a call".to_string());
+        }
+        check_relay(&self.relay, &mut problems);
+        if let Some(metrics) = &self.metrics {
+            if metrics.parse::<std::net::SocketAddr>().is_err() {
+                problems.push(format!("metrics : {} isn't an ip:port to listen on", metrics));
+            }
+        }
+        if self.metrics_token.as_ref().is_some_and(|token| token.trim().is_empty()) {
+            problems.push("metrics_token : empty token".to_string());
+        }
+        if let Some(trace) = &self.trace {
+            check_url("trace.endpoint", &trace.endpoint, &mut problems);
+        }
+        if let Some(webhook) = &self.webhook {
+            check_url("webhook.url", &webhook.url, &mut problems);
+        }
+        if let Some(doh) = &self.resolver.doh {
+            if !doh.starts_with("https://") {
+                problems.push(format!("resolver.doh : {} is not an https url", doh));
+            }
+        }
+        if let Some(prefix) = self.nat64.as_deref().filter(|prefix| *prefix != "auto") {
+            if let Err(e) = crate::nat64::parse(prefix) {
+                problems.push(format!("nat64 : {}", e));
+            }
+        }
+        check_blocklist(&self.blocklist, &mut problems);
+        let priority = &self.priority;
+        if priority.bandwidth > 0 && (priority.interactive_weight == 0 || priority.bulk_weight == 0) {
+            problems.push("priority : a weight of 0 starves its class while the other is busy, use at least 1".to_string());
+        }
+        if !priority.rules.is_empty() && priority.bandwidth == 0 {
+            problems.push("priority.rules : no effect while priority.bandwidth is 0".to_string());
+        }
+        let by_asn = priority.rules.iter().any(|rule| !rule.asn.is_empty()) || self.transfer.iter().any(|rule| !rule.asn.is_empty());
+        if by_asn && self.asn.is_none() {
+            problems.push("asn : rules match by asn but no [asn] table is configured, they match nothing".to_string());
+        }
+        if let Some(asn) = &self.asn {
+            if !asn.path.is_file() {
+                problems.push(format!("asn.path : {} is not a file", asn.path.display()));
+            }
+        }
+        if let Some(parent) = &self.parent {
+            check_parent(parent, &mut problems);
+            if self.pool.max_idle > 0 {
+                problems.push("pool : unused while parent is set, connections are made through the parent".to_string());
+            }
+        }
+        for rule in &self.outbound {
+            let name = rule.pattern.as_ref().map_or("*".to_string(), |p| p.to_string());
+            if rule.bind.is_none() && rule.interface.is_none() {
+                problems.push(format!("outbound : the rule for {} sets neither bind nor interface", name));
+            }
+            if rule.interface.is_some() && !cfg!(any(target_os = "linux", target_os = "android")) {
+                problems.push(format!("outbound : the rule for {} names an interface, only supported on linux", name));
+            }
+        }
+        for rule in &self.transfer {
+            let name = rule.pattern.as_ref().map_or("*".to_string(), |p| p.to_string());
+            if rule.max_bytes == 0 {
+                problems.push(format!("transfer : the rule for {} has max_bytes 0, no connection could move a byte", name));
+            }
+            if rule.action == TransferAction::Throttle && rule.rate == 0 {
+                problems.push(format!("transfer : the rule for {} throttles to a rate of 0, use close to cut it off", name));
+            }
+        }
+        problems
+    }
+}
+
+// for embedders: a config that only comes out of build() if check() has nothing to say about it,
+// the cipher known and keyed, users able to log in, listen and relay settings usable
+#[derive(Debug, Clone, Default)]
+pub struct ServerConfigBuilder {
+    config: ServerConfig,
+}
+
+impl ServerConfigBuilder {
+    pub fn host(mut self, host: impl Into<String>) -> Self {
+        self.config.host = host.into();
+        self
+    }
+
+    // 0 takes any free port
+    pub fn port(mut self, port: u16) -> Self {
+        self.config.port = port;
+        self
+    }
+
+    pub fn encrypt(mut self, method: impl Into<String>) -> Self {
+        self.config.encrypt = method.into();
+        self
+    }
+
+    pub fn password(mut self, password: impl Into<String>) -> Self {
+        self.config.password = password.into();
+        self
+    }
+
+    // a base64 key accepted besides the password one, may be given more than once
+    pub fn key(mut self, key: impl Into<String>) -> Self {
+        self.config.keys.push(key.into());
+        self
+    }
+
+    pub fn unix(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.unix = Some(path.into());
+        self
+    }
+
+    pub fn user(mut self, user: UserConfig) -> Self {
+        self.config.users.push(user);
+        self
+    }
+
+    pub fn metrics(mut self, addr: impl Into<String>) -> Self {
+        self.config.metrics = Some(addr.into());
+        self
+    }
+
+    pub fn build(self) -> Result<ServerConfig, ConfigError> {
+        self.config.validate()
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UserConfig {
+    pub name: String,
+    // for socks username/password auth
+    pub password: Option<String>,
+    // base64 tunnel key identifying the user without socks auth
+    pub key: Option<String>,
+    // bytes per quota period
+    pub quota: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LocalConfig {
+    pub host: String,
+    pub port: u16,
+    pub unix: Option<PathBuf>,
+    // also accept socks connections on this windows named pipe, e.g. \\.\pipe\ss5, for
+    // sandboxed apps that can't open loopback tcp
+    pub pipe: Option<String>,
+    // the remote rust-ss5 server, tcp or unix
+    pub server: Endpoint,
+    pub password: String,
+    pub encrypt: String,
+    // a base64 key from genkey, used instead of the password when set
+    pub key: Option<String>,
+    // a sip008 server list replacing `server` once fetched
+    pub subscription: Option<SubscriptionConfig>,
+    pub obfs: Option<ObfsMode>,
+    // host named in the http obfs request, the server's host when unset
+    pub obfs_host: Option<String>,
+    // carry every request over one long-lived connection per upstream
+    pub mux: bool,
+    // send the upstream the socks greeting and request together, a round trip less per connection
+    pub fast_open: bool,
+    pub blocklist: BlocklistConfig,
+    pub relay: RelayConfig,
+    // domains connected to from here instead of through the server, "example.com", "*.lan" or "regex:..."
+    pub direct: Vec<Pattern>,
+    // where the domains of requests through the server are resolved, the first matching rule wins
+    // and the server resolves those no rule matches
+    pub resolve: Vec<ResolveRule>,
+    // host:port serving /proxy.pac, sending browsers the same way as `direct` does
+    pub pac: Option<String>,
+    // point the os proxy settings here while running, at the pac file when there is one
+    pub system_proxy: bool,
+    // host:port serving /metrics in the prometheus text format, upstream and draining connections
+    pub metrics: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SubscriptionConfig {
+    pub url: String,
+    // seconds between refreshes
+    #[serde(default = "default_refresh")]
+    pub refresh: u64,
+    // seconds a fetch may take, startup falls back to server after this
+    #[serde(default = "default_fetch_timeout")]
+    pub timeout: u64,
+}
+
+fn default_refresh() -> u64 {
+    3600
+}
+
+fn default_fetch_timeout() -> u64 {
+    10
+}
+
+impl Default for LocalConfig {
+    fn default() -> Self {
+        LocalConfig {
+            host: DEFAULT_HOST.to_string(),
+            port: DEFAULT_LOCAL_PORT,
+            unix: None,
+            pipe: None,
+            server: Endpoint::Tcp(format!("127.0.0.1:{}", DEFAULT_SERVER_PORT)),
+            password: "".to_string(),
+            encrypt: "".to_string(),
+            key: None,
+            subscription: None,
+            obfs: None,
+            obfs_host: None,
+            mux: false,
+            fast_open: false,
+            blocklist: BlocklistConfig::default(),
+            relay: RelayConfig::default(),
+            direct: Vec::new(),
+            resolve: Vec::new(),
+            pac: None,
+            system_proxy: false,
+            metrics: None,
+        }
+    }
+}
+
+impl LocalConfig {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ConfigError> {
+        load(path)
+    }
+
+    pub fn load_profile<P: AsRef<Path>>(path: P, profile: Option<&str>) -> Result<Self, ConfigError> {
+        load_profile(path, profile)
+    }
+
+    pub fn endpoints(&self) -> Vec<Endpoint> {
+        let mut endpoints = endpoints(&self.host, self.port, &self.unix);
+        if let Some(name) = self.pipe.clone() {
+            endpoints.push(Endpoint::Pipe(name));
+        }
+        endpoints
+    }
+
+    pub fn keyring(&self) -> Result<Keyring, CryptoError> {
+        match &self.key {
+            None => Keyring::new(&self.encrypt, &self.password, &[]),
+            Some(key) => Keyring::new(&self.encrypt, "", std::slice::from_ref(key)),
+        }
+    }
+
+    // SS5_HOST, SS5_PORT, SS5_PASSWORD, SS5_METHOD, SS5_KEY and SS5_SERVER over what the file says
+    pub fn apply_env<F: Fn(&str) -> Option<String>>(&mut self, var: F) -> Result<(), ConfigError> {
+        let env = Env(var);
+        env.string("SS5_HOST", &mut self.host);
+        env.port(&mut self.port)?;
+        env.string("SS5_PASSWORD", &mut self.password);
+        env.string("SS5_METHOD", &mut self.encrypt);
+        if let Some(key) = env.get("SS5_KEY") {
+            self.key = Some(key);
+        }
+        if let Some(server) = env.get("SS5_SERVER") {
+            self.server = server.parse().map_err(|_| ConfigError::EnvError("SS5_SERVER".to_string(), server))?;
+        }
+        Ok(())
+    }
+
+    // what would fail at start or at the first request, each as a line to act on
+    pub fn check(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if let Err(e) = self.keyring() {
+            problems.push(format!("encrypt / password / key : {}", e));
+        }
+        check_listen(&self.host, &self.unix, &mut problems);
+        if let Some(name) = &self.pipe {
+            if !cfg!(windows) {
+                problems.push("pipe : named pipes are only supported on windows".to_string());
+            } else if !name.starts_with(PIPE_NAMESPACE) {
+                problems.push(format!("pipe : {} doesn't start with {}", name, PIPE_NAMESPACE));
+            }
+        }
+        if let Endpoint::Tcp(server) = &self.server {
+            if server.rsplit_once(':').and_then(|(_, port)| port.parse::<u16>().ok()).is_none() {
+                problems.push(format!("server : {} has no port", server));
+            } else if self.port != 0 && *server == format!("{}:{}", self.host, self.port) {
+                problems.push(format!("server : {} is this local listener itself", server));
+            }
+        }
+        if let Some(subscription) = &self.subscription {
+            check_url("subscription.url", &subscription.url, &mut problems);
+        }
+        check_blocklist(&self.blocklist, &mut problems);
+        check_relay(&self.relay, &mut problems);
+        if let Some(pac) = &self.pac {
+            if pac.parse::<std::net::SocketAddr>().is_err() {
+                problems.push(format!("pac : {} isn't an ip:port to listen on", pac));
+            }
+        }
+        if let Some(metrics) = &self.metrics {
+            if metrics.parse::<std::net::SocketAddr>().is_err() {
+                problems.push(format!("metrics : {} isn't an ip:port to listen on", metrics));
+            }
+        }
+        problems
+    }
+
+    pub fn resolve(&self, target: &Address) -> Resolve {
+        self.resolve.iter().find(|rule| matches_target(&rule.pattern, &rule.ports, target)).map_or(Resolve::Remote, |rule| rule.resolve)
+    }
+
+    pub fn obfs_host(&self, server: &Endpoint) -> String {
+        match (&self.obfs_host, server) {
+            (Some(host), _) => host.clone(),
+            (None, Endpoint::Tcp(addr)) => addr.rsplit_once(':').map_or(addr.as_str(), |(host, _)| host).to_string(),
+            (None, _) => DEFAULT_HOST.to_string(),
+        }
+    }
+}
+
+fn endpoints(host: &str, port: u16, unix: &Option<PathBuf>) -> Vec<Endpoint> {
+    let host = if host.contains(':') && !host.starts_with('[') { format!("[{}]", host) } else { host.to_string() };
+    let mut endpoints = vec![Endpoint::Tcp(format!("{}:{}", host, port))];
+    if let Some(path) = unix.clone() {
+        endpoints.push(Endpoint::Unix(path));
+    }
+    endpoints
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QuotaPeriod {
+    #[default]
+    Day,
+    Month,
+}
+
+// byte limits per period, counting both directions; None means unlimited
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QuotaConfig {
+    pub period: QuotaPeriod,
+    pub global: Option<u64>,
+    pub users: HashMap<String, u64>,
+    // where usage survives restarts, kept in memory only when unset
+    pub path: Option<PathBuf>,
+}
+
+// new connections per second from one source ip, disabled while rate is 0
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub rate: f64,
+    pub burst: u32,
+    // refused connections in a row before the source is banned, for ban seconds
+    pub strikes: u32,
+    pub ban: u64,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig { rate: 0.0, burst: 20, strikes: 20, ban: 600 }
+    }
+}
+
+// failed socks logins from one ip within window seconds before it is banned, disabled while failures is 0
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthBanConfig {
+    pub failures: u32,
+    pub window: u64,
+    pub ban: u64,
+    // run with the banned ip appended, e.g. "/usr/local/bin/ban-ip"
+    pub command: Option<String>,
+}
+
+impl Default for AuthBanConfig {
+    fn default() -> Self {
+        AuthBanConfig { failures: 0, window: 600, ban: 3600, command: None }
+    }
+}
+
+// thresholds for shedding new requests, each disabled while 0
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShedConfig {
+    // open connections, counting the one being shed
+    pub connections: u64,
+    // the one minute load average per cpu, 1.0 is every cpu busy
+    pub load: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UdpConfig {
+    // largest datagram payload relayed, bigger ones are counted as truncated and dropped
+    pub buffer: usize,
+    // datagrams moved a syscall with recvmmsg / sendmmsg on linux, each socket of an association
+    // keeps this many buffers of `buffer` bytes; 1 is a recv_from / send_to each
+    pub batch: usize,
+}
+
+impl Default for UdpConfig {
+    fn default() -> Self {
+        UdpConfig { buffer: MAX_UDP_PAYLOAD, batch: 1 }
+    }
+}
+
+// unset and empty variables leave the config alone
+struct Env<F>(F);
+
+impl<F: Fn(&str) -> Option<String>> Env<F> {
+    fn get(&self, name: &str) -> Option<String> {
+        (self.0)(name).filter(|value| !value.is_empty())
+    }
+
+    fn string(&self, name: &str, field: &mut String) {
+        if let Some(value) = self.get(name) {
+            *field = value;
+        }
+    }
+
+    fn port(&self, port: &mut u16) -> Result<(), ConfigError> {
+        if let Some(value) = self.get("SS5_PORT") {
+            *port = value.parse().map_err(|_| ConfigError::EnvError("SS5_PORT".to_string(), value))?;
+        }
+        Ok(())
+    }
+}
+
+// the port isn't bound to try it, the server being checked before a restart still holds it
+fn check_listen(host: &str, unix: &Option<PathBuf>, problems: &mut Vec<String>) {
+    if host.parse::<std::net::IpAddr>().is_err() && host != "localhost" {
+        problems.push(format!("host : {} is not an ip address", host));
+    }
+    if let Some(parent) = unix.as_ref().and_then(|path| path.parent()) {
+        if !parent.as_os_str().is_empty() && !parent.is_dir() {
+            problems.push(format!("unix : directory {} doesn't exist", parent.display()));
+        }
+    }
+}
+
+fn check_url(field: &str, url: &str, problems: &mut Vec<String>) {
+    if !url.starts_with("http://") && !url.starts_with("https://") {
+        problems.push(format!("{} : {} is not an http or https url", field, url));
+    }
+}
+
+fn check_blocklist(config: &BlocklistConfig, problems: &mut Vec<String>) {
+    for path in &config.files {
+        if let Err(e) = std::fs::metadata(path) {
+            problems.push(format!("blocklist.files : {} : {}", path.display(), e));
+        }
+    }
+    for remote in &config.remote {
+        check_url("blocklist.remote", &remote.url, problems);
+        if remote.public_key().is_none() {
+            problems.push(format!("blocklist.remote : {} : key isn't a base64 ed25519 public key", remote.url));
+        }
+    }
+}
+
+fn check_parent(parent: &ParentConfig, problems: &mut Vec<String>) {
+    if !parent.server.rsplit_once(':').is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok_and(|port| port > 0)) {
+        problems.push(format!("parent.server : {} isn't a host:port to connect to", parent.server));
+    }
+    match (&parent.username, &parent.password) {
+        (Some(username), Some(password)) => {
+            // RFC 1929 gives each a one byte length
+            if username.is_empty() || username.len() > 255 || password.is_empty() || password.len() > 255 {
+                problems.push("parent : username and password take 1 to 255 bytes".to_string());
+            }
+        }
+        (None, None) => {}
+        _ => problems.push("parent : username and password go together".to_string()),
+    }
+}
+
+fn check_relay(relay: &RelayConfig, problems: &mut Vec<String>) {
+    if relay.up_buffer == 0 || relay.down_buffer == 0 {
+        problems.push("relay : a buffer of 0 moves no data, use at least 1".to_string());
+    }
+}
+
+// domains refused with RepHostNo before anything is dialed
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BlocklistConfig {
+    // hosts files, adblock lists or one domain per line
+    pub files: Vec<PathBuf>,
+    pub domains: Vec<String>,
+    // checked in order ahead of the lists, the first one matching decides
+    pub rules: Vec<BlockRule>,
+    // lists fetched on a schedule, used once their signature checks out
+    pub remote: Vec<RemoteListConfig>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BlockRule {
+    // "example.com", "*.example.com" or "regex:..."
+    pub pattern: Pattern,
+    // let a matching domain through whatever the lists say
+    #[serde(default)]
+    pub allow: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RemoteListConfig {
+    // a list in any format `files` takes
+    pub url: String,
+    // base64 of the list's detached ed25519 signature, fetched from url + ".sig" when unset
+    pub signature: Option<String>,
+    // base64 of the signer's 32 byte ed25519 public key
+    pub key: String,
+    // seconds between fetches, a failed or badly signed fetch keeps the last good list
+    #[serde(default = "default_list_refresh")]
+    pub refresh: u64,
+    #[serde(default = "default_fetch_timeout")]
+    pub timeout: u64,
+}
+
+fn default_list_refresh() -> u64 {
+    86400
+}
+
+impl RemoteListConfig {
+    pub fn signature_url(&self) -> String {
+        self.signature.clone().unwrap_or_else(|| format!("{}.sig", self.url))
+    }
+
+    // None unless the key is a point of the curve outside the small order subgroup
+    pub fn public_key(&self) -> Option<[u8; 32]> {
+        let key: [u8; 32] = crate::crypto::decode_key(&self.key)?.try_into().ok()?;
+        let point = ed25519_dalek::VerifyingKey::from_bytes(&key).ok()?;
+        (!point.is_weak()).then_some(key)
+    }
+}
+
+// warm outbound connections kept per target, disabled while max_idle is 0
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PoolConfig {
+    pub max_idle: usize,
+    // seconds an idle connection is handed out for, expired ones are closed by a sweep
+    pub ttl: u64,
+    // connects to a target within ttl before it gets warm connections
+    pub hits: u32,
+    // idle connections across all targets
+    pub max_total: usize,
+    pub max_targets: usize,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        PoolConfig { max_idle: 0, ttl: 30, hits: 2, max_total: 256, max_targets: 64 }
+    }
+}
+
+// how a refused connection ends, e.g. one over its quota, blocked or rate limited; a
+// lingering close isn't offered, it would block a runtime thread until the data is out
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RejectMode {
+    // the reply, then a fin
+    #[default]
+    Close,
+    // a rst after the reply, freeing the socket without a TIME_WAIT; the client's stack
+    // may throw the reply away on receiving it
+    Reset,
+}
+
+// what a connection that fails the socks or tunnel handshake gets back
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProbeMode {
+    // close straight away
+    #[default]
+    Close,
+    // read and discard until the peer gives up or the delay runs out
+    Drain,
+    // keep the connection open without reading, then close
+    Stall,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProbeConfig {
+    pub mode: ProbeMode,
+    // seconds, the actual delay is picked between half of it and all of it
+    pub max_delay: u64,
+    // milliseconds, refusals to clients that did get through the handshake wait a random 0 to
+    // jitter first, so which check turned them down can't be told by timing
+    pub jitter: u64,
+}
+
+impl Default for ProbeConfig {
+    fn default() -> Self {
+        ProbeConfig { mode: ProbeMode::Close, max_delay: 30, jitter: 0 }
+    }
+}
+
+
+// bytes read ahead per direction of a relay; a side that stops reading stalls the other once
+// its buffer is full, so a slow link pushes back instead of piling data up
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RelayConfig {
+    // client to target
+    pub up_buffer: usize,
+    pub down_buffer: usize,
+    // seconds a connect relay may go without a byte either way before it's closed, 0 never
+    pub idle_timeout: u64,
+}
+
+impl Default for RelayConfig {
+    fn default() -> Self {
+        RelayConfig { up_buffer: 8192, down_buffer: 8192, idle_timeout: 0 }
+    }
+}
+
+impl RelayConfig {
+    pub fn idle(&self) -> Option<Duration> {
+        (self.idle_timeout > 0).then(|| Duration::from_secs(self.idle_timeout))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    // ssh-like sessions, small and latency bound
+    Interactive,
+    #[default]
+    Bulk,
+}
+
+impl Display for Priority {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Priority::Interactive => write!(f, "interactive"),
+            Priority::Bulk => write!(f, "bulk"),
+        }
+    }
+}
+
+// tcp relays share bandwidth by the weight of their class while more than one class is busy,
+// a class alone gets all of it; off while bandwidth is 0
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PriorityConfig {
+    // bytes per second through all relays, both directions, at most what the link carries
+    // so the queueing happens here rather than in the network
+    pub bandwidth: u64,
+    pub interactive_weight: u32,
+    pub bulk_weight: u32,
+    // the first rule matching the target decides, the rest are bulk
+    pub rules: Vec<PriorityRule>,
+}
+
+impl Default for PriorityConfig {
+    fn default() -> Self {
+        PriorityConfig { bandwidth: 0, interactive_weight: 4, bulk_weight: 1, rules: Vec::new() }
+    }
+}
+
+impl PriorityConfig {
+    pub fn priority(&self, target: &Address, asn: Option<u32>) -> Priority {
+        self.rules.iter().find(|rule| rule.matches(target, asn)).map_or(Priority::Bulk, |rule| rule.priority)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PriorityRule {
+    // the target's domain, or its ip as text; any target when unset
+    #[serde(default)]
+    pub pattern: Option<Pattern>,
+    // any port when empty
+    #[serde(default)]
+    pub ports: Vec<u16>,
+    // the target's autonomous system, any when empty, e.g. [13335] for a cdn
+    #[serde(default)]
+    pub asn: Vec<u32>,
+    pub priority: Priority,
+}
+
+impl PriorityRule {
+    pub fn matches(&self, target: &Address, asn: Option<u32>) -> bool {
+        matches_target(&self.pattern, &self.ports, target) && matches_asn(&self.asn, asn)
+    }
+}
+
+// the source of outbound connections to matching targets; a bind address only reaches targets
+// of its own family, a domain's other addresses are skipped
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OutboundRule {
+    // the target's domain, or its ip as text; any target when unset
+    #[serde(default)]
+    pub pattern: Option<Pattern>,
+    // any port when empty
+    #[serde(default)]
+    pub ports: Vec<u16>,
+    // the local address connections leave from
+    #[serde(default)]
+    pub bind: Option<std::net::IpAddr>,
+    // SO_BINDTODEVICE, e.g. "eth1" or "wg0", linux only
+    #[serde(default)]
+    pub interface: Option<String>,
+}
+
+// what happens to a connection past its rule's max_bytes, checked once a second with the quota
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TransferRule {
+    // the target's domain, or its ip as text; any target when unset
+    #[serde(default)]
+    pub pattern: Option<Pattern>,
+    // any port when empty
+    #[serde(default)]
+    pub ports: Vec<u16>,
+    // the target's autonomous system, any when empty
+    #[serde(default)]
+    pub asn: Vec<u32>,
+    pub max_bytes: u64,
+    #[serde(default)]
+    pub action: TransferAction,
+    // bytes per second each way a throttled connection keeps
+    #[serde(default)]
+    pub rate: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TransferAction {
+    #[default]
+    Close,
+    Throttle,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ResolveRule {
+    // the target's domain; any target when unset
+    #[serde(default)]
+    pub pattern: Option<Pattern>,
+    // any port when empty
+    #[serde(default)]
+    pub ports: Vec<u16>,
+    pub resolve: Resolve,
+}
+
+// local sends the server an ip, so it never sees the name; remote keeps the name off this
+// host's resolver, so its dns doesn't give away where the request goes
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Resolve {
+    Local,
+    #[default]
+    Remote,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ParentConfig {
+    // host:port
+    pub server: String,
+    // offered as username/password auth when set
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+}
+
+fn matches_target(pattern: &Option<Pattern>, ports: &[u16], target: &Address) -> bool {
+    let (host, port) = match target {
+        Address::Address(addr) => (addr.ip().to_string(), addr.port()),
+        Address::DomainName(host, port) => (host.clone(), *port),
+    };
+    (ports.is_empty() || ports.contains(&port)) && pattern.as_ref().is_none_or(|p| p.matches(&host))
+}
+
+// a rule naming asns doesn't match a target whose asn isn't known
+fn matches_asn(asns: &[u32], asn: Option<u32>) -> bool {
+    asns.is_empty() || asn.is_some_and(|asn| asns.contains(&asn))
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccessLogConfig {
+    pub path: PathBuf,
+    // bytes and seconds before the file is rotated, 0 turns either off
+    pub max_size: u64,
+    pub max_age: u64,
+    // rotated files kept, older ones are removed
+    pub keep: usize,
+    // compress rotated files with the system gzip
+    pub gzip: bool,
+}
+
+impl Default for AccessLogConfig {
+    fn default() -> Self {
+        AccessLogConfig {
+            path: PathBuf::from("access.log"),
+            max_size: 100 * 1024 * 1024,
+            max_age: 86400,
+            keep: 7,
+            gzip: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TraceConfig {
+    // the collector's base url, spans go to {endpoint}/v1/traces
+    pub endpoint: String,
+    pub service: String,
+    // spans per post, a partial batch goes out every interval seconds
+    pub batch: usize,
+    pub interval: u64,
+    // spans waiting for the exporter, more than this are dropped and counted
+    pub queue: usize,
+    // seconds a post may take before its batch is given up
+    pub timeout: u64,
+}
+
+impl Default for TraceConfig {
+    fn default() -> Self {
+        TraceConfig {
+            endpoint: "http://127.0.0.1:4318".to_string(),
+            service: "rust-ss5".to_string(),
+            batch: 512,
+            interval: 5,
+            queue: 4096,
+            timeout: 10,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookEvent {
+    // a request read, each is followed by a closed
+    Opened,
+    // a connection that got as far as a request ended, with its bytes and reply
+    Closed,
+    AuthFailed,
+    QuotaExceeded,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AsnConfig {
+    // e.g. iptoasn.com's ip2asn-combined.tsv, unzipped
+    pub path: PathBuf,
+    // seconds between looks at the file, it's loaded again once modified
+    #[serde(default = "default_asn_reload")]
+    pub reload: u64,
+}
+
+fn default_asn_reload() -> u64 {
+    60
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebhookConfig {
+    // events are posted here as {"events": [...]}
+    pub url: String,
+    // the events sent, all of them when empty
+    pub events: Vec<WebhookEvent>,
+    // events per post, a partial batch goes out every interval seconds
+    pub batch: usize,
+    pub interval: u64,
+    // events waiting to be posted, more than this are dropped and counted
+    pub queue: usize,
+    // seconds a post may take
+    pub timeout: u64,
+    // more tries for a post failing with a network error, 429 or 5xx, a second apart and doubling
+    pub retries: u32,
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        WebhookConfig {
+            url: "".to_string(),
+            events: Vec::new(),
+            batch: 100,
+            interval: 5,
+            queue: 4096,
+            timeout: 10,
+            retries: 3,
+        }
+    }
+}
+
+// how the server resolves domain targets, the system resolver unless doh is set
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ResolverConfig {
+    // an RFC 8484 endpoint, e.g. "https://1.1.1.1/dns-query"; naming it by ip keeps its own
+    // lookup off the system resolver
+    pub doh: Option<String>,
+    // seconds a query may take
+    pub timeout: u64,
+    // names cached for their record ttl, the cache is emptied once it holds more
+    pub cache: usize,
+    // seconds the system resolver's answers are cached, they come without a ttl; 0 leaves
+    // lookups to each connection when there's no doh either
+    pub system_ttl: u64,
+    // ask the upstream not to pass a client subnet on to authoritative servers, RFC 7871
+    pub strip_ecs: bool,
+    // mix the case of queried names and refuse answers that don't echo it, 0x20 encoding
+    pub randomize_case: bool,
+}
+
+impl Default for ResolverConfig {
+    fn default() -> Self {
+        ResolverConfig { doh: None, timeout: 5, cache: 4096, system_ttl: 0, strip_ecs: false, randomize_case: false }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{ConfigError, DEFAULT_METHOD, DEFAULT_SERVER_PORT, from_profile, LocalConfig, ServerConfig, Spans, UserConfig, wildcard};
+    use crate::socket5::Address;
+    use crate::transport::Endpoint;
+
+    #[test]
+    fn server_config_round_trip_test() {
+        let config = ServerConfig {
+            port: 1081,
+            encrypt: "aes-256-gcm".to_string(),
+            password: "secret".to_string(),
+            users: vec![UserConfig { name: "bob".to_string(), quota: Some(1024), ..UserConfig::default() }],
+            ..ServerConfig::default()
+        };
+        let text = toml::to_string(&config).unwrap();
+        let parsed: ServerConfig = toml::from_str(&text).unwrap();
+        assert_eq!(parsed.port, 1081);
+        assert_eq!(parsed.users[0].quota, Some(1024));
+        assert_eq!(toml::to_string(&parsed).unwrap(), text);
+    }
+
+    #[test]
+    fn zero_config_test() {
+        let config = ServerConfig::zero_config("secret");
+        assert_eq!(config.endpoints(), vec![Endpoint::Tcp(format!("0.0.0.0:{}", DEFAULT_SERVER_PORT))]);
+        assert_eq!(config.keyring().unwrap().method().to_string(), DEFAULT_METHOD);
+        assert!(config.check().is_empty());
+    }
+
+    #[test]
+    fn apply_env_test() {
+        let vars = |name: &str| match name {
+            "SS5_PORT" => Some("2080".to_string()),
+            "SS5_PASSWORD" => Some("from-env".to_string()),
+            "SS5_METHOD" => Some("aes-256-gcm".to_string()),
+            "SS5_HOST" => Some(String::new()),
+            _ => None,
+        };
+        let mut config = ServerConfig { password: "from-file".to_string(), ..ServerConfig::default() };
+        config.apply_env(vars).unwrap();
+        assert_eq!((config.port, config.password.as_str(), config.encrypt.as_str()), (2080, "from-env", "aes-256-gcm"));
+        // empty is the same as unset
+        assert_eq!(config.host, ServerConfig::default().host);
+        assert!(config.apply_env(|name| (name == "SS5_PORT").then(|| "http".to_string())).is_err());
+
+        let mut local = LocalConfig::default();
+        local.apply_env(|name| (name == "SS5_SERVER").then(|| "unix:/run/ss5.sock".to_string())).unwrap();
+        assert_eq!(local.server, Endpoint::Unix("/run/ss5.sock".into()));
+    }
+
+    #[test]
+    fn spans_test() {
+        let text = "port = 41000
+host = \"example.com\"
+blocklist = { files = [\"/etc/hosts\", \"/nonexistent/list\"] }
+[udp]
+batch = 0
+[[users]]
+name = \"alice\"
+password = \"x\"
+[[users]]
+name = \"bob\"
+[[outbound]]
+bind = \"192.0.2.1\"
+[[outbound]]
+pattern = \"*.example.com\"
+[profile.work]
+host = \"example.org\"
+";
+        let config: ServerConfig = from_profile(text, None).unwrap();
+        let spans = Spans::parse(text, None).unwrap();
+        let located: Vec<_> = config.check().iter().map(|problem| (spans.locate(problem), problem.clone())).collect();
+        for (position, field) in [((2, 1), "host"), ((3, 38), "blocklist.files"), ((5, 1), "udp.batch"), ((9, 1), "users : bob"), ((13, 1), "outbound : the rule for *.example.com")] {
+            assert!(located.iter().any(|(at, problem)| *at == Some(position) && problem.starts_with(field)), "no {} at {:?} in {:?}", field, position, located);
+        }
+        // keys the file doesn't set have no line
+        assert_eq!(spans.locate("udp.buffer : too small"), None);
+        assert_eq!(Spans::parse(text, Some("work")).unwrap().locate("host : example.org is not an ip address"), Some((16, 1)));
+    }
+
+    #[test]
+    fn check_test() {
+        let config = ServerConfig {
+            port: 41000,
+            encrypt: "aes-256-gcm".to_string(),
+            password: "secret".to_string(),
+            users: vec![UserConfig { name: "bob".to_string(), password: Some("x".to_string()), ..UserConfig::default() }],
+            ..ServerConfig::default()
+        };
+        assert!(config.check().is_empty());
+
+        let config: ServerConfig = toml::from_str(r#"
+            host = "example.com"
+            port = 41000
+            encrypt = "rot13"
+            relay_ports = "41000-41999"
+            blocklist = { files = ["/nonexistent/list"] }
+            parent = { server = "proxy.corp", username = "bob" }
+            [[users]]
+            name = "bob"
+            [[outbound]]
+            pattern = "*.example.com"
+            [[transfer]]
+            max_bytes = 1000
+            action = "throttle"
+            asn = [13335]
+            [udp]
+            batch = 0
+        "#).unwrap();
+        let problems = config.check();
+        for field in ["encrypt", "host", "users : bob", "relay_ports", "blocklist.files", "outbound", "transfer", "asn : ", "parent.server", "parent : ", "udp.batch"] {
+            assert!(problems.iter().any(|p| p.starts_with(field)), "no {} in {:?}", field, problems);
+        }
+
+        let local = LocalConfig {
+            port: 1080,
+            server: Endpoint::Tcp("127.0.0.1:1080".to_string()),
+            ..LocalConfig::default()
+        };
+        assert!(local.check()[0].starts_with("server : "));
+
+        let config: ServerConfig = toml::from_str(r#"
+            [[outbound]]
+            pattern = "*.example.com"
+            bind = "192.0.2.1"
+            [[outbound]]
+            ports = [25]
+            interface = "eth1"
+        "#).unwrap();
+        let rule = config.outbound(&Address::DomainName("mail.example.com".to_string(), 25)).unwrap();
+        assert_eq!(rule.bind, Some("192.0.2.1".parse().unwrap()));
+        assert_eq!(config.outbound(&"10.0.0.1:25".parse().unwrap()).unwrap().interface.as_deref(), Some("eth1"));
+        assert!(config.outbound(&"10.0.0.1:443".parse().unwrap()).is_none());
+
+        let local: LocalConfig = toml::from_str(r#"pipe = '\\.\pipe\ss5'"#).unwrap();
+        assert_eq!(local.endpoints()[1], Endpoint::Pipe(r"\\.\pipe\ss5".to_string()));
+        assert_eq!(local.check().iter().any(|p| p.starts_with("pipe")), !cfg!(windows));
+        // the parse error says where
+        let err = toml::from_str::<ServerConfig>("port = \"x\"").unwrap_err();
+        assert!(err.to_string().contains("line 1"));
+    }
+
+    #[test]
+    fn profile_test() {
+        let text = r#"
+            port = 1080
+            server = "home.example.com:9999"
+            password = "secret"
+            relay = { up_buffer = 4096 }
+            [profile.work]
+            server = "work.example.com:443"
+            direct = ["*.corp"]
+            relay = { down_buffer = 8192 }
+            [profile.home]
+        "#;
+        let base: LocalConfig = from_profile(text, None).unwrap();
+        assert_eq!(base.server, Endpoint::Tcp("home.example.com:9999".to_string()));
+        assert!(base.direct.is_empty());
+        let work: LocalConfig = from_profile(text, Some("work")).unwrap();
+        assert_eq!((work.port, work.password.as_str()), (1080, "secret"));
+        assert_eq!(work.server, Endpoint::Tcp("work.example.com:443".to_string()));
+        assert_eq!(work.direct.len(), 1);
+        // nested tables are merged, not replaced
+        assert_eq!((work.relay.up_buffer, work.relay.down_buffer), (4096, 8192));
+        let home: LocalConfig = from_profile(text, Some("home")).unwrap();
+        assert_eq!(home.server, base.server);
+        assert!(matches!(from_profile::<LocalConfig>(text, Some("cafe")), Err(ConfigError::UnknownProfile(_))));
+        assert!(matches!(from_profile::<LocalConfig>("port = 1080", Some("work")), Err(ConfigError::UnknownProfile(_))));
+    }
+
+    #[test]
+    fn include_test() {
+        let dir = std::env::temp_dir().join(format!("rust-ss5-include-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("users")).unwrap();
+        std::fs::write(dir.join("server.toml"), r#"
+            include = ["users/*.toml", "base.toml"]
+            port = 2000
+            [[users]]
+            name = "alice"
+        "#).unwrap();
+        std::fs::write(dir.join("base.toml"), "port = 3000\npassword = \"from-base\"\n").unwrap();
+        std::fs::write(dir.join("users/b.toml"), "include = [\"more/*.toml\"]\n[[users]]\nname = \"carol\"\n").unwrap();
+        std::fs::write(dir.join("users/a.toml"), "[[users]]\nname = \"bob\"\n").unwrap();
+        std::fs::write(dir.join("users/notes.txt"), "not toml").unwrap();
+        let config = ServerConfig::load(dir.join("server.toml")).unwrap();
+        // the file's own port wins, the included password fills in
+        assert_eq!((config.port, config.password.as_str()), (2000, "from-base"));
+        let names: Vec<&str> = config.users.iter().map(|user| user.name.as_str()).collect();
+        assert_eq!(names, ["alice", "bob", "carol"]);
+
+        std::fs::write(dir.join("loop.toml"), "include = [\"loop.toml\"]\n").unwrap();
+        assert!(matches!(ServerConfig::load(dir.join("loop.toml")), Err(ConfigError::IncludeError(..))));
+        std::fs::write(dir.join("missing.toml"), "include = [\"nonexistent.toml\"]\n").unwrap();
+        let err = ServerConfig::load(dir.join("missing.toml")).unwrap_err();
+        assert!(err.to_string().starts_with("include "), "{}", err);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(wildcard(b"*.toml", b"rules.toml"));
+        assert!(wildcard(b"r?les.*", b"rules.toml"));
+        assert!(!wildcard(b"*.toml", b"rules.txt"));
+    }
+
+    #[test]
+    fn builder_test() {
+        let config = ServerConfig::builder()
+            .port(0)
+            .encrypt("aes-256-gcm")
+            .password("secret")
+            .user(UserConfig { name: "bob".to_string(), password: Some("x".to_string()), ..UserConfig::default() })
+            .build()
+            .unwrap();
+        assert_eq!((config.port, config.users.len()), (0, 1));
+
+        // a cipher with nothing to key it, a name that isn't one
+        for builder in [ServerConfig::builder().encrypt("aes-256-gcm"), ServerConfig::builder().encrypt("rot13").password("secret")] {
+            match builder.build() {
+                Err(ConfigError::Invalid(problems)) => assert!(problems[0].starts_with("encrypt"), "{:?}", problems),
+                other => panic!("expected an invalid config, got {:?}", other),
+            }
+        }
+        let err = ServerConfig::builder().encrypt("none").host("example.com").user(UserConfig::default()).build().unwrap_err();
+        assert!(err.to_string().starts_with("invalid config : host"), "{}", err);
+        assert!(err.to_string().contains("; users : a user has an empty name"), "{}", err);
+    }
+}
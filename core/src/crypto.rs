@@ -0,0 +1,543 @@
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::{Arc, OnceLock};
+use std::task::{Context, Poll, ready};
+
+use aes_gcm::{Aes128Gcm, Aes256Gcm};
+use aes_gcm::aead::{AeadInPlace, KeyInit};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use bytes::{Buf, BytesMut};
+use chacha20poly1305::ChaCha20Poly1305;
+use hkdf::Hkdf;
+use sha2::Sha256;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::transport::RawStream;
+
+pub const TAG_LEN: usize = 16;
+pub const NONCE_LEN: usize = 12;
+// payload length is sent in 14 bits like shadowsocks aead
+pub const MAX_PAYLOAD: usize = 0x3FFF;
+
+const KEY_INFO: &[u8] = b"rust-ss5-key";
+const SUBKEY_INFO: &[u8] = b"rust-ss5-subkey";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CryptoError {
+    MethodNo(String),
+    KeyNo(String),
+    // neither a password nor a key was configured for an encrypting method
+    KeyMissing,
+}
+
+impl Display for CryptoError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CryptoError::MethodNo(m) => write!(f, "unknown encrypt method : {}", m),
+            CryptoError::KeyNo(k) => write!(f, "invalid key : {}", k),
+            CryptoError::KeyMissing => write!(f, "encrypt method set without password or key"),
+        }
+    }
+}
+
+impl From<CryptoError> for io::Error {
+    fn from(err: CryptoError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidInput, err.to_string())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Method {
+    // plain socks, the current behaviour when `encrypt` is empty
+    None,
+    Aes128Gcm,
+    Aes256Gcm,
+    Chacha20Poly1305,
+}
+
+impl FromStr for Method {
+    type Err = CryptoError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "" | "none" | "plain" => Ok(Method::None),
+            "aes-128-gcm" => Ok(Method::Aes128Gcm),
+            "aes-256-gcm" => Ok(Method::Aes256Gcm),
+            "chacha20-ietf-poly1305" | "chacha20-poly1305" => Ok(Method::Chacha20Poly1305),
+            _ => Err(CryptoError::MethodNo(s.to_string())),
+        }
+    }
+}
+
+impl Display for Method {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Method::None => "none",
+            Method::Aes128Gcm => "aes-128-gcm",
+            Method::Aes256Gcm => "aes-256-gcm",
+            Method::Chacha20Poly1305 => "chacha20-ietf-poly1305",
+        })
+    }
+}
+
+impl Method {
+    pub fn key_len(&self) -> usize {
+        match self {
+            Method::None => 0,
+            Method::Aes128Gcm => 16,
+            Method::Aes256Gcm | Method::Chacha20Poly1305 => 32,
+        }
+    }
+
+    // the salt sent ahead of each direction is as long as the key
+    pub fn salt_len(&self) -> usize {
+        self.key_len()
+    }
+}
+
+// random bytes from the operating system
+pub fn generate_key(len: usize) -> io::Result<Vec<u8>> {
+    let mut key = vec![0; len];
+    getrandom::fill(&mut key).map_err(|e| io::Error::other(e.to_string()))?;
+    Ok(key)
+}
+
+pub fn encode_key(key: &[u8]) -> String {
+    STANDARD.encode(key)
+}
+
+pub fn decode_key(key: &str) -> Option<Vec<u8>> {
+    STANDARD.decode(key.trim()).ok()
+}
+
+// HKDF-SHA256 stretching the configured password into a key for the method
+pub fn derive_key(password: &str, len: usize) -> Vec<u8> {
+    hkdf(None, password.as_bytes(), KEY_INFO, len)
+}
+
+fn hkdf(salt: Option<&[u8]>, ikm: &[u8], info: &[u8], len: usize) -> Vec<u8> {
+    let mut okm = vec![0; len];
+    Hkdf::<Sha256>::new(salt, ikm)
+        .expand(info, &mut okm)
+        .expect("key length within hkdf limits");
+    okm
+}
+
+// filled in with the user name once the peer's key identified one
+pub type UserSlot = Arc<OnceLock<String>>;
+
+// the keys a server accepts, the first one is used when dialing
+#[derive(Debug, Clone)]
+pub struct Keyring {
+    method: Method,
+    keys: Vec<Vec<u8>>,
+    // the user owning each key, None for the shared ones
+    users: Vec<Option<String>>,
+}
+
+impl Keyring {
+    // password derived key first, then the base64 keys from the config in order
+    pub fn new(method: &str, password: &str, keys: &[String]) -> Result<Self, CryptoError> {
+        Keyring::with_users(method, password, keys, &[])
+    }
+
+    // like new, plus (user, base64 key) pairs identifying users by their key
+    pub fn with_users(method: &str, password: &str, keys: &[String], users: &[(String, String)]) -> Result<Self, CryptoError> {
+        let method = method.parse::<Method>()?;
+        let mut ring = Keyring { method, keys: Vec::new(), users: Vec::new() };
+        if method == Method::None {
+            return Ok(ring);
+        }
+        if !password.is_empty() {
+            ring.keys.push(derive_key(password, method.key_len()));
+            ring.users.push(None);
+        }
+        for key in keys {
+            ring.keys.push(decode(method, key)?);
+            ring.users.push(None);
+        }
+        for (user, key) in users {
+            ring.keys.push(decode(method, key)?);
+            ring.users.push(Some(user.clone()));
+        }
+        if ring.keys.is_empty() {
+            return Err(CryptoError::KeyMissing);
+        }
+        Ok(ring)
+    }
+
+    pub fn method(&self) -> Method {
+        self.method
+    }
+
+    pub fn is_plain(&self) -> bool {
+        self.method == Method::None
+    }
+
+    pub fn keys(&self) -> &[Vec<u8>] {
+        &self.keys
+    }
+}
+
+fn decode(method: Method, key: &str) -> Result<Vec<u8>, CryptoError> {
+    match decode_key(key) {
+        Some(k) if k.len() == method.key_len() => Ok(k),
+        _ => Err(CryptoError::KeyNo(key.to_string())),
+    }
+}
+
+enum Cipher {
+    Aes128Gcm(Box<Aes128Gcm>),
+    Aes256Gcm(Box<Aes256Gcm>),
+    Chacha20Poly1305(Box<ChaCha20Poly1305>),
+}
+
+// one direction of a connection: a subkey from the salt and a counting nonce
+struct Aead {
+    cipher: Cipher,
+    nonce: [u8; NONCE_LEN],
+}
+
+impl Aead {
+    fn new(method: Method, key: &[u8], salt: &[u8]) -> Self {
+        let subkey = hkdf(Some(salt), key, SUBKEY_INFO, method.key_len());
+        let cipher = match method {
+            Method::Aes128Gcm => Cipher::Aes128Gcm(Box::new(Aes128Gcm::new_from_slice(&subkey).unwrap())),
+            Method::Aes256Gcm => Cipher::Aes256Gcm(Box::new(Aes256Gcm::new_from_slice(&subkey).unwrap())),
+            Method::Chacha20Poly1305 | Method::None => Cipher::Chacha20Poly1305(Box::new(ChaCha20Poly1305::new_from_slice(&subkey).unwrap())),
+        };
+        Aead { cipher, nonce: [0; NONCE_LEN] }
+    }
+
+    fn encrypt(&mut self, buf: &mut Vec<u8>) {
+        let nonce = self.nonce.into();
+        let result = match &self.cipher {
+            Cipher::Aes128Gcm(c) => c.encrypt_in_place(&nonce, b"", buf),
+            Cipher::Aes256Gcm(c) => c.encrypt_in_place(&nonce, b"", buf),
+            Cipher::Chacha20Poly1305(c) => c.encrypt_in_place(&nonce, b"", buf),
+        };
+        result.expect("vec buffer grows for the tag");
+        self.increase();
+    }
+
+    fn decrypt(&mut self, buf: &mut Vec<u8>) -> io::Result<()> {
+        let nonce = self.nonce.into();
+        let result = match &self.cipher {
+            Cipher::Aes128Gcm(c) => c.decrypt_in_place(&nonce, b"", buf),
+            Cipher::Aes256Gcm(c) => c.decrypt_in_place(&nonce, b"", buf),
+            Cipher::Chacha20Poly1305(c) => c.decrypt_in_place(&nonce, b"", buf),
+        };
+        result.map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "bad mac"))?;
+        self.increase();
+        Ok(())
+    }
+
+    // little endian counter, like shadowsocks
+    fn increase(&mut self) {
+        for b in self.nonce.iter_mut() {
+            *b = b.wrapping_add(1);
+            if *b != 0 {
+                break;
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ReadState {
+    Salt,
+    Length,
+    Payload(usize),
+}
+
+// salt, then [encrypted length][tag][encrypted payload][tag] chunks in each direction
+pub struct CipherStream<S> {
+    inner: S,
+    method: Method,
+    // candidates for the peer's key, narrowed down to one by the first chunk
+    keys: Vec<Vec<u8>>,
+    users: Vec<Option<String>>,
+    user: UserSlot,
+    key: Option<Vec<u8>>,
+    state: ReadState,
+    decrypt: Option<Aead>,
+    read_buf: BytesMut,
+    plain: BytesMut,
+    encrypt: Option<Aead>,
+    write_buf: BytesMut,
+}
+
+impl<S> CipherStream<S>
+    where S: AsyncRead + AsyncWrite + Unpin
+{
+    // dialing side, always uses the first key of the ring
+    pub fn client(inner: S, keyring: &Keyring) -> Self {
+        let mut stream = CipherStream::new(inner, keyring.method, vec![keyring.keys[0].clone()], vec![None]);
+        stream.key = Some(keyring.keys[0].clone());
+        stream
+    }
+
+    // accepting side, answers with whichever key the peer turned out to use
+    pub fn server(inner: S, keyring: &Keyring) -> Self {
+        CipherStream::new(inner, keyring.method, keyring.keys.clone(), keyring.users.clone())
+    }
+
+    fn new(inner: S, method: Method, keys: Vec<Vec<u8>>, users: Vec<Option<String>>) -> Self {
+        CipherStream {
+            inner,
+            method,
+            keys,
+            users,
+            user: UserSlot::default(),
+            key: None,
+            state: ReadState::Salt,
+            decrypt: None,
+            read_buf: BytesMut::new(),
+            plain: BytesMut::new(),
+            encrypt: None,
+            write_buf: BytesMut::new(),
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    // the index in the keyring of the key the peer authenticated with
+    pub fn key_index(&self) -> Option<usize> {
+        let key = self.key.as_ref()?;
+        self.keys.iter().position(|k| k == key)
+    }
+
+    // set as soon as the first chunk matched a user's key
+    pub fn user(&self) -> UserSlot {
+        self.user.clone()
+    }
+
+    // consume whatever complete chunks are buffered, true when progress was made
+    fn decode(&mut self) -> io::Result<bool> {
+        match self.state {
+            ReadState::Salt => {
+                let salt_len = self.method.salt_len();
+                if self.read_buf.len() < salt_len + 2 + TAG_LEN {
+                    return Ok(false);
+                }
+                let salt = self.read_buf.split_to(salt_len);
+                let chunk = self.read_buf.split_to(2 + TAG_LEN);
+                for (key, user) in self.keys.iter().zip(&self.users) {
+                    let mut aead = Aead::new(self.method, key, &salt);
+                    let mut len = chunk.to_vec();
+                    if aead.decrypt(&mut len).is_ok() {
+                        self.state = ReadState::Payload(payload_len(&len)?);
+                        self.decrypt = Some(aead);
+                        self.key = Some(key.clone());
+                        if let Some(user) = user {
+                            let _ = self.user.set(user.clone());
+                        }
+                        return Ok(true);
+                    }
+                }
+                Err(io::Error::new(io::ErrorKind::InvalidData, "bad mac, no key matches"))
+            }
+            ReadState::Length => {
+                if self.read_buf.len() < 2 + TAG_LEN {
+                    return Ok(false);
+                }
+                let mut len = self.read_buf.split_to(2 + TAG_LEN).to_vec();
+                self.decrypt.as_mut().unwrap().decrypt(&mut len)?;
+                self.state = ReadState::Payload(payload_len(&len)?);
+                Ok(true)
+            }
+            ReadState::Payload(len) => {
+                if self.read_buf.len() < len + TAG_LEN {
+                    return Ok(false);
+                }
+                let mut payload = self.read_buf.split_to(len + TAG_LEN).to_vec();
+                self.decrypt.as_mut().unwrap().decrypt(&mut payload)?;
+                self.plain.extend_from_slice(&payload);
+                self.state = ReadState::Length;
+                Ok(true)
+            }
+        }
+    }
+
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.write_buf.is_empty() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.write_buf))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.write_buf.advance(n);
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn encode(&mut self, data: &[u8]) -> io::Result<()> {
+        if self.encrypt.is_none() {
+            let key = match &self.key {
+                Some(key) => key.clone(),
+                None => return Err(io::Error::other("peer key unknown before its first chunk")),
+            };
+            let salt = generate_key(self.method.salt_len())?;
+            self.write_buf.extend_from_slice(&salt);
+            self.encrypt = Some(Aead::new(self.method, &key, &salt));
+        }
+        let aead = self.encrypt.as_mut().unwrap();
+        let mut len = (data.len() as u16).to_be_bytes().to_vec();
+        aead.encrypt(&mut len);
+        let mut payload = data.to_vec();
+        aead.encrypt(&mut payload);
+        self.write_buf.extend_from_slice(&len);
+        self.write_buf.extend_from_slice(&payload);
+        Ok(())
+    }
+}
+
+fn payload_len(len: &[u8]) -> io::Result<usize> {
+    let len = u16::from_be_bytes([len[0], len[1]]) as usize;
+    if len > MAX_PAYLOAD {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "payload too long"));
+    }
+    Ok(len)
+}
+
+impl<S> AsyncRead for CipherStream<S>
+    where S: AsyncRead + AsyncWrite + Unpin
+{
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if !this.plain.is_empty() {
+                let n = this.plain.len().min(buf.remaining());
+                buf.put_slice(&this.plain.split_to(n));
+                return Poll::Ready(Ok(()));
+            }
+            if this.decode()? {
+                continue;
+            }
+            let mut chunk = [0; 8192];
+            let mut chunk_buf = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut chunk_buf))?;
+            if chunk_buf.filled().is_empty() {
+                // eof is only clean on a chunk boundary
+                if this.read_buf.is_empty() && matches!(this.state, ReadState::Salt | ReadState::Length) {
+                    return Poll::Ready(Ok(()));
+                }
+                return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+            }
+            this.read_buf.extend_from_slice(chunk_buf.filled());
+        }
+    }
+}
+
+impl<S> AsyncWrite for CipherStream<S>
+    where S: AsyncRead + AsyncWrite + Unpin
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let n = buf.len().min(MAX_PAYLOAD);
+        this.encode(&buf[..n])?;
+        // the chunk is buffered, flush or the next write pushes it out
+        if let Poll::Ready(Err(e)) = this.poll_drain(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+impl<S> RawStream for CipherStream<S>
+    where S: AsyncRead + AsyncWrite + Unpin + RawStream
+{
+    type Raw = S::Raw;
+
+    fn raw(&mut self) -> &mut S::Raw {
+        self.inner.raw()
+    }
+
+    fn abort_on_close(&mut self) -> io::Result<()> {
+        self.inner.abort_on_close()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, duplex};
+
+    use crate::crypto::{CipherStream, encode_key, generate_key, Keyring, MAX_PAYLOAD};
+
+    #[tokio::test]
+    async fn round_trip_test() {
+        for method in ["aes-128-gcm", "aes-256-gcm", "chacha20-ietf-poly1305"] {
+            let keyring = Keyring::new(method, "password", &[]).unwrap();
+            let (a, b) = duplex(1024);
+            let mut client = CipherStream::client(a, &keyring);
+            let mut server = CipherStream::server(b, &keyring);
+            let data: Vec<u8> = (0..MAX_PAYLOAD * 3).map(|i| i as u8).collect();
+            let sent = data.clone();
+            let writer = tokio::spawn(async move {
+                client.write_all(&sent).await.unwrap();
+                client.flush().await.unwrap();
+                let mut answer = [0; 2];
+                client.read_exact(&mut answer).await.unwrap();
+                answer
+            });
+            let mut received = vec![0; data.len()];
+            server.read_exact(&mut received).await.unwrap();
+            assert_eq!(received, data);
+            server.write_all(b"ok").await.unwrap();
+            server.flush().await.unwrap();
+            assert_eq!(&writer.await.unwrap(), b"ok");
+        }
+    }
+
+    #[tokio::test]
+    async fn key_rotation_test() {
+        let old = encode_key(&generate_key(32).unwrap());
+        let new = encode_key(&generate_key(32).unwrap());
+        let server_ring = Keyring::new("aes-256-gcm", "", &[new.clone(), old.clone()]).unwrap();
+        for (key, index) in [(old, 1), (new, 0)] {
+            let client_ring = Keyring::new("aes-256-gcm", "", &[key]).unwrap();
+            let (a, b) = duplex(1024);
+            let mut client = CipherStream::client(a, &client_ring);
+            let mut server = CipherStream::server(b, &server_ring);
+            client.write_all(b"hello").await.unwrap();
+            client.flush().await.unwrap();
+            let mut buf = [0; 5];
+            server.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello");
+            assert_eq!(server.key_index(), Some(index));
+        }
+        let client_ring = Keyring::new("aes-256-gcm", "unknown", &[]).unwrap();
+        let (a, b) = duplex(1024);
+        let mut client = CipherStream::client(a, &client_ring);
+        let mut server = CipherStream::server(b, &server_ring);
+        client.write_all(b"hello").await.unwrap();
+        client.flush().await.unwrap();
+        let mut buf = [0; 5];
+        assert!(server.read_exact(&mut buf).await.is_err());
+    }
+}
@@ -0,0 +1,88 @@
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+
+use crate::socket5::{self, Reply};
+use crate::socket5::constant::*;
+
+// what serving or making a socks connection ends with: the codec's own errors, and the refusals of
+// the server's policy, which ss5-proto knows nothing of
+#[derive(Debug)]
+pub enum Error {
+    Socks(socket5::Error),
+    QuotaExceeded,
+    // the target is this server itself
+    Loop(SocketAddr),
+    // a destination the server refuses to reach, e.g. a private address
+    Forbidden(IpAddr),
+    // the domain is on the blocklist
+    Blocked(String),
+    // the host already has as many connections through the server as it may
+    TargetBusy(String),
+    // the client's ip already has as many connections open as it may
+    SourceBusy(IpAddr),
+    // new requests are shed while the server is past its load thresholds
+    Overloaded,
+    // the task serving the connection panicked, with what it panicked with
+    Panicked(String),
+    // the connection moved more bytes than its transfer rule allows
+    TransferExceeded(u64),
+    // new requests are turned away while the server is in maintenance mode
+    Maintenance,
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Socks(e) => write!(f, "{}", e),
+            Error::QuotaExceeded => write!(f, "quota exceeded"),
+            Error::Loop(addr) => write!(f, "{} loops back to this server", addr),
+            Error::Forbidden(ip) => write!(f, "destination {} not allowed", ip),
+            Error::Blocked(host) => write!(f, "{} is blocked", host),
+            Error::TargetBusy(host) => write!(f, "too many connections to {}", host),
+            Error::SourceBusy(ip) => write!(f, "too many connections from {}", ip),
+            Error::Overloaded => write!(f, "server overloaded"),
+            Error::Panicked(message) => write!(f, "panicked : {}", message),
+            Error::TransferExceeded(max) => write!(f, "transfer over {} bytes", max),
+            Error::Maintenance => write!(f, "server in maintenance"),
+        }
+    }
+}
+
+impl From<socket5::Error> for Error {
+    fn from(err: socket5::Error) -> Self {
+        Error::Socks(err)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Socks(socket5::Error::IoError(err))
+    }
+}
+
+impl Error {
+    // the server turned the request down itself, rather than failing to serve it
+    pub fn is_refusal(&self) -> bool {
+        matches!(self, Error::Socks(socket5::Error::AuthFailed(_)) | Error::QuotaExceeded | Error::Loop(_) | Error::Forbidden(_)
+            | Error::Blocked(_) | Error::TargetBusy(_) | Error::SourceBusy(_) | Error::TransferExceeded(_))
+    }
+
+    pub fn to_reply(&self) -> Reply {
+        Reply::from_u8(
+            match self {
+                Error::Socks(e) => return e.to_reply(),
+                Error::QuotaExceeded => REP_CONN_NO,
+                Error::Loop(_) => REP_CONN_NO,
+                Error::Forbidden(_) => REP_CONN_NO,
+                Error::Blocked(_) => REP_HOST_NO,
+                Error::TargetBusy(_) => REP_CONN_NO,
+                Error::SourceBusy(_) => REP_CONN_NO,
+                Error::Overloaded => REP_SERVER_FAIL,
+                Error::Panicked(_) => REP_SERVER_FAIL,
+                Error::TransferExceeded(_) => REP_CONN_NO,
+                Error::Maintenance => REP_SERVER_FAIL,
+            }
+        )
+    }
+}
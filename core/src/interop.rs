@@ -0,0 +1,112 @@
+// real socks clients against the server, built with `--features interop`; a client that isn't
+// installed skips its test rather than failing it
+use std::net::SocketAddr;
+use std::process::Output;
+use std::time::Duration;
+
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+use tokio::process::Command;
+
+use crate::config::{ServerConfig, UserConfig};
+use crate::test_util::{config, socks_server, test_state};
+use crate::transport::Endpoint;
+
+async fn run(program: &str, args: &[&str]) -> Option<Output> {
+    let child = Command::new(program)
+        .args(args)
+        .stdin(std::process::Stdio::null())
+        .kill_on_drop(true)
+        .output();
+    match tokio::time::timeout(Duration::from_secs(20), child).await.expect("client hung") {
+        Ok(output) => Some(output),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            eprintln!("{} not installed, skipping", program);
+            None
+        }
+        Err(e) => panic!("run {} : {}", program, e),
+    }
+}
+
+// answers every request with a fixed body
+async fn http_server() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                    match stream.read(&mut buf).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(n) => request.extend_from_slice(&buf[..n]),
+                    }
+                }
+                let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\nConnection: close\r\n\r\nhello").await;
+            });
+        }
+    });
+    addr
+}
+
+async fn server(config: ServerConfig) -> String {
+    socks_server(Endpoint::Tcp("127.0.0.1:0".to_string()), test_state(config)).await.to_string()
+}
+
+#[tokio::test]
+async fn curl_test() {
+    let http = http_server().await;
+    let url = format!("http://localhost:{}/", http.port());
+    let proxy = server(config()).await;
+    // the name goes to the server to resolve, and the address the client resolved
+    for flag in ["--socks5-hostname", "--socks5"] {
+        let Some(output) = run("curl", &["-sS", "--max-time", "10", flag, &proxy, &url]).await else {
+            return;
+        };
+        assert!(output.status.success(), "{} : {}", flag, String::from_utf8_lossy(&output.stderr));
+        assert_eq!(output.stdout, b"hello");
+    }
+
+    let proxy = server(ServerConfig {
+        users: vec![UserConfig { name: "alice".to_string(), password: Some("secret".to_string()), ..UserConfig::default() }],
+        ..config()
+    }).await;
+    let authed = run("curl", &["-sS", "--max-time", "10", "--socks5-hostname", &proxy, "--proxy-user", "alice:secret", &url]).await.unwrap();
+    assert!(authed.status.success(), "{}", String::from_utf8_lossy(&authed.stderr));
+    assert_eq!(authed.stdout, b"hello");
+    let refused = run("curl", &["-sS", "--max-time", "10", "--socks5-hostname", &proxy, "--proxy-user", "alice:wrong", &url]).await.unwrap();
+    assert!(!refused.status.success());
+}
+
+#[tokio::test]
+async fn ssh_test() {
+    // enough of an ssh server to see the client's banner come through
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target = listener.local_addr().unwrap();
+    let banner = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        stream.write_all(b"SSH-2.0-ss5_interop\r\n").await.unwrap();
+        let mut line = String::new();
+        BufReader::new(stream).read_line(&mut line).await.unwrap();
+        line
+    });
+    let proxy = server(config()).await;
+    let command = format!("ProxyCommand=nc -X 5 -x {} %h %p", proxy);
+    let port = target.port().to_string();
+    let args = [
+        "-o", &command, "-o", "BatchMode=yes", "-o", "StrictHostKeyChecking=no", "-o", "UserKnownHostsFile=/dev/null",
+        "-o", "ConnectTimeout=10", "-p", &port, "interop@localhost", "true",
+    ];
+    // the handshake can't finish against the fake server, only the banner matters
+    let Some(output) = run("ssh", &args).await else {
+        return;
+    };
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if stderr.contains("nc: ") && stderr.contains("not found") {
+        eprintln!("nc not installed, skipping");
+        return;
+    }
+    let line = tokio::time::timeout(Duration::from_secs(10), banner).await.expect("no banner").unwrap();
+    assert!(line.starts_with("SSH-2.0-"), "{:?} : {}", line, stderr);
+}
@@ -0,0 +1,219 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::socket5::Address;
+
+// traffic is kept in ten minute slots for a day
+const SLOT: u64 = 600;
+const HOUR_SLOTS: u64 = 6;
+const DAY_SLOTS: u64 = 144;
+// destinations beyond this many in the last day are counted together
+const MAX_DESTINATIONS: usize = 4096;
+pub const OTHER: &str = "(other)";
+// connections of clients that didn't authenticate
+pub const ANONYMOUS: &str = "(anonymous)";
+
+// bytes up and down per slot, oldest first
+#[derive(Default)]
+struct Series {
+    slots: VecDeque<(u64, u64, u64)>,
+}
+
+impl Series {
+    fn add(&mut self, slot: u64, up: u64, down: u64) {
+        match self.slots.back_mut() {
+            Some(last) if last.0 == slot => {
+                last.1 += up;
+                last.2 += down;
+            }
+            _ => self.slots.push_back((slot, up, down)),
+        }
+        self.prune(slot);
+    }
+
+    fn prune(&mut self, slot: u64) {
+        while self.slots.front().is_some_and(|first| first.0 + DAY_SLOTS <= slot) {
+            self.slots.pop_front();
+        }
+    }
+
+    // the last `slots` slots up to and including `slot`
+    fn sum(&self, slot: u64, slots: u64) -> (u64, u64) {
+        self.slots.iter()
+            .filter(|s| s.0 + slots > slot)
+            .fold((0, 0), |(up, down), s| (up + s.1, down + s.2))
+    }
+}
+
+#[derive(Default)]
+struct Tables {
+    users: HashMap<String, Series>,
+    destinations: HashMap<String, Series>,
+}
+
+// traffic per user and per destination host over the last hour and day
+#[derive(Default)]
+pub struct Ledger {
+    tables: Mutex<Tables>,
+}
+
+impl Ledger {
+    pub fn record(&self, user: Option<&str>, destination: Option<&Address>, up: u64, down: u64) {
+        self.record_at(now(), user, destination, up, down)
+    }
+
+    fn record_at(&self, secs: u64, user: Option<&str>, destination: Option<&Address>, up: u64, down: u64) {
+        let slot = secs / SLOT;
+        let mut tables = self.tables.lock().unwrap();
+        let user = user.unwrap_or(ANONYMOUS);
+        match tables.users.get_mut(user) {
+            Some(series) => series.add(slot, up, down),
+            None => tables.users.entry(user.to_string()).or_default().add(slot, up, down),
+        }
+        let Some(destination) = destination else {
+            return;
+        };
+        let mut host = match destination {
+            Address::Address(addr) => addr.ip().to_string(),
+            Address::DomainName(host, _) => host.to_ascii_lowercase(),
+        };
+        let destinations = &mut tables.destinations;
+        if !destinations.contains_key(&host) && destinations.len() >= MAX_DESTINATIONS {
+            retain_recent(destinations, slot);
+            if destinations.len() >= MAX_DESTINATIONS {
+                host = OTHER.to_string();
+            }
+        }
+        destinations.entry(host).or_default().add(slot, up, down);
+    }
+
+    pub fn report(&self) -> TrafficReport {
+        self.report_at(now())
+    }
+
+    fn report_at(&self, secs: u64) -> TrafficReport {
+        let slot = secs / SLOT;
+        let mut tables = self.tables.lock().unwrap();
+        retain_recent(&mut tables.users, slot);
+        retain_recent(&mut tables.destinations, slot);
+        TrafficReport { users: rows(&tables.users, slot), destinations: rows(&tables.destinations, slot) }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+// drops what fell out of the day
+fn retain_recent(table: &mut HashMap<String, Series>, slot: u64) {
+    table.retain(|_, series| {
+        series.prune(slot);
+        !series.slots.is_empty()
+    });
+}
+
+// the busiest of the day first
+fn rows(table: &HashMap<String, Series>, slot: u64) -> Vec<TrafficRow> {
+    let mut rows: Vec<TrafficRow> = table.iter().map(|(name, series)| {
+        let (hour_up, hour_down) = series.sum(slot, HOUR_SLOTS);
+        let (day_up, day_down) = series.sum(slot, DAY_SLOTS);
+        TrafficRow { name: name.clone(), hour_up, hour_down, day_up, day_down }
+    }).collect();
+    rows.sort_by(|a, b| (b.day_up + b.day_down).cmp(&(a.day_up + a.day_down)).then_with(|| a.name.cmp(&b.name)));
+    rows
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct TrafficRow {
+    pub name: String,
+    pub hour_up: u64,
+    pub hour_down: u64,
+    pub day_up: u64,
+    pub day_down: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct TrafficReport {
+    pub users: Vec<TrafficRow>,
+    pub destinations: Vec<TrafficRow>,
+}
+
+impl TrafficReport {
+    // the tables `rust-ss5 stats` prints, the top `limit` destinations only
+    pub fn table(&self, limit: usize) -> String {
+        let mut out = String::new();
+        table(&mut out, "user", &self.users, usize::MAX);
+        out.push('\n');
+        table(&mut out, "destination", &self.destinations, limit);
+        out
+    }
+}
+
+fn table(out: &mut String, title: &str, rows: &[TrafficRow], limit: usize) {
+    let width = rows.iter().take(limit).map(|row| row.name.len()).max().unwrap_or(0).max(title.len());
+    let _ = writeln!(out, "{:<width$}  {:>10}  {:>10}  {:>10}  {:>10}", title, "hour up", "hour down", "day up", "day down");
+    for row in rows.iter().take(limit) {
+        let _ = writeln!(out, "{:<width$}  {:>10}  {:>10}  {:>10}  {:>10}",
+                         row.name, bytes(row.hour_up), bytes(row.hour_down), bytes(row.day_up), bytes(row.day_down));
+    }
+    if rows.len() > limit {
+        let _ = writeln!(out, "... {} more", rows.len() - limit);
+    }
+}
+
+// 1023 B, 1.5 KiB, 20.0 MiB, ...
+pub fn bytes(n: u64) -> String {
+    const UNITS: [&str; 5] = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+    if n < 1024 {
+        return format!("{} B", n);
+    }
+    let mut value = n as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::ledger::{ANONYMOUS, bytes, Ledger, SLOT};
+    use crate::socket5::Address;
+
+    #[test]
+    fn ledger_test() {
+        let ledger = Ledger::default();
+        let start = 1_000_000 * SLOT;
+        let example = Address::DomainName("Example.com".to_string(), 443);
+        ledger.record_at(start, Some("alice"), Some(&example), 10, 100);
+        ledger.record_at(start + 2 * SLOT, Some("alice"), Some(&Address::Address("10.0.0.1:80".parse().unwrap())), 1, 2);
+        ledger.record_at(start + 7 * SLOT, None, Some(&example), 5, 50);
+
+        let report = ledger.report_at(start + 7 * SLOT);
+        let alice = report.users.iter().find(|row| row.name == "alice").unwrap();
+        // the first slot is out of the hour by now, still in the day
+        assert_eq!((alice.hour_up, alice.hour_down, alice.day_up, alice.day_down), (1, 2, 11, 102));
+        assert_eq!(report.users[1].name, ANONYMOUS);
+        assert_eq!(report.destinations[0].name, "example.com");
+        assert_eq!((report.destinations[0].hour_down, report.destinations[0].day_down), (50, 150));
+        assert_eq!(report.destinations[1].name, "10.0.0.1");
+        let table = report.table(1);
+        assert!(table.starts_with("user "), "{}", table);
+        assert!(table.contains("example.com"));
+        assert!(table.contains("... 1 more"));
+
+        // a day later only what's newer is left
+        let report = ledger.report_at(start + 146 * SLOT);
+        assert_eq!(report.users.len(), 1);
+        assert_eq!(report.destinations.len(), 1);
+        assert!(ledger.report_at(start + 200 * SLOT).users.is_empty());
+
+        assert_eq!((bytes(1023), bytes(1536), bytes(20 * 1024 * 1024)), ("1023 B".to_string(), "1.5 KiB".to_string(), "20.0 MiB".to_string()));
+    }
+}
@@ -0,0 +1,85 @@
+#[cfg(feature = "runtime")]
+pub mod config;
+pub use ss5_proto::socket5;
+#[cfg(feature = "runtime")]
+pub mod error;
+#[cfg(feature = "runtime")]
+pub mod tcp;
+#[cfg(feature = "runtime")]
+pub mod transport;
+#[cfg(feature = "runtime")]
+pub mod udp;
+#[cfg(feature = "runtime")]
+pub mod pool;
+#[cfg(feature = "runtime")]
+pub mod quota;
+#[cfg(feature = "runtime")]
+pub mod server;
+#[cfg(feature = "runtime")]
+pub mod stats;
+#[cfg(feature = "runtime")]
+pub mod local;
+#[cfg(feature = "runtime")]
+pub mod crypto;
+#[cfg(feature = "runtime")]
+pub mod bench;
+#[cfg(feature = "runtime")]
+pub mod upstream;
+#[cfg(feature = "runtime")]
+pub mod subscription;
+#[cfg(feature = "runtime")]
+pub mod obfs;
+#[cfg(feature = "runtime")]
+pub mod trace;
+#[cfg(feature = "runtime")]
+pub mod relay;
+#[cfg(feature = "runtime")]
+pub mod policy;
+#[cfg(feature = "runtime")]
+pub mod limit;
+#[cfg(feature = "runtime")]
+pub mod mux;
+#[cfg(feature = "runtime")]
+pub mod blocklist;
+#[cfg(feature = "runtime")]
+pub mod rules;
+#[cfg(feature = "runtime")]
+pub mod nat;
+#[cfg(feature = "runtime")]
+pub mod metrics;
+#[cfg(feature = "runtime")]
+pub mod sniff;
+#[cfg(feature = "runtime")]
+pub mod access;
+#[cfg(feature = "runtime")]
+pub mod store;
+#[cfg(feature = "runtime")]
+pub mod pac;
+#[cfg(feature = "runtime")]
+pub mod sysproxy;
+#[cfg(feature = "runtime")]
+pub mod priority;
+#[cfg(feature = "runtime")]
+pub mod resolver;
+#[cfg(feature = "runtime")]
+pub mod nat64;
+#[cfg(feature = "runtime")]
+pub mod ledger;
+#[cfg(feature = "runtime")]
+pub mod webhook;
+#[cfg(feature = "runtime")]
+pub mod buffers;
+#[cfg(feature = "runtime")]
+pub mod testing;
+#[cfg(feature = "runtime")]
+pub mod unwind;
+#[cfg(feature = "runtime")]
+pub mod asn;
+#[cfg(feature = "runtime")]
+pub mod selfcheck;
+#[cfg(feature = "runtime")]
+pub mod mmsg;
+#[cfg(all(test, feature = "runtime"))]
+mod test_util;
+#[cfg(all(test, feature = "interop"))]
+mod interop;
\ No newline at end of file
@@ -0,0 +1,407 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use log::warn;
+
+use crate::config::{AuthBanConfig, RateLimitConfig, ShedConfig};
+use crate::error::Error;
+use crate::socket5::Address;
+
+// sources tracked before idle ones are forgotten
+const MAX_TRACKED: usize = 65536;
+// how long a load average reading is reused
+const LOAD_TTL: Duration = Duration::from_secs(1);
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    // rejections since the bucket last had a token
+    strikes: u32,
+    banned_until: Option<Instant>,
+}
+
+// a token bucket of new connections per source ip, sources that keep hitting it empty get banned for a while
+#[derive(Clone)]
+pub struct RateLimiter {
+    config: Arc<RateLimitConfig>,
+    buckets: Arc<Mutex<HashMap<IpAddr, Bucket>>>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        RateLimiter {
+            config: Arc::new(config),
+            buckets: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.config.rate > 0.0
+    }
+
+    // takes a token for a new connection from the ip, false when it has none or is banned
+    pub fn allow(&self, ip: IpAddr) -> bool {
+        if !self.is_enabled() {
+            return true;
+        }
+        let now = Instant::now();
+        let burst = self.config.burst.max(1) as f64;
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_TRACKED && !buckets.contains_key(&ip) {
+            self.forget_idle(&mut buckets, now);
+        }
+        let bucket = buckets.entry(ip).or_insert(Bucket { tokens: burst, updated: now, strikes: 0, banned_until: None });
+        if let Some(until) = bucket.banned_until {
+            if now < until {
+                return false;
+            }
+            bucket.banned_until = None;
+            bucket.strikes = 0;
+        }
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.config.rate).min(burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            bucket.strikes = 0;
+            return true;
+        }
+        bucket.strikes += 1;
+        if self.config.ban > 0 && bucket.strikes >= self.config.strikes.max(1) {
+            warn!("ban {} for {}s, too many new connections", ip, self.config.ban);
+            bucket.banned_until = Some(now + Duration::from_secs(self.config.ban));
+        }
+        false
+    }
+
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        let buckets = self.buckets.lock().unwrap();
+        buckets.get(&ip).and_then(|b| b.banned_until).is_some_and(|until| Instant::now() < until)
+    }
+
+    // a bucket that filled up again and isn't banned is the same as no bucket
+    fn forget_idle(&self, buckets: &mut HashMap<IpAddr, Bucket>, now: Instant) {
+        let burst = self.config.burst.max(1) as f64;
+        let full = Duration::from_secs_f64(burst / self.config.rate);
+        buckets.retain(|_, b| {
+            b.banned_until.is_some_and(|until| now < until) || now.duration_since(b.updated) < full
+        });
+    }
+}
+
+
+struct Failures {
+    times: Vec<Instant>,
+    banned_until: Option<Instant>,
+}
+
+// failed logins per source ip, too many within the window ban it and run the hook
+#[derive(Clone)]
+pub struct AuthBans {
+    config: Arc<AuthBanConfig>,
+    failures: Arc<Mutex<HashMap<IpAddr, Failures>>>,
+}
+
+impl AuthBans {
+    pub fn new(config: AuthBanConfig) -> Self {
+        AuthBans {
+            config: Arc::new(config),
+            failures: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    // true when this failure got the ip banned
+    pub fn failed(&self, ip: IpAddr, user: &str) -> bool {
+        // one line per failure in a fixed shape, for fail2ban style filters
+        warn!("auth failure : ip={} user={}", ip, user);
+        if self.config.failures == 0 {
+            return false;
+        }
+        let now = Instant::now();
+        let window = Duration::from_secs(self.config.window);
+        let mut failures = self.failures.lock().unwrap();
+        if failures.len() >= MAX_TRACKED && !failures.contains_key(&ip) {
+            failures.retain(|_, f| f.banned_until.is_some_and(|until| now < until)
+                || f.times.last().is_some_and(|last| now.duration_since(*last) < window));
+        }
+        let entry = failures.entry(ip).or_insert(Failures { times: Vec::new(), banned_until: None });
+        entry.times.retain(|time| now.duration_since(*time) < window);
+        entry.times.push(now);
+        if (entry.times.len() as u32) < self.config.failures {
+            return false;
+        }
+        entry.times.clear();
+        entry.banned_until = Some(now + Duration::from_secs(self.config.ban));
+        warn!("auth ban : ip={} seconds={}", ip, self.config.ban);
+        if let Some(command) = &self.config.command {
+            run_hook(command, ip);
+        }
+        true
+    }
+
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        let failures = self.failures.lock().unwrap();
+        failures.get(&ip).and_then(|f| f.banned_until).is_some_and(|until| Instant::now() < until)
+    }
+}
+
+// the command split on whitespace with the ip as its last argument, no shell in between
+fn run_hook(command: &str, ip: IpAddr) {
+    let mut parts = command.split_whitespace();
+    let Some(program) = parts.next() else {
+        return;
+    };
+    let spawned = tokio::process::Command::new(program).args(parts).arg(ip.to_string()).spawn();
+    match spawned {
+        Ok(mut child) => {
+            tokio::spawn(async move {
+                let _ = child.wait().await;
+            });
+        }
+        Err(e) => warn!("run ban hook {} fail : {}", command, e),
+    }
+}
+
+// open connections by key: per destination host across every client, so one client can't pile
+// thousands onto a single site, ports of a host counting together; or per source ip, so one
+// misconfigured client can't take every connection the server has
+#[derive(Clone)]
+pub struct ConnectionLimit {
+    max: usize,
+    open: Arc<Mutex<HashMap<String, usize>>>,
+}
+
+impl ConnectionLimit {
+    // 0 is unlimited
+    pub fn new(max: usize) -> Self {
+        ConnectionLimit { max, open: Arc::default() }
+    }
+
+    // held for as long as the connection to the target is open
+    pub fn acquire_target(&self, target: &Address) -> Result<LimitGuard, Error> {
+        let host = match target {
+            Address::Address(addr) => addr.ip().to_string(),
+            Address::DomainName(host, _) => host.trim_end_matches('.').to_ascii_lowercase(),
+        };
+        self.hold(host.clone()).ok_or(Error::TargetBusy(host))
+    }
+
+    // held for as long as the client's connection is open
+    pub fn acquire_source(&self, ip: IpAddr) -> Result<LimitGuard, Error> {
+        self.hold(ip.to_string()).ok_or(Error::SourceBusy(ip))
+    }
+
+    fn hold(&self, key: String) -> Option<LimitGuard> {
+        if self.max == 0 {
+            return Some(LimitGuard { limit: None, key });
+        }
+        let mut open = self.open.lock().unwrap();
+        let count = open.entry(key.clone()).or_insert(0);
+        if *count >= self.max {
+            warn!("connection limit of {} reached for {}", self.max, key);
+            return None;
+        }
+        *count += 1;
+        Some(LimitGuard { limit: Some(self.clone()), key })
+    }
+
+    pub fn open(&self, key: &str) -> usize {
+        self.open.lock().unwrap().get(key).copied().unwrap_or(0)
+    }
+}
+
+pub struct LimitGuard {
+    limit: Option<ConnectionLimit>,
+    key: String,
+}
+
+impl Drop for LimitGuard {
+    fn drop(&mut self) {
+        let Some(limit) = &self.limit else {
+            return;
+        };
+        let mut open = limit.open.lock().unwrap();
+        if let Some(count) = open.get_mut(&self.key) {
+            *count -= 1;
+            if *count == 0 {
+                open.remove(&self.key);
+            }
+        }
+    }
+}
+// while on, new requests get a server failure and relays already open carry on, e.g. to let
+// them drain before a planned restart; toggled through the metrics listener
+#[derive(Debug, Clone, Default)]
+pub struct Maintenance {
+    on: Arc<AtomicBool>,
+}
+
+impl Maintenance {
+    pub fn set(&self, on: bool) {
+        if self.on.swap(on, Ordering::Relaxed) != on {
+            warn!("maintenance mode {}", if on { "on, new requests are refused" } else { "off" });
+        }
+    }
+
+    pub fn is_on(&self) -> bool {
+        self.on.load(Ordering::Relaxed)
+    }
+
+    pub fn check(&self) -> Result<(), Error> {
+        match self.is_on() {
+            true => Err(Error::Maintenance),
+            false => Ok(()),
+        }
+    }
+}
+
+// turns new requests away with a server failure while the server is overloaded, so the relays
+// already open keep their share instead of everything slowing down together
+#[derive(Clone)]
+pub struct LoadShedder {
+    config: Arc<ShedConfig>,
+    // the last load reading and when it was taken
+    load: Arc<Mutex<Option<(Instant, f64)>>>,
+}
+
+impl LoadShedder {
+    pub fn new(config: ShedConfig) -> Self {
+        LoadShedder { config: Arc::new(config), load: Arc::default() }
+    }
+
+    pub fn check(&self, connections: u64) -> Result<(), Error> {
+        if self.config.connections > 0 && connections > self.config.connections {
+            warn!("shedding request, {} connections open", connections);
+            return Err(Error::Overloaded);
+        }
+        if self.config.load > 0.0 {
+            let load = self.load();
+            if load > self.config.load {
+                warn!("shedding request, load {:.2} per cpu", load);
+                return Err(Error::Overloaded);
+            }
+        }
+        Ok(())
+    }
+
+    // 0 where there is no /proc/loadavg to read
+    fn load(&self) -> f64 {
+        let mut cached = self.load.lock().unwrap();
+        let now = Instant::now();
+        if let Some((read, load)) = *cached {
+            if now.duration_since(read) < LOAD_TTL {
+                return load;
+            }
+        }
+        let cpus = std::thread::available_parallelism().map_or(1, |n| n.get());
+        let load = std::fs::read_to_string("/proc/loadavg").ok()
+            .and_then(|text| loadavg(&text))
+            .map_or(0.0, |load| load / cpus as f64);
+        *cached = Some((now, load));
+        load
+    }
+}
+
+// the one minute average, "0.52 0.58 0.59 1/467 12345"
+fn loadavg(text: &str) -> Option<f64> {
+    text.split_whitespace().next()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use crate::config::{AuthBanConfig, RateLimitConfig, ShedConfig};
+    use crate::error::Error;
+    use crate::limit::{AuthBans, ConnectionLimit, LoadShedder, loadavg, RateLimiter};
+    use crate::socket5::Address;
+
+    #[test]
+    fn rate_limit_test() {
+        let limiter = RateLimiter::new(RateLimitConfig { rate: 0.001, burst: 2, strikes: 2, ban: 60 });
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let other: IpAddr = "10.0.0.2".parse().unwrap();
+        assert!(limiter.allow(ip));
+        assert!(limiter.allow(ip));
+        assert!(!limiter.allow(ip));
+        assert!(!limiter.is_banned(ip));
+        assert!(!limiter.allow(ip));
+        assert!(limiter.is_banned(ip));
+        // every source has a bucket of its own
+        assert!(limiter.allow(other));
+
+        let disabled = RateLimiter::new(RateLimitConfig::default());
+        assert!((0..100).all(|_| disabled.allow(ip)));
+    }
+
+    #[test]
+    fn connection_limit_test() {
+        let limit = ConnectionLimit::new(2);
+        let target = |host: &str, port| Address::DomainName(host.to_string(), port);
+        let first = limit.acquire_target(&target("example.com", 443)).unwrap();
+        let _second = limit.acquire_target(&target("Example.com.", 80)).unwrap();
+        assert!(matches!(limit.acquire_target(&target("example.com", 443)), Err(Error::TargetBusy(_))));
+        // other hosts aren't held back
+        let _other = limit.acquire_target(&target("example.org", 443)).unwrap();
+        drop(first);
+        assert_eq!(limit.open("example.com"), 1);
+        assert!(limit.acquire_target(&target("example.com", 443)).is_ok());
+        assert_eq!(limit.open("example.com"), 1);
+
+        let unlimited = ConnectionLimit::new(0);
+        let guards: Vec<_> = (0..100).map(|_| unlimited.acquire_target(&target("example.com", 443)).unwrap()).collect();
+        assert_eq!((guards.len(), unlimited.open("example.com")), (100, 0));
+
+        let sources = ConnectionLimit::new(1);
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let held = sources.acquire_source(ip).unwrap();
+        assert!(matches!(sources.acquire_source(ip), Err(Error::SourceBusy(busy)) if busy == ip));
+        assert!(sources.acquire_source("10.0.0.2".parse().unwrap()).is_ok());
+        drop(held);
+        assert!(sources.acquire_source(ip).is_ok());
+    }
+
+    #[test]
+    fn shed_test() {
+        let shedder = LoadShedder::new(ShedConfig { connections: 2, load: 0.0 });
+        assert!(shedder.check(2).is_ok());
+        assert!(matches!(shedder.check(3), Err(Error::Overloaded)));
+        assert!(LoadShedder::new(ShedConfig::default()).check(u64::MAX).is_ok());
+        assert_eq!(loadavg("0.52 0.58 0.59 1/467 12345\n"), Some(0.52));
+        assert_eq!(loadavg(""), None);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn auth_ban_test() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("rust-ss5-ban-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (hook, banned) = (dir.join("hook.sh"), dir.join("banned"));
+        std::fs::write(&hook, format!("#!/bin/sh\necho \"$1\" > {}\n", banned.display())).unwrap();
+        std::fs::set_permissions(&hook, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let bans = AuthBans::new(AuthBanConfig {
+            failures: 2,
+            window: 60,
+            ban: 60,
+            command: Some(hook.display().to_string()),
+        });
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        assert!(!bans.failed(ip, "alice"));
+        assert!(!bans.is_banned(ip));
+        assert!(bans.failed(ip, "alice"));
+        assert!(bans.is_banned(ip));
+        for _ in 0..100 {
+            if std::fs::read_to_string(&banned).is_ok_and(|s| s.ends_with('\n')) {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert_eq!(std::fs::read_to_string(&banned).unwrap(), "10.0.0.1\n");
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,582 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{info, warn};
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+use crate::blocklist;
+use crate::blocklist::Blocklist;
+use crate::config::LocalConfig;
+use crate::metrics;
+use crate::mux::{Sessions, StreamStats};
+use crate::pac;
+use crate::socket5::Address;
+use crate::subscription;
+use crate::sysproxy::{SystemProxy, Target};
+use crate::tcp::{ClientOptions, TcpSocksClient};
+use crate::transport::{Endpoint, Listener, Protect};
+use crate::upstream::{Upstream, Upstreams};
+use crate::unwind::CatchUnwind;
+
+#[derive(Clone)]
+pub struct LocalState {
+    pub config: LocalConfig,
+    pub upstreams: Upstreams,
+    pub sessions: Sessions,
+    pub blocklist: Arc<Blocklist>,
+    pub protect: Option<Protect>,
+}
+
+impl LocalState {
+    // matched against the name or, for targets sent as an ip, the ip as written
+    pub fn direct(&self, address: &Address) -> bool {
+        let host = match address {
+            Address::Address(addr) => addr.ip().to_string(),
+            Address::DomainName(host, _) => host.clone(),
+        };
+        self.config.direct.iter().any(|pattern| pattern.matches(&host))
+    }
+
+    // how requests are negotiated with the upstream servers
+    pub fn upstream_options(&self) -> ClientOptions {
+        ClientOptions { fast_open: self.config.fast_open, ..ClientOptions::default() }
+    }
+}
+
+// what LocalHandle::stats and the local /metrics report
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LocalStats {
+    pub servers: usize,
+    // connections relaying through the upstreams, and those of them still on a replaced set
+    pub active: usize,
+    pub draining: usize,
+    pub listeners: usize,
+}
+
+pub struct LocalHandle {
+    state: LocalState,
+    endpoints: Vec<Endpoint>,
+    shutdown: watch::Sender<bool>,
+    tasks: Vec<JoinHandle<()>>,
+    system_proxy: Option<SystemProxy>,
+}
+
+// bind the local socks listeners, every request is forwarded to one of the upstreams
+pub async fn start(config: LocalConfig) -> io::Result<LocalHandle> {
+    let mut listeners = Vec::new();
+    for endpoint in config.endpoints() {
+        listeners.push(endpoint.bind().await?);
+    }
+    start_with(config, listeners, None).await
+}
+
+// for an app embedding the client: listeners it bound itself, e.g. from fds it was handed, in
+// place of the configured ones, and protect run on every socket to the upstreams and direct
+// targets; the subscription fetch isn't covered, its http client opens its own sockets
+pub async fn start_with(config: LocalConfig, listeners: Vec<Listener>, protect: Option<Protect>) -> io::Result<LocalHandle> {
+    let upstreams = Upstreams::new(vec![Upstream {
+        name: config.server.to_string(),
+        endpoint: config.server.clone(),
+        keyring: config.keyring()?,
+    }]);
+    if let Some(subscription) = &config.subscription {
+        match subscription::fetch(&subscription.url, Duration::from_secs(subscription.timeout)).await {
+            Ok(servers) if !servers.is_empty() => upstreams.replace(servers),
+            Ok(_) => warn!("subscription {} has no usable server, keep {}", subscription.url, config.server),
+            Err(e) => warn!("fetch subscription {} fail, keep {} : {}", subscription.url, config.server, e),
+        }
+    }
+    let blocklist = Arc::new(Blocklist::new(&config.blocklist)?);
+    let state = LocalState { config, upstreams, sessions: Sessions::default(), blocklist, protect };
+    // everything that can fail comes before the first task is spawned, so an error leaves nothing
+    // running on the listeners the caller handed over
+    let endpoints = listeners.iter().map(|listener| listener.local_endpoint()).collect::<io::Result<Vec<_>>>()?;
+    let mut pac = None;
+    if let Some(addr) = &state.config.pac {
+        let socks = endpoints.iter().find_map(|endpoint| match endpoint {
+            Endpoint::Tcp(addr) => addr.parse().ok(),
+            _ => None,
+        });
+        let Some(socks) = socks else {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "pac needs a tcp socks listener"));
+        };
+        let listener = TcpListener::bind(addr.as_str()).await?;
+        pac = Some((listener.local_addr()?, listener, socks));
+    }
+    let mut metrics = None;
+    if let Some(addr) = &state.config.metrics {
+        let listener = TcpListener::bind(addr.as_str()).await?;
+        metrics = Some((listener.local_addr()?, listener));
+    }
+    let system_proxy = match state.config.system_proxy {
+        false => None,
+        true => Some(SystemProxy::set(&system_target(&endpoints, pac.as_ref().map(|(addr, ..)| *addr))?).await?),
+    };
+
+    let (shutdown, watcher) = watch::channel(false);
+    let mut tasks = Vec::new();
+    if let Some(subscription) = state.config.subscription.clone() {
+        let (interval, timeout) = (Duration::from_secs(subscription.refresh.max(1)), Duration::from_secs(subscription.timeout));
+        tasks.push(tokio::spawn(subscription::refresh(subscription.url, interval, timeout, state.upstreams.clone(), watcher.clone())));
+    }
+    for remote in state.config.blocklist.remote.clone() {
+        tasks.push(tokio::spawn(blocklist::refresh(state.blocklist.clone(), remote, watcher.clone())));
+    }
+    for (listener, endpoint) in listeners.into_iter().zip(&endpoints) {
+        info!("start socks5 local, listen : {}, servers : {}", endpoint, state.upstreams.list().len());
+        tasks.push(tokio::spawn(serve(listener, state.clone(), watcher.clone())));
+    }
+    if let Some((addr, listener, socks)) = pac {
+        info!("serve pac, listen : http://{}/proxy.pac", addr);
+        tasks.push(tokio::spawn(pac::serve(listener, socks, state.config.direct.clone(), watcher.clone())));
+    }
+    if let Some((addr, listener)) = metrics {
+        info!("serve metrics, listen : http://{}/metrics", addr);
+        let (state, listeners) = (state.clone(), endpoints.len());
+        tasks.push(tokio::spawn(metrics::serve_local(listener, move || collect(&state, listeners), watcher.clone())));
+    }
+    Ok(LocalHandle { state, endpoints, shutdown, tasks, system_proxy })
+}
+
+pub fn collect(state: &LocalState, listeners: usize) -> LocalStats {
+    LocalStats {
+        servers: state.upstreams.list().len(),
+        active: state.upstreams.active(),
+        draining: state.upstreams.draining(),
+        listeners,
+    }
+}
+
+// listeners on every address are pointed at over loopback
+fn system_target(endpoints: &[Endpoint], pac: Option<SocketAddr>) -> io::Result<Target> {
+    let loopback = |addr: SocketAddr| match addr.ip().is_unspecified() {
+        true => SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), addr.port()),
+        false => addr,
+    };
+    if let Some(pac) = pac {
+        return Ok(Target::Pac(format!("http://{}/proxy.pac", loopback(pac))));
+    }
+    endpoints.iter()
+        .find_map(|endpoint| match endpoint {
+            Endpoint::Tcp(addr) => addr.parse().ok().map(|addr| Target::Socks(loopback(addr))),
+            _ => None,
+        })
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "system_proxy needs a tcp socks listener"))
+}
+
+async fn serve(listener: Listener, state: LocalState, mut shutdown: watch::Receiver<bool>) {
+    loop {
+        tokio::select! {
+            _ = shutdown.changed() => break,
+            accepted = listener.accept() => match accepted {
+                Ok((stream, address)) => {
+                    info!("received request address : {}", address);
+                    let connection = TcpSocksClient::new(stream).local_connect(state.clone());
+                    tokio::spawn(async move {
+                        if let Err(panic) = CatchUnwind::new(Box::pin(connection)).await {
+                            warn!("connection from {} panicked : {}", address, panic);
+                        }
+                    });
+                }
+                Err(e) => {
+                    warn!("accept fail : {}", e);
+                    continue;
+                }
+            }
+        };
+    };
+}
+
+impl LocalHandle {
+    pub fn endpoints(&self) -> &[Endpoint] {
+        &self.endpoints
+    }
+
+    pub fn upstreams(&self) -> &Upstreams {
+        &self.state.upstreams
+    }
+
+    pub fn stats(&self) -> LocalStats {
+        collect(&self.state, self.endpoints.len())
+    }
+
+    // the open streams of the mux sessions with their upstream's name, empty without mux
+    pub async fn streams(&self) -> Vec<(String, StreamStats)> {
+        self.state.sessions.stream_stats().await
+    }
+
+    pub async fn wait(&mut self) {
+        for task in self.tasks.drain(..) {
+            let _ = task.await;
+        }
+    }
+
+    // the os proxy settings are put back once the listeners stopped
+    pub async fn shutdown(mut self) {
+        let _ = self.shutdown.send(true);
+        self.wait().await;
+        if let Some(proxy) = self.system_proxy.take() {
+            let _ = proxy.restore().await;
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use crate::config::{LocalConfig, Resolve, ResolveRule, ServerConfig, SubscriptionConfig};
+    use crate::error::Error;
+    use crate::socket5::{self, Address, Command, Proxy, Reply};
+    use crate::socket5::constant::*;
+    use crate::sysproxy::Target;
+    use crate::tcp::{Accepted, TcpSocksClient};
+    use crate::test_util::{config, echo_server, test_state};
+    use crate::testing::FakeServer;
+    use crate::transport::{Endpoint, Listener, Protect, Stream};
+    use crate::local::LocalStats;
+    use crate::{local, metrics, server};
+
+    #[tokio::test]
+    async fn encrypted_tunnel_test() {
+        let echo_addr = echo_server().await;
+        let server = server::start(ServerConfig {
+            port: 0,
+            password: "secret".to_string(),
+            encrypt: "chacha20-ietf-poly1305".to_string(),
+            ..ServerConfig::default()
+        }).await.unwrap();
+        // the greeting and request sent to the server one after the other, then together
+        for fast_open in [false, true] {
+            let local = local::start(LocalConfig {
+                port: 0,
+                server: server.stats().listeners[0].endpoint.clone(),
+                password: "secret".to_string(),
+                encrypt: "chacha20-ietf-poly1305".to_string(),
+                fast_open,
+                ..LocalConfig::default()
+            }).await.unwrap();
+            let mut client = TcpSocksClient::client_connect(
+                local.endpoints()[0].to_string(),
+                Proxy::new(Command::CONNECT, Address::Address(echo_addr)),
+            ).await.unwrap();
+            client.stream.write_all(b"hello").await.unwrap();
+            let mut buf = [0; 5];
+            client.stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello");
+        }
+    }
+
+    #[tokio::test]
+    async fn mux_tunnel_test() {
+        let echo_addr = echo_server().await;
+        let server = server::start(ServerConfig {
+            port: 0,
+            password: "secret".to_string(),
+            encrypt: "chacha20-ietf-poly1305".to_string(),
+            ..ServerConfig::default()
+        }).await.unwrap();
+        let local = local::start(LocalConfig {
+            port: 0,
+            server: server.stats().listeners[0].endpoint.clone(),
+            password: "secret".to_string(),
+            encrypt: "chacha20-ietf-poly1305".to_string(),
+            mux: true,
+            ..LocalConfig::default()
+        }).await.unwrap();
+        for _ in 0..2 {
+            let mut client = TcpSocksClient::client_connect(
+                local.endpoints()[0].to_string(),
+                Proxy::new(Command::CONNECT, Address::Address(echo_addr)),
+            ).await.unwrap();
+            client.stream.write_all(b"hello").await.unwrap();
+            let mut buf = [0; 5];
+            client.stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello");
+        }
+        // both requests went over the one tunnel connection
+        assert_eq!(server.stats().listeners[0].accepted, 1);
+    }
+
+    #[tokio::test]
+    async fn mux_retry_test() {
+        let echo_addr = echo_server().await;
+        // the first tunnel connection takes the mux method, then drops on the first stream's open frame
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut first, _) = listener.accept().await.unwrap();
+            let mut buf = [0; 16];
+            first.read_exact(&mut buf[..3]).await.unwrap();
+            first.write_all(&[SOCKET5_VERSION, METHOD_MUX]).await.unwrap();
+            first.read_exact(&mut buf[..7]).await.unwrap();
+            drop(first);
+            let (second, _) = listener.accept().await.unwrap();
+            let state = test_state(ServerConfig { ..config() });
+            match TcpSocksClient::new(Stream::Tcp(second)).server_accept(state.clone()).await.unwrap() {
+                Accepted::Mux(client) => client.serve_mux(state).await,
+                _ => panic!("expected a mux session"),
+            }
+        });
+        let local = local::start(LocalConfig {
+            port: 0,
+            server: Endpoint::Tcp(upstream.to_string()),
+            mux: true,
+            ..LocalConfig::default()
+        }).await.unwrap();
+        let mut client = TcpSocksClient::client_connect(
+            local.endpoints()[0].to_string(),
+            Proxy::new(Command::CONNECT, Address::Address(echo_addr)),
+        ).await.unwrap();
+        client.stream.write_all(b"hello").await.unwrap();
+        let mut buf = [0; 5];
+        client.stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+
+    // reads the whole request up to the client's fin, then answers with its length
+    async fn count_server() -> std::net::SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut request = Vec::new();
+                    stream.read_to_end(&mut request).await.unwrap();
+                    stream.write_all(request.len().to_string().as_bytes()).await.unwrap();
+                });
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn half_close_test() {
+        let target = count_server().await;
+        let server = server::start(ServerConfig {
+            port: 0,
+            password: "secret".to_string(),
+            encrypt: "aes-128-gcm".to_string(),
+            ..ServerConfig::default()
+        }).await.unwrap();
+        for mux in [false, true] {
+            let local = local::start(LocalConfig {
+                port: 0,
+                server: server.stats().listeners[0].endpoint.clone(),
+                password: "secret".to_string(),
+                encrypt: "aes-128-gcm".to_string(),
+                mux,
+                ..LocalConfig::default()
+            }).await.unwrap();
+            let mut client = TcpSocksClient::client_connect(
+                local.endpoints()[0].to_string(),
+                Proxy::new(Command::CONNECT, Address::Address(target)),
+            ).await.unwrap();
+            client.stream.write_all(&[7; 3000]).await.unwrap();
+            // only the direction to the target is closed, the answer still comes back
+            client.stream.shutdown().await.unwrap();
+            let mut answer = String::new();
+            tokio::time::timeout(Duration::from_secs(5), client.stream.read_to_string(&mut answer)).await.unwrap().unwrap();
+            assert_eq!(answer, "3000", "mux {}", mux);
+        }
+    }
+
+    #[tokio::test]
+    async fn upstream_reply_test() {
+        // a port nothing listens on anymore
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let server = server::start(ServerConfig {
+            port: 0,
+            ..ServerConfig::default()
+        }).await.unwrap();
+        let local = local::start(LocalConfig {
+            port: 0,
+            server: server.stats().listeners[0].endpoint.clone(),
+            ..LocalConfig::default()
+        }).await.unwrap();
+        let result = TcpSocksClient::client_connect(
+            local.endpoints()[0].to_string(),
+            Proxy::new(Command::CONNECT, Address::Address(closed)),
+        ).await;
+        match result {
+            Err(Error::Socks(socket5::Error::Rejected(reply))) => assert_eq!(reply, Reply::RepConnRefused),
+            _ => panic!("expected the upstream's refusal"),
+        }
+    }
+
+    #[tokio::test]
+    async fn direct_test() {
+        let echo_addr = echo_server().await;
+        // no server behind it, only direct targets get anywhere
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let local = local::start(LocalConfig {
+            port: 0,
+            server: Endpoint::Tcp(closed.to_string()),
+            direct: vec!["localhost".parse().unwrap()],
+            ..LocalConfig::default()
+        }).await.unwrap();
+        let target = Address::DomainName("localhost".to_string(), echo_addr.port());
+        let mut client = TcpSocksClient::client_connect(local.endpoints()[0].to_string(), Proxy::new(Command::CONNECT, target)).await.unwrap();
+        client.stream.write_all(b"hello").await.unwrap();
+        let mut buf = [0; 5];
+        client.stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+        let result = TcpSocksClient::client_connect(
+            local.endpoints()[0].to_string(),
+            Proxy::new(Command::CONNECT, Address::Address(echo_addr)),
+        ).await;
+        assert!(matches!(result, Err(Error::Socks(socket5::Error::Rejected(Reply::RepServerFail)))));
+    }
+
+    #[tokio::test]
+    async fn resolve_test() {
+        // an upstream that only says what it was asked for
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = upstream.local_addr().unwrap();
+        let (seen, mut requests) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = upstream.accept().await {
+                let (answer, _) = FakeServer::new().accept(stream).await.unwrap();
+                seen.send(answer.proxy.unwrap().address).unwrap();
+            }
+        });
+        let local = local::start(LocalConfig {
+            port: 0,
+            server: Endpoint::Tcp(server.to_string()),
+            resolve: vec![
+                ResolveRule { pattern: Some("localhost".parse().unwrap()), ports: vec![443], resolve: Resolve::Remote },
+                ResolveRule { pattern: Some("localhost".parse().unwrap()), ports: vec![], resolve: Resolve::Local },
+            ],
+            ..LocalConfig::default()
+        }).await.unwrap();
+        let listener = local.endpoints()[0].to_string();
+        for (target, resolved) in [("localhost:80", true), ("localhost:443", false), ("example.com:80", false)] {
+            TcpSocksClient::client_connect(&listener, Proxy::new(Command::CONNECT, target.parse().unwrap())).await.unwrap();
+            match requests.recv().await.unwrap() {
+                Address::Address(addr) => assert!(resolved && addr.ip().is_loopback() && addr.port() == 80, "{} went as {}", target, addr),
+                Address::DomainName(host, port) => assert!(!resolved && format!("{}:{}", host, port) == target, "{} went as {}", target, host),
+            }
+        }
+
+        // a name that doesn't resolve here isn't sent on
+        let local = local::start(LocalConfig {
+            port: 0,
+            server: Endpoint::Tcp(server.to_string()),
+            resolve: vec![ResolveRule { pattern: None, ports: vec![], resolve: Resolve::Local }],
+            ..LocalConfig::default()
+        }).await.unwrap();
+        let result = TcpSocksClient::client_connect(
+            local.endpoints()[0].to_string(),
+            Proxy::new(Command::CONNECT, "nothing.invalid:80".parse().unwrap()),
+        ).await;
+        assert!(matches!(result, Err(Error::Socks(socket5::Error::Rejected(Reply::RepHostNo)))));
+    }
+
+    #[tokio::test]
+    async fn start_with_test() {
+        let echo_addr = echo_server().await;
+        let server = server::start(ServerConfig { port: 0, ..ServerConfig::default() }).await.unwrap();
+        // bound by the embedding app, the client only accepts on it
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let protected = Arc::new(AtomicUsize::new(0));
+        let counter = protected.clone();
+        let protect: Protect = Arc::new(move |_socket| {
+            counter.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        });
+        let local = local::start_with(
+            LocalConfig { server: server.stats().listeners[0].endpoint.clone(), ..LocalConfig::default() },
+            vec![Listener::from_std(listener).unwrap()],
+            Some(protect),
+        ).await.unwrap();
+        assert_eq!(local.endpoints(), [Endpoint::Tcp(addr.to_string())]);
+        let mut client = TcpSocksClient::client_connect(addr, Proxy::new(Command::CONNECT, Address::Address(echo_addr))).await.unwrap();
+        client.stream.write_all(b"hello").await.unwrap();
+        let mut buf = [0; 5];
+        client.stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+        assert_eq!(protected.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn draining_test() {
+        let local = local::start(LocalConfig { port: 0, ..LocalConfig::default() }).await.unwrap();
+        let lease = local.upstreams().pick().unwrap().1;
+        local.upstreams().replace(vec![local.upstreams().list()[0].clone()]);
+        assert_eq!(local.stats(), LocalStats { servers: 1, active: 1, draining: 1, listeners: 1 });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (_shutdown, watcher) = tokio::sync::watch::channel(false);
+        let stats = local.stats();
+        tokio::spawn(metrics::serve_local(listener, move || stats.clone(), watcher));
+        // one that never sends its request holds up no one else
+        let _idle = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"GET /metrics HTTP/1.1\r\nHost: x\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        tokio::time::timeout(std::time::Duration::from_secs(1), stream.read_to_string(&mut response)).await.unwrap().unwrap();
+        assert!(response.contains("ss5_local_draining_connections 1\n"), "{}", response);
+        drop(lease);
+        assert_eq!(local.stats().draining, 0);
+
+        // with no upstream left the request is still answered
+        local.upstreams().replace(Vec::new());
+        let result = TcpSocksClient::client_connect(
+            local.endpoints()[0].to_string(),
+            Proxy::new(Command::CONNECT, "example.com:80".parse().unwrap()),
+        ).await;
+        assert!(matches!(result, Err(Error::Socks(socket5::Error::Rejected(Reply::RepServerFail)))));
+    }
+
+    #[tokio::test]
+    async fn start_with_fail_test() {
+        // the pac port is taken, start fails after the socks listener was already handed over
+        let taken = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let started = local::start_with(
+            LocalConfig { pac: Some(taken.local_addr().unwrap().to_string()), ..LocalConfig::default() },
+            vec![Listener::from_std(listener).unwrap()],
+            None,
+        ).await;
+        assert!(started.is_err());
+        // no task was left accepting on it
+        assert!(tokio::net::TcpStream::connect(addr).await.is_err());
+    }
+
+    #[test]
+    fn system_target_test() {
+        let endpoints = vec![Endpoint::Unix("/run/ss5.sock".into()), Endpoint::Tcp("0.0.0.0:1080".to_string())];
+        assert_eq!(local::system_target(&endpoints, None).unwrap(), Target::Socks("127.0.0.1:1080".parse().unwrap()));
+        let pac = Some("127.0.0.2:1081".parse().unwrap());
+        assert_eq!(local::system_target(&endpoints, pac).unwrap(), Target::Pac("http://127.0.0.2:1081/proxy.pac".to_string()));
+        assert!(local::system_target(&endpoints[..1], None).is_err());
+    }
+
+    #[tokio::test]
+    async fn subscription_timeout_test() {
+        // accepts the fetch and never answers it
+        let stalled = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/sip008.json", stalled.local_addr().unwrap());
+        let server = Endpoint::Tcp("127.0.0.1:1080".to_string());
+        let started = tokio::time::timeout(Duration::from_secs(5), local::start(LocalConfig {
+            port: 0,
+            server: server.clone(),
+            subscription: Some(SubscriptionConfig { url, refresh: 3600, timeout: 1 }),
+            ..LocalConfig::default()
+        })).await;
+        let local = started.unwrap().unwrap();
+        assert_eq!(local.upstreams().list()[0].endpoint, server);
+    }
+}
@@ -0,0 +1,372 @@
+use std::fmt::Write;
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::debug;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+
+use crate::ledger::TrafficReport;
+use crate::limit::Maintenance;
+use crate::local::LocalStats;
+use crate::stats::{HistogramSnapshot, ServerStats};
+
+// a scrape request larger than this isn't one
+const MAX_REQUEST: usize = 8192;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+// GET /metrics in the prometheus text format, GET /traffic the traffic report as json for
+// `rust-ss5 stats`, POST and DELETE /maintenance turn maintenance mode on and off for
+// `rust-ss5 maintenance` given the token as a bearer, anything else is a 404
+pub async fn serve<F>(listener: TcpListener, collect: F, maintenance: Maintenance, token: Option<String>, mut shutdown: watch::Receiver<bool>)
+    where F: Fn() -> ServerStats + Send + Sync + 'static
+{
+    let (collect, token) = (Arc::new(collect), token.map(Arc::<str>::from));
+    loop {
+        tokio::select! {
+            _ = shutdown.changed() => break,
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => {
+                    // a task each, so a client that never sends its request holds up no one else
+                    let (collect, maintenance, token) = (collect.clone(), maintenance.clone(), token.clone());
+                    tokio::spawn(async move {
+                        if let Err(e) = answer(stream, collect.as_ref(), &maintenance, token.as_deref()).await {
+                            debug!("metrics request fail : {}", e);
+                        }
+                    });
+                }
+                Err(e) => debug!("metrics accept fail : {}", e),
+            }
+        }
+    }
+}
+
+async fn answer<F: Fn() -> ServerStats>(mut stream: TcpStream, collect: &F, maintenance: &Maintenance, token: Option<&str>) -> std::io::Result<()> {
+    let head = request_head(&mut stream).await?;
+    let line = first_line(&head);
+    let json = |body: String| {
+        format!("HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body)
+    };
+    let response = match line.split(|b| *b == b' ').collect::<Vec<_>>().as_slice() {
+        [b"GET", b"/metrics", ..] => exposition(render(&collect())),
+        [b"GET", b"/traffic", ..] => json(serde_json::to_string(&collect().traffic).map_err(std::io::Error::other)?),
+        // without a token configured nothing may change the server from here
+        [b"POST" | b"DELETE", b"/maintenance", ..] if token.is_none() => FORBIDDEN.to_string(),
+        [b"POST" | b"DELETE", b"/maintenance", ..] if !bearer(&head).zip(token).is_some_and(|(given, token)| same(given, token.as_bytes())) => {
+            UNAUTHORIZED.to_string()
+        }
+        [method @ (b"GET" | b"POST" | b"DELETE"), b"/maintenance", ..] => {
+            match *method {
+                b"POST" => maintenance.set(true),
+                b"DELETE" => maintenance.set(false),
+                _ => {}
+            }
+            json(serde_json::json!({"maintenance": maintenance.is_on()}).to_string())
+        }
+        _ => NOT_FOUND.to_string(),
+    };
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+// GET /metrics of a local client, anything else is a 404
+pub async fn serve_local<F>(listener: TcpListener, collect: F, mut shutdown: watch::Receiver<bool>)
+    where F: Fn() -> LocalStats + Send + Sync + 'static
+{
+    let collect = Arc::new(collect);
+    loop {
+        tokio::select! {
+            _ = shutdown.changed() => break,
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => {
+                    let collect = collect.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = answer_local(stream, collect.as_ref()).await {
+                            debug!("metrics request fail : {}", e);
+                        }
+                    });
+                }
+                Err(e) => debug!("metrics accept fail : {}", e),
+            }
+        }
+    }
+}
+
+async fn answer_local<F: Fn() -> LocalStats>(mut stream: TcpStream, collect: &F) -> std::io::Result<()> {
+    let line = request_line(&mut stream).await?;
+    let response = match line.split(|b| *b == b' ').collect::<Vec<_>>().as_slice() {
+        [b"GET", b"/metrics", ..] => exposition(render_local(&collect())),
+        _ => NOT_FOUND.to_string(),
+    };
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+fn exposition(body: String) -> String {
+    format!("HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body)
+}
+
+pub const NOT_FOUND: &str = "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+const UNAUTHORIZED: &str = "HTTP/1.1 401 Unauthorized\r\nWWW-Authenticate: Bearer\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+const FORBIDDEN: &str = "HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+
+// reads the request head, then gives back its first line, "GET /metrics HTTP/1.1"
+pub async fn request_line(stream: &mut TcpStream) -> std::io::Result<Vec<u8>> {
+    Ok(first_line(&request_head(stream).await?).to_vec())
+}
+
+fn first_line(head: &[u8]) -> &[u8] {
+    head.split(|b| *b == b'\r').next().unwrap_or_default()
+}
+
+// the token of an "Authorization: Bearer <token>" header
+fn bearer(head: &[u8]) -> Option<&[u8]> {
+    head.split(|b| *b == b'\n').skip(1).find_map(|line| {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let colon = line.iter().position(|b| *b == b':')?;
+        let (name, value) = (&line[..colon], line[colon + 1..].trim_ascii());
+        if !name.eq_ignore_ascii_case(b"authorization") || value.len() < 7 || !value[..7].eq_ignore_ascii_case(b"bearer ") {
+            return None;
+        }
+        Some(value[7..].trim_ascii())
+    })
+}
+
+// looks at every byte whatever differs, so the time taken says nothing of how much of a guess was right
+fn same(given: &[u8], token: &[u8]) -> bool {
+    given.len() == token.len() && given.iter().zip(token).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+// reads the request head, up to the blank line
+async fn request_head(stream: &mut TcpStream) -> std::io::Result<Vec<u8>> {
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    let read = async {
+        while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < MAX_REQUEST {
+            let n = stream.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            request.extend_from_slice(&buf[..n]);
+        }
+        Ok::<_, std::io::Error>(())
+    };
+    tokio::time::timeout(REQUEST_TIMEOUT, read).await
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "request timeout"))??;
+    Ok(request)
+}
+
+// the traffic report of the server whose metrics listen on addr
+pub async fn traffic(addr: &str) -> std::io::Result<TrafficReport> {
+    call(addr, "GET", "/traffic", None).await
+}
+
+// turns maintenance mode on or off, which takes the server's metrics_token, or with None only
+// asks, and gives back whether it's on
+pub async fn maintenance(addr: &str, on: Option<bool>, token: Option<&str>) -> std::io::Result<bool> {
+    let method = match on {
+        Some(true) => "POST",
+        Some(false) => "DELETE",
+        None => "GET",
+    };
+    let status: serde_json::Value = call(addr, method, "/maintenance", token).await?;
+    status["maintenance"].as_bool().ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "no maintenance in the answer"))
+}
+
+async fn call<T: serde::de::DeserializeOwned>(addr: &str, method: &str, path: &str, token: Option<&str>) -> std::io::Result<T> {
+    let mut stream = TcpStream::connect(addr).await?;
+    let authorization = token.map_or(String::new(), |token| format!("Authorization: Bearer {}\r\n", token));
+    let request = format!("{} {} HTTP/1.1\r\nHost: {}\r\n{}Content-Length: 0\r\nConnection: close\r\n\r\n", method, path, addr, authorization);
+    stream.write_all(request.as_bytes()).await?;
+    let mut response = Vec::new();
+    tokio::time::timeout(REQUEST_TIMEOUT, stream.read_to_end(&mut response)).await
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "response timeout"))??;
+    let invalid = |e: String| std::io::Error::new(std::io::ErrorKind::InvalidData, e);
+    let head = response.windows(4).position(|w| w == b"\r\n\r\n").ok_or_else(|| invalid("response cut short".to_string()))?;
+    if !response.starts_with(b"HTTP/1.1 200") {
+        let status = String::from_utf8_lossy(response.split(|b| *b == b'\r').next().unwrap_or_default()).to_string();
+        return Err(invalid(format!("{} answered {}", addr, status)));
+    }
+    serde_json::from_slice(&response[head + 4..]).map_err(|e| invalid(e.to_string()))
+}
+
+pub fn render(stats: &ServerStats) -> String {
+    let mut out = String::new();
+    metric(&mut out, "ss5_connections", "gauge", stats.connections);
+    metric(&mut out, "ss5_connections_total", "counter", stats.total_connections);
+    metric(&mut out, "ss5_rejected_connections_total", "counter", stats.rejected);
+    metric(&mut out, "ss5_shed_requests_total", "counter", stats.shed);
+    metric(&mut out, "ss5_panics_total", "counter", stats.panics);
+    metric(&mut out, "ss5_maintenance", "gauge", stats.maintenance as u64);
+    let _ = writeln!(out, "# TYPE ss5_closes_total counter");
+    for (close, count) in &stats.closes {
+        let _ = writeln!(out, "ss5_closes_total{{reason=\"{}\"}} {}", close, count);
+    }
+    metric(&mut out, "ss5_bytes_up_total", "counter", stats.bytes_up);
+    metric(&mut out, "ss5_bytes_down_total", "counter", stats.bytes_down);
+    metric(&mut out, "ss5_udp_associations", "gauge", stats.udp_associations);
+    metric(&mut out, "ss5_udp_datagrams_total", "counter", stats.udp_datagrams);
+    metric(&mut out, "ss5_udp_truncated_total", "counter", stats.udp_truncated);
+    metric(&mut out, "ss5_udp_unreachable_total", "counter", stats.udp_unreachable);
+    metric(&mut out, "ss5_spans_dropped_total", "counter", stats.spans_dropped);
+    metric(&mut out, "ss5_events_dropped_total", "counter", stats.events_dropped);
+    metric(&mut out, "ss5_buffers_allocated_total", "counter", stats.buffers.allocated);
+    metric(&mut out, "ss5_buffers_reused_total", "counter", stats.buffers.reused);
+    metric(&mut out, "ss5_buffers_idle", "gauge", stats.buffers.idle);
+    metric(&mut out, "ss5_buffers_idle_bytes", "gauge", stats.buffers.idle_bytes);
+    metric(&mut out, "ss5_dns_queries_total", "counter", stats.dns_queries);
+    metric(&mut out, "ss5_dns_cache_hits_total", "counter", stats.dns_cache_hits);
+    histogram(&mut out, "ss5_handshake_seconds", &stats.handshake, 1e6);
+    histogram(&mut out, "ss5_dial_seconds", &stats.dial, 1e6);
+    histogram(&mut out, "ss5_throughput_bytes_per_second", &stats.throughput, 1.0);
+    out
+}
+
+pub fn render_local(stats: &LocalStats) -> String {
+    let mut out = String::new();
+    metric(&mut out, "ss5_local_servers", "gauge", stats.servers as u64);
+    metric(&mut out, "ss5_local_listeners", "gauge", stats.listeners as u64);
+    metric(&mut out, "ss5_local_upstream_connections", "gauge", stats.active as u64);
+    metric(&mut out, "ss5_local_draining_connections", "gauge", stats.draining as u64);
+    out
+}
+
+fn metric(out: &mut String, name: &str, kind: &str, value: u64) {
+    let _ = writeln!(out, "# TYPE {} {}\n{} {}", name, kind, name, value);
+}
+
+// recorded values per exported unit, dividing keeps bounds like 0.0001 printed exactly
+fn histogram(out: &mut String, name: &str, snapshot: &HistogramSnapshot, per_unit: f64) {
+    let _ = writeln!(out, "# TYPE {} histogram", name);
+    let mut cumulative = 0;
+    for (bound, count) in snapshot.bounds.iter().zip(&snapshot.counts) {
+        cumulative += count;
+        let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, *bound as f64 / per_unit, cumulative);
+    }
+    let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, snapshot.count());
+    let _ = writeln!(out, "{}_sum {}", name, snapshot.sum as f64 / per_unit);
+    let _ = writeln!(out, "{}_count {}", name, snapshot.count());
+}
+
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    use crate::error::Error;
+    use crate::limit::Maintenance;
+    use crate::metrics::{maintenance, serve, traffic};
+    use crate::server::collect;
+    use crate::socket5::{self, Address, Command, Proxy, Reply};
+    use crate::tcp::TcpSocksClient;
+    use crate::test_util::{assert_echo, config, echo_server, socks_server, test_state};
+    use crate::transport::Endpoint;
+
+    #[tokio::test]
+    async fn serve_test() {
+        let state = test_state(config());
+        state.stats.handshake(std::time::Duration::from_micros(150));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (_shutdown, watcher) = tokio::sync::watch::channel(false);
+        tokio::spawn(serve(listener, move || collect(&state, &[]), Maintenance::default(), None, watcher));
+
+        let get = |path: &'static str| async move {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream.write_all(format!("GET {} HTTP/1.1\r\nHost: x\r\n\r\n", path).as_bytes()).await.unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            response
+        };
+        // one that never sends its request holds up no one else
+        let _idle = TcpStream::connect(addr).await.unwrap();
+        let response = tokio::time::timeout(std::time::Duration::from_secs(1), get("/metrics")).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("# TYPE ss5_handshake_seconds histogram\n"));
+        assert!(response.contains("ss5_handshake_seconds_bucket{le=\"0.0001\"} 0\n"));
+        assert!(response.contains("ss5_handshake_seconds_bucket{le=\"0.0002\"} 1\n"));
+        assert!(response.contains("ss5_handshake_seconds_bucket{le=\"+Inf\"} 1\n"));
+        assert!(response.contains("ss5_handshake_seconds_count 1\n"));
+        assert!(response.contains("ss5_connections 0\n"));
+        assert!(response.contains("# TYPE ss5_closes_total counter\nss5_closes_total{reason=\"client_eof\"} 0\n"));
+        assert!(get("/").await.starts_with("HTTP/1.1 404"));
+    }
+
+    #[tokio::test]
+    async fn traffic_test() {
+        let state = test_state(config());
+        state.stats.record(Some("alice"), Some(&Address::DomainName("example.com".to_string(), 443)), 10, 20);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (_shutdown, watcher) = tokio::sync::watch::channel(false);
+        tokio::spawn(serve(listener, move || collect(&state, &[]), Maintenance::default(), None, watcher));
+
+        let report = traffic(&addr.to_string()).await.unwrap();
+        assert_eq!(report.users[0].name, "alice");
+        assert_eq!((report.users[0].hour_up, report.users[0].day_down), (10, 20));
+        assert_eq!(report.destinations[0].name, "example.com");
+    }
+
+    #[tokio::test]
+    async fn maintenance_test() {
+        let echo = echo_server().await;
+        let state = test_state(config());
+        let server = socks_server(Endpoint::Tcp("127.0.0.1:0".to_string()), state.clone()).await.to_string();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let (_shutdown, watcher) = tokio::sync::watch::channel(false);
+        let admin = state.maintenance.clone();
+        tokio::spawn(serve(listener, move || collect(&state, &[]), admin, Some("s3cret".to_string()), watcher));
+
+        let proxy = Proxy::new(Command::CONNECT, Address::Address(echo));
+        let mut open = TcpSocksClient::client_connect(server.clone(), proxy.clone()).await.unwrap();
+        assert!(!maintenance(&addr, None, None).await.unwrap());
+        assert!(maintenance(&addr, Some(true), Some("s3cret")).await.unwrap());
+        match TcpSocksClient::client_connect(server.clone(), proxy.clone()).await {
+            Err(Error::Socks(socket5::Error::Rejected(reply))) => assert_eq!(reply, Reply::RepServerFail),
+            _ => panic!("expected a server failure in maintenance"),
+        }
+        // what was open before keeps relaying
+        assert_echo(&mut open.stream).await;
+        assert!(!maintenance(&addr, Some(false), Some("s3cret")).await.unwrap());
+        let mut again = TcpSocksClient::client_connect(server, proxy).await.unwrap();
+        assert_echo(&mut again.stream).await;
+    }
+
+    #[tokio::test]
+    async fn maintenance_token_test() {
+        let request = |addr: String, request: &'static str| async move {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream.write_all(request.as_bytes()).await.unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            response
+        };
+        let (_shutdown, watcher) = tokio::sync::watch::channel(false);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let admin = Maintenance::default();
+        tokio::spawn(serve(listener, || collect(&test_state(config()), &[]), admin.clone(), Some("s3cret".to_string()), watcher.clone()));
+
+        // no token, a wrong one, or one that isn't a bearer is refused and changes nothing
+        assert!(request(addr.clone(), "POST /maintenance HTTP/1.1\r\n\r\n").await.starts_with("HTTP/1.1 401"));
+        assert!(request(addr.clone(), "POST /maintenance HTTP/1.1\r\nAuthorization: Bearer s3cre\r\n\r\n").await.starts_with("HTTP/1.1 401"));
+        assert!(request(addr.clone(), "DELETE /maintenance HTTP/1.1\r\nAuthorization: Basic s3cret\r\n\r\n").await.starts_with("HTTP/1.1 401"));
+        assert!(matches!(maintenance(&addr, Some(true), None).await, Err(e) if e.to_string().contains("401")));
+        assert!(!admin.is_on());
+        assert!(request(addr.clone(), "POST /maintenance HTTP/1.1\r\nauthorization: bearer s3cret\r\n\r\n").await.starts_with("HTTP/1.1 200"));
+        assert!(admin.is_on());
+        // asking needs no token
+        assert!(maintenance(&addr, None, None).await.unwrap());
+
+        // without a token configured the listener stays read only
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let admin = Maintenance::default();
+        tokio::spawn(serve(listener, || collect(&test_state(config()), &[]), admin.clone(), None, watcher));
+        assert!(matches!(maintenance(&addr, Some(true), Some("s3cret")).await, Err(e) if e.to_string().contains("403")));
+        assert!(!admin.is_on());
+    }
+}
@@ -0,0 +1,232 @@
+use std::io;
+use std::net::SocketAddr;
+use std::ops::DerefMut;
+
+use tokio::net::UdpSocket;
+
+// datagrams a single recvmmsg / sendmmsg moves at most
+pub const MAX_BATCH: usize = 64;
+
+// as many datagrams as are waiting, up to one a buffer, each as (length, source); one buffer is
+// a plain recv_from, and so is every call off linux
+pub async fn recv_batch<B>(socket: &UdpSocket, bufs: &mut [B], received: &mut Vec<(usize, SocketAddr)>) -> io::Result<()>
+    where B: DerefMut<Target = [u8]>
+{
+    received.clear();
+    #[cfg(target_os = "linux")]
+    if bufs.len() > 1 {
+        return sys::recv_batch(socket, bufs, received).await;
+    }
+    received.push(socket.recv_from(&mut bufs[0]).await?);
+    Ok(())
+}
+
+// every datagram sent, as many a call as the kernel takes; what became of each is given to done
+// with its index, as send_to would have said it, and one the kernel refuses doesn't hold up the rest
+pub async fn send_batch<F>(socket: &UdpSocket, datagrams: &[(&[u8], SocketAddr)], mut done: F)
+    where F: FnMut(usize, io::Result<usize>)
+{
+    #[cfg(target_os = "linux")]
+    if datagrams.len() > 1 {
+        return sys::send_batch(socket, datagrams, done).await;
+    }
+    for (i, (data, target)) in datagrams.iter().enumerate() {
+        done(i, socket.send_to(data, *target).await);
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use std::io;
+    use std::mem;
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+    use std::ops::DerefMut;
+    use std::os::fd::AsRawFd;
+    use std::ptr;
+
+    use tokio::io::Interest;
+    use tokio::net::UdpSocket;
+
+    use crate::mmsg::MAX_BATCH;
+
+    pub async fn recv_batch<B>(socket: &UdpSocket, bufs: &mut [B], received: &mut Vec<(usize, SocketAddr)>) -> io::Result<()>
+        where B: DerefMut<Target = [u8]>
+    {
+        loop {
+            socket.readable().await?;
+            match socket.try_io(Interest::READABLE, || recvmmsg(socket, bufs, received)) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                result => return result,
+            }
+        }
+    }
+
+    pub async fn send_batch<F>(socket: &UdpSocket, datagrams: &[(&[u8], SocketAddr)], mut done: F)
+        where F: FnMut(usize, io::Result<usize>)
+    {
+        let mut next = 0;
+        while next < datagrams.len() {
+            if let Err(e) = socket.writable().await {
+                done(next, Err(e));
+                next += 1;
+                continue;
+            }
+            match socket.try_io(Interest::WRITABLE, || sendmmsg(socket, &datagrams[next..])) {
+                Ok(sent) => {
+                    for (i, len) in sent.iter().enumerate() {
+                        done(next + i, Ok(*len));
+                    }
+                    next += sent.len();
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                // the kernel stops at the first datagram it can't send, that one is dropped
+                Err(e) => {
+                    done(next, Err(e));
+                    next += 1;
+                }
+            }
+        }
+    }
+
+    fn recvmmsg<B: DerefMut<Target = [u8]>>(socket: &UdpSocket, bufs: &mut [B], received: &mut Vec<(usize, SocketAddr)>) -> io::Result<()> {
+        let count = bufs.len().min(MAX_BATCH);
+        // SAFETY: all zeros is a valid sockaddr_storage, iovec and mmsghdr
+        let mut names: [libc::sockaddr_storage; MAX_BATCH] = unsafe { mem::zeroed() };
+        let mut iovecs: [libc::iovec; MAX_BATCH] = unsafe { mem::zeroed() };
+        let mut msgs: [libc::mmsghdr; MAX_BATCH] = unsafe { mem::zeroed() };
+        for i in 0..count {
+            let buf = &mut *bufs[i];
+            iovecs[i] = libc::iovec { iov_base: buf.as_mut_ptr().cast(), iov_len: buf.len() };
+            msgs[i].msg_hdr.msg_name = ptr::from_mut(&mut names[i]).cast();
+            msgs[i].msg_hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+            msgs[i].msg_hdr.msg_iov = &mut iovecs[i];
+            msgs[i].msg_hdr.msg_iovlen = 1;
+        }
+        // SAFETY: every header points at a name and an iovec over a live buffer, for count headers
+        let n = unsafe { libc::recvmmsg(socket.as_raw_fd(), msgs.as_mut_ptr(), count as libc::c_uint, libc::MSG_DONTWAIT, ptr::null_mut()) };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        for i in 0..n as usize {
+            received.push((msgs[i].msg_len as usize, from_sockaddr(&names[i])?));
+        }
+        Ok(())
+    }
+
+    // the lengths sent, of as many datagrams from the front as the kernel took
+    fn sendmmsg(socket: &UdpSocket, datagrams: &[(&[u8], SocketAddr)]) -> io::Result<Vec<usize>> {
+        let count = datagrams.len().min(MAX_BATCH);
+        // SAFETY: as in recvmmsg
+        let mut names: [libc::sockaddr_storage; MAX_BATCH] = unsafe { mem::zeroed() };
+        let mut iovecs: [libc::iovec; MAX_BATCH] = unsafe { mem::zeroed() };
+        let mut msgs: [libc::mmsghdr; MAX_BATCH] = unsafe { mem::zeroed() };
+        for (i, (data, target)) in datagrams[..count].iter().enumerate() {
+            // the kernel only reads through iov_base here
+            iovecs[i] = libc::iovec { iov_base: data.as_ptr().cast_mut().cast(), iov_len: data.len() };
+            msgs[i].msg_hdr.msg_name = ptr::from_mut(&mut names[i]).cast();
+            msgs[i].msg_hdr.msg_namelen = to_sockaddr(*target, &mut names[i]);
+            msgs[i].msg_hdr.msg_iov = &mut iovecs[i];
+            msgs[i].msg_hdr.msg_iovlen = 1;
+        }
+        // SAFETY: every header points at a name and an iovec over a live buffer, for count headers
+        let n = unsafe { libc::sendmmsg(socket.as_raw_fd(), msgs.as_mut_ptr(), count as libc::c_uint, libc::MSG_DONTWAIT) };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(msgs[..n as usize].iter().map(|msg| msg.msg_len as usize).collect())
+    }
+
+    pub(super) fn to_sockaddr(addr: SocketAddr, storage: &mut libc::sockaddr_storage) -> libc::socklen_t {
+        match addr {
+            SocketAddr::V4(addr) => {
+                // SAFETY: sockaddr_storage is large and aligned enough for any sockaddr
+                let sin = unsafe { &mut *ptr::from_mut(storage).cast::<libc::sockaddr_in>() };
+                sin.sin_family = libc::AF_INET as libc::sa_family_t;
+                sin.sin_port = addr.port().to_be();
+                sin.sin_addr = libc::in_addr { s_addr: u32::from_ne_bytes(addr.ip().octets()) };
+                mem::size_of::<libc::sockaddr_in>() as libc::socklen_t
+            }
+            SocketAddr::V6(addr) => {
+                // SAFETY: as above
+                let sin6 = unsafe { &mut *ptr::from_mut(storage).cast::<libc::sockaddr_in6>() };
+                sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                sin6.sin6_port = addr.port().to_be();
+                sin6.sin6_flowinfo = addr.flowinfo();
+                sin6.sin6_addr = libc::in6_addr { s6_addr: addr.ip().octets() };
+                sin6.sin6_scope_id = addr.scope_id();
+                mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t
+            }
+        }
+    }
+
+    pub(super) fn from_sockaddr(storage: &libc::sockaddr_storage) -> io::Result<SocketAddr> {
+        match storage.ss_family as libc::c_int {
+            libc::AF_INET => {
+                // SAFETY: the family says the storage holds a sockaddr_in
+                let sin = unsafe { &*ptr::from_ref(storage).cast::<libc::sockaddr_in>() };
+                let ip = Ipv4Addr::from(sin.sin_addr.s_addr.to_ne_bytes());
+                Ok(SocketAddr::V4(SocketAddrV4::new(ip, u16::from_be(sin.sin_port))))
+            }
+            libc::AF_INET6 => {
+                // SAFETY: the family says the storage holds a sockaddr_in6
+                let sin6 = unsafe { &*ptr::from_ref(storage).cast::<libc::sockaddr_in6>() };
+                let ip = Ipv6Addr::from(sin6.sin6_addr.s6_addr);
+                Ok(SocketAddr::V6(SocketAddrV6::new(ip, u16::from_be(sin6.sin6_port), sin6.sin6_flowinfo, sin6.sin6_scope_id)))
+            }
+            family => Err(io::Error::new(io::ErrorKind::InvalidData, format!("datagram from address family {}", family))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use tokio::net::UdpSocket;
+
+    use crate::mmsg::{recv_batch, send_batch};
+
+    #[tokio::test]
+    async fn batch_test() {
+        let (a, b) = (UdpSocket::bind("127.0.0.1:0").await.unwrap(), UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let to = b.local_addr().unwrap();
+        let payloads: Vec<Vec<u8>> = (0..10u8).map(|i| vec![i; 100 + i as usize]).collect();
+        let datagrams: Vec<(&[u8], SocketAddr)> = payloads.iter().map(|p| (p.as_slice(), to)).collect();
+        let mut sent = Vec::new();
+        send_batch(&a, &datagrams, |i, result| sent.push((i, result.unwrap()))).await;
+        assert_eq!(sent, (0..10).map(|i| (i, 100 + i)).collect::<Vec<_>>());
+
+        // one batch may be cut short by datagrams still on their way over loopback
+        let mut bufs = vec![vec![0; 200]; 8];
+        let mut received = Vec::new();
+        let mut got = Vec::new();
+        while got.len() < payloads.len() {
+            recv_batch(&b, &mut bufs, &mut received).await.unwrap();
+            assert!(!received.is_empty() && received.len() <= 8);
+            for (i, (n, from)) in received.iter().enumerate() {
+                assert_eq!(*from, a.local_addr().unwrap());
+                got.push(bufs[i][..*n].to_vec());
+            }
+        }
+        assert_eq!(got, payloads);
+
+        // a single buffer is a plain recv_from
+        a.send_to(b"one", to).await.unwrap();
+        recv_batch(&b, &mut bufs[..1], &mut received).await.unwrap();
+        assert_eq!((received.len(), &bufs[0][..3]), (1, &b"one"[..]));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn sockaddr_test() {
+        use crate::mmsg::sys::{from_sockaddr, to_sockaddr};
+
+        for addr in ["192.0.2.1:53", "[2001:db8::1]:443", "[fe80::1%3]:5353", "[::ffff:10.0.0.1]:80"] {
+            let addr: SocketAddr = addr.parse().unwrap();
+            // SAFETY: all zeros is a valid sockaddr_storage
+            let mut storage = unsafe { std::mem::zeroed() };
+            to_sockaddr(addr, &mut storage);
+            assert_eq!(from_sockaddr(&storage).unwrap(), addr);
+        }
+    }
+}
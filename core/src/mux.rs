@@ -0,0 +1,595 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+use bytes::{Buf, Bytes};
+use log::debug;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::sync::{mpsc, Mutex as AsyncMutex};
+
+use crate::error::Error;
+use crate::socket5::{self, MethodSelection, ShakeHands};
+use crate::socket5::constant::*;
+use crate::transport::RawStream;
+
+const FRAME_OPEN: u8 = 0;
+const FRAME_DATA: u8 = 1;
+const FRAME_FIN: u8 = 2;
+const FRAME_WINDOW: u8 = 3;
+const FRAME_RESET: u8 = 4;
+// type, stream id and payload length
+const FRAME_HEADER: usize = 1 + 4 + 2;
+const MAX_PAYLOAD: usize = 16384;
+// bytes a stream may have in flight before the reader acknowledges them
+const WINDOW: usize = 256 * 1024;
+// frames the writer coalesces into one write
+const MAX_BATCH: usize = 64 * 1024;
+
+struct Frame {
+    kind: u8,
+    id: u32,
+    payload: Bytes,
+}
+
+impl Frame {
+    fn new(kind: u8, id: u32) -> Self {
+        Frame { kind, id, payload: Bytes::new() }
+    }
+
+    fn window(id: u32, credit: u32) -> Self {
+        Frame { kind: FRAME_WINDOW, id, payload: Bytes::copy_from_slice(&credit.to_be_bytes()) }
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.push(self.kind);
+        buf.extend_from_slice(&self.id.to_be_bytes());
+        buf.extend_from_slice(&(self.payload.len() as u16).to_be_bytes());
+        buf.extend_from_slice(&self.payload);
+    }
+}
+
+#[derive(Default)]
+struct CreditState {
+    window: usize,
+    closed: bool,
+    waker: Option<Waker>,
+}
+
+// what a stream may still send, topped up by the peer's window frames
+#[derive(Default)]
+struct Credit {
+    state: Mutex<CreditState>,
+}
+
+impl Credit {
+    fn new(window: usize) -> Arc<Self> {
+        Arc::new(Credit { state: Mutex::new(CreditState { window, ..CreditState::default() }) })
+    }
+
+    fn add(&self, n: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.window += n;
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    }
+
+    fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+// what a stream moved so far, kept next to its entry so the session can list every stream's
+#[derive(Default)]
+struct Counters {
+    sent: AtomicU64,
+    received: AtomicU64,
+    stalls: AtomicU64,
+    // set on the side that opened the stream
+    opened: Option<Instant>,
+    // microseconds to the first frame back, 0 until it came
+    rtt: AtomicU64,
+}
+
+impl Counters {
+    fn received(&self, n: usize) {
+        self.received.fetch_add(n as u64, Ordering::Relaxed);
+        if let Some(opened) = self.opened {
+            let _ = self.rtt.compare_exchange(0, (opened.elapsed().as_micros() as u64).max(1), Ordering::Relaxed, Ordering::Relaxed);
+        }
+    }
+
+    fn stats(&self, id: u32) -> StreamStats {
+        let rtt = self.rtt.load(Ordering::Relaxed);
+        StreamStats {
+            id,
+            bytes_sent: self.sent.load(Ordering::Relaxed),
+            bytes_received: self.received.load(Ordering::Relaxed),
+            rtt: (rtt > 0).then(|| Duration::from_micros(rtt)),
+            stalls: self.stalls.load(Ordering::Relaxed),
+        }
+    }
+}
+
+// a stream's activity for frontends listing connections; the session runs over one tcp
+// connection and never retransmits itself, stalls count the writes that waited for the peer's
+// window instead, the sign of a slow or lossy link
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StreamStats {
+    pub id: u32,
+    // payload bytes, frame headers aside
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    // from opening the stream to the first frame back, which for a socks request is the method
+    // selection, about one round trip to the server; None until then and on accepted streams
+    pub rtt: Option<Duration>,
+    pub stalls: u64,
+}
+
+struct Entry {
+    // None once the peer sent fin
+    inbound: Option<mpsc::UnboundedSender<Bytes>>,
+    credit: Arc<Credit>,
+    counters: Arc<Counters>,
+}
+
+struct Shared {
+    streams: Mutex<HashMap<u32, Entry>>,
+    frames: mpsc::UnboundedSender<Frame>,
+    closed: AtomicBool,
+}
+
+impl Shared {
+    fn send(&self, frame: Frame) -> io::Result<()> {
+        self.frames.send(frame).map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "mux session closed"))
+    }
+
+    fn register(&self, id: u32, opened: Option<Instant>) -> (mpsc::UnboundedReceiver<Bytes>, Arc<Credit>, Arc<Counters>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let credit = Credit::new(WINDOW);
+        let counters = Arc::new(Counters { opened, ..Counters::default() });
+        self.streams.lock().unwrap().insert(id, Entry { inbound: Some(sender), credit: credit.clone(), counters: counters.clone() });
+        (receiver, credit, counters)
+    }
+
+    // every stream sees eof on read and an error on write
+    fn close(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+        for (_, entry) in self.streams.lock().unwrap().drain() {
+            entry.credit.close();
+        }
+    }
+}
+
+// many streams over one connection; the dialing side opens them, the accepting side gets them from Incoming
+#[derive(Clone)]
+pub struct Session {
+    shared: Arc<Shared>,
+    next_id: Arc<AtomicU32>,
+}
+
+pub struct Incoming {
+    streams: mpsc::UnboundedReceiver<MuxStream>,
+}
+
+impl Incoming {
+    // None once the session is gone
+    pub async fn accept(&mut self) -> Option<MuxStream> {
+        self.streams.recv().await
+    }
+}
+
+impl Session {
+    pub fn client<S>(stream: S) -> Session
+        where S: AsyncRead + AsyncWrite + Send + 'static
+    {
+        Session::new(stream, None)
+    }
+
+    pub fn server<S>(stream: S) -> (Session, Incoming)
+        where S: AsyncRead + AsyncWrite + Send + 'static
+    {
+        let (sender, streams) = mpsc::unbounded_channel();
+        (Session::new(stream, Some(sender)), Incoming { streams })
+    }
+
+    fn new<S>(stream: S, incoming: Option<mpsc::UnboundedSender<MuxStream>>) -> Session
+        where S: AsyncRead + AsyncWrite + Send + 'static
+    {
+        let (frames, outbound) = mpsc::unbounded_channel();
+        let shared = Arc::new(Shared {
+            streams: Mutex::new(HashMap::new()),
+            frames,
+            closed: AtomicBool::new(false),
+        });
+        let (read, write) = tokio::io::split(stream);
+        tokio::spawn(write_frames(write, outbound, shared.clone()));
+        tokio::spawn(read_frames(read, shared.clone(), incoming));
+        Session { shared, next_id: Arc::new(AtomicU32::new(1)) }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.shared.closed.load(Ordering::Relaxed)
+    }
+
+    // streams opened but not yet closed on both sides
+    pub fn streams(&self) -> usize {
+        self.shared.streams.lock().unwrap().len()
+    }
+
+    // the open streams, by id
+    pub fn stream_stats(&self) -> Vec<StreamStats> {
+        let mut stats: Vec<StreamStats> = self.shared.streams.lock().unwrap().iter()
+            .map(|(id, entry)| entry.counters.stats(*id))
+            .collect();
+        stats.sort_by_key(|stats| stats.id);
+        stats
+    }
+
+    pub fn open(&self) -> io::Result<MuxStream> {
+        if self.is_closed() {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "mux session closed"));
+        }
+        let id = self.next_id.fetch_add(2, Ordering::Relaxed);
+        let (inbound, credit, counters) = self.shared.register(id, Some(Instant::now()));
+        self.shared.send(Frame::new(FRAME_OPEN, id))?;
+        Ok(MuxStream::new(id, self.shared.clone(), inbound, credit, counters))
+    }
+}
+
+// the live session per upstream, shared by every request the local side forwards
+#[derive(Clone, Default)]
+pub struct Sessions {
+    sessions: Arc<AsyncMutex<HashMap<String, Session>>>,
+}
+
+impl Sessions {
+    // requests arriving together wait for the one dial instead of each opening a session
+    pub async fn get<F>(&self, key: &str, dial: F) -> Result<Session, Error>
+        where F: Future<Output=Result<Session, Error>>
+    {
+        let mut sessions = self.sessions.lock().await;
+        if let Some(session) = sessions.get(key) {
+            if !session.is_closed() {
+                return Ok(session.clone());
+            }
+        }
+        let session = dial.await?;
+        sessions.insert(key.to_string(), session.clone());
+        Ok(session)
+    }
+
+    // the open streams of every live session, by upstream
+    pub async fn stream_stats(&self) -> Vec<(String, StreamStats)> {
+        let sessions = self.sessions.lock().await;
+        let mut stats: Vec<(String, StreamStats)> = sessions.iter()
+            .filter(|(_, session)| !session.is_closed())
+            .flat_map(|(key, session)| session.stream_stats().into_iter().map(|stats| (key.clone(), stats)))
+            .collect();
+        stats.sort_by(|a, b| a.0.cmp(&b.0).then(a.1.id.cmp(&b.1.id)));
+        stats
+    }
+}
+
+// ask for the mux method on a tunnel connection, then run a session over it
+pub async fn connect<S>(mut stream: S) -> Result<Session, Error>
+    where S: AsyncRead + AsyncWrite + Unpin + Send + 'static
+{
+    ShakeHands::new(vec![METHOD_MUX]).write(&mut stream).await?;
+    let selection = MethodSelection::from(&mut stream).await?;
+    if selection.method != METHOD_MUX {
+        return Err(Error::Socks(socket5::Error::MethodNo(selection.method)));
+    }
+    Ok(Session::client(stream))
+}
+
+async fn write_frames<W>(mut write: W, mut frames: mpsc::UnboundedReceiver<Frame>, shared: Arc<Shared>)
+    where W: AsyncWrite + Unpin
+{
+    let mut buf = Vec::with_capacity(MAX_BATCH + FRAME_HEADER);
+    while let Some(frame) = frames.recv().await {
+        buf.clear();
+        frame.encode(&mut buf);
+        while buf.len() < MAX_BATCH {
+            match frames.try_recv() {
+                Ok(frame) => frame.encode(&mut buf),
+                Err(_) => break,
+            }
+        }
+        if let Err(e) = async { write.write_all(&buf).await?; write.flush().await }.await {
+            debug!("mux write fail : {}", e);
+            break;
+        }
+    }
+    shared.close();
+    let _ = write.shutdown().await;
+}
+
+async fn read_frames<R>(mut read: R, shared: Arc<Shared>, incoming: Option<mpsc::UnboundedSender<MuxStream>>)
+    where R: AsyncRead + Unpin
+{
+    let mut header = [0; FRAME_HEADER];
+    loop {
+        if read.read_exact(&mut header).await.is_err() {
+            break;
+        }
+        let kind = header[0];
+        let id = u32::from_be_bytes([header[1], header[2], header[3], header[4]]);
+        let len = u16::from_be_bytes([header[5], header[6]]) as usize;
+        let mut payload = vec![0; len];
+        if read.read_exact(&mut payload).await.is_err() {
+            break;
+        }
+        match kind {
+            FRAME_OPEN => {
+                let Some(incoming) = &incoming else {
+                    break;
+                };
+                let (inbound, credit, counters) = shared.register(id, None);
+                let _ = incoming.send(MuxStream::new(id, shared.clone(), inbound, credit, counters));
+            }
+            FRAME_DATA => {
+                let streams = shared.streams.lock().unwrap();
+                if let Some(entry) = streams.get(&id) {
+                    entry.counters.received(len);
+                    if let Some(inbound) = &entry.inbound {
+                        let _ = inbound.send(Bytes::from(payload));
+                    }
+                }
+            }
+            FRAME_FIN => {
+                let mut streams = shared.streams.lock().unwrap();
+                if let Some(entry) = streams.get_mut(&id) {
+                    entry.inbound = None;
+                }
+            }
+            FRAME_WINDOW if len == 4 => {
+                let credit = u32::from_be_bytes(payload[..4].try_into().unwrap()) as usize;
+                if let Some(entry) = shared.streams.lock().unwrap().get(&id) {
+                    entry.credit.add(credit);
+                }
+            }
+            FRAME_RESET => {
+                if let Some(entry) = shared.streams.lock().unwrap().remove(&id) {
+                    entry.credit.close();
+                }
+            }
+            _ => {
+                debug!("mux frame type {} not understood, closing the session", kind);
+                break;
+            }
+        }
+    }
+    shared.close();
+}
+
+pub struct MuxStream {
+    id: u32,
+    shared: Arc<Shared>,
+    inbound: mpsc::UnboundedReceiver<Bytes>,
+    pending: Bytes,
+    // read but not yet acknowledged with a window frame
+    consumed: usize,
+    credit: Arc<Credit>,
+    counters: Arc<Counters>,
+    read_eof: bool,
+    fin_sent: bool,
+}
+
+impl MuxStream {
+    fn new(id: u32, shared: Arc<Shared>, inbound: mpsc::UnboundedReceiver<Bytes>, credit: Arc<Credit>, counters: Arc<Counters>) -> Self {
+        MuxStream { id, shared, inbound, pending: Bytes::new(), consumed: 0, credit, counters, read_eof: false, fin_sent: false }
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn stats(&self) -> StreamStats {
+        self.counters.stats(self.id)
+    }
+}
+
+impl AsyncRead for MuxStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.pending.is_empty() {
+            match this.inbound.poll_recv(cx) {
+                Poll::Ready(Some(data)) => this.pending = data,
+                Poll::Ready(None) => {
+                    this.read_eof = true;
+                    return Poll::Ready(Ok(()));
+                }
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        let n = this.pending.len().min(buf.remaining());
+        buf.put_slice(&this.pending[..n]);
+        this.pending.advance(n);
+        this.consumed += n;
+        if this.consumed >= WINDOW / 2 {
+            let _ = this.shared.send(Frame::window(this.id, this.consumed as u32));
+            this.consumed = 0;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for MuxStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.fin_sent {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        let n = {
+            let mut credit = this.credit.state.lock().unwrap();
+            if credit.closed {
+                return Poll::Ready(Err(io::Error::new(io::ErrorKind::ConnectionReset, "mux stream reset")));
+            }
+            if credit.window == 0 {
+                if credit.waker.is_none() {
+                    this.counters.stalls.fetch_add(1, Ordering::Relaxed);
+                }
+                credit.waker = Some(cx.waker().clone());
+                return Poll::Pending;
+            }
+            let n = buf.len().min(credit.window).min(MAX_PAYLOAD);
+            credit.window -= n;
+            n
+        };
+        this.shared.send(Frame { kind: FRAME_DATA, id: this.id, payload: Bytes::copy_from_slice(&buf[..n]) })?;
+        this.counters.sent.fetch_add(n as u64, Ordering::Relaxed);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.fin_sent {
+            this.fin_sent = true;
+            this.shared.send(Frame::new(FRAME_FIN, this.id))?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for MuxStream {
+    // a stream dropped before the peer finished gets reset, the peer's writes then fail instead of stalling
+    fn drop(&mut self) {
+        let frame = if self.read_eof { FRAME_FIN } else { FRAME_RESET };
+        if frame == FRAME_RESET || !self.fin_sent {
+            let _ = self.shared.send(Frame::new(frame, self.id));
+        }
+        self.shared.streams.lock().unwrap().remove(&self.id);
+    }
+}
+
+// probes never reach a mux stream, the session's own connection went through the handshake first
+impl RawStream for MuxStream {
+    type Raw = MuxStream;
+
+    fn raw(&mut self) -> &mut MuxStream {
+        self
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, duplex};
+
+    use crate::mux::{Session, WINDOW};
+
+    #[tokio::test]
+    async fn mux_session_test() {
+        let (a, b) = duplex(64 * 1024);
+        let client = Session::client(a);
+        let (_server, mut incoming) = Session::server(b);
+        tokio::spawn(async move {
+            while let Some(mut stream) = incoming.accept().await {
+                tokio::spawn(async move {
+                    let (mut read, mut write) = tokio::io::split(&mut stream);
+                    let _ = tokio::io::copy(&mut read, &mut write).await;
+                    let _ = write.shutdown().await;
+                });
+            }
+        });
+
+        // several times the window each way, on streams running at once
+        let data: Vec<u8> = (0..1024 * 1024).map(|i| (i % 251) as u8).collect();
+        let mut tasks = Vec::new();
+        for _ in 0..3 {
+            let mut stream = client.open().unwrap();
+            let data = data.clone();
+            tasks.push(tokio::spawn(async move {
+                let (mut read, mut write) = tokio::io::split(&mut stream);
+                let sent = data.clone();
+                let writer = async move {
+                    write.write_all(&sent).await.unwrap();
+                    write.shutdown().await.unwrap();
+                };
+                let mut received = Vec::new();
+                let reader = read.read_to_end(&mut received);
+                let (_, read) = tokio::join!(writer, reader);
+                read.unwrap();
+                assert_eq!(received, data);
+            }));
+        }
+        for task in tasks {
+            task.await.unwrap();
+        }
+        for _ in 0..100 {
+            if client.streams() == 0 {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert_eq!(client.streams(), 0);
+    }
+
+    #[tokio::test]
+    async fn mux_stats_test() {
+        let (a, b) = duplex(64 * 1024);
+        let client = Session::client(a);
+        let (_server, mut incoming) = Session::server(b);
+        let mut stream = client.open().unwrap();
+        stream.write_all(b"ping").await.unwrap();
+        let mut accepted = incoming.accept().await.unwrap();
+        let mut buf = [0; 4];
+        accepted.read_exact(&mut buf).await.unwrap();
+        accepted.write_all(b"pong!").await.unwrap();
+        let mut buf = [0; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+
+        let stats = stream.stats();
+        assert_eq!((stats.bytes_sent, stats.bytes_received, stats.stalls), (4, 5, 0));
+        assert!(stats.rtt.is_some());
+        assert_eq!(accepted.stats().rtt, None);
+        assert_eq!(client.stream_stats(), vec![stats]);
+
+        // past the window while the peer doesn't read, the write waits for credit
+        let write = tokio::spawn(async move {
+            stream.write_all(&vec![0; WINDOW + 1]).await.unwrap();
+            stream
+        });
+        for _ in 0..100 {
+            if client.stream_stats()[0].stalls > 0 {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert_eq!(client.stream_stats()[0].stalls, 1);
+        let mut sink = vec![0; WINDOW + 1];
+        accepted.read_exact(&mut sink).await.unwrap();
+        assert_eq!(write.await.unwrap().stats().bytes_sent, 4 + WINDOW as u64 + 1);
+    }
+
+    #[tokio::test]
+    async fn mux_close_test() {
+        let (a, b) = duplex(1024);
+        let client = Session::client(a);
+        let mut stream = client.open().unwrap();
+        drop(b);
+        let mut buf = [0; 8];
+        assert_eq!(stream.read(&mut buf).await.unwrap(), 0);
+        for _ in 0..100 {
+            if client.is_closed() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert!(client.open().is_err());
+    }
+}
@@ -0,0 +1,266 @@
+use std::fmt::{Display, Formatter};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use log::debug;
+use tokio::net::ToSocketAddrs;
+
+use crate::crypto::generate_key;
+use crate::error::Error;
+use crate::socket5::Address;
+use crate::tcp::TcpSocksClient;
+use crate::udp::SocksUdpSocket;
+
+// https://www.rfc-editor.org/rfc/rfc5389 and the change request of rfc5780
+const BINDING_REQUEST: u16 = 0x0001;
+const BINDING_RESPONSE: u16 = 0x0101;
+const BINDING_ERROR: u16 = 0x0111;
+const MAGIC_COOKIE: u32 = 0x2112A442;
+const ATTR_MAPPED_ADDRESS: u16 = 0x0001;
+const ATTR_CHANGE_REQUEST: u16 = 0x0003;
+const ATTR_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+const CHANGE_IP: u32 = 0x04;
+const CHANGE_PORT: u32 = 0x02;
+const STUN_HEADER: usize = 20;
+// requests sent before a server counts as silent
+const ATTEMPTS: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Behavior {
+    EndpointIndependent,
+    AddressDependent,
+    AddressAndPortDependent,
+    // a single stun server answered, or none supports change requests
+    Unknown,
+}
+
+impl Display for Behavior {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Behavior::EndpointIndependent => "endpoint independent",
+            Behavior::AddressDependent => "address dependent",
+            Behavior::AddressAndPortDependent => "address and port dependent",
+            Behavior::Unknown => "unknown",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct NatReport {
+    // the address each stun server saw the relay's datagrams come from
+    pub mapped: Vec<(Address, SocketAddr)>,
+    pub mapping: Behavior,
+    pub filtering: Behavior,
+}
+
+impl NatReport {
+    // the classic rfc3489 names
+    pub fn kind(&self) -> &'static str {
+        match (self.mapping, self.filtering) {
+            (Behavior::EndpointIndependent, Behavior::EndpointIndependent) => "full cone",
+            (Behavior::EndpointIndependent, Behavior::AddressDependent) => "restricted cone",
+            (Behavior::EndpointIndependent, Behavior::AddressAndPortDependent) => "port restricted cone",
+            (Behavior::AddressDependent | Behavior::AddressAndPortDependent, _) => "symmetric",
+            _ => "unknown",
+        }
+    }
+}
+
+struct Response {
+    // None for an error response, e.g. a server refusing change requests
+    mapped: Option<SocketAddr>,
+    from: Address,
+}
+
+// stun probes through one UDP ASSOCIATE on the server: mapping from what several stun
+// servers report, filtering from whether answers sent from another ip or port get back
+pub async fn detect<A: ToSocketAddrs>(proxy: A, stun: &[Address], timeout: Duration) -> Result<NatReport, Error> {
+    let socket = TcpSocksClient::udp_associate(proxy).await?;
+    let mut mapped = Vec::new();
+    for server in stun {
+        match query(&socket, server, 0, timeout).await? {
+            Some(Response { mapped: Some(addr), .. }) => mapped.push((server.clone(), addr)),
+            _ => debug!("stun server {} gave no mapped address", server),
+        }
+    }
+    if mapped.is_empty() {
+        return Err(Error::from(std::io::Error::new(std::io::ErrorKind::TimedOut, "no stun server answered")));
+    }
+    let mapping = mapping(&mapped);
+    let filtering = filtering(&socket, &mapped[0].0, timeout).await?;
+    Ok(NatReport { mapped, mapping, filtering })
+}
+
+fn mapping(mapped: &[(Address, SocketAddr)]) -> Behavior {
+    let first = mapped[0].1;
+    if mapped.len() < 2 {
+        Behavior::Unknown
+    } else if mapped.iter().all(|(_, addr)| *addr == first) {
+        Behavior::EndpointIndependent
+    } else if mapped.iter().all(|(_, addr)| addr.ip() == first.ip()) {
+        Behavior::AddressAndPortDependent
+    } else {
+        Behavior::AddressDependent
+    }
+}
+
+async fn filtering(socket: &SocksUdpSocket, server: &Address, timeout: Duration) -> Result<Behavior, Error> {
+    // only an answer that really came from elsewhere says anything
+    let changed = |response: &Option<Response>| match response {
+        Some(Response { mapped: Some(_), from }) => from != server,
+        _ => false,
+    };
+    let answer = query(socket, server, CHANGE_IP | CHANGE_PORT, timeout).await?;
+    if changed(&answer) {
+        return Ok(Behavior::EndpointIndependent);
+    }
+    if matches!(answer, Some(Response { mapped: None, .. })) {
+        return Ok(Behavior::Unknown);
+    }
+    let answer = query(socket, server, CHANGE_PORT, timeout).await?;
+    if changed(&answer) {
+        return Ok(Behavior::AddressDependent);
+    }
+    // a server silent to both can't be told from one that drops change requests
+    Ok(Behavior::AddressAndPortDependent)
+}
+
+async fn query(socket: &SocksUdpSocket, server: &Address, change: u32, timeout: Duration) -> Result<Option<Response>, Error> {
+    let id: [u8; 12] = generate_key(12)?.try_into().unwrap();
+    let request = binding_request(&id, change);
+    let mut buf = [0; 1024];
+    for _ in 0..ATTEMPTS {
+        socket.send_to(&request, server).await?;
+        let deadline = tokio::time::Instant::now() + timeout;
+        while let Ok(received) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await {
+            let (n, from) = received?;
+            if let Some(mapped) = parse_response(&buf[..n], &id) {
+                return Ok(Some(Response { mapped, from }));
+            }
+        }
+    }
+    Ok(None)
+}
+
+fn binding_request(id: &[u8; 12], change: u32) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(STUN_HEADER + 8);
+    packet.extend_from_slice(&BINDING_REQUEST.to_be_bytes());
+    packet.extend_from_slice(&(if change == 0 { 0u16 } else { 8 }).to_be_bytes());
+    packet.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    packet.extend_from_slice(id);
+    if change != 0 {
+        packet.extend_from_slice(&ATTR_CHANGE_REQUEST.to_be_bytes());
+        packet.extend_from_slice(&4u16.to_be_bytes());
+        packet.extend_from_slice(&change.to_be_bytes());
+    }
+    packet
+}
+
+// None when it isn't an answer to this request, Some(None) for an error answer
+fn parse_response(packet: &[u8], id: &[u8; 12]) -> Option<Option<SocketAddr>> {
+    if packet.len() < STUN_HEADER || packet[4..8] != MAGIC_COOKIE.to_be_bytes() || packet[8..20] != *id {
+        return None;
+    }
+    match u16::from_be_bytes([packet[0], packet[1]]) {
+        BINDING_RESPONSE => {}
+        BINDING_ERROR => return Some(None),
+        _ => return None,
+    }
+    let mut attributes = &packet[STUN_HEADER..];
+    let mut mapped = None;
+    while attributes.len() >= 4 {
+        let kind = u16::from_be_bytes([attributes[0], attributes[1]]);
+        let len = u16::from_be_bytes([attributes[2], attributes[3]]) as usize;
+        let value = attributes.get(4..4 + len)?;
+        match kind {
+            ATTR_XOR_MAPPED_ADDRESS => return Some(address(value, Some(packet[4..20].try_into().unwrap()))),
+            ATTR_MAPPED_ADDRESS => mapped = address(value, None),
+            _ => {}
+        }
+        // attributes are padded to 4 bytes
+        attributes = attributes.get(4 + len.div_ceil(4) * 4..).unwrap_or_default();
+    }
+    Some(mapped)
+}
+
+// family, port and ip, xored with the cookie and transaction id for XOR-MAPPED-ADDRESS
+fn address(value: &[u8], xor: Option<[u8; 16]>) -> Option<SocketAddr> {
+    let key = xor.unwrap_or([0; 16]);
+    let port = u16::from_be_bytes([value.get(2)? ^ key[0], value.get(3)? ^ key[1]]);
+    let ip = match value[1] {
+        0x01 => {
+            let mut ip: [u8; 4] = value.get(4..8)?.try_into().ok()?;
+            ip.iter_mut().zip(key).for_each(|(b, k)| *b ^= k);
+            IpAddr::V4(Ipv4Addr::from(ip))
+        }
+        0x02 => {
+            let mut ip: [u8; 16] = value.get(4..20)?.try_into().ok()?;
+            ip.iter_mut().zip(key).for_each(|(b, k)| *b ^= k);
+            IpAddr::V6(Ipv6Addr::from(ip))
+        }
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, port))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    use tokio::net::UdpSocket;
+
+    use crate::nat::{ATTR_XOR_MAPPED_ADDRESS, Behavior, BINDING_RESPONSE, CHANGE_PORT, detect, MAGIC_COOKIE};
+    use crate::socket5::Address;
+    use crate::test_util::{config, socks_server, test_state};
+    use crate::transport::Endpoint;
+
+    // answers binding requests, from a second socket when asked to change the port
+    async fn stun_server(change: bool) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let other = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 1024];
+            while let Ok((n, from)) = socket.recv_from(&mut buf).await {
+                let request = &buf[..n];
+                let changing = n >= 28 && u32::from_be_bytes(request[24..28].try_into().unwrap()) & CHANGE_PORT != 0;
+                let SocketAddr::V4(v4) = from else { continue };
+                let mut response = Vec::new();
+                response.extend_from_slice(&BINDING_RESPONSE.to_be_bytes());
+                response.extend_from_slice(&12u16.to_be_bytes());
+                response.extend_from_slice(&request[4..20]);
+                response.extend_from_slice(&ATTR_XOR_MAPPED_ADDRESS.to_be_bytes());
+                response.extend_from_slice(&8u16.to_be_bytes());
+                response.extend_from_slice(&[0, 1]);
+                response.extend_from_slice(&(v4.port() ^ (MAGIC_COOKIE >> 16) as u16).to_be_bytes());
+                response.extend_from_slice(&(u32::from(*v4.ip()) ^ MAGIC_COOKIE).to_be_bytes());
+                match (changing, change) {
+                    (false, _) => socket.send_to(&response, from).await.unwrap(),
+                    (true, true) => other.send_to(&response, from).await.unwrap(),
+                    (true, false) => continue,
+                };
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn detect_test() {
+        let server = socks_server(Endpoint::Tcp("127.0.0.1:0".to_string()), test_state(config())).await.to_string();
+        let stun = vec![Address::Address(stun_server(true).await), Address::Address(stun_server(false).await)];
+        let report = detect(&server, &stun, Duration::from_millis(200)).await.unwrap();
+        assert_eq!(report.mapped.len(), 2);
+        assert_eq!(report.mapped[0].1, report.mapped[1].1);
+        assert_eq!(report.mapping, Behavior::EndpointIndependent);
+        // the relay passes on answers from anywhere
+        assert_eq!(report.filtering, Behavior::EndpointIndependent);
+        assert_eq!(report.kind(), "full cone");
+
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let stun = vec![Address::Address(silent.local_addr().unwrap())];
+        assert!(detect(&server, &stun, Duration::from_millis(50)).await.is_err());
+    }
+}
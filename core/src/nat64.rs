@@ -0,0 +1,159 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+
+use log::{info, warn};
+use tokio::sync::OnceCell;
+
+use crate::policy::Cidr;
+
+// RFC 7050, resolved through a DNS64 resolver its A records come back inside the nat64 prefix
+const DISCOVERY_NAME: &str = "ipv4only.arpa";
+const WELL_KNOWN: [Ipv4Addr; 2] = [Ipv4Addr::new(192, 0, 0, 170), Ipv4Addr::new(192, 0, 0, 171)];
+// the prefix lengths RFC 6052 defines
+const LENGTHS: [u8; 6] = [32, 40, 48, 56, 64, 96];
+
+// ipv4 targets dialed through a nat64 gateway, for servers on ipv6-only networks: "auto" finds
+// the prefix through the network's DNS64 resolver, or it's given, e.g. "64:ff9b::/96"
+#[derive(Clone, Default)]
+pub struct Nat64 {
+    auto: bool,
+    prefix: Arc<OnceCell<Option<Cidr>>>,
+}
+
+impl Nat64 {
+    pub fn new(config: Option<&str>) -> io::Result<Self> {
+        match config {
+            None => Ok(Nat64::default()),
+            Some("auto") => Ok(Nat64 { auto: true, prefix: Arc::default() }),
+            Some(prefix) => Ok(Nat64 { auto: false, prefix: Arc::new(OnceCell::new_with(Some(Some(parse(prefix)?)))) }),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.auto || self.prefix.initialized()
+    }
+
+    // the ipv4 address inside an address of the prefix, once there is one
+    pub fn embedded(&self, addr: Ipv6Addr) -> Option<Ipv4Addr> {
+        self.prefix.get().copied().flatten().and_then(|prefix| extract(&prefix, addr))
+    }
+
+    // the address to dial, ipv4 ones inside the prefix once there is one
+    pub async fn map(&self, addr: SocketAddr) -> SocketAddr {
+        let SocketAddr::V4(v4) = addr else {
+            return addr;
+        };
+        if !self.is_enabled() {
+            return addr;
+        }
+        let prefix = self.prefix.get_or_init(|| async {
+            let found = discover().await;
+            match found {
+                Some(prefix) => info!("nat64 prefix {} found through {}", prefix, DISCOVERY_NAME),
+                None => warn!("no nat64 prefix found through {}, ipv4 targets are dialed as they are", DISCOVERY_NAME),
+            }
+            found
+        }).await;
+        match prefix {
+            Some(prefix) => SocketAddr::new(IpAddr::V6(synthesize(prefix, *v4.ip())), v4.port()),
+            None => addr,
+        }
+    }
+}
+
+pub fn parse(prefix: &str) -> io::Result<Cidr> {
+    let cidr: Cidr = prefix.parse()?;
+    if !cidr.network().is_ipv6() || !LENGTHS.contains(&cidr.prefix()) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("nat64 prefix {} isn't ipv6 /32, /40, /48, /56, /64 or /96", prefix)));
+    }
+    Ok(cidr)
+}
+
+async fn discover() -> Option<Cidr> {
+    let addrs = tokio::net::lookup_host((DISCOVERY_NAME, 0)).await.ok()?;
+    addrs.filter_map(|addr| match addr.ip() {
+        IpAddr::V6(v6) => prefix_of(v6),
+        IpAddr::V4(_) => None,
+    }).next()
+}
+
+// RFC 6052: the ipv4 bytes follow the prefix, skipping bits 64 to 71, any suffix is zero
+pub fn synthesize(prefix: &Cidr, v4: Ipv4Addr) -> Ipv6Addr {
+    let IpAddr::V6(network) = prefix.network() else {
+        return v4.to_ipv6_mapped();
+    };
+    let mut bytes = network.octets();
+    let mut at = prefix.prefix() as usize / 8;
+    for byte in v4.octets() {
+        if at == 8 {
+            at += 1;
+        }
+        bytes[at] = byte;
+        at += 1;
+    }
+    Ipv6Addr::from(bytes)
+}
+
+// the ipv4 address synthesize put in an address inside the prefix
+pub fn extract(prefix: &Cidr, addr: Ipv6Addr) -> Option<Ipv4Addr> {
+    if !prefix.contains(IpAddr::V6(addr)) {
+        return None;
+    }
+    let bytes = addr.octets();
+    let mut at = prefix.prefix() as usize / 8;
+    let mut v4 = [0; 4];
+    for byte in &mut v4 {
+        if at == 8 {
+            at += 1;
+        }
+        *byte = bytes[at];
+        at += 1;
+    }
+    Some(Ipv4Addr::from(v4))
+}
+
+// the prefix an address from the discovery name was synthesized with
+fn prefix_of(addr: Ipv6Addr) -> Option<Cidr> {
+    LENGTHS.iter().find_map(|len| {
+        let prefix: Cidr = format!("{}/{}", addr, len).parse().ok()?;
+        WELL_KNOWN.iter().any(|v4| synthesize(&prefix, *v4) == addr).then_some(prefix)
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::nat64::{extract, Nat64, parse, prefix_of, synthesize};
+
+    #[test]
+    fn synthesize_test() {
+        let v4 = "192.0.2.33".parse().unwrap();
+        // the examples of RFC 6052 section 2.4
+        for (prefix, expected) in [
+            ("2001:db8::/32", "2001:db8:c000:221::"),
+            ("2001:db8:100::/40", "2001:db8:1c0:2:21::"),
+            ("2001:db8:122::/48", "2001:db8:122:c000:2:2100::"),
+            ("2001:db8:122:300::/56", "2001:db8:122:3c0:0:221::"),
+            ("2001:db8:122:344::/64", "2001:db8:122:344:c0:2:2100:0"),
+            ("64:ff9b::/96", "64:ff9b::c000:221"),
+        ] {
+            let prefix = parse(prefix).unwrap();
+            assert_eq!(synthesize(&prefix, v4), expected.parse::<std::net::Ipv6Addr>().unwrap());
+            assert_eq!(extract(&prefix, expected.parse().unwrap()), Some(v4));
+        }
+        assert!(parse("64:ff9b::/80").is_err());
+        assert!(parse("10.0.0.0/8").is_err());
+        assert_eq!(prefix_of("64:ff9b::c000:aa".parse().unwrap()), Some(parse("64:ff9b::/96").unwrap()));
+        assert_eq!(prefix_of("2001:db8::1".parse().unwrap()), None);
+    }
+
+    #[tokio::test]
+    async fn map_test() {
+        let nat64 = Nat64::new(Some("64:ff9b::/96")).unwrap();
+        assert_eq!(nat64.map("192.0.2.33:443".parse().unwrap()).await, "[64:ff9b::c000:221]:443".parse().unwrap());
+        assert_eq!(nat64.map("[2001:db8::1]:443".parse().unwrap()).await, "[2001:db8::1]:443".parse().unwrap());
+        let off = Nat64::new(None).unwrap();
+        assert_eq!(off.map("192.0.2.33:443".parse().unwrap()).await, "192.0.2.33:443".parse().unwrap());
+    }
+}
@@ -0,0 +1,323 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+
+use bytes::{Buf, BytesMut};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::crypto::{encode_key, generate_key};
+use crate::transport::RawStream;
+
+const TLS_HANDSHAKE: u8 = 0x16;
+const TLS_CHANGE_CIPHER_SPEC: u8 = 0x14;
+const TLS_APPLICATION_DATA: u8 = 0x17;
+const TLS_RECORD_HEADER: usize = 5;
+const MAX_TLS_RECORD: usize = 16384;
+// a request or response head longer than this is not ours
+const MAX_HTTP_HEAD: usize = 8192;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ObfsMode {
+    // a websocket upgrade request and response ahead of the raw stream
+    Http,
+    // fake hello records, then every write in an application data record
+    Tls,
+}
+
+#[derive(PartialEq)]
+enum ReadState {
+    Head,
+    Body,
+}
+
+// wraps the tunnel so it reads like http or tls to a passive observer
+pub struct ObfsStream<S> {
+    inner: S,
+    mode: ObfsMode,
+    client: bool,
+    host: String,
+    state: ReadState,
+    read_buf: BytesMut,
+    plain: BytesMut,
+    head_written: bool,
+    write_buf: BytesMut,
+}
+
+impl<S> ObfsStream<S>
+    where S: AsyncRead + AsyncWrite + Unpin
+{
+    // host is what the http request claims to be talking to
+    pub fn client(inner: S, mode: ObfsMode, host: &str) -> Self {
+        ObfsStream::new(inner, mode, true, host)
+    }
+
+    pub fn server(inner: S, mode: ObfsMode) -> Self {
+        ObfsStream::new(inner, mode, false, "")
+    }
+
+    fn new(inner: S, mode: ObfsMode, client: bool, host: &str) -> Self {
+        ObfsStream {
+            inner,
+            mode,
+            client,
+            host: host.to_string(),
+            state: ReadState::Head,
+            read_buf: BytesMut::new(),
+            plain: BytesMut::new(),
+            head_written: false,
+            write_buf: BytesMut::new(),
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    // consume whatever is buffered, true when progress was made
+    fn decode(&mut self) -> io::Result<bool> {
+        match self.mode {
+            ObfsMode::Http => self.decode_http(),
+            ObfsMode::Tls => self.decode_tls(),
+        }
+    }
+
+    fn decode_http(&mut self) -> io::Result<bool> {
+        if self.state == ReadState::Body {
+            if self.read_buf.is_empty() {
+                return Ok(false);
+            }
+            let data = self.read_buf.split();
+            self.plain.extend_from_slice(&data);
+            return Ok(true);
+        }
+        let end = match self.read_buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            Some(end) => end,
+            None if self.read_buf.len() > MAX_HTTP_HEAD => return Err(invalid("http head too long")),
+            None => return Ok(false),
+        };
+        let head = self.read_buf.split_to(end + 4);
+        let expected: &[u8] = if self.client { b"HTTP/1.1 101 " } else { b"GET " };
+        if !head.starts_with(expected) {
+            return Err(invalid("unexpected http head"));
+        }
+        self.state = ReadState::Body;
+        Ok(true)
+    }
+
+    fn decode_tls(&mut self) -> io::Result<bool> {
+        if self.read_buf.len() < TLS_RECORD_HEADER {
+            return Ok(false);
+        }
+        let len = u16::from_be_bytes([self.read_buf[3], self.read_buf[4]]) as usize;
+        if len > MAX_TLS_RECORD + 2048 {
+            return Err(invalid("tls record too long"));
+        }
+        if self.read_buf.len() < TLS_RECORD_HEADER + len {
+            return Ok(false);
+        }
+        let record = self.read_buf.split_to(TLS_RECORD_HEADER + len);
+        match (record[0], &self.state) {
+            (TLS_HANDSHAKE | TLS_CHANGE_CIPHER_SPEC, ReadState::Head) => {}
+            (TLS_APPLICATION_DATA, _) => {
+                self.state = ReadState::Body;
+                self.plain.extend_from_slice(&record[TLS_RECORD_HEADER..]);
+            }
+            _ => return Err(invalid("unexpected tls record")),
+        }
+        Ok(true)
+    }
+
+    fn encode(&mut self, data: &[u8]) -> io::Result<()> {
+        if !self.head_written {
+            self.head_written = true;
+            self.write_head()?;
+        }
+        match self.mode {
+            ObfsMode::Http => self.write_buf.extend_from_slice(data),
+            ObfsMode::Tls => {
+                self.write_buf.extend_from_slice(&[TLS_APPLICATION_DATA, 0x03, 0x03]);
+                self.write_buf.extend_from_slice(&(data.len() as u16).to_be_bytes());
+                self.write_buf.extend_from_slice(data);
+            }
+        }
+        Ok(())
+    }
+
+    fn write_head(&mut self) -> io::Result<()> {
+        match (self.mode, self.client) {
+            (ObfsMode::Http, true) => {
+                let key = encode_key(&generate_key(16)?);
+                self.write_buf.extend_from_slice(format!(
+                    "GET / HTTP/1.1\r\nHost: {}\r\nUser-Agent: curl/8.5.0\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: {}\r\n\r\n",
+                    self.host, key,
+                ).as_bytes());
+            }
+            (ObfsMode::Http, false) => {
+                let accept = encode_key(&generate_key(20)?);
+                self.write_buf.extend_from_slice(format!(
+                    "HTTP/1.1 101 Switching Protocols\r\nServer: nginx\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+                    accept,
+                ).as_bytes());
+            }
+            (ObfsMode::Tls, client) => {
+                // hello bodies are random, only the record framing has to look right
+                let version = if client { 0x01 } else { 0x03 };
+                let hello = generate_key(if client { 512 } else { 90 })?;
+                self.write_buf.extend_from_slice(&[TLS_HANDSHAKE, 0x03, version]);
+                self.write_buf.extend_from_slice(&(hello.len() as u16).to_be_bytes());
+                self.write_buf.extend_from_slice(&hello);
+                if !client {
+                    self.write_buf.extend_from_slice(&[TLS_CHANGE_CIPHER_SPEC, 0x03, 0x03, 0x00, 0x01, 0x01]);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.write_buf.is_empty() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.write_buf))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.write_buf.advance(n);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+impl<S> AsyncRead for ObfsStream<S>
+    where S: AsyncRead + AsyncWrite + Unpin
+{
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if !this.plain.is_empty() {
+                let n = this.plain.len().min(buf.remaining());
+                buf.put_slice(&this.plain.split_to(n));
+                return Poll::Ready(Ok(()));
+            }
+            if this.decode()? {
+                continue;
+            }
+            let mut chunk = [0; 8192];
+            let mut chunk_buf = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut chunk_buf))?;
+            if chunk_buf.filled().is_empty() {
+                if this.read_buf.is_empty() {
+                    return Poll::Ready(Ok(()));
+                }
+                return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+            }
+            this.read_buf.extend_from_slice(chunk_buf.filled());
+        }
+    }
+}
+
+impl<S> AsyncWrite for ObfsStream<S>
+    where S: AsyncRead + AsyncWrite + Unpin
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let n = buf.len().min(MAX_TLS_RECORD);
+        this.encode(&buf[..n])?;
+        if let Poll::Ready(Err(e)) = this.poll_drain(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+impl<S> RawStream for ObfsStream<S>
+    where S: AsyncRead + AsyncWrite + Unpin + RawStream
+{
+    type Raw = S::Raw;
+
+    fn raw(&mut self) -> &mut S::Raw {
+        self.inner.raw()
+    }
+
+    fn abort_on_close(&mut self) -> io::Result<()> {
+        self.inner.abort_on_close()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, duplex};
+
+    use crate::obfs::{ObfsMode, ObfsStream};
+
+    #[tokio::test]
+    async fn obfs_round_trip_test() {
+        for mode in [ObfsMode::Http, ObfsMode::Tls] {
+            let (a, b) = duplex(1024);
+            let mut client = ObfsStream::client(a, mode, "example.com");
+            let mut server = ObfsStream::server(b, mode);
+            let data: Vec<u8> = (0..40000).map(|i| i as u8).collect();
+            let sent = data.clone();
+            let writer = tokio::spawn(async move {
+                client.write_all(&sent).await.unwrap();
+                client.flush().await.unwrap();
+                let mut answer = [0; 2];
+                client.read_exact(&mut answer).await.unwrap();
+                answer
+            });
+            let mut received = vec![0; data.len()];
+            server.read_exact(&mut received).await.unwrap();
+            assert_eq!(received, data);
+            server.write_all(b"ok").await.unwrap();
+            server.flush().await.unwrap();
+            assert_eq!(&writer.await.unwrap(), b"ok");
+        }
+    }
+
+    #[tokio::test]
+    async fn obfs_framing_test() {
+        let (a, mut b) = duplex(4096);
+        let mut client = ObfsStream::client(a, ObfsMode::Http, "example.com");
+        client.write_all(b"hello").await.unwrap();
+        client.flush().await.unwrap();
+        let mut buf = vec![0; 4096];
+        let n = b.read(&mut buf).await.unwrap();
+        let head = String::from_utf8_lossy(&buf[..n]).to_string();
+        assert!(head.starts_with("GET / HTTP/1.1\r\nHost: example.com\r\n"));
+        assert!(head.ends_with("\r\n\r\nhello"));
+
+        let (a, mut b) = duplex(4096);
+        let mut server = ObfsStream::server(a, ObfsMode::Tls);
+        b.write_all(b"\x17\x03\x03\x00\x02hi").await.unwrap();
+        let mut buf = [0; 2];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hi");
+        b.write_all(b"\x15\x03\x03\x00\x00").await.unwrap();
+        assert!(server.read(&mut [0; 2]).await.is_err());
+    }
+}
@@ -0,0 +1,113 @@
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use log::debug;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+
+use crate::metrics::{NOT_FOUND, request_line};
+use crate::rules::Pattern;
+
+// GET /proxy.pac, the same routing the local client does: `direct` patterns DIRECT, the rest over socks
+pub async fn serve(listener: TcpListener, socks: SocketAddr, direct: Vec<Pattern>, mut shutdown: watch::Receiver<bool>) {
+    let direct = Arc::new(direct);
+    loop {
+        tokio::select! {
+            _ = shutdown.changed() => break,
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => {
+                    // a task each, so a browser that never sends its request holds up no one else
+                    let direct = direct.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = answer(stream, socks, &direct).await {
+                            debug!("pac request fail : {}", e);
+                        }
+                    });
+                }
+                Err(e) => debug!("pac accept fail : {}", e),
+            }
+        }
+    }
+}
+
+async fn answer(mut stream: TcpStream, socks: SocketAddr, direct: &[Pattern]) -> std::io::Result<()> {
+    let line = request_line(&mut stream).await?;
+    let response = match line.split(|b| *b == b' ').collect::<Vec<_>>().as_slice() {
+        [b"GET", b"/proxy.pac", ..] => {
+            // a listener on 0.0.0.0 is reached at whatever address the browser fetched the file from
+            let socks = match socks.ip().is_unspecified() {
+                true => SocketAddr::new(stream.local_addr()?.ip(), socks.port()),
+                false => socks,
+            };
+            let body = render(direct, socks);
+            format!("HTTP/1.1 200 OK\r\nContent-Type: application/x-ns-proxy-autoconfig\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body)
+        }
+        _ => NOT_FOUND.to_string(),
+    };
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+pub fn render(direct: &[Pattern], socks: SocketAddr) -> String {
+    let mut out = String::from("function FindProxyForURL(url, host) {\n    host = host.toLowerCase();\n");
+    for pattern in direct {
+        let _ = writeln!(out, "    if ({}) return \"DIRECT\";", condition(pattern));
+    }
+    let _ = writeln!(out, "    return \"SOCKS5 {socks}; SOCKS {socks}\";\n}}", socks = socks);
+    out
+}
+
+// the pac helpers that match like Pattern::matches does
+fn condition(pattern: &Pattern) -> String {
+    let quote = |s: &str| serde_json::to_string(s).unwrap_or_default();
+    match pattern {
+        Pattern::Suffix(domain) => format!("host == {} || dnsDomainIs(host, {})", quote(domain), quote(&format!(".{}", domain))),
+        Pattern::Wildcard(glob) => format!("shExpMatch(host, {})", quote(glob)),
+        Pattern::Regex(regex) => format!("new RegExp({}, \"i\").test(host)", quote(regex.pattern())),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    use crate::pac::{render, serve};
+
+    #[test]
+    fn render_test() {
+        let direct = vec!["example.com".parse().unwrap(), "*.lan".parse().unwrap(), r"regex:^intra\d*\.".parse().unwrap()];
+        let pac = render(&direct, "127.0.0.1:1080".parse().unwrap());
+        assert!(pac.contains("    if (host == \"example.com\" || dnsDomainIs(host, \".example.com\")) return \"DIRECT\";\n"));
+        assert!(pac.contains("    if (shExpMatch(host, \"*.lan\")) return \"DIRECT\";\n"));
+        assert!(pac.contains("    if (new RegExp(\"^intra\\\\d*\\\\.\", \"i\").test(host)) return \"DIRECT\";\n"));
+        assert!(pac.ends_with("    return \"SOCKS5 127.0.0.1:1080; SOCKS 127.0.0.1:1080\";\n}\n"));
+    }
+
+    #[tokio::test]
+    async fn serve_test() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (_shutdown, watcher) = tokio::sync::watch::channel(false);
+        tokio::spawn(serve(listener, "0.0.0.0:1080".parse().unwrap(), vec![], watcher));
+
+        let get = |path: &'static str| async move {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream.write_all(format!("GET {} HTTP/1.1\r\nHost: x\r\n\r\n", path).as_bytes()).await.unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            response
+        };
+        // one that never sends its request holds up no one else
+        let _idle = TcpStream::connect(addr).await.unwrap();
+        let response = tokio::time::timeout(std::time::Duration::from_secs(1), get("/proxy.pac")).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("Content-Type: application/x-ns-proxy-autoconfig\r\n"));
+        // the unspecified listen address is swapped for the one the file was fetched from
+        assert!(response.contains("\"SOCKS5 127.0.0.1:1080; SOCKS 127.0.0.1:1080\""));
+        assert!(get("/").await.starts_with("HTTP/1.1 404"));
+    }
+}
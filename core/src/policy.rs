@@ -0,0 +1,219 @@
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::error::Error;
+use crate::nat64::{self, Nat64};
+use crate::resolver::Resolver;
+use crate::socket5::{self, Address};
+
+// the nat64 well-known prefix, RFC 6052, and the local-use one, RFC 8215
+const NAT64_PREFIXES: [Cidr; 2] = [
+    Cidr { network: IpAddr::V6(Ipv6Addr::new(0x64, 0xff9b, 0, 0, 0, 0, 0, 0)), prefix: 96 },
+    Cidr { network: IpAddr::V6(Ipv6Addr::new(0x64, 0xff9b, 1, 0, 0, 0, 0, 0)), prefix: 48 },
+];
+
+// addresses a public server has no business reaching for its clients: private ranges,
+// loopback, link-local (cloud metadata at 169.254.169.254 included) and the like
+pub fn is_private(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_private_v4(ip),
+        IpAddr::V6(ip) => match embedded_v4(ip) {
+            Some(ip) => is_private_v4(ip),
+            None => is_private_v6(ip),
+        },
+    }
+}
+
+// the ipv4 address an ipv6 one is a way to reach: mapped ::ffff:a.b.c.d, compatible ::a.b.c.d,
+// inside a nat64 prefix, or a 6to4 2002:aabb:ccdd::/48 relay
+fn embedded_v4(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    let [a, b, c, d] = match ip.segments() {
+        [0, 0, 0, 0, 0, 0xffff | 0, ..] => ip.octets()[12..16].try_into().unwrap(),
+        [0x2002, ..] => ip.octets()[2..6].try_into().unwrap(),
+        _ => return NAT64_PREFIXES.iter().find_map(|prefix| nat64::extract(prefix, ip)),
+    };
+    Some(Ipv4Addr::new(a, b, c, d))
+}
+
+fn is_private_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip.is_unspecified()
+        || ip.is_broadcast() || ip.is_multicast()
+        // shared address space for carrier-grade nat, RFC 6598
+        || (a == 100 && (64..128).contains(&b))
+        // "this network", RFC 1122
+        || a == 0
+}
+
+fn is_private_v6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    ip.is_loopback() || ip.is_unspecified() || ip.is_multicast()
+        // unique local fc00::/7, link-local fe80::/10
+        || (first & 0xfe00) == 0xfc00
+        || (first & 0xffc0) == 0xfe80
+}
+
+// the address to dial for a target, a domain is resolved here so none of its addresses
+// can point somewhere private behind the check's back, and so a doh resolver is the one asked
+pub async fn permitted(address: &Address, block_private: bool, resolver: &Resolver, nat64: &Nat64) -> Result<Address, Error> {
+    if !block_private && !resolver.is_enabled() {
+        return Ok(address.clone());
+    }
+    let check = |addr| if block_private { check(addr, nat64) } else { Ok(addr) };
+    match address {
+        Address::Address(addr) => check(*addr).map(Address::Address),
+        Address::DomainName(host, port) => {
+            let mut refused = None;
+            for addr in resolver.lookup(host, *port).await? {
+                match check(addr) {
+                    Ok(addr) => return Ok(Address::Address(addr)),
+                    Err(e) => refused = Some(e),
+                }
+            }
+            Err(refused.unwrap_or(Error::Socks(socket5::Error::AddressDomainNo)))
+        }
+    }
+}
+
+// an address inside the configured nat64 prefix is as private as the ipv4 one it reaches
+pub fn check(addr: SocketAddr, nat64: &Nat64) -> Result<SocketAddr, Error> {
+    let embedded = match addr.ip() {
+        IpAddr::V6(ip) => nat64.embedded(ip),
+        IpAddr::V4(_) => None,
+    };
+    if is_private(addr.ip()) || embedded.is_some_and(is_private_v4) {
+        return Err(Error::Forbidden(addr.ip()));
+    }
+    Ok(addr)
+}
+
+// an address range, "10.0.0.0/8" or "2001:db8::/32" in config files, a bare address is just it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn network(&self) -> IpAddr {
+        self.network
+    }
+
+    pub fn prefix(&self) -> u8 {
+        self.prefix
+    }
+
+    // ipv4-mapped ipv6 addresses, as dual stack listeners see ipv4 clients, match ipv4 ranges
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            ip => ip,
+        };
+        match (self.network, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => mask(u32::from(ip) as u128, self.prefix, 32) == u32::from(network) as u128,
+            (IpAddr::V6(network), IpAddr::V6(ip)) => mask(u128::from(ip), self.prefix, 128) == u128::from(network),
+            _ => false,
+        }
+    }
+}
+
+// the top prefix bits of a bits wide address
+fn mask(value: u128, prefix: u8, bits: u8) -> u128 {
+    match prefix {
+        0 => 0,
+        prefix => value & (u128::MAX << (bits - prefix)) & (u128::MAX >> (128 - bits)),
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidInput, format!("invalid cidr : {}", s));
+        let (ip, prefix) = s.trim().split_once('/').map_or((s.trim(), None), |(ip, prefix)| (ip, Some(prefix)));
+        let ip: IpAddr = ip.parse().map_err(|_| invalid())?;
+        let bits = if ip.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            None => bits,
+            Some(prefix) => prefix.parse().ok().filter(|p| *p <= bits).ok_or_else(invalid)?,
+        };
+        // host bits are dropped, 10.1.2.3/8 is 10.0.0.0/8
+        let network = match ip {
+            IpAddr::V4(v4) => IpAddr::V4(Ipv4Addr::from(mask(u32::from(v4) as u128, prefix, 32) as u32)),
+            IpAddr::V6(v6) => IpAddr::V6(Ipv6Addr::from(mask(u128::from(v6), prefix, 128))),
+        };
+        Ok(Cidr { network, prefix })
+    }
+}
+
+impl Display for Cidr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix)
+    }
+}
+
+impl Serialize for Cidr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Cidr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::nat64::Nat64;
+    use crate::policy::{check, Cidr, is_private};
+
+    #[test]
+    fn cidr_test() {
+        let office: Cidr = "10.1.2.3/16".parse().unwrap();
+        assert_eq!(office.to_string(), "10.1.0.0/16");
+        assert!(office.contains("10.1.200.7".parse().unwrap()));
+        assert!(office.contains("::ffff:10.1.0.1".parse().unwrap()));
+        assert!(!office.contains("10.2.0.1".parse().unwrap()));
+        let v6: Cidr = "2001:db8::/32".parse().unwrap();
+        assert!(v6.contains("2001:db8:1::1".parse().unwrap()));
+        assert!(!v6.contains("10.1.0.1".parse().unwrap()));
+        let host: Cidr = "192.168.1.1".parse().unwrap();
+        assert!(host.contains("192.168.1.1".parse().unwrap()) && !host.contains("192.168.1.2".parse().unwrap()));
+        assert!("0.0.0.0/0".parse::<Cidr>().unwrap().contains("8.8.8.8".parse().unwrap()));
+        for invalid in ["10.0.0.0/33", "example.com/8", "10.0.0.0/x"] {
+            assert!(invalid.parse::<Cidr>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn is_private_test() {
+        for ip in ["10.1.2.3", "172.16.0.1", "192.168.1.1", "127.0.0.1", "169.254.169.254", "100.64.0.1",
+                   "0.0.0.0", "::1", "fd00:ec2::254", "fe80::1", "::ffff:192.168.1.1",
+                   // ipv4 inside ipv6: compatible, nat64 well-known and local-use, 6to4
+                   "::169.254.169.254", "64:ff9b::a9fe:a9fe", "64:ff9b:1:a9fe:a9:fe00::", "2002:a9fe:a9fe::1", "2002:7f00:1::"] {
+            assert!(is_private(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in ["8.8.8.8", "172.32.0.1", "100.128.0.1", "2001:4860:4860::8888", "::ffff:1.1.1.1",
+                   "::1.1.1.1", "64:ff9b::808:808", "64:ff9b:1:808:8:800::", "2002:808:808::1"] {
+            assert!(!is_private(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn nat64_check_test() {
+        // the configured prefix hides an ipv4 address only it knows of
+        let nat64 = Nat64::new(Some("2001:db8:64::/96")).unwrap();
+        let metadata = "[2001:db8:64::a9fe:a9fe]:80".parse().unwrap();
+        assert!(check(metadata, &nat64).is_err());
+        assert!(check(metadata, &Nat64::new(None).unwrap()).is_ok());
+        assert!(check("[2001:db8:64::808:808]:80".parse().unwrap(), &nat64).is_ok());
+    }
+}
@@ -0,0 +1,283 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+use log::debug;
+use tokio::net::TcpStream;
+
+use crate::config::PoolConfig;
+use crate::error::Error;
+use crate::socket5::Address;
+
+// targets whose hits are counted, one-off targets past this aren't until the sweep makes room
+const MAX_TRACKED: usize = 4096;
+
+struct Idle {
+    stream: TcpStream,
+    since: Instant,
+}
+
+struct Hits {
+    count: u32,
+    last: Instant,
+}
+
+#[derive(Default)]
+struct Idles {
+    targets: HashMap<Address, Vec<Idle>>,
+    hits: HashMap<Address, Hits>,
+    total: usize,
+}
+
+impl Idles {
+    fn retain(&mut self, ttl: Duration) {
+        self.targets.retain(|_, streams| {
+            streams.retain(|i| i.since.elapsed() < ttl);
+            !streams.is_empty()
+        });
+        self.hits.retain(|_, hits| hits.last.elapsed() < ttl);
+        self.total = self.targets.values().map(Vec::len).sum();
+    }
+}
+
+// spare outbound connections dialed ahead of time for targets seen often enough
+#[derive(Clone)]
+pub struct Pool {
+    config: Arc<PoolConfig>,
+    idle: Arc<Mutex<Idles>>,
+}
+
+impl Pool {
+    // a sweep closing expired connections runs while the pool is alive, so this needs a runtime when enabled
+    pub fn new(config: PoolConfig) -> Self {
+        let pool = Pool {
+            config: Arc::new(config),
+            idle: Arc::new(Mutex::new(Idles::default())),
+        };
+        if pool.is_enabled() {
+            tokio::spawn(sweep(Arc::downgrade(&pool.idle), pool.ttl()));
+        }
+        pool
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.config.max_idle > 0
+    }
+
+    // hand out a warm connection when one is alive, then dial replacements in the background
+    pub async fn connect(&self, address: &Address) -> Result<TcpStream, Error> {
+        if !self.is_enabled() {
+            return dial(address).await;
+        }
+        let stream = match self.take(address).await {
+            Some(stream) => {
+                debug!("reuse pooled connection to {:?}", address);
+                stream
+            }
+            None => dial(address).await?,
+        };
+        self.refill(address);
+        Ok(stream)
+    }
+
+    // idle connections for the address, expired ones are dropped on the way
+    pub fn idle(&self, address: &Address) -> usize {
+        let mut idle = self.idle.lock().unwrap();
+        let ttl = self.ttl();
+        let Some(streams) = idle.targets.get_mut(address) else {
+            return 0;
+        };
+        let before = streams.len();
+        streams.retain(|i| i.since.elapsed() < ttl);
+        let after = streams.len();
+        idle.total -= before - after;
+        after
+    }
+
+    // idle connections across every target
+    pub fn total(&self) -> usize {
+        self.idle.lock().unwrap().total
+    }
+
+    async fn take(&self, address: &Address) -> Option<TcpStream> {
+        loop {
+            let idle = {
+                let mut idle = self.idle.lock().unwrap();
+                let idle = &mut *idle;
+                let streams = idle.targets.get_mut(address)?;
+                let taken = streams.pop();
+                if streams.is_empty() {
+                    idle.targets.remove(address);
+                }
+                let taken = taken?;
+                idle.total -= 1;
+                taken
+            };
+            if idle.since.elapsed() < self.ttl() && alive(&idle.stream).await {
+                return Some(idle.stream);
+            }
+        }
+    }
+
+    // counts the hit, and dials up to max_idle once the target has had enough of them
+    fn refill(&self, address: &Address) {
+        let wanted = {
+            let mut idle = self.idle.lock().unwrap();
+            let tracked = idle.hits.len() < MAX_TRACKED;
+            let hits = match idle.hits.get_mut(address) {
+                Some(hits) if hits.last.elapsed() < self.ttl() => {
+                    hits.count = hits.count.saturating_add(1);
+                    hits.last = Instant::now();
+                    hits.count
+                }
+                Some(hits) => {
+                    *hits = Hits { count: 1, last: Instant::now() };
+                    1
+                }
+                None if tracked => {
+                    idle.hits.insert(address.clone(), Hits { count: 1, last: Instant::now() });
+                    1
+                }
+                None => 0,
+            };
+            let pooled = idle.targets.get(address).map_or(0, Vec::len);
+            if hits < self.config.hits.max(1) || (pooled == 0 && idle.targets.len() >= self.config.max_targets) {
+                0
+            } else {
+                self.config.max_idle.saturating_sub(pooled)
+                    .min(self.config.max_total.saturating_sub(idle.total))
+            }
+        };
+        for _ in 0..wanted {
+            let pool = self.clone();
+            let address = address.clone();
+            tokio::spawn(async move {
+                match dial(&address).await {
+                    Ok(stream) => pool.put(address, stream),
+                    Err(e) => debug!("pool dial {:?} fail : {:?}", address, e),
+                }
+            });
+        }
+    }
+
+    // dials finishing together can overshoot the caps, the surplus is closed here
+    fn put(&self, address: Address, stream: TcpStream) {
+        let mut idle = self.idle.lock().unwrap();
+        let idle = &mut *idle;
+        if idle.total >= self.config.max_total {
+            return;
+        }
+        if !idle.targets.contains_key(&address) && idle.targets.len() >= self.config.max_targets {
+            return;
+        }
+        let streams = idle.targets.entry(address).or_default();
+        if streams.len() < self.config.max_idle {
+            streams.push(Idle { stream, since: Instant::now() });
+            idle.total += 1;
+        }
+    }
+
+    fn ttl(&self) -> Duration {
+        Duration::from_secs(self.config.ttl)
+    }
+}
+
+// closes what expired, targets nobody asks for again would otherwise keep their connections
+// a connection of its own, a domain resolved by the system
+async fn dial(address: &Address) -> Result<TcpStream, Error> {
+    Ok(match address {
+        Address::Address(addr) => TcpStream::connect(addr).await?,
+        Address::DomainName(host, port) => TcpStream::connect((host.as_str(), *port)).await?,
+    })
+}
+
+async fn sweep(idle: Weak<Mutex<Idles>>, ttl: Duration) {
+    let mut interval = tokio::time::interval(ttl.max(Duration::from_secs(1)));
+    loop {
+        interval.tick().await;
+        match idle.upgrade() {
+            Some(idle) => idle.lock().unwrap().retain(ttl),
+            None => break,
+        }
+    }
+}
+
+// an idle connection the target already closed reads eof straight away
+async fn alive(stream: &TcpStream) -> bool {
+    let mut buf = [0; 1];
+    match tokio::time::timeout(Duration::ZERO, stream.peek(&mut buf)).await {
+        Ok(Ok(0)) | Ok(Err(_)) => false,
+        Ok(Ok(_)) | Err(_) => true,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::config::PoolConfig;
+    use crate::pool::Pool;
+    use crate::socket5::Address;
+    use crate::test_util::echo_server;
+
+    #[tokio::test]
+    async fn pool_reuse_test() {
+        let address = Address::Address(echo_server().await);
+        let pool = Pool::new(PoolConfig { max_idle: 2, ttl: 60, ..PoolConfig::default() });
+        for _ in 0..3 {
+            let mut stream = pool.connect(&address).await.unwrap();
+            stream.write_all(b"hello").await.unwrap();
+            let mut buf = [0; 5];
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello");
+        }
+        for _ in 0..100 {
+            if pool.idle(&address) == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(pool.idle(&address), 2);
+
+        let expired = Pool::new(PoolConfig { max_idle: 2, ttl: 0, hits: 1, ..PoolConfig::default() });
+        expired.connect(&address).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(expired.idle(&address), 0);
+    }
+
+    async fn settle(pool: &Pool, total: usize) {
+        for _ in 0..100 {
+            if pool.total() == total {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn pool_limits_test() {
+        let first = Address::Address(echo_server().await);
+        let second = Address::Address(echo_server().await);
+        let pool = Pool::new(PoolConfig { max_idle: 3, ttl: 1, hits: 2, max_total: 2, max_targets: 1 });
+        // a target seen once isn't worth warm connections
+        pool.connect(&first).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(pool.total(), 0);
+        pool.connect(&first).await.unwrap();
+        settle(&pool, 2).await;
+        assert_eq!(pool.idle(&first), 2);
+
+        // max_targets is taken by the first one
+        pool.connect(&second).await.unwrap();
+        pool.connect(&second).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(pool.idle(&second), 0);
+
+        // nobody asks for the first one again, the sweep closes its connections
+        tokio::time::sleep(Duration::from_millis(2100)).await;
+        assert_eq!(pool.total(), 0);
+    }
+}
@@ -0,0 +1,259 @@
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, ready};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{Instant, Sleep};
+
+use crate::config::{Priority, PriorityConfig};
+
+// a read is held back until at least this much can be granted, so a busy class moves data
+// in useful chunks rather than a byte at a time
+const MIN_GRANT: usize = 1024;
+
+#[derive(Default)]
+struct Class {
+    tokens: f64,
+    // relays of the class that are open, only those take a share
+    open: usize,
+}
+
+struct Buckets {
+    classes: [Class; 2],
+    updated: Instant,
+}
+
+// weighted fair sharing of the configured bandwidth between priority classes: every class
+// with relays open gets its weight's share, and what a class leaves unused goes to the others
+#[derive(Clone)]
+pub struct Scheduler {
+    bandwidth: f64,
+    weights: [f64; 2],
+    // tokens a class can save up while it is quiet
+    burst: f64,
+    buckets: Arc<Mutex<Buckets>>,
+}
+
+fn index(priority: Priority) -> usize {
+    match priority {
+        Priority::Interactive => 0,
+        Priority::Bulk => 1,
+    }
+}
+
+impl Scheduler {
+    pub fn new(config: &PriorityConfig) -> Self {
+        let bandwidth = config.bandwidth as f64;
+        Scheduler {
+            bandwidth,
+            weights: [config.interactive_weight.max(1) as f64, config.bulk_weight.max(1) as f64],
+            // a tenth of a second, never less than one grant
+            burst: (bandwidth / 10.0).max(MIN_GRANT as f64),
+            buckets: Arc::new(Mutex::new(Buckets { classes: Default::default(), updated: Instant::now() })),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.bandwidth > 0.0
+    }
+
+    // counts a relay of the class as open until the guard is dropped
+    pub fn open(&self, priority: Priority) -> ClassGuard {
+        let mut buckets = self.buckets.lock().unwrap();
+        self.refill(&mut buckets, Instant::now());
+        buckets.classes[index(priority)].open += 1;
+        ClassGuard { scheduler: self.clone(), priority }
+    }
+
+    // wraps one direction's reader, writes pass through
+    pub fn pace<S>(&self, inner: S, priority: Priority) -> Paced<S> {
+        Paced { inner, scheduler: self.clone(), priority, sleep: None }
+    }
+
+    fn refill(&self, buckets: &mut Buckets, now: Instant) {
+        let mut budget = now.duration_since(buckets.updated).as_secs_f64() * self.bandwidth;
+        buckets.updated = now;
+        // a share a full bucket can't take is handed on to the classes still below burst
+        while budget > 0.0 {
+            let hungry: Vec<usize> = (0..2)
+                .filter(|&i| buckets.classes[i].open > 0 && buckets.classes[i].tokens < self.burst)
+                .collect();
+            let total: f64 = hungry.iter().map(|&i| self.weights[i]).sum();
+            if hungry.is_empty() {
+                break;
+            }
+            let mut spilled = 0.0;
+            for i in hungry {
+                let class = &mut buckets.classes[i];
+                class.tokens += budget * self.weights[i] / total;
+                if class.tokens > self.burst {
+                    spilled += class.tokens - self.burst;
+                    class.tokens = self.burst;
+                }
+            }
+            budget = spilled;
+        }
+    }
+
+    // bytes the class may read now, up to want, or how long to wait before asking again
+    fn acquire(&self, priority: Priority, want: usize) -> Result<usize, Duration> {
+        let mut buckets = self.buckets.lock().unwrap();
+        self.refill(&mut buckets, Instant::now());
+        let i = index(priority);
+        let need = want.min(MIN_GRANT) as f64;
+        let class = &mut buckets.classes[i];
+        if class.tokens >= need {
+            let grant = (class.tokens as usize).min(want);
+            class.tokens -= grant as f64;
+            return Ok(grant);
+        }
+        // the share while every other class with relays open stays busy
+        let total: f64 = (0..2).filter(|&j| j == i || buckets.classes[j].open > 0).map(|j| self.weights[j]).sum();
+        let rate = self.bandwidth * self.weights[i] / total;
+        Err(Duration::from_secs_f64((need - buckets.classes[i].tokens) / rate))
+    }
+
+    // what a read granted but didn't use
+    fn refund(&self, priority: Priority, unused: usize) {
+        if unused > 0 {
+            let mut buckets = self.buckets.lock().unwrap();
+            let class = &mut buckets.classes[index(priority)];
+            class.tokens = (class.tokens + unused as f64).min(self.burst);
+        }
+    }
+}
+
+pub struct ClassGuard {
+    scheduler: Scheduler,
+    priority: Priority,
+}
+
+impl Drop for ClassGuard {
+    fn drop(&mut self) {
+        let mut buckets = self.scheduler.buckets.lock().unwrap();
+        self.scheduler.refill(&mut buckets, Instant::now());
+        let class = &mut buckets.classes[index(self.priority)];
+        class.open -= 1;
+        if class.open == 0 {
+            class.tokens = 0.0;
+        }
+    }
+}
+
+// a reader that only reads what its class's share allows
+pub struct Paced<S> {
+    inner: S,
+    scheduler: Scheduler,
+    priority: Priority,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Paced<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.scheduler.is_enabled() || buf.remaining() == 0 {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        }
+        let grant = loop {
+            if let Some(sleep) = &mut this.sleep {
+                ready!(sleep.as_mut().poll(cx));
+                this.sleep = None;
+            }
+            match this.scheduler.acquire(this.priority, buf.remaining()) {
+                Ok(grant) => break grant,
+                Err(wait) => this.sleep = Some(Box::pin(tokio::time::sleep(wait))),
+            }
+        };
+        let mut limited = ReadBuf::new(buf.initialize_unfilled_to(grant));
+        let result = Pin::new(&mut this.inner).poll_read(cx, &mut limited);
+        let n = match &result {
+            Poll::Ready(Ok(())) => limited.filled().len(),
+            _ => 0,
+        };
+        this.scheduler.refund(this.priority, grant - n);
+        buf.advance(n);
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Paced<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::time::Instant;
+
+    use crate::config::{Priority, PriorityConfig, PriorityRule};
+    use crate::priority::Scheduler;
+    use crate::socket5::Address;
+
+    #[test]
+    fn rule_test() {
+        let config = PriorityConfig {
+            rules: vec![
+                PriorityRule { pattern: None, ports: vec![22], asn: vec![], priority: Priority::Interactive },
+                PriorityRule { pattern: Some("ssh.example.com".parse().unwrap()), ports: vec![], asn: vec![], priority: Priority::Interactive },
+                PriorityRule { pattern: None, ports: vec![], asn: vec![64500], priority: Priority::Interactive },
+            ],
+            ..PriorityConfig::default()
+        };
+        assert_eq!(config.priority(&Address::Address("10.0.0.1:22".parse().unwrap()), None), Priority::Interactive);
+        assert_eq!(config.priority(&Address::DomainName("a.ssh.example.com".to_string(), 443), None), Priority::Interactive);
+        assert_eq!(config.priority(&Address::DomainName("example.com".to_string(), 443), None), Priority::Bulk);
+        assert_eq!(config.priority(&Address::DomainName("example.com".to_string(), 443), Some(64500)), Priority::Interactive);
+        assert_eq!(config.priority(&Address::DomainName("example.com".to_string(), 443), Some(64501)), Priority::Bulk);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn share_test() {
+        let scheduler = Scheduler::new(&PriorityConfig { bandwidth: 100_000, ..PriorityConfig::default() });
+        let (_bulk, _interactive) = (scheduler.open(Priority::Bulk), scheduler.open(Priority::Interactive));
+        let start = Instant::now();
+        tokio::time::advance(Duration::from_millis(50)).await;
+        // 5000 bytes came in, split 4 to 1
+        assert_eq!(scheduler.acquire(Priority::Interactive, 100_000), Ok(4000));
+        assert_eq!(scheduler.acquire(Priority::Bulk, 1000), Ok(1000));
+        assert!(scheduler.acquire(Priority::Bulk, 100_000).is_err());
+        tokio::time::advance(Duration::from_millis(50)).await;
+
+        // a quiet class's share goes to the busy one
+        drop(_interactive);
+        tokio::time::advance(Duration::from_millis(50)).await;
+        assert_eq!(scheduler.acquire(Priority::Bulk, 100_000), Ok(6000));
+        assert!(start.elapsed() >= Duration::from_millis(150));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn paced_test() {
+        let scheduler = Scheduler::new(&PriorityConfig { bandwidth: 10_000, ..PriorityConfig::default() });
+        let _bulk = scheduler.open(Priority::Bulk);
+        let (mut writer, reader) = tokio::io::duplex(64 * 1024);
+        writer.write_all(&[0; 20_000]).await.unwrap();
+        drop(writer);
+        let mut reader = scheduler.pace(reader, Priority::Bulk);
+        let start = Instant::now();
+        let mut read = Vec::new();
+        reader.read_to_end(&mut read).await.unwrap();
+        assert_eq!(read.len(), 20_000);
+        // about two seconds at 10KB/s, the first burst aside
+        assert!(start.elapsed() >= Duration::from_millis(1800), "{:?}", start.elapsed());
+    }
+}
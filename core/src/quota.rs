@@ -0,0 +1,218 @@
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::warn;
+
+use crate::config::{QuotaConfig, QuotaPeriod};
+use crate::store::{FileStore, MemoryStore, Store};
+
+// key used for the global counter in the state file, user names can't contain spaces
+const GLOBAL: &str = "*";
+
+#[derive(Debug, Default, PartialEq)]
+struct State {
+    period: String,
+    global: u64,
+    users: HashMap<String, u64>,
+}
+
+// byte usage for the current day/month, shared by all connections
+#[derive(Clone)]
+pub struct Quota {
+    config: Arc<QuotaConfig>,
+    state: Arc<Mutex<State>>,
+    store: Arc<dyn Store>,
+}
+
+impl Quota {
+    // usage survives restarts in the file at path, and lasts as long as the process without one
+    pub fn new(config: QuotaConfig) -> io::Result<Self> {
+        let store: Arc<dyn Store> = match &config.path {
+            Some(path) => Arc::new(FileStore::new(path)),
+            None => Arc::new(MemoryStore::default()),
+        };
+        Quota::with_store(config, store)
+    }
+
+    // for embedders keeping usage somewhere of their own, config.path is ignored
+    pub fn with_store(config: QuotaConfig, store: Arc<dyn Store>) -> io::Result<Self> {
+        let period = period_key(config.period, now());
+        let mut state = match store.load()? {
+            Some(content) => parse(&content)?,
+            None => State::default(),
+        };
+        if state.period != period {
+            state = State { period, ..State::default() };
+        }
+        Ok(Quota {
+            config: Arc::new(config),
+            state: Arc::new(Mutex::new(state)),
+            store,
+        })
+    }
+
+    // false once the global or the user's quota is used up
+    pub fn check(&self, user: Option<&str>) -> bool {
+        let mut state = self.state.lock().unwrap();
+        self.roll(&mut state);
+        if let Some(limit) = self.config.global {
+            if state.global >= limit {
+                return false;
+            }
+        }
+        if let Some(user) = user {
+            if let Some(limit) = self.config.users.get(user) {
+                if state.users.get(user).copied().unwrap_or(0) >= *limit {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    pub fn record(&self, user: Option<&str>, bytes: u64) {
+        let mut state = self.state.lock().unwrap();
+        self.roll(&mut state);
+        state.global += bytes;
+        if let Some(user) = user {
+            *state.users.entry(user.to_string()).or_insert(0) += bytes;
+        }
+        if let Err(e) = self.store.save(&format(&state)) {
+            warn!("save quota state fail : {}", e);
+        }
+    }
+
+    pub fn used(&self, user: Option<&str>) -> u64 {
+        let state = self.state.lock().unwrap();
+        match user {
+            None => state.global,
+            Some(user) => state.users.get(user).copied().unwrap_or(0),
+        }
+    }
+
+    fn roll(&self, state: &mut State) {
+        let period = period_key(self.config.period, now());
+        if state.period != period {
+            *state = State { period, ..State::default() };
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+// "2022-01-31" for daily quotas, "2022-01" for monthly ones (UTC)
+fn period_key(period: QuotaPeriod, secs: u64) -> String {
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    match period {
+        QuotaPeriod::Day => format!("{:04}-{:02}-{:02}", year, month, day),
+        QuotaPeriod::Month => format!("{:04}-{:02}", year, month),
+    }
+}
+
+// http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+// first line is the period, then one "<name> <bytes>" per line
+fn parse(content: &str) -> io::Result<State> {
+    let invalid = |line: &str| io::Error::new(io::ErrorKind::InvalidData, format!("invalid quota line : {}", line));
+    let mut lines = content.lines();
+    let mut state = State {
+        period: lines.next().unwrap_or("").trim().to_string(),
+        ..State::default()
+    };
+    for line in lines.filter(|l| !l.trim().is_empty()) {
+        let (name, bytes) = line.rsplit_once(' ').ok_or_else(|| invalid(line))?;
+        let bytes = bytes.parse::<u64>().map_err(|_| invalid(line))?;
+        if name == GLOBAL {
+            state.global = bytes;
+        } else {
+            state.users.insert(name.to_string(), bytes);
+        }
+    }
+    Ok(state)
+}
+
+fn format(state: &State) -> String {
+    let mut content = format!("{}\n{} {}\n", state.period, GLOBAL, state.global);
+    for (user, bytes) in &state.users {
+        content.push_str(&format!("{} {}\n", user, bytes));
+    }
+    content
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::config::{QuotaConfig, QuotaPeriod};
+    use crate::quota::{parse, period_key, Quota};
+    use crate::store::{MemoryStore, Store};
+
+    #[test]
+    fn period_key_test() {
+        assert_eq!(period_key(QuotaPeriod::Day, 0), "1970-01-01");
+        assert_eq!(period_key(QuotaPeriod::Day, 1643587200), "2022-01-31");
+        assert_eq!(period_key(QuotaPeriod::Month, 1646006400), "2022-02");
+        assert_eq!(period_key(QuotaPeriod::Day, 951782400), "2000-02-29");
+    }
+
+    #[test]
+    fn quota_exhausted_test() {
+        let mut users = HashMap::new();
+        users.insert("alice".to_string(), 10);
+        let quota = Quota::new(QuotaConfig {
+            period: QuotaPeriod::Day,
+            global: Some(100),
+            users,
+            path: None,
+        }).unwrap();
+        assert!(quota.check(Some("alice")));
+        quota.record(Some("alice"), 10);
+        assert!(!quota.check(Some("alice")));
+        assert!(quota.check(Some("bob")));
+        quota.record(None, 90);
+        assert!(!quota.check(None));
+        assert_eq!(quota.used(None), 100);
+    }
+
+    #[test]
+    fn persist_test() {
+        let path = std::env::temp_dir().join(format!("rust-ss5-quota-{}", std::process::id()));
+        let config = QuotaConfig {
+            period: QuotaPeriod::Month,
+            global: None,
+            users: HashMap::new(),
+            path: Some(path.clone()),
+        };
+        Quota::new(config.clone()).unwrap().record(Some("alice"), 42);
+        let state = parse(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(state.global, 42);
+        assert_eq!(state.users["alice"], 42);
+        assert_eq!(Quota::new(config).unwrap().used(Some("alice")), 42);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn with_store_test() {
+        let config = QuotaConfig { period: QuotaPeriod::Day, global: None, users: HashMap::new(), path: None };
+        let store = std::sync::Arc::new(MemoryStore::default());
+        Quota::with_store(config.clone(), store.clone()).unwrap().record(None, 7);
+        assert!(store.load().unwrap().unwrap().contains("* 7\n"));
+        assert_eq!(Quota::with_store(config, store).unwrap().used(None), 7);
+    }
+}
@@ -0,0 +1,491 @@
+use std::fmt::{Display, Formatter};
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, OnceLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll, ready};
+use std::time::Duration;
+
+use log::info;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{Instant, Sleep};
+
+use crate::buffers::{Buffer, BufferPool};
+use crate::config::{TransferAction, TransferRule};
+use crate::error::Error;
+use crate::server::ServerState;
+use crate::socket5::Address;
+
+// how often a running relay is charged to the quota and checked against it
+const QUOTA_CHECK: Duration = Duration::from_secs(1);
+
+// why a relay ended, client eof and target eof being the normal ones
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Close {
+    ClientEof,
+    TargetEof,
+    // nothing moved either way for relay.idle_timeout
+    IdleTimeout,
+    // reading the client or writing the target failed
+    ErrorUp,
+    ErrorDown,
+    // a failure not tied to a direction, e.g. of a udp association
+    Error,
+    // cut off by the server: the quota or the transfer rule's max_bytes ran out, or a sniffed
+    // host is blocked
+    Policy,
+}
+
+impl Close {
+    pub const ALL: [Close; 7] = [
+        Close::ClientEof, Close::TargetEof, Close::IdleTimeout, Close::ErrorUp, Close::ErrorDown, Close::Error, Close::Policy,
+    ];
+
+    fn index(self) -> usize {
+        Close::ALL.iter().position(|close| *close == self).unwrap_or_default()
+    }
+}
+
+impl Display for Close {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Close::ClientEof => write!(f, "client_eof"),
+            Close::TargetEof => write!(f, "target_eof"),
+            Close::IdleTimeout => write!(f, "idle_timeout"),
+            Close::ErrorUp => write!(f, "error_up"),
+            Close::ErrorDown => write!(f, "error_down"),
+            Close::Error => write!(f, "error"),
+            Close::Policy => write!(f, "policy"),
+        }
+    }
+}
+
+// a count per close reason
+#[derive(Debug, Default)]
+pub struct Closes {
+    counts: [AtomicU64; Close::ALL.len()],
+}
+
+impl Closes {
+    pub fn closed(&self, close: Close) {
+        self.counts[close.index()].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> Vec<(Close, u64)> {
+        Close::ALL.iter().map(|close| (*close, self.counts[close.index()].load(Ordering::Relaxed))).collect()
+    }
+}
+
+// bytes through a relay so far, readable while it runs and after it failed, and why it ended
+#[derive(Debug, Clone, Default)]
+pub struct Traffic {
+    up: Arc<AtomicU64>,
+    down: Arc<AtomicU64>,
+    close: Arc<OnceLock<Close>>,
+    // bytes per second each way once throttled, 0 while not
+    throttle: Arc<AtomicU64>,
+}
+
+impl Traffic {
+    // from the client to the target
+    pub fn up(&self) -> u64 {
+        self.up.load(Ordering::Relaxed)
+    }
+
+    pub fn down(&self) -> u64 {
+        self.down.load(Ordering::Relaxed)
+    }
+
+    pub fn add_up(&self, n: u64) {
+        self.up.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn add_down(&self, n: u64) {
+        self.down.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn close(&self) -> Option<Close> {
+        self.close.get().copied()
+    }
+
+    // the first reason given sticks, the copy that saw the eof knows better than the relay around it
+    pub fn set_close(&self, close: Close) {
+        let _ = self.close.set(close);
+    }
+
+    pub fn throttle(&self, rate: u64) {
+        self.throttle.store(rate, Ordering::Relaxed);
+    }
+
+    fn rate(&self) -> u64 {
+        self.throttle.load(Ordering::Relaxed)
+    }
+}
+
+// a second's worth of bytes for one direction of a throttled relay
+#[derive(Default)]
+struct Window {
+    start: Option<Instant>,
+    used: u64,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl Window {
+    // how many of want may go now; reads can't be cut short, so one read may take the window past
+    // rate, by at most a relay buffer, and the next waits it out
+    fn poll_grant(&mut self, cx: &mut Context<'_>, rate: u64, want: usize) -> Poll<usize> {
+        loop {
+            let now = Instant::now();
+            let start = *self.start.get_or_insert(now);
+            if now >= start + Duration::from_secs(1) {
+                (self.start, self.used) = (Some(now), 0);
+            }
+            if self.used < rate {
+                return Poll::Ready(want.min((rate - self.used) as usize));
+            }
+            let sleep = self.sleep.get_or_insert_with(|| Box::pin(tokio::time::sleep_until(start)));
+            sleep.as_mut().reset(start + Duration::from_secs(1));
+            ready!(sleep.as_mut().poll(cx));
+        }
+    }
+}
+
+// the client side of a relay, reads count as up and writes as down, and both are held to the
+// traffic's rate once it's throttled
+pub struct Counted<S> {
+    inner: S,
+    traffic: Traffic,
+    reads: Window,
+    writes: Window,
+}
+
+impl<S> Counted<S> {
+    pub fn new(inner: S, traffic: Traffic) -> Self {
+        Counted { inner, traffic, reads: Window::default(), writes: Window::default() }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Counted<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let rate = this.traffic.rate();
+        if rate > 0 {
+            ready!(this.reads.poll_grant(cx, rate, buf.remaining()));
+        }
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        let n = (buf.filled().len() - before) as u64;
+        if rate > 0 {
+            this.reads.used += n;
+        }
+        this.traffic.add_up(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Counted<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let rate = this.traffic.rate();
+        let grant = match rate {
+            0 => buf.len(),
+            rate => ready!(this.writes.poll_grant(cx, rate, buf.len())),
+        };
+        let n = ready!(Pin::new(&mut this.inner).poll_write(cx, &buf[..grant]))?;
+        if rate > 0 {
+            this.writes.used += n as u64;
+        }
+        this.traffic.add_down(n as u64);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+// run the copy, charging its traffic as it goes and cutting it off once the quota is used up, or
+// the transfer rule's max_bytes is; whatever was copied and why it ended are recorded however the
+// copy ends
+pub async fn relay<F>(copy: F, traffic: &Traffic, state: &ServerState, user: Option<&str>, destination: Option<&Address>, transfer: Option<&TransferRule>) -> Result<(), Error>
+    where F: Future<Output = Result<(), Error>>
+{
+    let mut recorded = (0, 0);
+    let mut check = tokio::time::interval_at(Instant::now() + QUOTA_CHECK, QUOTA_CHECK);
+    tokio::pin!(copy);
+    let result = loop {
+        tokio::select! {
+            result = &mut copy => break result,
+            _ = check.tick() => {
+                charge(traffic, &mut recorded, state, user, destination);
+                if !state.quota.check(user) {
+                    break Err(Error::QuotaExceeded);
+                }
+                match transfer {
+                    Some(rule) if traffic.up() + traffic.down() > rule.max_bytes => match rule.action {
+                        TransferAction::Close => break Err(Error::TransferExceeded(rule.max_bytes)),
+                        TransferAction::Throttle if traffic.rate() == 0 => {
+                            info!("throttle {} to {} bytes/s after {} bytes", destination.map_or("-".to_string(), |d| d.to_string()), rule.rate, rule.max_bytes);
+                            traffic.throttle(rule.rate);
+                        }
+                        TransferAction::Throttle => {}
+                    },
+                    _ => {}
+                }
+            }
+        }
+    };
+    charge(traffic, &mut recorded, state, user, destination);
+    // a copy that didn't say ended with the client, the builtin targets and udp associations do
+    traffic.set_close(match &result {
+        Ok(()) => Close::ClientEof,
+        Err(Error::QuotaExceeded | Error::TransferExceeded(_) | Error::Blocked(_) | Error::Forbidden(_)) => Close::Policy,
+        Err(_) => Close::Error,
+    });
+    state.stats.closed(traffic.close().unwrap_or(Close::Error));
+    result
+}
+
+fn charge(traffic: &Traffic, recorded: &mut (u64, u64), state: &ServerState, user: Option<&str>, destination: Option<&Address>) {
+    let (up, down) = (traffic.up() - recorded.0, traffic.down() - recorded.1);
+    if up + down == 0 {
+        return;
+    }
+    *recorded = (traffic.up(), traffic.down());
+    state.quota.record(user, up + down);
+    state.stats.record(user, destination, up, down);
+}
+
+// tokio's copy_bidirectional_with_sizes with its two buffers from the pool: each direction
+// shuts its writer down at the reader's eof, it's done when both are, (a to b, b to a) bytes
+pub async fn copy_bidirectional<A, B>(a: &mut A, b: &mut B, a_to_b: usize, b_to_a: usize) -> io::Result<(u64, u64)>
+    where A: AsyncRead + AsyncWrite + Unpin + ?Sized, B: AsyncRead + AsyncWrite + Unpin + ?Sized
+{
+    copy_closing(a, b, a_to_b, b_to_a, None).await.1
+}
+
+// copy_bidirectional that also says why it ended, with a the client and b the target: the side
+// whose eof came first, the direction that failed, or nothing moving for `idle`, which ends it
+// without an error
+pub async fn copy_closing<A, B>(a: &mut A, b: &mut B, a_to_b: usize, b_to_a: usize, idle: Option<Duration>) -> (Close, io::Result<(u64, u64)>)
+    where A: AsyncRead + AsyncWrite + Unpin + ?Sized, B: AsyncRead + AsyncWrite + Unpin + ?Sized
+{
+    let pool = BufferPool::global();
+    let mut a_to_b = Direction::Copying(CopyBuffer::new(pool.get(a_to_b.max(1))));
+    let mut b_to_a = Direction::Copying(CopyBuffer::new(pool.get(b_to_a.max(1))));
+    let mut eof = None;
+    let mut idle = idle.map(|idle| (idle, Box::pin(tokio::time::sleep(idle)), 0));
+    std::future::poll_fn(|cx| {
+        let up = match a_to_b.poll(cx, &mut *a, &mut *b) {
+            Poll::Ready(Err(e)) => return Poll::Ready((Close::ErrorUp, Err(e))),
+            up => up,
+        };
+        let down = match b_to_a.poll(cx, &mut *b, &mut *a) {
+            Poll::Ready(Err(e)) => return Poll::Ready((Close::ErrorDown, Err(e))),
+            down => down,
+        };
+        if eof.is_none() {
+            eof = (!a_to_b.is_copying()).then_some(Close::ClientEof).or((!b_to_a.is_copying()).then_some(Close::TargetEof));
+        }
+        if let (Poll::Ready(Ok(up)), Poll::Ready(Ok(down))) = (&up, &down) {
+            return Poll::Ready((eof.unwrap_or(Close::ClientEof), Ok((*up, *down))));
+        }
+        if let Some((timeout, sleep, moved)) = &mut idle {
+            let copied = a_to_b.copied() + b_to_a.copied();
+            if copied != *moved {
+                *moved = copied;
+                sleep.as_mut().reset(tokio::time::Instant::now() + *timeout);
+            }
+            if sleep.as_mut().poll(cx).is_ready() {
+                return Poll::Ready((Close::IdleTimeout, Ok((a_to_b.copied(), b_to_a.copied()))));
+            }
+        }
+        Poll::Pending
+    }).await
+}
+
+enum Direction {
+    Copying(CopyBuffer),
+    ShuttingDown(u64),
+    Done(u64),
+}
+
+impl Direction {
+    fn is_copying(&self) -> bool {
+        matches!(self, Direction::Copying(_))
+    }
+
+    fn copied(&self) -> u64 {
+        match self {
+            Direction::Copying(copy) => copy.copied,
+            Direction::ShuttingDown(n) | Direction::Done(n) => *n,
+        }
+    }
+
+    fn poll<R, W>(&mut self, cx: &mut Context<'_>, reader: &mut R, writer: &mut W) -> Poll<io::Result<u64>>
+        where R: AsyncRead + Unpin + ?Sized, W: AsyncWrite + Unpin + ?Sized
+    {
+        loop {
+            match self {
+                Direction::Copying(copy) => *self = Direction::ShuttingDown(ready!(copy.poll(cx, reader, writer))?),
+                Direction::ShuttingDown(n) => {
+                    ready!(Pin::new(&mut *writer).poll_shutdown(cx))?;
+                    *self = Direction::Done(*n);
+                }
+                Direction::Done(n) => return Poll::Ready(Ok(*n)),
+            }
+        }
+    }
+}
+
+struct CopyBuffer {
+    buf: Buffer<'static>,
+    pos: usize,
+    cap: usize,
+    eof: bool,
+    unflushed: bool,
+    copied: u64,
+}
+
+impl CopyBuffer {
+    fn new(buf: Buffer<'static>) -> Self {
+        CopyBuffer { buf, pos: 0, cap: 0, eof: false, unflushed: false, copied: 0 }
+    }
+
+    // until the reader's eof is written and flushed; a reader with nothing to give flushes
+    // what was written so far, so data doesn't sit in a writer's buffer meanwhile
+    fn poll<R, W>(&mut self, cx: &mut Context<'_>, reader: &mut R, writer: &mut W) -> Poll<io::Result<u64>>
+        where R: AsyncRead + Unpin + ?Sized, W: AsyncWrite + Unpin + ?Sized
+    {
+        loop {
+            if self.pos == self.cap && !self.eof {
+                let mut read = ReadBuf::new(&mut self.buf);
+                match Pin::new(&mut *reader).poll_read(cx, &mut read) {
+                    Poll::Ready(Ok(())) => {
+                        let n = read.filled().len();
+                        (self.pos, self.cap, self.eof) = (0, n, n == 0);
+                    }
+                    Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                    Poll::Pending => {
+                        if self.unflushed {
+                            ready!(Pin::new(&mut *writer).poll_flush(cx))?;
+                            self.unflushed = false;
+                        }
+                        return Poll::Pending;
+                    }
+                }
+            }
+            while self.pos < self.cap {
+                let n = ready!(Pin::new(&mut *writer).poll_write(cx, &self.buf[self.pos..self.cap]))?;
+                if n == 0 {
+                    return Poll::Ready(Err(io::Error::new(io::ErrorKind::WriteZero, "write zero bytes into writer")));
+                }
+                self.pos += n;
+                self.copied += n as u64;
+                self.unflushed = true;
+            }
+            if self.eof {
+                ready!(Pin::new(&mut *writer).poll_flush(cx))?;
+                return Poll::Ready(Ok(self.copied));
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, duplex};
+
+    use std::time::Duration;
+
+    use crate::relay::{Close, copy_bidirectional, copy_closing, Counted, Traffic};
+
+    #[tokio::test]
+    async fn copy_test() {
+        let (mut client, mut a) = duplex(64);
+        let (mut b, mut target) = duplex(64);
+        let copy = tokio::spawn(async move { copy_bidirectional(&mut a, &mut b, 7, 5).await });
+        // more than either buffer holds
+        let data: Vec<u8> = (0..1000u32).map(|i| i as u8).collect();
+        let sent = data.clone();
+        let writer = tokio::spawn(async move {
+            client.write_all(&sent).await.unwrap();
+            client.shutdown().await.unwrap();
+            let mut back = Vec::new();
+            client.read_to_end(&mut back).await.unwrap();
+            back
+        });
+        let mut received = Vec::new();
+        target.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, data);
+        target.write_all(b"done").await.unwrap();
+        drop(target);
+        assert_eq!(writer.await.unwrap(), b"done");
+        assert_eq!(copy.await.unwrap().unwrap(), (1000, 4));
+    }
+
+    #[tokio::test]
+    async fn close_test() {
+        // the client's eof comes first, then the target's
+        let (mut client, mut a) = duplex(64);
+        let (mut b, mut target) = duplex(64);
+        let copy = tokio::spawn(async move { copy_closing(&mut a, &mut b, 8, 8, None).await });
+        client.shutdown().await.unwrap();
+        let mut buf = Vec::new();
+        target.read_to_end(&mut buf).await.unwrap();
+        drop(target);
+        assert_eq!(copy.await.unwrap().0, Close::ClientEof);
+
+        let (client, mut a) = duplex(64);
+        let (mut b, target) = duplex(64);
+        let copy = tokio::spawn(async move { copy_closing(&mut a, &mut b, 8, 8, None).await });
+        drop(target);
+        // writing to the target that's gone fails once the client sends anything
+        let (mut read, mut write) = tokio::io::split(client);
+        read.read_to_end(&mut buf).await.unwrap();
+        let _ = write.write_all(b"late").await;
+        let (close, result) = copy.await.unwrap();
+        assert_eq!(close, Close::ErrorUp);
+        assert!(result.is_err());
+
+        tokio::time::pause();
+        let (mut client, mut a) = duplex(64);
+        let (mut b, mut target) = duplex(64);
+        let copy = tokio::spawn(async move { copy_closing(&mut a, &mut b, 8, 8, Some(Duration::from_secs(30))).await });
+        client.write_all(b"hi").await.unwrap();
+        let mut hi = [0; 2];
+        target.read_exact(&mut hi).await.unwrap();
+        tokio::time::sleep(Duration::from_secs(20)).await;
+        // traffic puts the timeout off
+        target.write_all(b"ok").await.unwrap();
+        client.read_exact(&mut hi).await.unwrap();
+        tokio::time::sleep(Duration::from_secs(20)).await;
+        assert!(!copy.is_finished());
+        let (close, result) = copy.await.unwrap();
+        assert_eq!(close, Close::IdleTimeout);
+        assert_eq!(result.unwrap(), (2, 2));
+    }
+
+    #[tokio::test]
+    async fn throttle_test() {
+        tokio::time::pause();
+        let (inner, mut peer) = duplex(4096);
+        let traffic = Traffic::default();
+        let mut counted = Counted::new(inner, traffic.clone());
+        counted.write_all(&[0; 300]).await.unwrap();
+        traffic.throttle(100);
+        let start = tokio::time::Instant::now();
+        counted.write_all(&[0; 250]).await.unwrap();
+        // 100 a second, the last 50 go in the third
+        assert_eq!(start.elapsed().as_secs(), 2);
+        assert_eq!(traffic.down(), 550);
+        let mut buf = vec![0; 550];
+        peer.read_exact(&mut buf).await.unwrap();
+    }
+}
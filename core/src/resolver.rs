@@ -0,0 +1,331 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use log::debug;
+use tokio::sync::OnceCell;
+
+use crate::config::ResolverConfig;
+use crate::crypto::generate_key;
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const TYPE_OPT: u16 = 41;
+const CLASS_IN: u16 = 1;
+const OPTION_ECS: u16 = 8;
+// the edns payload size advertised, the dns flag day 2020 value
+const EDNS_PAYLOAD: u16 = 1232;
+// answers are cached for at least this long, so a ttl of 0 doesn't send a query per connection
+const MIN_TTL: u64 = 5;
+const MAX_TTL: u64 = 86400;
+
+struct Cached {
+    expires: Instant,
+    ips: Vec<IpAddr>,
+}
+
+// one lookup under way, every connection asking for the name meanwhile waits on its answer;
+// errors are kept as kind and text so each waiter gets its own
+type Flight = Arc<OnceCell<Result<Vec<IpAddr>, (io::ErrorKind, String)>>>;
+
+// the system resolver, or DNS over HTTPS to a configured upstream so the local network's
+// resolver can't hand out other answers; clones share the cache and the lookups under way
+#[derive(Clone)]
+pub struct Resolver {
+    config: Arc<ResolverConfig>,
+    agent: Option<ureq::Agent>,
+    cache: Arc<Mutex<HashMap<String, Cached>>>,
+    flights: Arc<Mutex<HashMap<String, Flight>>>,
+    counters: Arc<Counters>,
+}
+
+#[derive(Default)]
+struct Counters {
+    // lookups sent to the system resolver or the doh server, each for both families
+    queries: AtomicU64,
+    hits: AtomicU64,
+}
+
+impl Resolver {
+    pub fn new(config: ResolverConfig) -> Self {
+        let agent = config.doh.as_ref().map(|_| {
+            ureq::Agent::config_builder()
+                .timeout_global(Some(Duration::from_secs(config.timeout.max(1))))
+                .build()
+                .into()
+        });
+        Resolver { config: Arc::new(config), agent, cache: Arc::default(), flights: Arc::default(), counters: Arc::default() }
+    }
+
+    // names are resolved here, cached and shared, rather than left to connect
+    pub fn is_enabled(&self) -> bool {
+        self.agent.is_some() || self.config.system_ttl > 0
+    }
+
+    pub fn queries(&self) -> u64 {
+        self.counters.queries.load(Ordering::Relaxed)
+    }
+
+    pub fn cache_hits(&self) -> u64 {
+        self.counters.hits.load(Ordering::Relaxed)
+    }
+
+    // ipv4 addresses first for doh, as the system resolver usually orders them
+    pub async fn lookup(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        if !self.is_enabled() {
+            return Ok(tokio::net::lookup_host((host, port)).await?.collect());
+        }
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok(vec![SocketAddr::new(ip, port)]);
+        }
+        let name = host.trim_end_matches('.').to_ascii_lowercase();
+        let ips = match self.cached(&name) {
+            Some(ips) => {
+                self.counters.hits.fetch_add(1, Ordering::Relaxed);
+                ips
+            }
+            None => self.resolve_once(&name).await?,
+        };
+        if ips.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("{} has no address", name)));
+        }
+        Ok(ips.into_iter().map(|ip| SocketAddr::new(ip, port)).collect())
+    }
+
+    fn cached(&self, name: &str) -> Option<Vec<IpAddr>> {
+        self.cache.lock().unwrap().get(name)
+            .filter(|cached| cached.expires > Instant::now())
+            .map(|cached| cached.ips.clone())
+    }
+
+    // a burst of connections to one name sends one query; a waiter takes the query over when
+    // the connection that started it goes away first
+    async fn resolve_once(&self, name: &str) -> io::Result<Vec<IpAddr>> {
+        let flight = self.flights.lock().unwrap().entry(name.to_string()).or_default().clone();
+        let result = flight.get_or_init(|| async {
+            // a flight that ended just before this one began has filled the cache
+            if let Some(ips) = self.cached(name) {
+                return Ok(ips);
+            }
+            let (ips, ttl) = self.resolve(name).await.map_err(|e| (e.kind(), e.to_string()))?;
+            let mut cache = self.cache.lock().unwrap();
+            if cache.len() >= self.config.cache {
+                cache.clear();
+            }
+            if self.config.cache > 0 {
+                cache.insert(name.to_string(), Cached { expires: Instant::now() + Duration::from_secs(ttl), ips: ips.clone() });
+            }
+            Ok(ips)
+        }).await.clone();
+        // whoever comes next finds the cache, or after a failure starts a flight of its own
+        let mut flights = self.flights.lock().unwrap();
+        if flights.get(name).is_some_and(|current| Arc::ptr_eq(current, &flight)) {
+            flights.remove(name);
+        }
+        result.map_err(|(kind, e)| io::Error::new(kind, e))
+    }
+
+    // the addresses and how long they may be cached
+    async fn resolve(&self, name: &str) -> io::Result<(Vec<IpAddr>, u64)> {
+        self.counters.queries.fetch_add(1, Ordering::Relaxed);
+        let Some(agent) = &self.agent else {
+            let ips = tokio::net::lookup_host((name, 0)).await?.map(|addr| addr.ip()).collect();
+            return Ok((ips, self.config.system_ttl));
+        };
+        let (v4, v6) = tokio::join!(self.query(agent, name, TYPE_A), self.query(agent, name, TYPE_AAAA));
+        let ((mut ips, ttl4), (v6, ttl6)) = (v4?, v6?);
+        ips.extend(v6);
+        Ok((ips, ttl4.min(ttl6).clamp(MIN_TTL, MAX_TTL)))
+    }
+
+    async fn query(&self, agent: &ureq::Agent, name: &str, qtype: u16) -> io::Result<(Vec<IpAddr>, u64)> {
+        let name = match self.config.randomize_case {
+            true => randomize_case(name)?,
+            false => name.to_string(),
+        };
+        let query = query(&name, qtype, self.config.strip_ecs)?;
+        let (agent, url, sent) = (agent.clone(), self.config.doh.clone().unwrap_or_default(), query.clone());
+        let answer = tokio::task::spawn_blocking(move || {
+            agent.post(&url)
+                .header("Content-Type", "application/dns-message")
+                .header("Accept", "application/dns-message")
+                .send(&sent[..])
+                .map_err(|e| io::Error::other(format!("doh {} : {}", url, e)))?
+                .into_body()
+                .read_to_vec()
+                .map_err(|e| io::Error::other(format!("doh {} : {}", url, e)))
+        }).await.map_err(io::Error::other)??;
+        let answers = answers(&answer, &query, qtype)?;
+        debug!("doh {} type {} : {:?}", name, qtype, answers.0);
+        Ok(answers)
+    }
+}
+
+// each letter upper or lower case at random, "ExAmPLe.cOm"
+fn randomize_case(name: &str) -> io::Result<String> {
+    let random = generate_key(name.len())?;
+    Ok(name.chars().zip(random).map(|(c, bit)| if bit & 1 == 1 { c.to_ascii_uppercase() } else { c.to_ascii_lowercase() }).collect())
+}
+
+// one question with recursion desired; id 0 as RFC 8484 suggests, https already ties the answer to it.
+// strip_ecs adds an edns client subnet option with a source prefix of 0, which tells the
+// upstream to leave the subnet out of its own queries
+pub fn query(name: &str, qtype: u16, strip_ecs: bool) -> io::Result<Vec<u8>> {
+    let mut message = vec![0, 0, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, strip_ecs as u8];
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        if label.len() > 63 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("label too long in {}", name)));
+        }
+        message.push(label.len() as u8);
+        message.extend_from_slice(label.as_bytes());
+    }
+    message.push(0);
+    message.extend_from_slice(&qtype.to_be_bytes());
+    message.extend_from_slice(&CLASS_IN.to_be_bytes());
+    if strip_ecs {
+        // root name, OPT, payload size as the class, a ttl of 0 for rcode, version and flags
+        message.push(0);
+        message.extend_from_slice(&TYPE_OPT.to_be_bytes());
+        message.extend_from_slice(&EDNS_PAYLOAD.to_be_bytes());
+        message.extend_from_slice(&[0, 0, 0, 0, 0, 8]);
+        // family 1, source and scope prefix 0, no address bytes
+        message.extend_from_slice(&OPTION_ECS.to_be_bytes());
+        message.extend_from_slice(&[0, 4, 0, 1, 0, 0]);
+    }
+    Ok(message)
+}
+
+// the addresses of type qtype in a response to query and the lowest of their ttls, none for a
+// name that doesn't exist; the question has to come back as it was sent, case included
+pub fn answers(message: &[u8], query: &[u8], qtype: u16) -> io::Result<(Vec<IpAddr>, u64)> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "malformed dns response");
+    let u16_at = |at: usize| message.get(at..at + 2).map(|b| u16::from_be_bytes([b[0], b[1]])).ok_or_else(invalid);
+    let flags = u16_at(2)?;
+    if flags & 0x8000 == 0 {
+        return Err(invalid());
+    }
+    match flags & 0x000f {
+        0 => {}
+        3 => return Ok((Vec::new(), MIN_TTL)),
+        rcode => return Err(io::Error::other(format!("dns server answered rcode {}", rcode))),
+    }
+    let question = skip_name(query, 12).ok_or_else(invalid)? + 4;
+    if u16_at(4)? != 1 || message.get(12..question) != query.get(12..question) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "dns response is for another question"));
+    }
+    let (records, mut at) = (u16_at(6)?, question);
+    let (mut ips, mut ttl) = (Vec::new(), MAX_TTL);
+    for _ in 0..records {
+        at = skip_name(message, at).ok_or_else(invalid)?;
+        let (kind, len) = (u16_at(at)?, u16_at(at + 8)? as usize);
+        let record_ttl = message.get(at + 4..at + 8).map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]])).ok_or_else(invalid)?;
+        let data = message.get(at + 10..at + 10 + len).ok_or_else(invalid)?;
+        at += 10 + len;
+        // cnames on the way are followed by the server, only the final records count
+        let ip = match (kind, data.len()) {
+            (TYPE_A, 4) if qtype == TYPE_A => IpAddr::V4(Ipv4Addr::new(data[0], data[1], data[2], data[3])),
+            (TYPE_AAAA, 16) if qtype == TYPE_AAAA => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(data).map_err(|_| invalid())?)),
+            _ => continue,
+        };
+        ips.push(ip);
+        ttl = ttl.min(record_ttl as u64);
+    }
+    Ok((ips, ttl))
+}
+
+// past a name of labels, possibly ending in a compression pointer
+fn skip_name(message: &[u8], mut at: usize) -> Option<usize> {
+    loop {
+        let len = *message.get(at)? as usize;
+        match len {
+            0 => return Some(at + 1),
+            len if len & 0xc0 == 0xc0 => return message.get(at + 1).map(|_| at + 2),
+            len => at += 1 + len,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use crate::config::ResolverConfig;
+    use crate::resolver::{answers, query, randomize_case, Resolver, TYPE_A, TYPE_AAAA};
+
+    #[test]
+    fn message_test() {
+        let question = query("example.com", TYPE_A, false).unwrap();
+        assert_eq!(&question[12..], b"\x07example\x03com\x00\x00\x01\x00\x01");
+
+        // the question echoed, a cname to the name at offset 12, then an a record behind a pointer
+        let mut response = question.clone();
+        response[2..4].copy_from_slice(&[0x81, 0x80]);
+        response[6..8].copy_from_slice(&[0, 2]);
+        response.extend_from_slice(b"\xc0\x0c\x00\x05\x00\x01\x00\x00\x01\x2c\x00\x02\xc0\x0c");
+        response.extend_from_slice(b"\xc0\x0c\x00\x01\x00\x01\x00\x00\x00\x3c\x00\x04\x5d\xb8\xd7\x0e");
+        let ip: IpAddr = "93.184.215.14".parse().unwrap();
+        assert_eq!(answers(&response, &question, TYPE_A).unwrap(), (vec![ip], 60));
+        assert!(answers(&response, &question, TYPE_AAAA).unwrap().0.is_empty());
+        // cut short
+        assert!(answers(&response[..response.len() - 2], &question, TYPE_A).is_err());
+
+        let mut missing = question.clone();
+        missing[2..4].copy_from_slice(&[0x81, 0x83]);
+        assert!(answers(&missing, &question, TYPE_A).unwrap().0.is_empty());
+        assert!(query(&"a".repeat(64), TYPE_A, false).is_err());
+    }
+
+    #[test]
+    fn privacy_test() {
+        let plain = query("example.com", TYPE_A, false).unwrap();
+        let stripped = query("example.com", TYPE_A, true).unwrap();
+        assert_eq!(&stripped[10..12], &[0, 1]);
+        assert_eq!(&stripped[plain.len()..], b"\x00\x00\x29\x04\xd0\x00\x00\x00\x00\x00\x08\x00\x08\x00\x04\x00\x01\x00\x00");
+
+        let name = randomize_case("example.com").unwrap();
+        assert!(name.eq_ignore_ascii_case("example.com"));
+        // a response whose question lost the mixed case isn't taken
+        let sent = query("ExAmple.com", TYPE_A, false).unwrap();
+        let mut response = plain.clone();
+        response[2..4].copy_from_slice(&[0x81, 0x80]);
+        assert!(answers(&response, &sent, TYPE_A).is_err());
+        assert!(answers(&response, &plain, TYPE_A).is_ok());
+    }
+
+    #[tokio::test]
+    async fn single_flight_test() {
+        let resolver = Resolver::new(ResolverConfig { system_ttl: 30, ..ResolverConfig::default() });
+        assert!(resolver.is_enabled());
+        let lookups: Vec<_> = (0..20).map(|_| {
+            let resolver = resolver.clone();
+            tokio::spawn(async move { resolver.lookup("localhost", 80).await.unwrap() })
+        }).collect();
+        for lookup in lookups {
+            assert!(lookup.await.unwrap().iter().all(|addr| addr.ip().is_loopback() && addr.port() == 80));
+        }
+        assert_eq!(resolver.queries(), 1);
+        // then from the cache, whatever the case or trailing dot
+        resolver.lookup("LocalHost.", 443).await.unwrap();
+        assert_eq!((resolver.queries(), resolver.cache_hits()), (1, 1));
+
+        // failures aren't cached, the next lookup asks again
+        assert!(resolver.lookup("nothing.invalid", 80).await.is_err());
+        assert!(resolver.lookup("nothing.invalid", 80).await.is_err());
+        assert_eq!(resolver.queries(), 3);
+    }
+
+    #[tokio::test]
+    async fn lookup_test() {
+        let system = Resolver::new(ResolverConfig::default());
+        assert!(!system.is_enabled());
+        assert_eq!(system.lookup("127.0.0.1", 80).await.unwrap(), vec!["127.0.0.1:80".parse().unwrap()]);
+        // ip literals never reach the doh server
+        let doh = Resolver::new(ResolverConfig { doh: Some("https://127.0.0.1:1/dns-query".to_string()), ..ResolverConfig::default() });
+        assert_eq!(doh.lookup("::1", 443).await.unwrap(), vec!["[::1]:443".parse().unwrap()]);
+        assert!(doh.lookup("example.com", 443).await.is_err());
+    }
+}
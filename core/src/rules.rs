@@ -0,0 +1,561 @@
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::str::FromStr;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+// programs past this are refused, "a{1000}{1000}" would otherwise take the memory
+const MAX_PROGRAM: usize = 10000;
+
+// what a rule matches a domain with, all of them case insensitive:
+// "example.com" is it and everything under it, "*.example.com" globs with * over any
+// characters dots included, "regex:^ads?[0-9]*\." is a regex searched anywhere in the name
+#[derive(Debug, Clone)]
+pub enum Pattern {
+    Suffix(String),
+    Wildcard(String),
+    Regex(Regex),
+}
+
+impl Pattern {
+    pub fn matches(&self, host: &str) -> bool {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        match self {
+            Pattern::Suffix(domain) => {
+                host == *domain || (host.ends_with(domain.as_str()) && host.as_bytes()[host.len() - domain.len() - 1] == b'.')
+            }
+            Pattern::Wildcard(glob) => wildcard(glob.as_bytes(), host.as_bytes()),
+            Pattern::Regex(regex) => regex.is_match(&host),
+        }
+    }
+}
+
+impl PartialEq for Pattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.to_string() == other.to_string()
+    }
+}
+
+impl FromStr for Pattern {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(regex) = s.strip_prefix("regex:") {
+            return Regex::new(regex).map(Pattern::Regex);
+        }
+        let domain = s.trim().trim_end_matches('.').to_ascii_lowercase();
+        if domain.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("empty domain pattern : {:?}", s)));
+        }
+        if domain.contains('*') {
+            Ok(Pattern::Wildcard(domain))
+        } else {
+            Ok(Pattern::Suffix(domain))
+        }
+    }
+}
+
+impl Display for Pattern {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Pattern::Suffix(domain) | Pattern::Wildcard(domain) => write!(f, "{}", domain),
+            Pattern::Regex(regex) => write!(f, "regex:{}", regex.pattern),
+        }
+    }
+}
+
+impl Serialize for Pattern {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Pattern {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+// the usual greedy glob with one point to backtrack to
+fn wildcard(glob: &[u8], text: &[u8]) -> bool {
+    let (mut g, mut t) = (0, 0);
+    let mut star = None;
+    while t < text.len() {
+        if g < glob.len() && glob[g] == b'*' {
+            star = Some((g, t));
+            g += 1;
+        } else if g < glob.len() && glob[g] == text[t] {
+            g += 1;
+            t += 1;
+        } else if let Some((star_g, star_t)) = star {
+            g = star_g + 1;
+            t = star_t + 1;
+            star = Some((star_g, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    glob[g..].iter().all(|&b| b == b'*')
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct ByteSet([u64; 4]);
+
+impl ByteSet {
+    fn empty() -> Self {
+        ByteSet([0; 4])
+    }
+
+    fn insert(&mut self, b: u8) {
+        self.0[(b / 64) as usize] |= 1 << (b % 64);
+    }
+
+    // a literal letter matches either case
+    fn insert_folded(&mut self, b: u8) {
+        self.insert(b.to_ascii_lowercase());
+        self.insert(b.to_ascii_uppercase());
+    }
+
+    fn insert_range(&mut self, from: u8, to: u8) {
+        for b in from..=to {
+            self.insert_folded(b);
+        }
+    }
+
+    fn contains(&self, b: u8) -> bool {
+        self.0[(b / 64) as usize] & (1 << (b % 64)) != 0
+    }
+
+    fn negate(&mut self) {
+        for word in &mut self.0 {
+            *word = !*word;
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Node {
+    Empty,
+    Class(ByteSet),
+    Start,
+    End,
+    Concat(Vec<Node>),
+    Alternate(Vec<Node>),
+    Repeat(Box<Node>, u32, Option<u32>),
+}
+
+#[derive(Debug, Clone)]
+enum Inst {
+    Byte(ByteSet),
+    Split(usize, usize),
+    Jump(usize),
+    Start,
+    End,
+    Match,
+}
+
+// compiled once when the config loads, then run as a thompson nfa so matching stays linear in the name;
+// groups, alternation, classes, anchors and the * + ? {n,m} repetitions, no captures or backreferences
+#[derive(Debug, Clone)]
+pub struct Regex {
+    pattern: String,
+    program: Vec<Inst>,
+}
+
+impl Regex {
+    pub fn new(pattern: &str) -> io::Result<Regex> {
+        let invalid = |why: &str| io::Error::new(io::ErrorKind::InvalidInput, format!("invalid regex {:?} : {}", pattern, why));
+        if !pattern.is_ascii() {
+            return Err(invalid("only ascii is supported"));
+        }
+        let mut parser = Parser { pattern: pattern.as_bytes(), pos: 0 };
+        let node = parser.alternate().map_err(invalid)?;
+        if parser.pos < pattern.len() {
+            return Err(invalid("unmatched )"));
+        }
+        let mut program = Vec::new();
+        compile(&node, &mut program).map_err(invalid)?;
+        program.push(Inst::Match);
+        Ok(Regex { pattern: pattern.to_string(), program })
+    }
+
+    pub fn pattern(&self) -> &str {
+        &self.pattern
+    }
+
+    // true when the regex matches anywhere in text
+    pub fn is_match(&self, text: &str) -> bool {
+        let text = text.as_bytes();
+        let mut current = Threads::new(self.program.len());
+        let mut next = Threads::new(self.program.len());
+        for pos in 0..=text.len() {
+            if self.add(&mut current, 0, pos, text.len()) {
+                return true;
+            }
+            let Some(&b) = text.get(pos) else {
+                break;
+            };
+            next.clear();
+            for &pc in &current.dense {
+                if let Inst::Byte(set) = &self.program[pc] {
+                    if set.contains(b) && self.add(&mut next, pc + 1, pos + 1, text.len()) {
+                        return true;
+                    }
+                }
+            }
+            std::mem::swap(&mut current, &mut next);
+        }
+        false
+    }
+
+    // follows jumps, splits and anchors from pc, true once that reaches a match
+    fn add(&self, threads: &mut Threads, pc: usize, pos: usize, len: usize) -> bool {
+        let mut stack = vec![pc];
+        while let Some(pc) = stack.pop() {
+            if !threads.insert(pc) {
+                continue;
+            }
+            match self.program[pc] {
+                Inst::Match => return true,
+                Inst::Jump(to) => stack.push(to),
+                Inst::Split(first, second) => {
+                    stack.push(second);
+                    stack.push(first);
+                }
+                Inst::Start if pos == 0 => stack.push(pc + 1),
+                Inst::End if pos == len => stack.push(pc + 1),
+                Inst::Start | Inst::End | Inst::Byte(_) => {}
+            }
+        }
+        false
+    }
+}
+
+struct Threads {
+    seen: Vec<bool>,
+    dense: Vec<usize>,
+}
+
+impl Threads {
+    fn new(len: usize) -> Self {
+        Threads { seen: vec![false; len], dense: Vec::with_capacity(len) }
+    }
+
+    fn insert(&mut self, pc: usize) -> bool {
+        if self.seen[pc] {
+            return false;
+        }
+        self.seen[pc] = true;
+        self.dense.push(pc);
+        true
+    }
+
+    fn clear(&mut self) {
+        for &pc in &self.dense {
+            self.seen[pc] = false;
+        }
+        self.dense.clear();
+    }
+}
+
+struct Parser<'a> {
+    pattern: &'a [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<u8> {
+        self.pattern.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<u8> {
+        let b = self.peek();
+        self.pos += 1;
+        b
+    }
+
+    fn alternate(&mut self) -> Result<Node, &'static str> {
+        let mut branches = vec![self.concat()?];
+        while self.peek() == Some(b'|') {
+            self.pos += 1;
+            branches.push(self.concat()?);
+        }
+        Ok(if branches.len() == 1 { branches.pop().unwrap() } else { Node::Alternate(branches) })
+    }
+
+    fn concat(&mut self) -> Result<Node, &'static str> {
+        let mut nodes = Vec::new();
+        while let Some(b) = self.peek() {
+            if b == b'|' || b == b')' {
+                break;
+            }
+            let atom = self.atom()?;
+            nodes.push(self.repeat(atom)?);
+        }
+        Ok(match nodes.len() {
+            0 => Node::Empty,
+            1 => nodes.pop().unwrap(),
+            _ => Node::Concat(nodes),
+        })
+    }
+
+    fn repeat(&mut self, mut node: Node) -> Result<Node, &'static str> {
+        loop {
+            let (min, max) = match self.next() {
+                Some(b'*') => (0, None),
+                Some(b'+') => (1, None),
+                Some(b'?') => (0, Some(1)),
+                Some(b'{') => self.counts()?,
+                _ => {
+                    self.pos -= 1;
+                    return Ok(node);
+                }
+            };
+            // lazy or greedy doesn't change whether there is a match
+            if self.peek() == Some(b'?') {
+                self.pos += 1;
+            }
+            if matches!(node, Node::Start | Node::End) {
+                return Err("nothing to repeat");
+            }
+            node = Node::Repeat(Box::new(node), min, max);
+        }
+    }
+
+    // after the {, up to and including the }
+    fn counts(&mut self) -> Result<(u32, Option<u32>), &'static str> {
+        let min = self.number().ok_or("expected a repetition count")?;
+        let max = match self.next() {
+            Some(b'}') => return Ok((min, Some(min))),
+            Some(b',') if self.peek() == Some(b'}') => None,
+            Some(b',') => Some(self.number().ok_or("expected a repetition count")?),
+            _ => return Err("unclosed {"),
+        };
+        if self.next() != Some(b'}') {
+            return Err("unclosed {");
+        }
+        if max.is_some_and(|max| max < min) {
+            return Err("repetition range out of order");
+        }
+        Ok((min, max))
+    }
+
+    fn number(&mut self) -> Option<u32> {
+        let start = self.pos;
+        while self.peek().is_some_and(|b| b.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        std::str::from_utf8(&self.pattern[start..self.pos]).ok()?.parse().ok()
+    }
+
+    fn atom(&mut self) -> Result<Node, &'static str> {
+        let b = self.next().ok_or("unexpected end")?;
+        Ok(match b {
+            b'(' => {
+                // non-capturing groups are the same as groups here
+                if self.pattern[self.pos..].starts_with(b"?:") {
+                    self.pos += 2;
+                }
+                let node = self.alternate()?;
+                if self.next() != Some(b')') {
+                    return Err("unclosed (");
+                }
+                node
+            }
+            b'[' => Node::Class(self.class()?),
+            b'.' => {
+                let mut set = ByteSet::empty();
+                set.negate();
+                Node::Class(set)
+            }
+            b'^' => Node::Start,
+            b'$' => Node::End,
+            b'\\' => Node::Class(self.escape()?),
+            b'*' | b'+' | b'?' | b'{' => return Err("nothing to repeat"),
+            b => {
+                let mut set = ByteSet::empty();
+                set.insert_folded(b);
+                Node::Class(set)
+            }
+        })
+    }
+
+    fn escape(&mut self) -> Result<ByteSet, &'static str> {
+        let mut set = ByteSet::empty();
+        let b = self.next().ok_or("trailing \\")?;
+        match b {
+            b'd' | b'D' => set.insert_range(b'0', b'9'),
+            b'w' | b'W' => {
+                set.insert_range(b'a', b'z');
+                set.insert_range(b'0', b'9');
+                set.insert(b'_');
+            }
+            b's' | b'S' => {
+                for b in [b' ', b'\t', b'\n', b'\r', 0x0b, 0x0c] {
+                    set.insert(b);
+                }
+            }
+            b if b.is_ascii_alphanumeric() => return Err("unknown escape"),
+            b => set.insert(b),
+        }
+        if b.is_ascii_uppercase() {
+            set.negate();
+        }
+        Ok(set)
+    }
+
+    // after the [, up to and including the ]
+    fn class(&mut self) -> Result<ByteSet, &'static str> {
+        let mut set = ByteSet::empty();
+        let negated = self.peek() == Some(b'^');
+        if negated {
+            self.pos += 1;
+        }
+        let mut first = true;
+        loop {
+            let b = self.next().ok_or("unclosed [")?;
+            if b == b']' && !first {
+                break;
+            }
+            first = false;
+            if b == b'\\' {
+                let escaped = self.escape()?;
+                for (word, escaped) in set.0.iter_mut().zip(escaped.0) {
+                    *word |= escaped;
+                }
+                continue;
+            }
+            if self.peek() == Some(b'-') && self.pattern.get(self.pos + 1).is_some_and(|&to| to != b']') {
+                let to = self.pattern[self.pos + 1];
+                self.pos += 2;
+                if to < b {
+                    return Err("class range out of order");
+                }
+                set.insert_range(b, to);
+            } else {
+                set.insert_folded(b);
+            }
+        }
+        if negated {
+            set.negate();
+        }
+        Ok(set)
+    }
+}
+
+fn compile(node: &Node, program: &mut Vec<Inst>) -> Result<(), &'static str> {
+    if program.len() > MAX_PROGRAM {
+        return Err("too large");
+    }
+    match node {
+        Node::Empty => {}
+        Node::Class(set) => program.push(Inst::Byte(*set)),
+        Node::Start => program.push(Inst::Start),
+        Node::End => program.push(Inst::End),
+        Node::Concat(nodes) => {
+            for node in nodes {
+                compile(node, program)?;
+            }
+        }
+        Node::Alternate(branches) => {
+            let mut jumps = Vec::new();
+            for (i, branch) in branches.iter().enumerate() {
+                if i + 1 == branches.len() {
+                    compile(branch, program)?;
+                    break;
+                }
+                let split = program.len();
+                program.push(Inst::Split(split + 1, 0));
+                compile(branch, program)?;
+                jumps.push(program.len());
+                program.push(Inst::Jump(0));
+                program[split] = Inst::Split(split + 1, program.len());
+            }
+            let end = program.len();
+            for jump in jumps {
+                program[jump] = Inst::Jump(end);
+            }
+        }
+        Node::Repeat(node, min, max) => {
+            for _ in 0..*min {
+                compile(node, program)?;
+            }
+            match max {
+                None => {
+                    let split = program.len();
+                    program.push(Inst::Split(split + 1, 0));
+                    compile(node, program)?;
+                    program.push(Inst::Jump(split));
+                    program[split] = Inst::Split(split + 1, program.len());
+                }
+                Some(max) => {
+                    let mut splits = Vec::new();
+                    for _ in *min..*max {
+                        splits.push(program.len());
+                        program.push(Inst::Split(0, 0));
+                        compile(node, program)?;
+                        if program.len() > MAX_PROGRAM {
+                            return Err("too large");
+                        }
+                    }
+                    let end = program.len();
+                    for split in splits {
+                        program[split] = Inst::Split(split + 1, end);
+                    }
+                }
+            }
+        }
+    }
+    if program.len() > MAX_PROGRAM {
+        return Err("too large");
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::rules::{Pattern, Regex};
+
+    #[test]
+    fn pattern_test() {
+        let suffix: Pattern = "Example.com.".parse().unwrap();
+        assert!(suffix.matches("example.com"));
+        assert!(suffix.matches("www.EXAMPLE.com"));
+        assert!(!suffix.matches("badexample.com"));
+
+        let wildcard: Pattern = "*.example.com".parse().unwrap();
+        assert!(wildcard.matches("a.b.example.com"));
+        assert!(!wildcard.matches("example.com"));
+        let wildcard: Pattern = "ads*.example.*".parse().unwrap();
+        assert!(wildcard.matches("ads1.example.net"));
+        assert!(!wildcard.matches("www.example.net"));
+
+        let regex: Pattern = r"regex:^ads?[0-9]*\.".parse().unwrap();
+        assert!(regex.matches("ad12.example.com"));
+        assert!(!regex.matches("www.ad12.example.com"));
+        assert_eq!(regex.to_string(), r"regex:^ads?[0-9]*\.");
+        assert!("".parse::<Pattern>().is_err());
+    }
+
+    #[test]
+    fn regex_test() {
+        let matches = |pattern: &str, text: &str| Regex::new(pattern).unwrap().is_match(text);
+        assert!(matches("track", "tracker.example.com"));
+        assert!(matches("^(www|cdn)\\.example\\.com$", "cdn.example.com"));
+        assert!(!matches("^(www|cdn)\\.example\\.com$", "cdn.example.com.evil.org"));
+        assert!(matches("^[a-f0-9]{8}\\.", "deadbeef.example.com"));
+        assert!(!matches("^[a-f0-9]{8}\\.", "deadbee.example.com"));
+        assert!(matches("^\\d{2,3}-[^.]+\\.net$", "123-x.net"));
+        assert!(!matches("^\\d{2,3}-[^.]+\\.net$", "1-x.net"));
+        assert!(matches("(?:ad|track)s?\\.", "ads.example.com"));
+        assert!(matches("^$", ""));
+        assert!(matches("^(a*)*$", "aaaa"));
+        // the classic backtracking blowup stays linear here
+        assert!(!matches("^(a+)+$", &format!("{}b", "a".repeat(200))));
+        for invalid in ["(", ")", "[a-", "*a", "a{2,1}", "\\q", "a{1000}{1000}"] {
+            assert!(Regex::new(invalid).is_err(), "{} should not compile", invalid);
+        }
+    }
+}
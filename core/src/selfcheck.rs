@@ -0,0 +1,148 @@
+// what a server or local client is checked for before it starts, so a bad port, file or cipher
+// stops it with every problem listed instead of showing up at the first request
+use std::fmt::{Display, Formatter};
+use std::fs::OpenOptions;
+use std::io;
+use std::time::Duration;
+
+use crate::asn::Asns;
+use crate::blocklist::Blocklist;
+use crate::config::{BlocklistConfig, LocalConfig, ServerConfig};
+use crate::resolver::Resolver;
+use crate::transport::{Endpoint, ListenOptions};
+
+// a name that always exists, asked of a doh upstream to see it answers
+const PROBE_NAME: &str = "example.com";
+
+// each check with what it found, in the order they ran
+#[derive(Debug, Default)]
+pub struct Report {
+    pub checks: Vec<(String, Result<String, String>)>,
+}
+
+impl Report {
+    fn push<T: Display, E: Display>(&mut self, name: impl Into<String>, result: Result<T, E>) {
+        self.checks.push((name.into(), result.map(|ok| ok.to_string()).map_err(|e| e.to_string())));
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.checks.iter().all(|(_, result)| result.is_ok())
+    }
+
+    // every failure in one error, None when all passed
+    pub fn error(&self) -> Option<io::Error> {
+        let failed: Vec<String> = self.checks.iter()
+            .filter_map(|(name, result)| result.as_ref().err().map(|e| format!("{} : {}", name, e)))
+            .collect();
+        (!failed.is_empty()).then(|| io::Error::other(format!("self-check failed, {}", failed.join("; "))))
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for (name, result) in &self.checks {
+            match result {
+                Ok(found) => writeln!(f, "ok   {} : {}", name, found)?,
+                Err(e) => writeln!(f, "FAIL {} : {}", name, e)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+pub async fn server(config: &ServerConfig) -> Report {
+    let mut report = Report::default();
+    let problems = config.check();
+    report.push("config", if problems.is_empty() { Ok("valid".to_string()) } else { Err(problems.join(", ")) });
+    report.push("cipher", config.keyring().map(|_| cipher(&config.encrypt)));
+    for endpoint in config.endpoints() {
+        bind(&mut report, &endpoint, &config.listen_options()).await;
+    }
+    if let Some(metrics) = &config.metrics {
+        bind(&mut report, &Endpoint::Tcp(metrics.clone()), &ListenOptions::default()).await;
+    }
+    blocklist(&mut report, &config.blocklist);
+    if let Some(asn) = &config.asn {
+        report.push(format!("asn {}", asn.path.display()), Asns::new(Some(asn)).map(|_| "loaded"));
+    }
+    if let Some(access) = &config.access_log {
+        let opened = OpenOptions::new().create(true).append(true).open(&access.path);
+        report.push(format!("access log {}", access.path.display()), opened.map(|_| "writable"));
+    }
+    if let Some(doh) = &config.resolver.doh {
+        let resolver = Resolver::new(config.resolver.clone());
+        let timeout = Duration::from_secs(config.resolver.timeout.max(1));
+        let answered = match tokio::time::timeout(timeout, resolver.lookup(PROBE_NAME, 443)).await {
+            Ok(Ok(addrs)) => Ok(format!("{} is {} addresses", PROBE_NAME, addrs.len())),
+            Ok(Err(e)) => Err(e.to_string()),
+            Err(_) => Err("no answer in time".to_string()),
+        };
+        report.push(format!("doh {}", doh), answered);
+    }
+    report
+}
+
+pub async fn local(config: &LocalConfig) -> Report {
+    let mut report = Report::default();
+    let problems = config.check();
+    report.push("config", if problems.is_empty() { Ok("valid".to_string()) } else { Err(problems.join(", ")) });
+    report.push("cipher", config.keyring().map(|_| cipher(&config.encrypt)));
+    for endpoint in config.endpoints() {
+        bind(&mut report, &endpoint, &ListenOptions::default()).await;
+    }
+    if let Some(metrics) = &config.metrics {
+        bind(&mut report, &Endpoint::Tcp(metrics.clone()), &ListenOptions::default()).await;
+    }
+    blocklist(&mut report, &config.blocklist);
+    report
+}
+
+fn cipher(encrypt: &str) -> &str {
+    if encrypt.is_empty() { "none" } else { encrypt }
+}
+
+// bound and let go at once, the listener proper binds it again right after
+async fn bind(report: &mut Report, endpoint: &Endpoint, options: &ListenOptions) {
+    report.push(format!("bind {}", endpoint), endpoint.bind_with(options).await.map(|_| "free"));
+}
+
+fn blocklist(report: &mut Report, config: &BlocklistConfig) {
+    if !config.files.is_empty() || !config.domains.is_empty() {
+        report.push("blocklist", Blocklist::new(config).map(|blocklist| format!("{} domains", blocklist.len())));
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use crate::config::{BlocklistConfig, ServerConfig};
+    use crate::selfcheck::server;
+    use crate::test_util::config;
+
+    #[tokio::test]
+    async fn server_test() {
+        let report = server(&config()).await;
+        assert!(report.is_ok(), "{}", report);
+        assert!(report.error().is_none());
+
+        // everything that fails is reported together
+        let taken = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let report = server(&ServerConfig {
+            host: "127.0.0.1".to_string(),
+            port: taken.local_addr().unwrap().port(),
+            encrypt: "rot13".to_string(),
+            blocklist: BlocklistConfig { files: vec!["/nonexistent/list".into()], ..BlocklistConfig::default() },
+            ..config()
+        }).await;
+        let failed: Vec<&str> = report.checks.iter().filter(|(_, result)| result.is_err()).map(|(name, _)| name.as_str()).collect();
+        assert_eq!(failed.len(), 4, "{}", report);
+        for name in ["config", "cipher", "bind ", "blocklist"] {
+            assert!(failed.iter().any(|failed| failed.starts_with(name)), "no {} in {}", name, report);
+        }
+        let error = report.error().unwrap().to_string();
+        assert!(error.starts_with("self-check failed, config : "));
+        assert!(report.to_string().contains("FAIL bind 127.0.0.1:"));
+    }
+}
@@ -0,0 +1,536 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::{info, warn};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{lookup_host, TcpListener, TcpStream};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+use crate::access::AccessLog;
+use crate::asn;
+use crate::asn::Asns;
+use crate::blocklist;
+use crate::buffers::BufferPool;
+use crate::blocklist::Blocklist;
+use crate::config::{RejectMode, ServerConfig};
+use crate::crypto::{CipherStream, Keyring};
+use crate::error::Error;
+use crate::obfs::ObfsStream;
+use crate::limit::{AuthBans, ConnectionLimit, LoadShedder, Maintenance, RateLimiter};
+use crate::metrics;
+use crate::nat64::Nat64;
+use crate::pool::Pool;
+use crate::priority::Scheduler;
+use crate::quota::Quota;
+use crate::resolver::Resolver;
+use crate::socket5::Address;
+use crate::stats::{ListenerStats, ServerStats, Stats};
+use crate::tcp::{Accepted, TcpSocksClient};
+use crate::trace::Tracer;
+use crate::transport::{Endpoint, Listener, RawStream};
+use crate::webhook::Webhook;
+
+// how often a draining server looks whether its connections are done
+const DRAIN_CHECK: Duration = Duration::from_millis(100);
+
+// everything a connection needs, shared between all of them
+#[derive(Clone)]
+pub struct ServerState {
+    pub config: ServerConfig,
+    pub quota: Quota,
+    pub stats: Stats,
+    pub keyring: Keyring,
+    // users logging in with socks username/password auth
+    pub passwords: Arc<HashMap<String, String>>,
+    pub pool: Pool,
+    pub tracer: Tracer,
+    // tcp addresses the listeners got, for telling a target that is this server
+    pub listening: Arc<Mutex<Vec<SocketAddr>>>,
+    pub limiter: RateLimiter,
+    pub bans: AuthBans,
+    pub blocklist: Arc<Blocklist>,
+    pub targets: ConnectionLimit,
+    pub sources: ConnectionLimit,
+    pub shedder: LoadShedder,
+    pub maintenance: Maintenance,
+    pub scheduler: Scheduler,
+    pub resolver: Resolver,
+    pub nat64: Nat64,
+    pub access: AccessLog,
+    pub webhook: Webhook,
+    pub asns: Asns,
+}
+
+impl ServerState {
+    // needs a runtime, the trace exporter, access log writer and webhook poster are spawned here
+    pub fn new(config: ServerConfig) -> io::Result<Self> {
+        Ok(ServerState {
+            quota: Quota::new(config.quota_config())?,
+            stats: Stats::default(),
+            keyring: config.keyring()?,
+            passwords: Arc::new(config.passwords()),
+            pool: Pool::new(config.pool.clone()),
+            tracer: Tracer::new(config.trace.clone()),
+            listening: Arc::default(),
+            limiter: RateLimiter::new(config.rate_limit.clone()),
+            bans: AuthBans::new(config.auth_ban.clone()),
+            blocklist: Arc::new(Blocklist::new(&config.blocklist)?),
+            targets: ConnectionLimit::new(config.target_limit),
+            sources: ConnectionLimit::new(config.source_limit),
+            shedder: LoadShedder::new(config.shed.clone()),
+            maintenance: Maintenance::default(),
+            scheduler: Scheduler::new(&config.priority),
+            resolver: Resolver::new(config.resolver.clone()),
+            nat64: Nat64::new(config.nat64.as_deref())?,
+            access: AccessLog::new(config.access_log.clone())?,
+            webhook: Webhook::new(config.webhook.clone()),
+            asns: Asns::new(config.asn.as_ref())?,
+            config,
+        })
+    }
+
+    // a target dialed from here that is one of our listening addresses, refused before the dial;
+    // names are only looked up when their port is one we listen on. with a parent it's the parent
+    // that dials, and what is local to it isn't known here
+    pub async fn refuse_loop(&self, requested: &Address, target: &Address) -> Result<(), Error> {
+        if self.config.hairpin.contains(requested) || self.config.parent.is_some() {
+            return Ok(());
+        }
+        let port = match target {
+            Address::Address(addr) => addr.port(),
+            Address::DomainName(_, port) => *port,
+        };
+        let listening: Vec<SocketAddr> = self.listening.lock().unwrap().iter().filter(|addr| addr.port() == port).copied().collect();
+        if listening.is_empty() {
+            return Ok(());
+        }
+        let addrs = match target {
+            Address::Address(addr) => vec![*addr],
+            // a name that doesn't resolve fails the dial instead
+            Address::DomainName(host, port) => lookup_host((host.as_str(), *port)).await.map_or(Vec::new(), |addrs| addrs.collect()),
+        };
+        for addr in addrs {
+            let reaches = |listen: &SocketAddr| listen.ip() == addr.ip()
+                || (listen.ip().is_unspecified() && is_local(addr.ip()))
+                || (addr.ip().is_unspecified() && listen.ip().is_loopback());
+            if listening.iter().any(reaches) {
+                return Err(Error::Loop(addr));
+            }
+        }
+        Ok(())
+    }
+
+    // a connection to a local address on a listening port would be relayed straight back to us;
+    // the fallback for what refuse_loop can't see, a name resolved differently by the dial
+    pub fn reject_loop(&self, target: &Address, stream: &TcpStream) -> Result<(), Error> {
+        if self.config.hairpin.contains(target) {
+            return Ok(());
+        }
+        let (peer, local) = (stream.peer_addr()?, stream.local_addr()?);
+        let is_local = peer.ip().is_loopback() || peer.ip() == local.ip();
+        let listening = self.listening.lock().unwrap();
+        if is_local && listening.iter().any(|addr| addr.port() == peer.port() && (addr.ip().is_unspecified() || addr.ip() == peer.ip())) {
+            return Err(Error::Loop(peer));
+        }
+        Ok(())
+    }
+}
+
+// an address of this host: binding to one only works where it's assigned to an interface
+fn is_local(ip: IpAddr) -> bool {
+    ip.is_loopback() || ip.is_unspecified() || std::net::UdpSocket::bind((ip, 0)).is_ok()
+}
+
+pub struct ServerHandle {
+    state: ServerState,
+    listeners: Vec<Arc<ListenerStats>>,
+    shutdown: watch::Sender<bool>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+// bind every configured endpoint and start accepting in the background
+pub async fn start(config: ServerConfig) -> io::Result<ServerHandle> {
+    // a config from the builder, a file or the ffi is refused the same way
+    let config = config.validate().map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+    let state = ServerState::new(config)?;
+    let (shutdown, watcher) = watch::channel(false);
+    let mut listeners = Vec::new();
+    let mut tasks = Vec::new();
+    for remote in state.config.blocklist.remote.clone() {
+        tasks.push(tokio::spawn(blocklist::refresh(state.blocklist.clone(), remote, watcher.clone())));
+    }
+    if let Some(config) = state.config.asn.clone() {
+        tasks.push(tokio::spawn(asn::reload(state.asns.clone(), config, watcher.clone())));
+    }
+    let options = state.config.listen_options();
+    for endpoint in state.config.endpoints() {
+        let listener = endpoint.bind_with(&options).await?;
+        let local = listener.local_endpoint()?;
+        let mut bound = vec![listener];
+        if let Endpoint::Tcp(addr) = &local {
+            if let Ok(addr) = addr.parse() {
+                state.listening.lock().unwrap().push(addr);
+            }
+            // the rest join the port the first one got, which matters for port 0
+            for _ in 1..state.config.acceptors() {
+                bound.push(local.bind_with(&options).await?);
+            }
+        }
+        for listener in bound {
+            let listener_stats = Arc::new(ListenerStats::new(local.clone()));
+            info!("start socks5 server, listen : {}", listener_stats.endpoint);
+            tasks.push(tokio::spawn(serve(listener, listener_stats.clone(), state.clone(), watcher.clone())));
+            listeners.push(listener_stats);
+        }
+    }
+    if let Some(addr) = &state.config.metrics {
+        let listener = TcpListener::bind(addr).await?;
+        info!("serve metrics, listen : http://{}/metrics", listener.local_addr()?);
+        let (state, listeners) = (state.clone(), listeners.clone());
+        let (maintenance, token) = (state.maintenance.clone(), state.config.metrics_token.clone());
+        tasks.push(tokio::spawn(metrics::serve(listener, move || collect(&state, &listeners), maintenance, token, watcher.clone())));
+    }
+    Ok(ServerHandle { state, listeners, shutdown, tasks })
+}
+
+pub fn collect(state: &ServerState, listeners: &[Arc<ListenerStats>]) -> ServerStats {
+    let stats = &state.stats;
+    ServerStats {
+        connections: stats.connections(),
+        total_connections: stats.total_connections(),
+        bytes_up: stats.bytes_up(),
+        bytes_down: stats.bytes_down(),
+        udp_datagrams: stats.udp_datagrams(),
+        udp_truncated: stats.udp_truncated(),
+        udp_unreachable: stats.udp_unreachable_count(),
+        spans_dropped: state.tracer.dropped(),
+        events_dropped: state.webhook.dropped(),
+        buffers: BufferPool::global().stats(),
+        dns_queries: state.resolver.queries(),
+        dns_cache_hits: state.resolver.cache_hits(),
+        rejected: stats.rejected_connections(),
+        shed: stats.shed_requests(),
+        panics: stats.panics(),
+        maintenance: state.maintenance.is_on(),
+        closes: stats.closes(),
+        udp_associations: stats.udp_associations(),
+        handshake: stats.handshake_histogram(),
+        dial: stats.dial_histogram(),
+        throughput: stats.throughput_histogram(),
+        listeners: listeners.iter().map(|l| l.status()).collect(),
+        users: stats.users(),
+        traffic: stats.traffic(),
+    }
+}
+
+async fn serve(listener: Listener, listener_stats: Arc<ListenerStats>, state: ServerState, mut shutdown: watch::Receiver<bool>) {
+    loop {
+        tokio::select! {
+            _ = shutdown.changed() => break,
+            accepted = listener.accept() => match accepted {
+                Ok((mut stream, address)) => {
+                    info!("received request address : {}", address);
+                    listener_stats.accepted();
+                    let peer = stream.peer_ip();
+                    if peer.is_some_and(|ip| !state.config.allows_source(ip) || state.bans.is_banned(ip) || !state.limiter.allow(ip)) {
+                        // closed before a single byte is read
+                        state.stats.rejected();
+                        if state.config.reject == RejectMode::Reset {
+                            let _ = stream.abort_on_close();
+                        }
+                        continue;
+                    }
+                    match state.config.obfs {
+                        None => spawn_connection(stream, peer, state.clone()),
+                        Some(mode) => spawn_connection(ObfsStream::server(stream, mode), peer, state.clone()),
+                    }
+                }
+                Err(e) => {
+                    warn!("accept on {} fail : {}", listener_stats.endpoint, e);
+                    listener_stats.error(e.to_string());
+                    continue;
+                }
+            }
+        };
+    };
+    listener_stats.stopped();
+}
+
+fn spawn_connection<S>(stream: S, peer: Option<IpAddr>, state: ServerState)
+    where S: AsyncRead + AsyncWrite + RawStream + Unpin + Send + 'static
+{
+    if state.keyring.is_plain() {
+        tokio::spawn(serve_connection(TcpSocksClient::new(stream).with_peer(peer), state));
+    } else {
+        let stream = CipherStream::server(stream, &state.keyring);
+        let user = stream.user();
+        tokio::spawn(serve_connection(TcpSocksClient::new(stream).with_user(user).with_peer(peer), state));
+    }
+}
+
+async fn serve_connection<S>(client: TcpSocksClient<S>, state: ServerState)
+    where S: AsyncRead + AsyncWrite + RawStream + Unpin + Send + 'static
+{
+    if let Ok(Accepted::Mux(client)) = client.server_accept(state.clone()).await {
+        client.serve_mux(state).await;
+    }
+}
+
+impl ServerHandle {
+    pub fn stats(&self) -> ServerStats {
+        collect(&self.state, &self.listeners)
+    }
+
+    pub fn state(&self) -> &ServerState {
+        &self.state
+    }
+
+    // what POST /maintenance on the metrics listener does
+    pub fn set_maintenance(&self, on: bool) {
+        self.state.maintenance.set(on);
+    }
+
+    // waits until every listener task has finished
+    pub async fn wait(&mut self) {
+        for task in self.tasks.drain(..) {
+            let _ = task.await;
+        }
+    }
+
+    // stop accepting, connections already relaying are left to finish
+    pub async fn shutdown(mut self) {
+        let _ = self.shutdown.send(true);
+        self.wait().await;
+    }
+
+    // stop accepting, then wait up to timeout for the open connections to finish, giving back how
+    // many were still open; what a process exiting after it leaves is cut
+    pub async fn drain(self, timeout: Duration) -> u64 {
+        let stats = self.state.stats.clone();
+        self.shutdown().await;
+        let deadline = tokio::time::Instant::now() + timeout;
+        while stats.connections() > 0 && tokio::time::Instant::now() < deadline {
+            tokio::time::sleep(DRAIN_CHECK).await;
+        }
+        stats.connections()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::config::{LocalConfig, RateLimitConfig, ServerConfig, UserConfig};
+    use crate::crypto::{encode_key, generate_key};
+    use crate::error::Error;
+    use crate::local;
+    use crate::server::{is_local, start};
+    use crate::socket5::{self, Address, Command, Proxy, Reply};
+    use crate::tcp::TcpSocksClient;
+    use crate::test_util::{assert_echo, echo_server};
+
+    #[tokio::test]
+    async fn stats_and_shutdown_test() {
+        let echo_addr = echo_server().await;
+        let handle = start(ServerConfig {
+            port: 0,
+            ..ServerConfig::default()
+        }).await.unwrap();
+        let server = handle.stats().listeners[0].endpoint.to_string();
+        let mut client = TcpSocksClient::client_connect(
+            server,
+            Proxy::new(Command::CONNECT, Address::Address(echo_addr)),
+        ).await.unwrap();
+        client.stream.write_all(b"hello").await.unwrap();
+        let mut buf = [0; 5];
+        client.stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(handle.stats().connections, 1);
+        drop(client);
+        for _ in 0..100 {
+            if handle.stats().connections == 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let stats = handle.stats();
+        assert_eq!(stats.connections, 0);
+        assert_eq!(stats.total_connections, 1);
+        assert_eq!((stats.bytes_up, stats.bytes_down), (5, 5));
+        assert_eq!(stats.listeners[0].accepted, 1);
+        assert_eq!((stats.handshake.count(), stats.dial.count(), stats.throughput.count()), (1, 1, 1));
+        let listeners = handle.listeners.clone();
+        handle.shutdown().await;
+        assert!(!listeners[0].status().running);
+    }
+
+    #[tokio::test]
+    async fn start_invalid_test() {
+        // what build() refuses, start refuses too for a config put together any other way
+        let config = ServerConfig { port: 0, encrypt: "aes-256-gcm".to_string(), ..ServerConfig::default() };
+        let err = start(config).await.err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        assert!(err.to_string().starts_with("invalid config : encrypt"), "{}", err);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn reuse_port_test() {
+        let echo_addr = echo_server().await;
+        let handle = start(ServerConfig {
+            port: 0,
+            reuse_port: true,
+            acceptors: 2,
+            backlog: 16,
+            ..ServerConfig::default()
+        }).await.unwrap();
+        let listeners = handle.stats().listeners;
+        assert_eq!(listeners.len(), 2);
+        assert_eq!(listeners[0].endpoint, listeners[1].endpoint);
+        for _ in 0..4 {
+            let mut client = TcpSocksClient::client_connect(
+                listeners[0].endpoint.to_string(),
+                Proxy::new(Command::CONNECT, Address::Address(echo_addr)),
+            ).await.unwrap();
+            client.stream.write_all(b"hello").await.unwrap();
+            let mut buf = [0; 5];
+            client.stream.read_exact(&mut buf).await.unwrap();
+        }
+        let accepted: u64 = handle.stats().listeners.iter().map(|l| l.accepted).sum();
+        assert_eq!(accepted, 4);
+    }
+
+    // the upgrade: a second server on the same port, the first one draining its connection
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn drain_test() {
+        let echo = Address::Address(echo_server().await);
+        let config = ServerConfig { host: "127.0.0.1".to_string(), port: 0, reuse_port: true, ..ServerConfig::default() };
+        let old = start(config.clone()).await.unwrap();
+        let endpoint = old.stats().listeners[0].endpoint.to_string();
+        let port = endpoint.rsplit(':').next().unwrap().parse().unwrap();
+        let mut open = TcpSocksClient::client_connect(endpoint.clone(), Proxy::new(Command::CONNECT, echo.clone())).await.unwrap();
+        let new = start(ServerConfig { port, ..config }).await.unwrap();
+        let drained = tokio::spawn(old.drain(Duration::from_secs(10)));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(!drained.is_finished());
+        assert_echo(&mut open.stream).await;
+        // only the new server is listening now
+        let mut client = TcpSocksClient::client_connect(endpoint, Proxy::new(Command::CONNECT, echo)).await.unwrap();
+        assert_echo(&mut client.stream).await;
+        assert_eq!(new.stats().total_connections, 1);
+        drop(open);
+        assert_eq!(drained.await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn user_key_identity_test() {
+        let echo_addr = echo_server().await;
+        let key = encode_key(&generate_key(32).unwrap());
+        let handle = start(ServerConfig {
+            port: 0,
+            encrypt: "aes-256-gcm".to_string(),
+            users: vec![UserConfig {
+                name: "bob".to_string(),
+                key: Some(key.clone()),
+                ..UserConfig::default()
+            }],
+            ..ServerConfig::default()
+        }).await.unwrap();
+        let local = local::start(LocalConfig {
+            port: 0,
+            server: handle.stats().listeners[0].endpoint.clone(),
+            encrypt: "aes-256-gcm".to_string(),
+            key: Some(key),
+            ..LocalConfig::default()
+        }).await.unwrap();
+        let mut client = TcpSocksClient::client_connect(
+            local.endpoints()[0].to_string(),
+            Proxy::new(Command::CONNECT, Address::Address(echo_addr)),
+        ).await.unwrap();
+        client.stream.write_all(b"hello").await.unwrap();
+        let mut buf = [0; 5];
+        client.stream.read_exact(&mut buf).await.unwrap();
+        let users = handle.stats().users;
+        assert_eq!(users.len(), 1);
+        assert_eq!(users[0].name, "bob");
+    }
+
+    #[tokio::test]
+    async fn loop_test() {
+        let handle = start(ServerConfig {
+            port: 0,
+            ..ServerConfig::default()
+        }).await.unwrap();
+        let server = handle.stats().listeners[0].endpoint.to_string();
+        let own: SocketAddr = server.parse().unwrap();
+        // refused before dialing, so the listener only ever accepts the clients
+        for target in [Address::Address(own), Address::DomainName("localhost".to_string(), own.port())] {
+            match TcpSocksClient::client_connect(&server, Proxy::new(Command::CONNECT, target)).await {
+                Err(Error::Socks(socket5::Error::Rejected(reply))) => assert_eq!(reply, Reply::RepConnNo),
+                _ => panic!("expected the loop to be refused"),
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(handle.stats().listeners[0].accepted, 2);
+        assert!(is_local("127.0.0.1".parse().unwrap()) && !is_local("192.0.2.1".parse().unwrap()));
+        let other_port: SocketAddr = format!("127.0.0.1:{}", echo_server().await.port()).parse().unwrap();
+        assert!(TcpSocksClient::client_connect(&server, Proxy::new(Command::CONNECT, Address::Address(other_port))).await.is_ok());
+
+        // a free port, for a server allowed to reach itself
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let own = Address::DomainName("localhost".to_string(), port);
+        let handle = start(ServerConfig {
+            host: "127.0.0.1".to_string(),
+            port,
+            hairpin: vec![own.clone()],
+            ..ServerConfig::default()
+        }).await.unwrap();
+        let server = handle.stats().listeners[0].endpoint.to_string();
+        assert!(TcpSocksClient::client_connect(&server, Proxy::new(Command::CONNECT, own)).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn rate_limit_test() {
+        let echo_addr = echo_server().await;
+        let handle = start(ServerConfig {
+            port: 0,
+            rate_limit: RateLimitConfig { rate: 0.001, burst: 1, ..RateLimitConfig::default() },
+            ..ServerConfig::default()
+        }).await.unwrap();
+        let server = handle.stats().listeners[0].endpoint.to_string();
+        let proxy = Proxy::new(Command::CONNECT, Address::Address(echo_addr));
+        assert!(TcpSocksClient::client_connect(&server, proxy.clone()).await.is_ok());
+        assert!(TcpSocksClient::client_connect(&server, proxy).await.is_err());
+        assert_eq!(handle.stats().rejected, 1);
+    }
+
+    #[tokio::test]
+    async fn allow_sources_test() {
+        let echo_addr = echo_server().await;
+        let proxy = Proxy::new(Command::CONNECT, Address::Address(echo_addr));
+        let handle = start(ServerConfig {
+            port: 0,
+            allow_sources: vec!["10.0.0.0/8".parse().unwrap()],
+            ..ServerConfig::default()
+        }).await.unwrap();
+        let server = handle.stats().listeners[0].endpoint.to_string();
+        assert!(TcpSocksClient::client_connect(&server, proxy.clone()).await.is_err());
+        assert_eq!(handle.stats().rejected, 1);
+
+        let handle = start(ServerConfig {
+            port: 0,
+            allow_sources: vec!["10.0.0.0/8".parse().unwrap(), "127.0.0.1".parse().unwrap()],
+            ..ServerConfig::default()
+        }).await.unwrap();
+        let server = handle.stats().listeners[0].endpoint.to_string();
+        assert!(TcpSocksClient::client_connect(&server, proxy).await.is_ok());
+    }
+}
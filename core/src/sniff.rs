@@ -0,0 +1,227 @@
+use std::io;
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+// a client hello past a full tls record isn't one we wait for
+const MAX_SNIFF: usize = 5 + 16384;
+const TLS_HANDSHAKE: u8 = 0x16;
+const CLIENT_HELLO: u8 = 0x01;
+const EXTENSION_SERVER_NAME: u16 = 0x0000;
+const HOST_NAME: u8 = 0x00;
+// request line and headers, a Host past this isn't waited for
+const MAX_HTTP_HEAD: usize = 8192;
+const HTTP_METHODS: [&[u8]; 9] = [b"GET ", b"POST ", b"HEAD ", b"PUT ", b"DELETE ", b"OPTIONS ", b"PATCH ", b"CONNECT ", b"TRACE "];
+
+// what the client sent first, forwarded untouched once the name is read from it
+pub struct Head {
+    pub bytes: Vec<u8>,
+    pub host: Option<String>,
+}
+
+enum Parse {
+    Incomplete,
+    Found(String),
+    NotFound,
+}
+
+// reads until the tls server name or http Host is known or can't be, giving up after timeout
+// so protocols where the server speaks first aren't held back longer than that
+pub async fn sniff<R: AsyncRead + Unpin>(read: &mut R, timeout: Duration) -> io::Result<Head> {
+    let mut bytes = Vec::new();
+    let mut buf = [0; 4096];
+    let deadline = tokio::time::Instant::now() + timeout;
+    loop {
+        match host(&bytes) {
+            Parse::Found(host) => return Ok(Head { bytes, host: Some(host) }),
+            Parse::NotFound => break,
+            Parse::Incomplete if bytes.len() >= MAX_SNIFF => break,
+            Parse::Incomplete => {}
+        }
+        match tokio::time::timeout_at(deadline, read.read(&mut buf)).await {
+            Ok(Ok(0)) | Err(_) => break,
+            Ok(Ok(n)) => bytes.extend_from_slice(&buf[..n]),
+            Ok(Err(e)) => return Err(e),
+        }
+    }
+    Ok(Head { bytes, host: None })
+}
+
+fn host(data: &[u8]) -> Parse {
+    match data.first() {
+        None => Parse::Incomplete,
+        Some(&TLS_HANDSHAKE) => server_name(data),
+        Some(_) if HTTP_METHODS.iter().any(|m| data.starts_with(m) || m.starts_with(data)) => http_host(data),
+        Some(_) => Parse::NotFound,
+    }
+}
+
+// the host_name of the server_name extension, from a client hello in the first record
+fn server_name(data: &[u8]) -> Parse {
+    if data.len() < 5 {
+        return Parse::Incomplete;
+    }
+    let len = u16::from_be_bytes([data[3], data[4]]) as usize;
+    let Some(record) = data.get(5..5 + len) else {
+        return Parse::Incomplete;
+    };
+    client_hello(record).map_or(Parse::NotFound, |host| host.map_or(Parse::NotFound, Parse::Found))
+}
+
+// the Host header without its port, read as soon as its line is complete
+fn http_host(data: &[u8]) -> Parse {
+    let head = &data[..data.len().min(MAX_HTTP_HEAD)];
+    let complete = &head[..head.iter().rposition(|b| *b == b'\n').map_or(0, |i| i + 1)];
+    // the request line first, then headers up to the empty line
+    for line in complete.split_inclusive(|b| *b == b'\n').skip(1) {
+        let line = line.strip_suffix(b"\n").unwrap_or(line);
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.is_empty() {
+            return Parse::NotFound;
+        }
+        let Some((name, value)) = std::str::from_utf8(line).ok().and_then(|line| line.split_once(':')) else {
+            continue;
+        };
+        if name.eq_ignore_ascii_case("host") {
+            let value = value.trim();
+            let host = match value.strip_prefix('[') {
+                Some(v6) => v6.split(']').next().unwrap_or_default(),
+                None => value.rsplit_once(':').filter(|(_, port)| port.parse::<u16>().is_ok()).map_or(value, |(host, _)| host),
+            };
+            return match host.is_empty() {
+                true => Parse::NotFound,
+                false => Parse::Found(host.to_ascii_lowercase()),
+            };
+        }
+    }
+    if data.len() >= MAX_HTTP_HEAD {
+        return Parse::NotFound;
+    }
+    Parse::Incomplete
+}
+
+// None for a malformed hello, Some(None) for one without a name
+fn client_hello(record: &[u8]) -> Option<Option<String>> {
+    let mut reader = Reader(record);
+    if reader.u8()? != CLIENT_HELLO {
+        return None;
+    }
+    // a hello split over several records gets cut at the first one's end, the extensions
+    // come last so the name is usually in it anyway
+    reader.take(3)?;
+    // version and random
+    reader.take(2 + 32)?;
+    let session = reader.u8()? as usize;
+    reader.take(session)?;
+    let suites = reader.u16()? as usize;
+    reader.take(suites)?;
+    let compression = reader.u8()? as usize;
+    reader.take(compression)?;
+    let len = reader.u16()? as usize;
+    let mut extensions = Reader(reader.take(len).unwrap_or(reader.0));
+    while let (Some(kind), Some(len)) = (extensions.u16(), extensions.u16()) {
+        let body = extensions.take(len as usize)?;
+        if kind == EXTENSION_SERVER_NAME {
+            let mut names = Reader(body);
+            let len = names.u16()? as usize;
+            let mut names = Reader(names.take(len)?);
+            while let Some(kind) = names.u8() {
+                let len = names.u16()? as usize;
+                let name = names.take(len)?;
+                if kind == HOST_NAME {
+                    return Some(std::str::from_utf8(name).ok().map(|name| name.to_ascii_lowercase()));
+                }
+            }
+        }
+    }
+    Some(None)
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io::AsyncWriteExt;
+
+    use crate::sniff::{client_hello, host, http_host, Parse, sniff};
+    use crate::test_util::client_hello_record;
+
+    #[test]
+    fn client_hello_test() {
+        let record = client_hello_record("Example.COM");
+        assert_eq!(client_hello(&record[5..]), Some(Some("example.com".to_string())));
+        assert_eq!(client_hello(&record[5..50]), None);
+        assert_eq!(client_hello(&[0x02, 0, 0, 0]), None);
+    }
+
+    #[test]
+    fn http_host_test() {
+        let found = |data: &[u8]| match http_host(data) {
+            Parse::Found(host) => Some(host),
+            _ => None,
+        };
+        assert_eq!(found(b"GET / HTTP/1.1\r\nUser-Agent: x\r\nHost: Example.com:8080\r\n\r\n").as_deref(), Some("example.com"));
+        assert_eq!(found(b"GET / HTTP/1.1\r\nhost: [::1]:80\r\n").as_deref(), Some("::1"));
+        assert_eq!(found(b"GET / HTTP/1.1\nHost: example.org\n").as_deref(), Some("example.org"));
+        // the line isn't over, more of the name may follow
+        assert!(matches!(http_host(b"GET / HTTP/1.1\r\nHost: exam"), Parse::Incomplete));
+        assert!(matches!(http_host(b"GET / HTTP/1.0\r\nAccept: */*\r\n\r\n"), Parse::NotFound));
+        let mut long = b"GET / HTTP/1.1\r\n".to_vec();
+        long.resize(9000, b'a');
+        assert!(matches!(http_host(&long), Parse::NotFound));
+        // a method cut short still might be one
+        assert!(matches!(host(b"OPT"), Parse::Incomplete));
+        assert!(matches!(host(b"OPTX"), Parse::NotFound));
+    }
+
+    #[tokio::test]
+    async fn sniff_test() {
+        let record = client_hello_record("example.com");
+        // split over two writes, the first cut inside the record header
+        let (mut client, mut server) = tokio::io::duplex(64 * 1024);
+        let (first, second) = record.split_at(3);
+        client.write_all(first).await.unwrap();
+        let sent = second.to_vec();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            client.write_all(&sent).await.unwrap();
+            client
+        });
+        let head = sniff(&mut server, Duration::from_secs(1)).await.unwrap();
+        assert_eq!(head.host.as_deref(), Some("example.com"));
+        assert_eq!(head.bytes, record);
+
+        // not tls, handed back at once
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client.write_all(b"SSH-2.0-OpenSSH_9.6\r\n").await.unwrap();
+        let head = sniff(&mut server, Duration::from_secs(10)).await.unwrap();
+        assert_eq!((head.bytes.as_slice(), head.host), (&b"SSH-2.0-OpenSSH_9.6\r\n"[..], None));
+
+        // a silent client costs the timeout and nothing more
+        let (_client, mut server) = tokio::io::duplex(1024);
+        let head = sniff(&mut server, Duration::from_millis(50)).await.unwrap();
+        assert!(head.bytes.is_empty() && head.host.is_none());
+    }
+}
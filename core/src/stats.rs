@@ -0,0 +1,423 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::buffers::BufferStats;
+use crate::ledger::{Ledger, TrafficReport};
+use crate::relay::{Close, Closes};
+use crate::socket5::Address;
+use crate::transport::Endpoint;
+
+#[derive(Default)]
+struct Counters {
+    connections: AtomicU64,
+    total_connections: AtomicU64,
+    bytes_up: AtomicU64,
+    bytes_down: AtomicU64,
+    udp_datagrams: AtomicU64,
+    udp_truncated: AtomicU64,
+    udp_unreachable: AtomicU64,
+    rejected: AtomicU64,
+    shed: AtomicU64,
+    panics: AtomicU64,
+    closes: Closes,
+    udp_associations: AtomicU64,
+    users: Mutex<HashMap<String, UserStats>>,
+    ledger: Ledger,
+    histograms: Histograms,
+}
+
+struct Histograms {
+    // microseconds
+    handshake: Histogram,
+    dial: Histogram,
+    // bytes per second, both directions, over the connection's relay
+    throughput: Histogram,
+}
+
+impl Default for Histograms {
+    fn default() -> Self {
+        Histograms {
+            // 100us to ~54s
+            handshake: Histogram::new(100, 20),
+            dial: Histogram::new(100, 20),
+            // 1KB/s to 512MB/s
+            throughput: Histogram::new(1024, 20),
+        }
+    }
+}
+
+// process wide counters, cheap to clone into every connection
+#[derive(Clone, Default)]
+pub struct Stats {
+    counters: Arc<Counters>,
+}
+
+impl Stats {
+    // counts the connection as open until the guard is dropped
+    pub fn connection(&self) -> ConnectionGuard {
+        self.counters.connections.fetch_add(1, Ordering::Relaxed);
+        self.counters.total_connections.fetch_add(1, Ordering::Relaxed);
+        ConnectionGuard { stats: self.clone() }
+    }
+
+    // udp associations have no one destination and leave it out
+    pub fn record(&self, user: Option<&str>, destination: Option<&Address>, up: u64, down: u64) {
+        self.counters.bytes_up.fetch_add(up, Ordering::Relaxed);
+        self.counters.bytes_down.fetch_add(down, Ordering::Relaxed);
+        self.counters.ledger.record(user, destination, up, down);
+        if let Some(user) = user {
+            let mut users = self.counters.users.lock().unwrap();
+            let stats = users.entry(user.to_string()).or_insert_with(|| UserStats::new(user));
+            stats.bytes_up += up;
+            stats.bytes_down += down;
+        }
+    }
+
+    // truncated datagrams were larger than the relay buffer and dropped
+    pub fn udp_datagram(&self, truncated: bool) {
+        self.counters.udp_datagrams.fetch_add(1, Ordering::Relaxed);
+        if truncated {
+            self.counters.udp_truncated.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // an icmp unreachable reported on an outbound socket
+    pub fn udp_unreachable(&self) {
+        self.counters.udp_unreachable.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // counts the association as open until the guard is dropped, with its relay socket
+    pub fn udp_association(&self) -> AssociationGuard {
+        self.counters.udp_associations.fetch_add(1, Ordering::Relaxed);
+        AssociationGuard { stats: self.clone() }
+    }
+
+    pub fn handshake(&self, elapsed: Duration) {
+        self.counters.histograms.handshake.record(elapsed.as_micros() as u64);
+    }
+
+    pub fn dial(&self, elapsed: Duration) {
+        self.counters.histograms.dial.record(elapsed.as_micros() as u64);
+    }
+
+    // connections that moved nothing say nothing about throughput and are left out
+    pub fn throughput(&self, bytes: u64, elapsed: Duration) {
+        if bytes > 0 {
+            let seconds = elapsed.as_secs_f64().max(0.001);
+            self.counters.histograms.throughput.record((bytes as f64 / seconds) as u64);
+        }
+    }
+
+    pub fn handshake_histogram(&self) -> HistogramSnapshot {
+        self.counters.histograms.handshake.snapshot()
+    }
+
+    pub fn dial_histogram(&self) -> HistogramSnapshot {
+        self.counters.histograms.dial.snapshot()
+    }
+
+    pub fn throughput_histogram(&self) -> HistogramSnapshot {
+        self.counters.histograms.throughput.snapshot()
+    }
+
+    // a connection turned away before the handshake, e.g. by the rate limit
+    pub fn rejected(&self) {
+        self.counters.rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // a request answered with a server failure because the server was overloaded
+    pub fn shed(&self) {
+        self.counters.shed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn panicked(&self) {
+        self.counters.panics.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // once per relay, as it ends
+    pub fn closed(&self, close: Close) {
+        self.counters.closes.closed(close);
+    }
+
+    // called once the connection's user is known
+    pub fn user_connection(&self, user: &str) {
+        let mut users = self.counters.users.lock().unwrap();
+        users.entry(user.to_string()).or_insert_with(|| UserStats::new(user)).connections += 1;
+    }
+
+    // sorted by name
+    pub fn users(&self) -> Vec<UserStats> {
+        let mut users: Vec<UserStats> = self.counters.users.lock().unwrap().values().cloned().collect();
+        users.sort_by(|a, b| a.name.cmp(&b.name));
+        users
+    }
+
+    // the last hour and day per user and destination
+    pub fn traffic(&self) -> TrafficReport {
+        self.counters.ledger.report()
+    }
+
+    pub fn connections(&self) -> u64 {
+        self.counters.connections.load(Ordering::Relaxed)
+    }
+
+    pub fn total_connections(&self) -> u64 {
+        self.counters.total_connections.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_up(&self) -> u64 {
+        self.counters.bytes_up.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_down(&self) -> u64 {
+        self.counters.bytes_down.load(Ordering::Relaxed)
+    }
+
+    pub fn udp_datagrams(&self) -> u64 {
+        self.counters.udp_datagrams.load(Ordering::Relaxed)
+    }
+
+    pub fn udp_truncated(&self) -> u64 {
+        self.counters.udp_truncated.load(Ordering::Relaxed)
+    }
+
+    pub fn udp_unreachable_count(&self) -> u64 {
+        self.counters.udp_unreachable.load(Ordering::Relaxed)
+    }
+
+    pub fn rejected_connections(&self) -> u64 {
+        self.counters.rejected.load(Ordering::Relaxed)
+    }
+
+    pub fn shed_requests(&self) -> u64 {
+        self.counters.shed.load(Ordering::Relaxed)
+    }
+
+    pub fn panics(&self) -> u64 {
+        self.counters.panics.load(Ordering::Relaxed)
+    }
+
+    pub fn closes(&self) -> Vec<(Close, u64)> {
+        self.counters.closes.snapshot()
+    }
+
+    pub fn udp_associations(&self) -> u64 {
+        self.counters.udp_associations.load(Ordering::Relaxed)
+    }
+}
+
+pub struct ConnectionGuard {
+    stats: Stats,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.stats.counters.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub struct AssociationGuard {
+    stats: Stats,
+}
+
+impl Drop for AssociationGuard {
+    fn drop(&mut self) {
+        self.stats.counters.udp_associations.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// buckets doubling from the first bound, with one more for everything past the last
+pub struct Histogram {
+    bounds: Vec<u64>,
+    counts: Vec<AtomicU64>,
+    sum: AtomicU64,
+    max: AtomicU64,
+}
+
+impl Histogram {
+    pub fn new(first: u64, buckets: usize) -> Self {
+        Histogram {
+            bounds: (0..buckets).map(|i| first << i).collect(),
+            counts: (0..=buckets).map(|_| AtomicU64::new(0)).collect(),
+            sum: AtomicU64::new(0),
+            max: AtomicU64::new(0),
+        }
+    }
+
+    pub fn record(&self, value: u64) {
+        let bucket = self.bounds.partition_point(|bound| *bound < value);
+        self.counts[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(value, Ordering::Relaxed);
+        self.max.fetch_max(value, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> HistogramSnapshot {
+        HistogramSnapshot {
+            bounds: self.bounds.clone(),
+            counts: self.counts.iter().map(|c| c.load(Ordering::Relaxed)).collect(),
+            sum: self.sum.load(Ordering::Relaxed),
+            max: self.max.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistogramSnapshot {
+    // upper bounds, inclusive
+    pub bounds: Vec<u64>,
+    // per bucket, not cumulative, the last one past every bound
+    pub counts: Vec<u64>,
+    pub sum: u64,
+    pub max: u64,
+}
+
+impl HistogramSnapshot {
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    // the upper bound of the bucket holding the p-th percentile, at most a factor 2 over,
+    // never more than the largest value seen
+    pub fn percentile(&self, p: f64) -> Option<u64> {
+        let count = self.count();
+        if count == 0 {
+            return None;
+        }
+        let rank = ((p / 100.0 * count as f64).ceil() as u64).clamp(1, count);
+        let mut seen = 0;
+        for (i, n) in self.counts.iter().enumerate() {
+            seen += n;
+            if seen >= rank {
+                return Some(self.bounds.get(i).map_or(self.max, |bound| (*bound).min(self.max)));
+            }
+        }
+        Some(self.max)
+    }
+}
+
+pub struct ListenerStats {
+    pub endpoint: Endpoint,
+    accepted: AtomicU64,
+    running: AtomicBool,
+    error: Mutex<Option<String>>,
+}
+
+impl ListenerStats {
+    pub fn new(endpoint: Endpoint) -> Self {
+        ListenerStats {
+            endpoint,
+            accepted: AtomicU64::new(0),
+            running: AtomicBool::new(true),
+            error: Mutex::new(None),
+        }
+    }
+
+    pub fn accepted(&self) {
+        self.accepted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn error(&self, err: String) {
+        *self.error.lock().unwrap() = Some(err);
+    }
+
+    pub fn stopped(&self) {
+        self.running.store(false, Ordering::Relaxed);
+    }
+
+    pub fn status(&self) -> ListenerStatus {
+        ListenerStatus {
+            endpoint: self.endpoint.clone(),
+            accepted: self.accepted.load(Ordering::Relaxed),
+            running: self.running.load(Ordering::Relaxed),
+            last_error: self.error.lock().unwrap().clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ListenerStatus {
+    pub endpoint: Endpoint,
+    pub accepted: u64,
+    pub running: bool,
+    // the last accept error, the listener keeps running after one
+    pub last_error: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct UserStats {
+    pub name: String,
+    pub connections: u64,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+}
+
+impl UserStats {
+    fn new(name: &str) -> Self {
+        UserStats { name: name.to_string(), connections: 0, bytes_up: 0, bytes_down: 0 }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerStats {
+    pub connections: u64,
+    pub total_connections: u64,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+    pub udp_datagrams: u64,
+    pub udp_truncated: u64,
+    pub udp_unreachable: u64,
+    // spans the trace exporter couldn't keep up with
+    pub spans_dropped: u64,
+    // webhook events the poster couldn't keep up with
+    pub events_dropped: u64,
+    // the relay and udp buffer pool
+    pub buffers: BufferStats,
+    // names resolved by the server, and those answered from its cache instead
+    pub dns_queries: u64,
+    pub dns_cache_hits: u64,
+    pub rejected: u64,
+    // requests turned away by load shedding
+    pub shed: u64,
+    // connection tasks that panicked, each one closed and logged
+    pub panics: u64,
+    // new requests are being refused
+    pub maintenance: bool,
+    // relays ended, by why
+    pub closes: Vec<(Close, u64)>,
+    // open associations, each holding a relay socket
+    pub udp_associations: u64,
+    // microseconds
+    pub handshake: HistogramSnapshot,
+    pub dial: HistogramSnapshot,
+    // bytes per second
+    pub throughput: HistogramSnapshot,
+    pub listeners: Vec<ListenerStatus>,
+    pub users: Vec<UserStats>,
+    pub traffic: TrafficReport,
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::stats::Histogram;
+
+    #[test]
+    fn histogram_test() {
+        let histogram = Histogram::new(10, 4);
+        assert_eq!(histogram.snapshot().percentile(50.0), None);
+        for value in [1, 10, 11, 35, 500] {
+            histogram.record(value);
+        }
+        let snapshot = histogram.snapshot();
+        assert_eq!(snapshot.bounds, vec![10, 20, 40, 80]);
+        assert_eq!(snapshot.counts, vec![2, 1, 1, 0, 1]);
+        assert_eq!((snapshot.count(), snapshot.sum, snapshot.max), (5, 557, 500));
+        assert_eq!(snapshot.percentile(40.0), Some(10));
+        assert_eq!(snapshot.percentile(60.0), Some(20));
+        assert_eq!(snapshot.percentile(80.0), Some(40));
+        // past the last bound only the max is known
+        assert_eq!(snapshot.percentile(100.0), Some(500));
+    }
+}
@@ -0,0 +1,77 @@
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+// where a piece of state outlives the process, or doesn't; one document per store
+pub trait Store: Send + Sync {
+    // None until the first save
+    fn load(&self) -> io::Result<Option<String>>;
+    fn save(&self, content: &str) -> io::Result<()>;
+}
+
+// a file written whole and renamed into place, so a crash never leaves half of it
+pub struct FileStore {
+    path: PathBuf,
+}
+
+impl FileStore {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        FileStore { path: path.into() }
+    }
+}
+
+impl Store for FileStore {
+    fn load(&self) -> io::Result<Option<String>> {
+        match fs::read_to_string(&self.path) {
+            Ok(content) => Ok(Some(content)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn save(&self, content: &str) -> io::Result<()> {
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, content)?;
+        fs::rename(tmp, &self.path)
+    }
+}
+
+// kept for the life of the process only
+#[derive(Default)]
+pub struct MemoryStore {
+    content: Mutex<Option<String>>,
+}
+
+impl Store for MemoryStore {
+    fn load(&self) -> io::Result<Option<String>> {
+        Ok(self.content.lock().unwrap().clone())
+    }
+
+    fn save(&self, content: &str) -> io::Result<()> {
+        *self.content.lock().unwrap() = Some(content.to_string());
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::store::{FileStore, MemoryStore, Store};
+
+    #[test]
+    fn store_test() {
+        let path = std::env::temp_dir().join(format!("rust-ss5-store-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let stores: [Box<dyn Store>; 2] = [Box::new(FileStore::new(&path)), Box::new(MemoryStore::default())];
+        for store in &stores {
+            assert_eq!(store.load().unwrap(), None);
+            store.save("first").unwrap();
+            store.save("second").unwrap();
+            assert_eq!(store.load().unwrap().as_deref(), Some("second"));
+        }
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "second");
+        assert!(!path.with_extension("tmp").exists());
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -0,0 +1,126 @@
+use std::time::Duration;
+
+use log::{info, warn};
+use serde::Deserialize;
+use tokio::sync::watch;
+
+use crate::crypto::Keyring;
+use crate::transport::Endpoint;
+use crate::upstream::{Upstream, Upstreams};
+
+// https://shadowsocks.org/doc/sip008.html
+#[derive(Debug, Deserialize)]
+pub struct Sip008 {
+    pub version: u32,
+    pub servers: Vec<Sip008Server>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Sip008Server {
+    #[serde(default)]
+    pub id: String,
+    #[serde(default)]
+    pub remarks: String,
+    pub server: String,
+    pub server_port: u16,
+    pub password: String,
+    pub method: String,
+    #[serde(default)]
+    pub plugin: String,
+}
+
+// servers with unsupported methods or plugins are skipped with a warning
+pub fn parse(json: &str) -> Result<Vec<Upstream>, String> {
+    let config: Sip008 = serde_json::from_str(json).map_err(|e| e.to_string())?;
+    if config.version != 1 {
+        return Err(format!("unsupported sip008 version : {}", config.version));
+    }
+    let mut upstreams = Vec::new();
+    for server in config.servers {
+        let name = if server.remarks.is_empty() { server.id.clone() } else { server.remarks.clone() };
+        if !server.plugin.is_empty() {
+            warn!("skip server {} : plugin {} not supported", name, server.plugin);
+            continue;
+        }
+        let keyring = match Keyring::new(&server.method, &server.password, &[]) {
+            Ok(keyring) => keyring,
+            Err(e) => {
+                warn!("skip server {} : {}", name, e);
+                continue;
+            }
+        };
+        let host = if server.server.contains(':') && !server.server.starts_with('[') {
+            format!("[{}]", server.server)
+        } else {
+            server.server
+        };
+        upstreams.push(Upstream {
+            name,
+            endpoint: Endpoint::Tcp(format!("{}:{}", host, server.server_port)),
+            keyring,
+        });
+    }
+    Ok(upstreams)
+}
+
+pub async fn fetch(url: &str, timeout: Duration) -> Result<Vec<Upstream>, String> {
+    let url = url.to_string();
+    let agent: ureq::Agent = ureq::Agent::config_builder()
+        .timeout_global(Some(timeout.max(Duration::from_secs(1))))
+        .build()
+        .into();
+    let json = tokio::task::spawn_blocking(move || {
+        agent.get(&url).call()
+            .map_err(|e| e.to_string())?
+            .into_body()
+            .read_to_string()
+            .map_err(|e| e.to_string())
+    }).await.map_err(|e| e.to_string())??;
+    parse(&json)
+}
+
+// refetch every interval until shutdown, an empty or failed fetch keeps the old set
+pub async fn refresh(url: String, interval: Duration, timeout: Duration, upstreams: Upstreams, mut shutdown: watch::Receiver<bool>) {
+    loop {
+        tokio::select! {
+            _ = shutdown.changed() => break,
+            _ = tokio::time::sleep(interval) => match fetch(&url, timeout).await {
+                Ok(servers) if !servers.is_empty() => {
+                    upstreams.replace(servers);
+                    info!("subscription {} refreshed, {} servers, {} connections draining", url, upstreams.list().len(), upstreams.draining());
+                }
+                Ok(_) => warn!("subscription {} has no usable server", url),
+                Err(e) => warn!("fetch subscription {} fail : {}", url, e),
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::crypto::Method;
+    use crate::subscription::parse;
+    use crate::transport::Endpoint;
+
+    #[test]
+    fn parse_test() {
+        let json = r#"{
+            "version": 1,
+            "servers": [
+                {"id": "1", "remarks": "tokyo", "server": "example.com", "server_port": 8388, "password": "p", "method": "aes-256-gcm"},
+                {"id": "2", "server": "::1", "server_port": 8389, "password": "p", "method": "chacha20-ietf-poly1305", "plugin": ""},
+                {"id": "3", "server": "1.2.3.4", "server_port": 1, "password": "p", "method": "rc4-md5"},
+                {"id": "4", "server": "1.2.3.4", "server_port": 2, "password": "p", "method": "aes-128-gcm", "plugin": "obfs-local"}
+            ],
+            "bytes_used": 1
+        }"#;
+        let upstreams = parse(json).unwrap();
+        assert_eq!(upstreams.len(), 2);
+        assert_eq!(upstreams[0].name, "tokyo");
+        assert_eq!(upstreams[0].endpoint, Endpoint::Tcp("example.com:8388".to_string()));
+        assert_eq!(upstreams[0].keyring.method(), Method::Aes256Gcm);
+        assert_eq!(upstreams[1].endpoint, Endpoint::Tcp("[::1]:8389".to_string()));
+        assert!(parse(r#"{"version": 2, "servers": []}"#).is_err());
+    }
+}
@@ -0,0 +1,208 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+
+use log::{info, warn};
+
+const WINDOWS_KEY: &str = r"HKCU\Software\Microsoft\Windows\CurrentVersion\Internet Settings";
+const GNOME_PROXY: &str = "org.gnome.system.proxy";
+const GNOME_SOCKS: &str = "org.gnome.system.proxy.socks";
+
+// what the os is pointed at, the pac file when one is served so browsers follow `direct` too
+#[derive(Debug, Clone, PartialEq)]
+pub enum Target {
+    Socks(SocketAddr),
+    Pac(String),
+}
+
+// the os proxy settings as they were, put back by restore
+pub struct SystemProxy {
+    restore: Vec<Vec<String>>,
+}
+
+#[derive(Default)]
+struct Plan {
+    set: Vec<Vec<String>>,
+    restore: Vec<Vec<String>>,
+}
+
+impl SystemProxy {
+    // through the platform's own tool: reg on windows, networksetup on macos, gsettings elsewhere;
+    // apps already running may only see the change once restarted
+    pub async fn set(target: &Target) -> io::Result<Self> {
+        let plan = if cfg!(windows) {
+            windows(target).await?
+        } else if cfg!(target_os = "macos") {
+            macos(target).await?
+        } else if cfg!(unix) {
+            gnome(target).await?
+        } else {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "system_proxy isn't supported on this platform"));
+        };
+        let proxy = SystemProxy { restore: plan.restore };
+        if let Err(e) = apply(&plan.set).await {
+            // whatever got through is undone before giving up
+            let _ = proxy.restore().await;
+            return Err(e);
+        }
+        info!("system proxy set to {:?}", target);
+        Ok(proxy)
+    }
+
+    // every command is tried, the last failure is returned
+    pub async fn restore(&self) -> io::Result<()> {
+        let mut result = Ok(());
+        for command in &self.restore {
+            if let Err(e) = apply(std::slice::from_ref(command)).await {
+                warn!("restore system proxy fail : {}", e);
+                result = Err(e);
+            }
+        }
+        result
+    }
+}
+
+// None when the command ran and failed, e.g. reg query of a value that isn't there
+async fn run(command: &[String]) -> io::Result<Option<String>> {
+    let output = tokio::process::Command::new(&command[0]).args(&command[1..]).output().await
+        .map_err(|e| io::Error::new(e.kind(), format!("{} : {}", command[0], e)))?;
+    Ok(output.status.success().then(|| String::from_utf8_lossy(&output.stdout).into_owned()))
+}
+
+async fn apply(commands: &[Vec<String>]) -> io::Result<()> {
+    for command in commands {
+        if run(command).await?.is_none() {
+            return Err(io::Error::other(format!("{} failed", command.join(" "))));
+        }
+    }
+    Ok(())
+}
+
+fn command(args: &[&str]) -> Vec<String> {
+    args.iter().map(|arg| arg.to_string()).collect()
+}
+
+async fn gnome(target: &Target) -> io::Result<Plan> {
+    let mut plan = Plan::default();
+    for (schema, key, value) in gnome_values(target) {
+        let Some(old) = run(&command(&["gsettings", "get", schema, key])).await? else {
+            return Err(io::Error::other(format!("gsettings get {} {} failed", schema, key)));
+        };
+        plan.set.push(command(&["gsettings", "set", schema, key, &value]));
+        plan.restore.insert(0, command(&["gsettings", "set", schema, key, old.trim()]));
+    }
+    Ok(plan)
+}
+
+// the mode goes last, so the proxy isn't switched on before it's filled in
+fn gnome_values(target: &Target) -> Vec<(&'static str, &'static str, String)> {
+    match target {
+        Target::Socks(addr) => vec![
+            (GNOME_SOCKS, "host", format!("'{}'", addr.ip())),
+            (GNOME_SOCKS, "port", addr.port().to_string()),
+            (GNOME_PROXY, "mode", "'manual'".to_string()),
+        ],
+        Target::Pac(url) => vec![
+            (GNOME_PROXY, "autoconfig-url", format!("'{}'", url)),
+            (GNOME_PROXY, "mode", "'auto'".to_string()),
+        ],
+    }
+}
+
+async fn windows(target: &Target) -> io::Result<Plan> {
+    let values = match target {
+        Target::Socks(addr) => vec![("ProxyServer", "REG_SZ", format!("socks={}", addr)), ("ProxyEnable", "REG_DWORD", "1".to_string())],
+        Target::Pac(url) => vec![("AutoConfigURL", "REG_SZ", url.clone())],
+    };
+    let mut plan = Plan::default();
+    for (name, kind, data) in values {
+        let old = run(&command(&["reg", "query", WINDOWS_KEY, "/v", name])).await?;
+        plan.set.push(command(&["reg", "add", WINDOWS_KEY, "/v", name, "/t", kind, "/d", &data, "/f"]));
+        plan.restore.insert(0, match old.as_deref().and_then(|output| reg_value(output, name)) {
+            Some((kind, data)) => command(&["reg", "add", WINDOWS_KEY, "/v", name, "/t", &kind, "/d", &data, "/f"]),
+            None => command(&["reg", "delete", WINDOWS_KEY, "/v", name, "/f"]),
+        });
+    }
+    Ok(plan)
+}
+
+// "    ProxyEnable    REG_DWORD    0x1" from reg query, as the type and the data
+fn reg_value(output: &str, name: &str) -> Option<(String, String)> {
+    output.lines().find_map(|line| {
+        let mut fields = line.split_whitespace();
+        if fields.next()? != name {
+            return None;
+        }
+        let kind = fields.next()?.to_string();
+        Some((kind, fields.collect::<Vec<_>>().join(" ")))
+    })
+}
+
+async fn macos(target: &Target) -> io::Result<Plan> {
+    let Some(services) = run(&command(&["networksetup", "-listallnetworkservices"])).await? else {
+        return Err(io::Error::other("networksetup -listallnetworkservices failed"));
+    };
+    let mut plan = Plan::default();
+    for service in network_services(&services) {
+        let (get, set, state) = match target {
+            Target::Socks(_) => ("-getsocksfirewallproxy", "-setsocksfirewallproxy", "-setsocksfirewallproxystate"),
+            Target::Pac(_) => ("-getautoproxyurl", "-setautoproxyurl", "-setautoproxystate"),
+        };
+        let old = run(&command(&["networksetup", get, &service])).await?.unwrap_or_default();
+        let old = fields(&old);
+        match target {
+            Target::Socks(addr) => {
+                plan.set.push(command(&["networksetup", set, &service, &addr.ip().to_string(), &addr.port().to_string()]));
+                if let (Some(server), Some(port)) = (old.get("Server").filter(|s| !s.is_empty()), old.get("Port").filter(|p| **p != "0")) {
+                    plan.restore.push(command(&["networksetup", set, &service, server, port]));
+                }
+            }
+            Target::Pac(url) => {
+                plan.set.push(command(&["networksetup", set, &service, url]));
+                if let Some(url) = old.get("URL").filter(|url| **url != "(null)") {
+                    plan.restore.push(command(&["networksetup", set, &service, url]));
+                }
+            }
+        }
+        // setting the proxy turns it on, it's turned back off if it was
+        if old.get("Enabled") != Some(&"Yes") {
+            plan.restore.push(command(&["networksetup", state, &service, "off"]));
+        }
+    }
+    Ok(plan)
+}
+
+// the first line explains the asterisk, services starting with one are disabled
+fn network_services(output: &str) -> Vec<String> {
+    output.lines().skip(1)
+        .filter(|line| !line.is_empty() && !line.starts_with('*'))
+        .map(|line| line.to_string())
+        .collect()
+}
+
+// "Enabled: Yes\nServer: 127.0.0.1\nPort: 1080\n" as a map
+fn fields(output: &str) -> HashMap<&str, &str> {
+    output.lines().filter_map(|line| line.split_once(':')).map(|(key, value)| (key.trim(), value.trim())).collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::sysproxy::{fields, gnome_values, network_services, reg_value, Target};
+
+    #[test]
+    fn parse_test() {
+        let output = "\r\nHKEY_CURRENT_USER\\Software\\Microsoft\\Windows\\CurrentVersion\\Internet Settings\r\n    ProxyServer    REG_SZ    http=a:80;https=a:443\r\n";
+        assert_eq!(reg_value(output, "ProxyServer"), Some(("REG_SZ".to_string(), "http=a:80;https=a:443".to_string())));
+        assert_eq!(reg_value(output, "ProxyEnable"), None);
+
+        let output = "An asterisk (*) denotes that a network service is disabled.\nWi-Fi\n*Bluetooth PAN\nUSB 10/100 LAN\n";
+        assert_eq!(network_services(output), vec!["Wi-Fi", "USB 10/100 LAN"]);
+        let old = fields("Enabled: No\nServer: \nPort: 0\nAuthenticated Proxy Enabled: 0\n");
+        assert_eq!((old["Enabled"], old["Server"], old["Port"]), ("No", "", "0"));
+
+        let values = gnome_values(&Target::Socks("127.0.0.1:1080".parse().unwrap()));
+        assert_eq!(values.last().unwrap().2, "'manual'");
+        assert_eq!(values[0].2, "'127.0.0.1'");
+    }
+}
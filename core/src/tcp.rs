@@ -0,0 +1,1516 @@
+use std::net::IpAddr;
+use std::time::SystemTime;
+
+use bytes::BytesMut;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpStream, ToSocketAddrs};
+use tokio::time::{Duration, Instant};
+
+use crate::access::Access;
+use crate::bench::Builtin;
+use crate::config::{ProbeConfig, ProbeMode, RejectMode, RelayConfig, Resolve};
+use crate::crypto::{CipherStream, generate_key, UserSlot};
+use crate::error::Error;
+use crate::limit::LimitGuard;
+use crate::local::LocalState;
+use crate::obfs::ObfsStream;
+use crate::mux;
+use crate::mux::Session;
+use crate::policy;
+use crate::relay::{copy_bidirectional, copy_closing, Counted, relay, Traffic};
+use crate::server::ServerState;
+use crate::sniff;
+use crate::socket5::{self, Address, Command, ConnectReply, MethodSelection, Proxy, Reply, ShakeHands, UserPassAuth};
+use crate::socket5::constant::*;
+use crate::trace::ConnectionTrace;
+use crate::transport;
+use crate::transport::{Endpoint, RawStream, Stream};
+use crate::udp;
+use crate::udp::{ClientSource, SocksUdpSocket};
+use crate::unwind::CatchUnwind;
+use crate::upstream::Upstream;
+use log::{info, warn};
+
+
+pub enum Accepted<S> {
+    // the request was served, or failed, on the connection itself
+    Relayed,
+    // the client asked for a mux session, serve_mux takes it from here
+    Mux(TcpSocksClient<S>),
+}
+
+enum Handled {
+    Relayed,
+    Mux,
+}
+
+pub struct TcpSocksClient<S = TcpStream> {
+    stream: S,
+    user: UserSlot,
+    peer: Option<IpAddr>,
+    // the request was answered, a reply after that would land in the relayed bytes
+    replied: bool,
+}
+
+impl<S> TcpSocksClient<S>
+    where S: AsyncRead + AsyncWrite + Unpin
+{
+    pub fn new(stream: S) -> Self {
+        TcpSocksClient {
+            stream,
+            user: UserSlot::default(),
+            peer: None,
+            replied: false,
+        }
+    }
+
+    // the client's address, UDP ASSOCIATE only relays for datagrams coming from it
+    pub fn with_peer(mut self, peer: Option<IpAddr>) -> Self {
+        self.peer = peer;
+        self
+    }
+
+    // the user the transport identified, e.g. by a per-user tunnel key
+    pub fn with_user(mut self, user: UserSlot) -> Self {
+        self.user = user;
+        self
+    }
+
+    // serve one socks request, a client asking for a mux session is refused it
+    pub async fn server_connect(self, state: ServerState) -> Result<(), Error>
+        where S: RawStream
+    {
+        self.accept(state, false).await.map(|_| ())
+    }
+
+    // like server_connect, but hands the connection back when the client selects the mux method
+    pub async fn server_accept(self, state: ServerState) -> Result<Accepted<S>, Error>
+        where S: RawStream
+    {
+        self.accept(state, true).await
+    }
+
+    // every stream opened on the session is a socks connection of its own, under the session's user and peer
+    pub async fn serve_mux(self, state: ServerState)
+        where S: Send + 'static
+    {
+        let (_session, mut incoming) = Session::server(self.stream);
+        while let Some(stream) = incoming.accept().await {
+            let client = TcpSocksClient::new(stream).with_user(self.user.clone()).with_peer(self.peer);
+            tokio::spawn(client.server_connect(state.clone()));
+        }
+    }
+
+    async fn accept(mut self, state: ServerState, mux: bool) -> Result<Accepted<S>, Error>
+        where S: RawStream
+    {
+        let mut access = Access::new(self.peer);
+        let result = match CatchUnwind::new(Box::pin(self.handle(state.clone(), mux, &mut access))).await {
+            Ok(result) => result,
+            Err(panic) => Err(self.panicked(&state, &access, panic).await),
+        };
+        let error = match &result {
+            Ok(Handled::Mux) => return Ok(Accepted::Mux(self)),
+            Ok(Handled::Relayed) => None,
+            Err(e) => Some(e),
+        };
+        state.access.record(&access, error);
+        if access.proxy.is_some() {
+            // refused up front or cut off mid relay
+            if let Some(Error::QuotaExceeded) = error {
+                state.webhook.quota_exceeded(&access);
+            }
+            state.webhook.closed(&access, error);
+        }
+        if let Err(e) = &result {
+            if e.is_refusal() && state.config.reject == RejectMode::Reset {
+                let _ = self.stream.abort_on_close();
+            }
+        }
+        result.map(|_| Accepted::Relayed)
+    }
+
+    // the panic is counted and logged with what's known of the connection, a client still waiting
+    // on its reply is told the server failed
+    async fn panicked(&mut self, state: &ServerState, access: &Access, panic: String) -> Error {
+        state.stats.panicked();
+        let target = access.proxy.as_ref().map_or("-".to_string(), |proxy| proxy.address.to_string());
+        warn!("connection panicked : ip={} user={} target={} : {}",
+              access.source.map_or("-".to_string(), |ip| ip.to_string()), access.user.as_deref().unwrap_or("-"), target, panic);
+        if let (Some(proxy), false) = (&access.proxy, self.replied) {
+            let _ = ConnectReply::new(Reply::RepServerFail, proxy.address.clone()).write(&mut self.stream).await;
+        }
+        Error::Panicked(panic)
+    }
+
+    async fn handle(&mut self, state: ServerState, mux: bool, access: &mut Access) -> Result<Handled, Error>
+        where S: RawStream
+    {
+        let _connection = state.stats.connection();
+        let mut trace = state.tracer.connection();
+        let start = SystemTime::now();
+        let mut print = Fingerprint::new(self.peer);
+        let stream = &mut self.stream;
+        let hands = match ShakeHands::from(stream).await.map_err(Error::from) {
+            Ok(hands) => hands,
+            Err(e) => {
+                print.finish(&state, Some(&e));
+                // the wrapper that failed would fail every read, drain the socket under it
+                resist_probe(stream.raw(), &state.config.probe).await;
+                return Err(e);
+            }
+        };
+        print.hands(&hands);
+        // the greeting is all a client over its limit gets read, it's refused every method
+        let _source = match self.peer.map(|ip| state.sources.acquire_source(ip)).transpose() {
+            Ok(guard) => guard,
+            Err(e) => {
+                print.finish(&state, Some(&e));
+                jitter(&state.config.probe).await;
+                MethodSelection::new(METHOD_NO_ACCEPTABLE).write(stream).await?;
+                return Err(e);
+            }
+        };
+        if mux && hands.methods.contains(&METHOD_MUX) {
+            print.finish(&state, None);
+            MethodSelection::new(METHOD_MUX).write(stream).await?;
+            return Ok(Handled::Mux);
+        }
+        let (user, proxy, early) = match Self::authenticate(stream, &hands, &state, &self.user).await {
+            Ok(negotiated) => negotiated,
+            Err(e) => {
+                print.finish(&state, Some(&e));
+                if let Error::Socks(socket5::Error::AuthFailed(user)) = &e {
+                    state.webhook.auth_failed(self.peer, user);
+                    match self.peer {
+                        Some(ip) => {
+                            state.bans.failed(ip, user);
+                        }
+                        // unix socket peers are on this host, there is nothing to ban
+                        None => warn!("auth failure : ip=- user={}", user),
+                    }
+                }
+                return Err(e);
+            }
+        };
+        print.proxy(&proxy);
+        print.finish(&state, None);
+        access.user = user.clone();
+        access.proxy = Some(proxy.clone());
+        state.webhook.opened(access);
+        // a connect dialed with the auth status had its handshake done when the dial started
+        let handshaked = match &early {
+            Some(Early::Dialed(dialing)) => dialing.started,
+            _ => SystemTime::now(),
+        };
+        state.stats.handshake(handshaked.duration_since(start).unwrap_or_default());
+        trace.span("handshake", start);
+        trace.attribute("socks.command", &proxy.command);
+        trace.attribute("socks.target", &proxy.address);
+        info!("[{}] [{}] {:?}", trace.id(), user.as_deref().unwrap_or("-"), proxy);
+        if let Some(user) = &user {
+            state.stats.user_connection(user);
+            trace.attribute("socks.user", user);
+        }
+        let (admitted, mut dialing) = match early {
+            Some(Early::Refused(e)) => (Err(e), None),
+            Some(Early::Dialed(dialing)) => (Ok(()), Some(dialing)),
+            None => (admit(&state, user.as_deref(), &proxy), None),
+        };
+        if let Err(e) = admitted {
+            if let Error::Overloaded = e {
+                state.stats.shed();
+            }
+            trace.attribute("error", &e);
+            refuse(stream, e.to_reply(), proxy.address, &state.config.probe).await?;
+            return Err(e);
+        }
+        let builtin = Builtin::target(&proxy.address).filter(|builtin| builtin.enabled(state.config.diag, state.config.bench));
+        if let (Command::CONNECT, Some(builtin)) = (&proxy.command, builtin) {
+            ConnectReply::new(Reply::RepSuccess, proxy.address.clone()).write(stream).await?;
+            self.replied = true;
+            let started = SystemTime::now();
+            let traffic = access.traffic.clone();
+            let transfer = state.config.transfer(&proxy.address, None);
+            let result = relay(builtin.serve(Counted::new(&mut *stream, traffic.clone())), &traffic, &state, user.as_deref(), Some(&proxy.address), transfer).await;
+            Self::record_relay(&state, &mut trace, started, &traffic);
+            result?;
+        } else if proxy.command == Command::CONNECT {
+            let (_target, dial, dialed) = match dialing.take() {
+                Some(Dialing { target, started, dialed }) => (target, started, dialed),
+                None => {
+                    let target = match state.targets.acquire_target(&proxy.address) {
+                        Ok(guard) => guard,
+                        Err(e) => {
+                            trace.attribute("error", &e);
+                            refuse(stream, e.to_reply(), proxy.address, &state.config.probe).await?;
+                            return Err(e);
+                        }
+                    };
+                    let dial = SystemTime::now();
+                    (target, dial, dial_request(&state, &proxy.address).await)
+                }
+            };
+            let mut proxy_stream = match dialed {
+                Ok(proxy_stream) => proxy_stream,
+                Err(e) => {
+                    trace.span("dial", dial);
+                    trace.attribute("error", &e);
+                    refuse(stream, e.to_reply(), proxy.address, &state.config.probe).await?;
+                    return Err(e);
+                }
+            };
+            trace.span("dial", dial);
+            state.stats.dial(dial.elapsed().unwrap_or_default());
+            ConnectReply::new(Reply::RepSuccess, proxy.address.clone()).write(stream).await?;
+            self.replied = true;
+            // through a parent the peer is the parent, only an ip target is known
+            let ip = match &proxy.address {
+                Address::Address(addr) => Some(addr.ip()),
+                Address::DomainName(..) if state.config.parent.is_none() => proxy_stream.peer_addr().ok().map(|addr| addr.ip()),
+                Address::DomainName(..) => None,
+            };
+            let asn = ip.and_then(|ip| state.asns.lookup(ip));
+            if let Some(asn) = asn {
+                trace.attribute("target.asn", asn);
+            }
+            let priority = state.config.priority.priority(&proxy.address, asn);
+            let _class = state.scheduler.is_enabled().then(|| state.scheduler.open(priority));
+            trace.attribute("relay.priority", priority);
+            let started = SystemTime::now();
+            let traffic = access.traffic.clone();
+            let copy = async {
+                let mut client = Counted::new(&mut *stream, traffic.clone());
+                if let (true, Address::Address(addr)) = (state.config.sniff, &proxy.address) {
+                    let head = sniff::sniff(&mut client, Duration::from_millis(state.config.sniff_timeout)).await?;
+                    if let Some(host) = head.host {
+                        info!("[{}] {} is {}", trace.id(), addr, host);
+                        trace.attribute("sniff.host", &host);
+                        state.blocklist.check(&Address::DomainName(host, addr.port()))?;
+                    }
+                    proxy_stream.write_all(&head.bytes).await?;
+                }
+                let buffers = &state.config.relay;
+                let (mut client, mut target) = (state.scheduler.pace(client, priority), state.scheduler.pace(&mut proxy_stream, priority));
+                let (close, copied) = copy_closing(&mut client, &mut target, buffers.up_buffer.max(1), buffers.down_buffer.max(1), buffers.idle()).await;
+                traffic.set_close(close);
+                copied?;
+                Ok(())
+            };
+            let result = relay(copy, &traffic, &state, user.as_deref(), Some(&proxy.address), state.config.transfer(&proxy.address, asn)).await;
+            Self::record_relay(&state, &mut trace, started, &traffic);
+            result?;
+        } else if proxy.command == Command::UDP && state.config.parent.is_some() {
+            // the parent only carries tcp here, datagrams would leave the network on their own
+            let e = Error::Socks(socket5::Error::CommandNo(CMD_UDP));
+            refuse(stream, e.to_reply(), proxy.address, &state.config.probe).await?;
+            return Err(e);
+        } else if proxy.command == Command::UDP {
+            // the association replies itself
+            self.replied = true;
+            let started = SystemTime::now();
+            let source = ClientSource::new(self.peer, &proxy.address);
+            let traffic = access.traffic.clone();
+            let result = relay(udp::associate(stream, &state, source, &traffic), &traffic, &state, user.as_deref(), None, None).await;
+            Self::record_relay(&state, &mut trace, started, &traffic);
+            result?;
+        }
+        Ok(Handled::Relayed)
+    }
+
+    fn record_relay(state: &ServerState, trace: &mut ConnectionTrace, start: SystemTime, traffic: &Traffic) {
+        state.stats.throughput(traffic.up() + traffic.down(), start.elapsed().unwrap_or_default());
+        trace.span("relay", start);
+        trace.attribute("bytes.up", traffic.up());
+        trace.attribute("bytes.down", traffic.down());
+        if let Some(close) = traffic.close() {
+            info!("[{}] closed {} : up={} down={}", trace.id(), close, traffic.up(), traffic.down());
+            trace.attribute("relay.close", close);
+        }
+    }
+
+    // pick the method, run the username/password sub-negotiation when users require it and read
+    // the request; once the auth passed, a connect pipelined behind it is dialed while its status
+    // is still being written
+    async fn authenticate(stream: &mut S, hands: &ShakeHands, state: &ServerState, user: &UserSlot) -> Result<(Option<String>, Proxy, Option<Early>), Error> {
+        if let Some(user) = user.get() {
+            MethodSelection::new(METHOD_NO_AUTHENTICATION).write(stream).await?;
+            return Ok((Some(user.clone()), Proxy::from(stream).await?, None));
+        }
+        if state.passwords.is_empty() {
+            MethodSelection::new(METHOD_NO_AUTHENTICATION).write(stream).await?;
+            return Ok((None, Proxy::from(stream).await?, None));
+        }
+        if !hands.methods.contains(&METHOD_USERNAME_PASSWORD) {
+            jitter(&state.config.probe).await;
+            MethodSelection::new(METHOD_NO_ACCEPTABLE).write(stream).await?;
+            return Err(Error::Socks(socket5::Error::MethodNo(METHOD_NO_ACCEPTABLE)));
+        }
+        MethodSelection::new(METHOD_USERNAME_PASSWORD).write(stream).await?;
+        let auth = UserPassAuth::from(stream).await?;
+        // the check is a lookup, nothing is done for the request before it passed
+        if state.passwords.get(&auth.username) != Some(&auth.password) {
+            jitter(&state.config.probe).await;
+            UserPassAuth::write_status(stream, false).await?;
+            return Err(Error::Socks(socket5::Error::AuthFailed(auth.username)));
+        }
+        let (mut reader, mut writer) = tokio::io::split(&mut *stream);
+        let status = async { Ok::<_, Error>(UserPassAuth::write_status(&mut writer, true).await?) };
+        let request = async {
+            let proxy = Proxy::from(&mut reader).await?;
+            let early = dial_early(state, &auth.username, &proxy).await;
+            Ok::<_, Error>((proxy, early))
+        };
+        let ((), (proxy, early)) = tokio::try_join!(status, request)?;
+        Ok((Some(auth.username), proxy, early))
+    }
+
+    // local side: accept the socks request and forward it through the remote server
+    pub async fn local_connect(mut self, state: LocalState) -> Result<(), Error> {
+        let stream = &mut self.stream;
+        let proxy = Self::accept_proxy(stream).await?;
+        if let Err(e) = state.blocklist.check(&proxy.address) {
+            ConnectReply::new(e.to_reply(), proxy.address).write(stream).await?;
+            return Err(e);
+        }
+        if proxy.command == Command::CONNECT && state.direct(&proxy.address) {
+            return Self::connect_direct(stream, proxy, &state).await;
+        }
+        let proxy = match Self::resolve_local(&proxy, &state).await {
+            Ok(proxy) => proxy,
+            Err(e) => {
+                ConnectReply::new(e.to_reply(), proxy.address).write(stream).await?;
+                return Err(e);
+            }
+        };
+        let (upstream, _lease) = match state.upstreams.pick() {
+            Some(picked) => picked,
+            None => {
+                ConnectReply::new(Reply::RepServerFail, proxy.address).write(stream).await?;
+                return Err(Error::from(std::io::Error::other("no upstream server")));
+            }
+        };
+        if state.config.mux {
+            let key = upstream.endpoint.to_string();
+            // the client's data only flows after the reply, so a stream lost with its session is
+            // tried once more on a fresh one instead of failing the request
+            let mut retry = true;
+            let remote = loop {
+                let error = match state.sessions.get(&key, dial_session(&state, &upstream)).await {
+                    Err(e) => e,
+                    Ok(session) => match session.open() {
+                        Err(e) => Error::from(e),
+                        Ok(remote) => match TcpSocksClient::handshake_with(remote, proxy.clone(), &state.upstream_options()).await {
+                            Ok(remote) => break remote,
+                            // the upstream's own answer, the session is fine
+                            Err(e) if !session.is_closed() => {
+                                ConnectReply::new(e.to_reply(), proxy.address).write(stream).await?;
+                                return Err(e);
+                            }
+                            Err(e) => e,
+                        },
+                    },
+                };
+                if !std::mem::take(&mut retry) {
+                    ConnectReply::new(Reply::RepServerFail, proxy.address).write(stream).await?;
+                    return Err(error);
+                }
+                info!("mux session to {} lost, retrying on a new one : {}", key, error);
+            };
+            return Self::relay_local(stream, remote, &state.config.relay).await;
+        }
+        let remote = match upstream.endpoint.connect_with(state.protect.as_ref()).await {
+            Ok(remote) => remote,
+            Err(e) => {
+                // the target was never tried, don't pass this off as its refusal
+                ConnectReply::new(Reply::RepServerFail, proxy.address).write(stream).await?;
+                return Err(Error::from(e));
+            }
+        };
+        match state.config.obfs {
+            None => Self::forward_local(stream, remote, &upstream, proxy, &state).await,
+            Some(mode) => {
+                let host = state.config.obfs_host(&upstream.endpoint);
+                Self::forward_local(stream, ObfsStream::client(remote, mode, &host), &upstream, proxy, &state).await
+            }
+        }
+    }
+
+    // the request as sent on, with the domain swapped for its first address when a rule says so
+    async fn resolve_local(proxy: &Proxy, state: &LocalState) -> Result<Proxy, Error> {
+        let Address::DomainName(host, port) = &proxy.address else {
+            return Ok(proxy.clone());
+        };
+        if proxy.command != Command::CONNECT || state.config.resolve(&proxy.address) != Resolve::Local {
+            return Ok(proxy.clone());
+        }
+        // answered as host-unreachable, as the server would for a name it can't resolve
+        let unresolved = |e| Error::from(std::io::Error::new(std::io::ErrorKind::HostUnreachable, e));
+        let addr = tokio::net::lookup_host((host.as_str(), *port)).await.map_err(unresolved)?.next();
+        match addr {
+            Some(addr) => Ok(Proxy::new(Command::CONNECT, Address::Address(addr))),
+            None => Err(Error::Socks(socket5::Error::AddressDomainNo)),
+        }
+    }
+
+    // a `direct` target, dialed from here as the server would
+    async fn connect_direct(stream: &mut S, proxy: Proxy, state: &LocalState) -> Result<(), Error> {
+        let protect = state.protect.as_ref();
+        let connected = match &proxy.address {
+            Address::Address(addr) => transport::connect_tcp(*addr, protect).await,
+            Address::DomainName(host, port) => transport::connect_tcp((host.as_str(), *port), protect).await,
+        };
+        let mut remote = match connected {
+            Ok(remote) => remote,
+            Err(e) => {
+                let e = Error::from(e);
+                ConnectReply::new(e.to_reply(), proxy.address).write(stream).await?;
+                return Err(e);
+            }
+        };
+        let buffers = &state.config.relay;
+        ConnectReply::new(Reply::RepSuccess, Address::Address(remote.local_addr()?)).write(stream).await?;
+        copy_bidirectional(stream, &mut remote, buffers.up_buffer.max(1), buffers.down_buffer.max(1)).await?;
+        Ok(())
+    }
+
+    async fn forward_local<R>(stream: &mut S, remote: R, upstream: &Upstream, proxy: Proxy, state: &LocalState) -> Result<(), Error>
+        where R: AsyncRead + AsyncWrite + Unpin
+    {
+        if upstream.keyring.is_plain() {
+            Self::relay_handshake(stream, remote, proxy, state).await
+        } else {
+            Self::relay_handshake(stream, CipherStream::client(remote, &upstream.keyring), proxy, state).await
+        }
+    }
+
+    // a failed handshake is answered with the upstream's own reply, not a generic failure
+    async fn relay_handshake<R>(stream: &mut S, remote: R, proxy: Proxy, state: &LocalState) -> Result<(), Error>
+        where R: AsyncRead + AsyncWrite + Unpin
+    {
+        let address = proxy.address.clone();
+        match TcpSocksClient::handshake_with(remote, proxy, &state.upstream_options()).await {
+            Ok(remote) => Self::relay_local(stream, remote, &state.config.relay).await,
+            Err(e) => {
+                ConnectReply::new(e.to_reply(), address).write(stream).await?;
+                Err(e)
+            }
+        }
+    }
+
+    async fn relay_local<R>(stream: &mut S, mut remote: SocksStream<R>, buffers: &RelayConfig) -> Result<(), Error>
+        where R: AsyncRead + AsyncWrite + Unpin
+    {
+        ConnectReply::new(Reply::RepSuccess, remote.bound.clone()).write(stream).await?;
+        copy_bidirectional(stream, &mut remote.stream, buffers.up_buffer.max(1), buffers.down_buffer.max(1)).await?;
+        Ok(())
+    }
+
+    async fn accept_proxy(stream: &mut S) -> Result<Proxy, Error> {
+        let _hands = ShakeHands::from(stream).await?;
+        MethodSelection::new(METHOD_NO_AUTHENTICATION).write(stream).await?;
+        let proxy = Proxy::from(stream).await?;
+        info!("{:?}", proxy);
+        Ok(proxy)
+    }
+
+    // negotiate with a socks5 server over an already connected stream
+    pub async fn handshake(stream: S, proxy: Proxy) -> Result<SocksStream<S>, Error> {
+        TcpSocksClient::handshake_with(stream, proxy, &ClientOptions::default()).await
+    }
+
+    // with credentials username/password auth is offered too, and used when the server picks it
+    pub async fn handshake_with(mut stream: S, proxy: Proxy, options: &ClientOptions) -> Result<SocksStream<S>, Error> {
+        let auth = options.credentials.as_ref().map(|credentials| UserPassAuth::new(credentials.username.clone(), credentials.password.clone()));
+        // before anything is sent, rather than once the server has picked the method
+        if let Some(auth) = &auth {
+            auth.validate()?;
+        }
+        if options.fast_open {
+            // the one method there's auth for, so the server can't pick one the bytes after don't fit
+            let method = if auth.is_some() { METHOD_USERNAME_PASSWORD } else { METHOD_NO_AUTHENTICATION };
+            let mut buf = BytesMut::new();
+            ShakeHands::new(vec![method]).encode(&mut buf)?;
+            if let Some(auth) = &auth {
+                auth.encode(&mut buf)?;
+            }
+            proxy.encode(&mut buf)?;
+            stream.write_all(&buf).await?;
+        } else {
+            let mut methods = vec![METHOD_NO_AUTHENTICATION];
+            if auth.is_some() {
+                methods.push(METHOD_USERNAME_PASSWORD);
+            }
+            ShakeHands::new(methods).write(&mut stream).await?;
+        }
+        let selection = MethodSelection::from(&mut stream).await?;
+        match (selection.method, &auth) {
+            (METHOD_NO_AUTHENTICATION, None) => {}
+            (METHOD_NO_AUTHENTICATION, Some(_)) if !options.fast_open => {}
+            (METHOD_USERNAME_PASSWORD, Some(auth)) => {
+                if !options.fast_open {
+                    auth.write(&mut stream).await?;
+                }
+                if !UserPassAuth::read_status(&mut stream).await? {
+                    return Err(Error::Socks(socket5::Error::AuthFailed(auth.username.clone())));
+                }
+            }
+            (method, _) => return Err(Error::Socks(socket5::Error::MethodNo(method))),
+        }
+        if !options.fast_open {
+            proxy.write(&mut stream).await?;
+        }
+        let ConnectReply { reply, bound } = ConnectReply::from(&mut stream).await?.into_result()?;
+        Ok(SocksStream { stream, reply, bound })
+    }
+
+    // BIND for the peer at the address, port 0 when it isn't known; the bound address of the
+    // first reply is where the peer is to connect, accept waits for it to
+    pub async fn bind(stream: S, peer: Address, options: &ClientOptions) -> Result<SocksBind<S>, Error> {
+        let SocksStream { stream, bound, .. } = TcpSocksClient::handshake_with(stream, Proxy::new(Command::BIND, peer), options).await?;
+        Ok(SocksBind { stream, bound })
+    }
+}
+
+// the policy checks a request passes before anything is dialed for it
+fn admit(state: &ServerState, user: Option<&str>, proxy: &Proxy) -> Result<(), Error> {
+    state.maintenance.check()?;
+    state.shedder.check(state.stats.connections())?;
+    if !state.quota.check(user) {
+        return Err(Error::QuotaExceeded);
+    }
+    if proxy.command == Command::CONNECT {
+        state.blocklist.check(&proxy.address)?;
+    }
+    Ok(())
+}
+
+// a connect dialed while the status of the auth it was pipelined behind was still being written
+struct Dialing {
+    target: LimitGuard,
+    started: SystemTime,
+    dialed: Result<TcpStream, Error>,
+}
+
+// what a request read with the auth status got before it was handled
+enum Early {
+    Refused(Error),
+    Dialed(Dialing),
+}
+
+// a connect read with the auth status, under the user that passed
+async fn dial_early(state: &ServerState, user: &str, proxy: &Proxy) -> Option<Early> {
+    let builtin = Builtin::target(&proxy.address).filter(|builtin| builtin.enabled(state.config.diag, state.config.bench));
+    if proxy.command != Command::CONNECT || builtin.is_some() {
+        return None;
+    }
+    if let Err(e) = admit(state, Some(user), proxy) {
+        return Some(Early::Refused(e));
+    }
+    let target = match state.targets.acquire_target(&proxy.address) {
+        Ok(guard) => guard,
+        Err(e) => return Some(Early::Refused(e)),
+    };
+    let started = SystemTime::now();
+    let dialed = dial_request(state, &proxy.address).await;
+    Some(Early::Dialed(Dialing { target, started, dialed }))
+}
+
+async fn dial_request(state: &ServerState, requested: &Address) -> Result<TcpStream, Error> {
+    let target = match policy::permitted(requested, state.config.blocks_private(), &state.resolver, &state.nat64).await? {
+        Address::Address(addr) if state.config.parent.is_none() => Address::Address(state.nat64.map(addr).await),
+        target => target,
+    };
+    state.refuse_loop(requested, &target).await?;
+    let proxy_stream = dial_target(state, requested, target).await?;
+    state.reject_loop(requested, &proxy_stream)?;
+    Ok(proxy_stream)
+}
+
+// a server's connection to a target, matched against outbound rules by the requested address;
+// with a parent proxy the connection to it is what the rule binds
+async fn dial_target(state: &ServerState, requested: &Address, target: Address) -> Result<TcpStream, Error> {
+    let rule = state.config.outbound(requested);
+    let (bind, interface) = rule.map_or((None, None), |rule| (rule.bind, rule.interface.as_deref()));
+    let Some(parent) = &state.config.parent else {
+        // pooled connections leave the default way, those of a rule are dialed as it says
+        return match (rule, &target) {
+            (None, _) => state.pool.connect(&target).await,
+            (Some(_), Address::Address(addr)) => Ok(transport::connect_bound(*addr, bind, interface).await?),
+            (Some(_), Address::DomainName(host, port)) => Ok(transport::connect_bound((host.as_str(), *port), bind, interface).await?),
+        };
+    };
+    let stream = match rule {
+        Some(_) => transport::connect_bound(parent.server.as_str(), bind, interface).await?,
+        None => TcpStream::connect(parent.server.as_str()).await?,
+    };
+    let credentials = parent.username.as_deref().zip(parent.password.as_deref()).map(|(username, password)| Credentials::new(username, password));
+    let remote = TcpSocksClient::handshake_with(stream, Proxy::new(Command::CONNECT, target), &ClientOptions { credentials, ..ClientOptions::default() }).await?;
+    Ok(remote.stream)
+}
+
+// one tunnel connection for every request to the upstream, wrapped just like a connection of their own
+async fn dial_session(state: &LocalState, upstream: &Upstream) -> Result<Session, Error> {
+    let remote = upstream.endpoint.connect_with(state.protect.as_ref()).await?;
+    match state.config.obfs {
+        None => mux_session(remote, upstream).await,
+        Some(mode) => {
+            let host = state.config.obfs_host(&upstream.endpoint);
+            mux_session(ObfsStream::client(remote, mode, &host), upstream).await
+        }
+    }
+}
+
+async fn mux_session<R>(remote: R, upstream: &Upstream) -> Result<Session, Error>
+    where R: AsyncRead + AsyncWrite + Unpin + Send + 'static
+{
+    if upstream.keyring.is_plain() {
+        mux::connect(remote).await
+    } else {
+        mux::connect(CipherStream::client(remote, &upstream.keyring)).await
+    }
+}
+
+// an error reply, held back like every other refusal so its timing doesn't give the reason away
+async fn refuse<W: AsyncWrite + Unpin>(stream: &mut W, reply: Reply, address: Address, probe: &ProbeConfig) -> Result<(), Error> {
+    jitter(probe).await;
+    Ok(ConnectReply::new(reply, address).write(stream).await?)
+}
+
+// a random pause of up to probe.jitter milliseconds
+async fn jitter(probe: &ProbeConfig) {
+    if probe.jitter == 0 {
+        return;
+    }
+    let delay = match generate_key(8) {
+        Ok(random) => u64::from_be_bytes(random.try_into().unwrap()) % (probe.jitter + 1),
+        Err(_) => probe.jitter,
+    };
+    tokio::time::sleep(Duration::from_millis(delay)).await;
+}
+
+// garbage or a bad mac, don't give a prober an answer to fingerprint
+async fn resist_probe<R: AsyncRead + Unpin>(stream: &mut R, probe: &ProbeConfig) {
+    if probe.mode == ProbeMode::Close {
+        return;
+    }
+    let max = probe.max_delay.saturating_mul(1000);
+    let delay = match generate_key(8) {
+        Ok(random) => max / 2 + u64::from_be_bytes(random.try_into().unwrap()) % (max / 2 + 1),
+        Err(_) => max,
+    };
+    let deadline = Instant::now() + Duration::from_millis(delay);
+    if probe.mode == ProbeMode::Drain {
+        let mut buf = [0; 4096];
+        // a read error means the peer reset, it then just waits
+        while let Ok(Ok(n)) = tokio::time::timeout_at(deadline, stream.read(&mut buf)).await {
+            if n == 0 {
+                return;
+            }
+        }
+    }
+    tokio::time::sleep_until(deadline).await;
+}
+
+impl TcpSocksClient {
+    pub async fn client_connect<A: ToSocketAddrs>(addr: A, proxy: Proxy) -> Result<SocksStream<TcpStream>, Error> {
+        let stream = TcpStream::connect(addr).await?;
+        TcpSocksClient::handshake(stream, proxy).await
+    }
+
+    pub async fn client_connect_with<A: ToSocketAddrs>(addr: A, proxy: Proxy, options: &ClientOptions) -> Result<SocksStream<TcpStream>, Error> {
+        let stream = TcpStream::connect(addr).await?;
+        TcpSocksClient::handshake_with(stream, proxy, options).await
+    }
+
+    pub async fn client_bind<A: ToSocketAddrs>(addr: A, peer: Address) -> Result<SocksBind<TcpStream>, Error> {
+        let stream = TcpStream::connect(addr).await?;
+        TcpSocksClient::bind(stream, peer, &ClientOptions::default()).await
+    }
+
+    // keep the returned socket, dropping it closes the association
+    pub async fn udp_associate<A: ToSocketAddrs>(addr: A) -> Result<SocksUdpSocket, Error> {
+        let stream = TcpStream::connect(addr).await?;
+        let unspecified = Address::Address((std::net::Ipv4Addr::UNSPECIFIED, 0).into());
+        let SocksStream { stream, bound, .. } = TcpSocksClient::handshake(stream, Proxy::new(Command::UDP, unspecified)).await?;
+        SocksUdpSocket::associate(stream, bound).await
+    }
+
+    #[cfg(unix)]
+    pub async fn client_connect_unix<P: AsRef<std::path::Path>>(path: P, proxy: Proxy) -> Result<SocksStream<tokio::net::UnixStream>, Error> {
+        let stream = tokio::net::UnixStream::connect(path).await?;
+        TcpSocksClient::handshake(stream, proxy).await
+    }
+
+    pub async fn client_connect_endpoint(endpoint: &Endpoint, proxy: Proxy) -> Result<SocksStream<Stream>, Error> {
+        let stream = endpoint.connect().await?;
+        TcpSocksClient::handshake(stream, proxy).await
+    }
+}
+
+// how the client side negotiates with the proxy
+#[derive(Debug, Clone, Default)]
+pub struct ClientOptions {
+    // for proxies requiring RFC 1929 username/password auth
+    pub credentials: Option<Credentials>,
+    // send the greeting, auth and request in one go instead of waiting for each answer, saving a
+    // round trip or two; only offers the one method, so the server has to accept it
+    pub fast_open: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl Credentials {
+    pub fn new(username: &str, password: &str) -> Self {
+        Credentials { username: username.to_string(), password: password.to_string() }
+    }
+}
+
+// a stream on which the socks request has been answered with success
+pub struct SocksStream<S> {
+    pub stream: S,
+    pub reply: Reply,
+    pub bound: Address,
+}
+
+// a BIND the server is listening for
+pub struct SocksBind<S> {
+    stream: S,
+    // the server's listening address, to hand to the peer
+    pub bound: Address,
+}
+
+impl<S> SocksBind<S>
+    where S: AsyncRead + Unpin
+{
+    // the second reply, once the peer has connected; its bound address is the peer's, and the
+    // stream carries the peer's connection from here
+    pub async fn accept(mut self) -> Result<SocksStream<S>, Error> {
+        let ConnectReply { reply, bound } = ConnectReply::from(&mut self.stream).await?.into_result()?;
+        Ok(SocksStream { stream: self.stream, reply, bound })
+    }
+}
+
+
+// how a client went through the handshake, for telling broken clients and scanners apart
+struct Fingerprint {
+    peer: Option<IpAddr>,
+    start: Instant,
+    methods: Option<Vec<u8>>,
+    // from the connection to the method list, and to the request
+    hello: Option<Duration>,
+    request: Option<Duration>,
+    command: Option<Command>,
+    address: Option<&'static str>,
+}
+
+impl Fingerprint {
+    fn new(peer: Option<IpAddr>) -> Self {
+        Fingerprint { peer, start: Instant::now(), methods: None, hello: None, request: None, command: None, address: None }
+    }
+
+    fn hands(&mut self, hands: &ShakeHands) {
+        self.methods = Some(hands.methods.clone());
+        self.hello = Some(self.start.elapsed());
+    }
+
+    fn proxy(&mut self, proxy: &Proxy) {
+        self.request = Some(self.start.elapsed());
+        self.command = Some(proxy.command.clone());
+        self.address = Some(match &proxy.address {
+            Address::Address(addr) if addr.is_ipv4() => "ipv4",
+            Address::Address(_) => "ipv6",
+            Address::DomainName(..) => "domain",
+        });
+    }
+
+    fn finish(&self, state: &ServerState, error: Option<&Error>) {
+        if state.config.fingerprint {
+            info!("{}", self.line(error));
+        }
+    }
+
+    // a fixed key=value shape, "-" for what the client never got to
+    fn line(&self, error: Option<&Error>) -> String {
+        let ms = |d: Option<Duration>| d.map_or("-".to_string(), |d| d.as_millis().to_string());
+        let methods = match &self.methods {
+            Some(methods) => methods.iter().map(|m| format!("{:02x}", m)).collect::<Vec<_>>().join(","),
+            None => "-".to_string(),
+        };
+        format!(
+            "fingerprint : ip={} methods={} hello_ms={} request_ms={} command={} atyp={} error={}",
+            self.peer.map_or("-".to_string(), |ip| ip.to_string()),
+            methods,
+            ms(self.hello),
+            ms(self.request),
+            self.command.as_ref().map_or("-".to_string(), |c| c.to_string()),
+            self.address.unwrap_or("-"),
+            // quoted, errors have spaces
+            error.map_or("-".to_string(), |e| format!("{:?}", e.to_string())),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, ReadBuf};
+    use tokio::net::{TcpListener, TcpStream};
+
+    use crate::asn::AsnTable;
+    use crate::config::{BlocklistConfig, OutboundRule, ParentConfig, ProbeConfig, ProbeMode, QuotaConfig, RejectMode, RelayConfig, ServerConfig, ShedConfig, TransferAction, TransferRule, UserConfig};
+    use crate::error::Error;
+    use crate::relay::Close;
+    use crate::server::start;
+    use crate::socket5::{self, Address, Command, ConnectReply, MethodSelection, Proxy, Reply, ShakeHands, UserPassAuth};
+    use crate::socket5::constant::*;
+    use crate::tcp::{ClientOptions, Credentials, Fingerprint, jitter, TcpSocksClient};
+    use crate::test_util::{assert_echo, client_hello_record, config, echo_server, socks_server, test_state};
+    use crate::testing::{duplex, FakeServer};
+    use crate::transport::{Endpoint, RawStream};
+
+    #[tokio::test]
+    async fn client_connect_test() {
+        let echo = echo_server().await;
+        let server = socks_server(Endpoint::Tcp("127.0.0.1:0".to_string()), test_state(config())).await;
+        let mut client = TcpSocksClient::client_connect(
+            server.to_string(),
+            Proxy::new(
+                Command::CONNECT,
+                Address::DomainName("localhost".to_string(), echo.port()),
+            ),
+        ).await.unwrap();
+        assert_eq!(client.reply, Reply::RepSuccess);
+        assert_echo(&mut client.stream).await;
+    }
+
+    #[tokio::test]
+    async fn client_connect_rejected_test() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let _hands = ShakeHands::from(&mut stream).await.unwrap();
+            stream.write_all(&[SOCKET5_VERSION, METHOD_NO_AUTHENTICATION]).await.unwrap();
+            let proxy = Proxy::from(&mut stream).await.unwrap();
+            ConnectReply::new(Reply::RepConnRefused, proxy.address).write(&mut stream).await.unwrap();
+        });
+        let result = TcpSocksClient::client_connect(
+            addr,
+            Proxy::new(Command::CONNECT, Address::DomainName("localhost".to_string(), 1)),
+        ).await;
+        match result {
+            Err(Error::Socks(socket5::Error::Rejected(reply))) => assert_eq!(reply, Reply::RepConnRefused),
+            _ => panic!("expected the request to be rejected"),
+        }
+    }
+
+    #[tokio::test]
+    async fn bind_test() {
+        // a server doing BIND the RFC 1928 way, with the peer relayed over the control connection
+        let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = server.accept().await.unwrap();
+            ShakeHands::from(&mut stream).await.unwrap();
+            stream.write_all(&[SOCKET5_VERSION, METHOD_NO_AUTHENTICATION]).await.unwrap();
+            let proxy = Proxy::from(&mut stream).await.unwrap();
+            assert_eq!(proxy.command, Command::BIND);
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            ConnectReply::new(Reply::RepSuccess, Address::Address(listener.local_addr().unwrap())).write(&mut stream).await.unwrap();
+            let (mut peer, from) = listener.accept().await.unwrap();
+            ConnectReply::new(Reply::RepSuccess, Address::Address(from)).write(&mut stream).await.unwrap();
+            tokio::io::copy_bidirectional(&mut stream, &mut peer).await.unwrap();
+        });
+
+        let bind = TcpSocksClient::client_bind(addr, "127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let Address::Address(listening) = bind.bound.clone() else { panic!("expected an ip") };
+        let mut peer = TcpStream::connect(listening).await.unwrap();
+        let mut socks = bind.accept().await.unwrap();
+        assert_eq!(socks.bound, Address::Address(peer.local_addr().unwrap()));
+        peer.write_all(b"hello").await.unwrap();
+        let mut buf = [0; 5];
+        socks.stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+
+        // the peer never came, or came from somewhere it shouldn't
+        let (client, server) = duplex();
+        tokio::spawn(async move {
+            let (_, mut stream) = FakeServer::new().reply(Reply::RepSuccess, "192.0.2.1:4000".parse().unwrap()).accept(server).await.unwrap();
+            ConnectReply::new(Reply::RepConnNo, "0.0.0.0:0".parse().unwrap()).write(&mut stream).await.unwrap();
+        });
+        let bind = TcpSocksClient::bind(client, "192.0.2.2:0".parse().unwrap(), &ClientOptions::default()).await.unwrap();
+        assert_eq!(bind.bound, "192.0.2.1:4000".parse().unwrap());
+        assert!(matches!(bind.accept().await, Err(Error::Socks(socket5::Error::Rejected(Reply::RepConnNo)))));
+    }
+
+    #[tokio::test]
+    async fn username_password_auth_test() {
+        let echo = echo_server().await;
+        let state = test_state(ServerConfig {
+            users: vec![UserConfig {
+                name: "alice".to_string(),
+                password: Some("secret".to_string()),
+                ..UserConfig::default()
+            }],
+            ..config()
+        });
+        let stats = state.stats.clone();
+        let server = socks_server(Endpoint::Tcp("127.0.0.1:0".to_string()), state).await;
+        for (password, success) in [("wrong", false), ("secret", true)] {
+            let mut stream = TcpStream::connect(server.to_string()).await.unwrap();
+            ShakeHands::new(vec![METHOD_NO_AUTHENTICATION, METHOD_USERNAME_PASSWORD]).write(&mut stream).await.unwrap();
+            let mut selection = [0; 2];
+            stream.read_exact(&mut selection).await.unwrap();
+            assert_eq!(selection, [SOCKET5_VERSION, METHOD_USERNAME_PASSWORD]);
+            UserPassAuth::new("alice".to_string(), password.to_string()).write(&mut stream).await.unwrap();
+            assert_eq!(UserPassAuth::read_status(&mut stream).await.unwrap(), success);
+            if success {
+                Proxy::new(Command::CONNECT, Address::Address(echo)).write(&mut stream).await.unwrap();
+                ConnectReply::from(&mut stream).await.unwrap().into_result().unwrap();
+                assert_echo(&mut stream).await;
+            }
+        }
+        assert_eq!(stats.users()[0].name, "alice");
+        assert_eq!(stats.users()[0].connections, 1);
+    }
+
+    #[tokio::test]
+    async fn client_credentials_test() {
+        let echo = echo_server().await;
+        let state = test_state(ServerConfig {
+            users: vec![UserConfig {
+                name: "alice".to_string(),
+                password: Some("secret".to_string()),
+                ..UserConfig::default()
+            }],
+            ..config()
+        });
+        let server = socks_server(Endpoint::Tcp("127.0.0.1:0".to_string()), state).await.to_string();
+        let proxy = Proxy::new(Command::CONNECT, Address::Address(echo));
+        let options = ClientOptions { credentials: Some(Credentials::new("alice", "secret")), ..ClientOptions::default() };
+        let mut client = TcpSocksClient::client_connect_with(&server, proxy.clone(), &options).await.unwrap();
+        assert_echo(&mut client.stream).await;
+
+        let wrong = ClientOptions { credentials: Some(Credentials::new("alice", "wrong")), ..ClientOptions::default() };
+        match TcpSocksClient::client_connect_with(&server, proxy.clone(), &wrong).await {
+            Err(Error::Socks(socket5::Error::AuthFailed(user))) => assert_eq!(user, "alice"),
+            _ => panic!("expected the credentials to be refused"),
+        }
+        // a password past the one byte length is refused before anything goes on the wire
+        let long = ClientOptions { credentials: Some(Credentials::new("alice", &"p".repeat(256))), ..ClientOptions::default() };
+        assert!(matches!(TcpSocksClient::handshake_with(duplex().0, proxy.clone(), &long).await, Err(Error::Socks(socket5::Error::PasswordLength(256)))));
+        // without credentials the only method offered isn't acceptable
+        assert!(TcpSocksClient::client_connect(&server, proxy).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn fast_open_test() {
+        // answers nothing until the whole handshake is in, which only a pipelining client gets past
+        let (client, mut server) = duplex();
+        tokio::spawn(async move {
+            assert_eq!(ShakeHands::from(&mut server).await.unwrap().methods, [METHOD_USERNAME_PASSWORD]);
+            let auth = UserPassAuth::from(&mut server).await.unwrap();
+            let proxy = Proxy::from(&mut server).await.unwrap();
+            assert_eq!((auth.username.as_str(), proxy.address), ("alice", "example.com:443".parse().unwrap()));
+            MethodSelection::new(METHOD_USERNAME_PASSWORD).write(&mut server).await.unwrap();
+            UserPassAuth::write_status(&mut server, true).await.unwrap();
+            ConnectReply::new(Reply::RepSuccess, "192.0.2.1:1080".parse().unwrap()).write(&mut server).await.unwrap();
+        });
+        let options = ClientOptions { credentials: Some(Credentials::new("alice", "secret")), fast_open: true };
+        let proxy = Proxy::new(Command::CONNECT, "example.com:443".parse().unwrap());
+        let socks = tokio::time::timeout(Duration::from_secs(5), TcpSocksClient::handshake_with(client, proxy, &options)).await.unwrap().unwrap();
+        assert_eq!(socks.bound, "192.0.2.1:1080".parse().unwrap());
+
+        // the server takes the request pipelined behind the auth as well
+        let echo = echo_server().await;
+        let state = test_state(ServerConfig {
+            users: vec![UserConfig { name: "alice".to_string(), password: Some("secret".to_string()), ..UserConfig::default() }],
+            ..config()
+        });
+        let server = socks_server(Endpoint::Tcp("127.0.0.1:0".to_string()), state).await.to_string();
+        let proxy = Proxy::new(Command::CONNECT, Address::Address(echo));
+        let mut client = TcpSocksClient::client_connect_with(&server, proxy.clone(), &options).await.unwrap();
+        assert_echo(&mut client.stream).await;
+        let wrong = ClientOptions { credentials: Some(Credentials::new("alice", "wrong")), fast_open: true };
+        assert!(matches!(TcpSocksClient::client_connect_with(&server, proxy.clone(), &wrong).await, Err(Error::Socks(socket5::Error::AuthFailed(_)))));
+        let anonymous = ClientOptions { fast_open: true, ..ClientOptions::default() };
+        assert!(matches!(TcpSocksClient::client_connect_with(&server, proxy, &anonymous).await, Err(Error::Socks(socket5::Error::MethodNo(METHOD_NO_ACCEPTABLE)))));
+    }
+
+    #[tokio::test]
+    async fn pipelined_dial_test() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = Address::Address(target.local_addr().unwrap());
+        let state = test_state(ServerConfig {
+            users: vec![UserConfig { name: "alice".to_string(), password: Some("secret".to_string()), ..UserConfig::default() }],
+            ..config()
+        });
+        let pipelined = |password: &'static str| {
+            let proxy = Proxy::new(Command::CONNECT, address.clone());
+            async move {
+                let mut pipelined = Vec::new();
+                ShakeHands::new(vec![METHOD_USERNAME_PASSWORD]).write(&mut pipelined).await.unwrap();
+                UserPassAuth::new("alice".to_string(), password.to_string()).write(&mut pipelined).await.unwrap();
+                proxy.write(&mut pipelined).await.unwrap();
+                pipelined
+            }
+        };
+
+        // nothing is dialed for a request behind a failed auth
+        let (mut client, server) = duplex();
+        tokio::spawn(TcpSocksClient::new(server).server_connect(state.clone()));
+        client.write_all(&pipelined("wrong").await).await.unwrap();
+        let mut selection = [0; 2];
+        client.read_exact(&mut selection).await.unwrap();
+        assert!(!UserPassAuth::read_status(&mut client).await.unwrap());
+        assert_eq!(client.read(&mut [0; 1]).await.unwrap(), 0);
+        assert!(tokio::time::timeout(Duration::from_millis(200), target.accept()).await.is_err());
+
+        // the selection fills the server's side of the pipe, so the status of a passed auth waits
+        // on the client reading it, and the request pipelined behind it is dialed in the meantime
+        let (mut client, server) = tokio::io::duplex(2);
+        tokio::spawn(TcpSocksClient::new(server).server_connect(state));
+        client.write_all(&pipelined("secret").await).await.unwrap();
+        let (mut dialed, _) = tokio::time::timeout(Duration::from_secs(5), target.accept()).await.unwrap().unwrap();
+        client.read_exact(&mut selection).await.unwrap();
+        assert!(UserPassAuth::read_status(&mut client).await.unwrap());
+        ConnectReply::from(&mut client).await.unwrap().into_result().unwrap();
+        client.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        dialed.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+
+    #[tokio::test]
+    async fn block_private_test() {
+        let echo = echo_server().await;
+        let state = test_state(ServerConfig { block_private: Some(true), ..config() });
+        let server = socks_server(Endpoint::Tcp("127.0.0.1:0".to_string()), state).await.to_string();
+        for target in [Address::Address(echo), Address::DomainName("localhost".to_string(), echo.port())] {
+            match TcpSocksClient::client_connect(&server, Proxy::new(Command::CONNECT, target)).await {
+                Err(Error::Socks(socket5::Error::Rejected(reply))) => assert_eq!(reply, Reply::RepConnNo),
+                _ => panic!("expected the private destination to be refused"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn blocklist_test() {
+        let echo = echo_server().await;
+        let state = test_state(ServerConfig {
+            blocklist: BlocklistConfig { domains: vec!["localhost".to_string()], ..BlocklistConfig::default() },
+            ..config()
+        });
+        let server = socks_server(Endpoint::Tcp("127.0.0.1:0".to_string()), state).await.to_string();
+        let target = Address::DomainName("localhost".to_string(), echo.port());
+        match TcpSocksClient::client_connect(&server, Proxy::new(Command::CONNECT, target)).await {
+            Err(Error::Socks(socket5::Error::Rejected(reply))) => assert_eq!(reply, Reply::RepHostNo),
+            _ => panic!("expected the blocked domain to be refused"),
+        }
+        let mut client = TcpSocksClient::client_connect(&server, Proxy::new(Command::CONNECT, Address::Address(echo))).await.unwrap();
+        assert_echo(&mut client.stream).await;
+    }
+
+    #[tokio::test]
+    async fn parent_test() {
+        let echo = echo_server().await;
+        let parent = test_state(ServerConfig {
+            users: vec![UserConfig { name: "alice".to_string(), password: Some("secret".to_string()), ..UserConfig::default() }],
+            ..config()
+        });
+        let parent = socks_server(Endpoint::Tcp("127.0.0.1:0".to_string()), parent).await.to_string();
+        let gateway = |password: &str| test_state(ServerConfig {
+            parent: Some(ParentConfig { server: parent.clone(), username: Some("alice".to_string()), password: Some(password.to_string()) }),
+            ..config()
+        });
+        let server = socks_server(Endpoint::Tcp("127.0.0.1:0".to_string()), gateway("secret")).await.to_string();
+        let mut client = TcpSocksClient::client_connect(&server, Proxy::new(Command::CONNECT, Address::Address(echo))).await.unwrap();
+        assert_echo(&mut client.stream).await;
+        match TcpSocksClient::client_connect(&server, Proxy::new(Command::UDP, "0.0.0.0:0".parse().unwrap())).await {
+            Err(Error::Socks(socket5::Error::Rejected(reply))) => assert_eq!(reply, Reply::RepCmdNo),
+            _ => panic!("expected udp to be refused in gateway mode"),
+        }
+
+        let refused = socks_server(Endpoint::Tcp("127.0.0.1:0".to_string()), gateway("wrong")).await.to_string();
+        match TcpSocksClient::client_connect(&refused, Proxy::new(Command::CONNECT, Address::Address(echo))).await {
+            Err(Error::Socks(socket5::Error::Rejected(reply))) => assert_eq!(reply, Reply::RepConnNo),
+            _ => panic!("expected the parent to refuse the gateway"),
+        }
+    }
+
+    #[tokio::test]
+    async fn outbound_test() {
+        let echo = echo_server().await;
+        let state = test_state(ServerConfig {
+            outbound: vec![
+                OutboundRule { pattern: Some("localhost".parse().unwrap()), ports: vec![], bind: Some("::1".parse().unwrap()), interface: None },
+                OutboundRule { pattern: None, ports: vec![echo.port()], bind: Some("127.0.0.1".parse().unwrap()), interface: None },
+            ],
+            ..config()
+        });
+        let server = socks_server(Endpoint::Tcp("127.0.0.1:0".to_string()), state).await.to_string();
+        // the echo server only listens on ipv4, there is nothing to reach from ::1
+        let target = Address::DomainName("localhost".to_string(), echo.port());
+        assert!(matches!(TcpSocksClient::client_connect(&server, Proxy::new(Command::CONNECT, target)).await, Err(Error::Socks(socket5::Error::Rejected(_)))));
+        let mut client = TcpSocksClient::client_connect(&server, Proxy::new(Command::CONNECT, Address::Address(echo))).await.unwrap();
+        assert_echo(&mut client.stream).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn jitter_test() {
+        let probe = ProbeConfig { jitter: 50, ..ProbeConfig::default() };
+        let mut delays = std::collections::HashSet::new();
+        for _ in 0..20 {
+            let start = tokio::time::Instant::now();
+            jitter(&probe).await;
+            assert!(start.elapsed() <= Duration::from_millis(50));
+            delays.insert(start.elapsed());
+        }
+        assert!(delays.len() > 1);
+        let start = tokio::time::Instant::now();
+        jitter(&ProbeConfig::default()).await;
+        assert_eq!(start.elapsed(), Duration::ZERO);
+    }
+
+    #[test]
+    fn fingerprint_test() {
+        let mut print = Fingerprint::new(Some("10.0.0.1".parse().unwrap()));
+        assert_eq!(
+            print.line(Some(&Error::Socks(socket5::Error::VersionNo(0x47)))),
+            "fingerprint : ip=10.0.0.1 methods=- hello_ms=- request_ms=- command=- atyp=- error=\"unsupported socks version 71\"",
+        );
+        print.hands(&ShakeHands::new(vec![0x00, 0x02]));
+        print.proxy(&Proxy::new(Command::CONNECT, Address::DomainName("example.com".to_string(), 443)));
+        let line = print.line(None);
+        assert!(line.starts_with("fingerprint : ip=10.0.0.1 methods=00,02 hello_ms=0 request_ms=0 command=connect atyp=domain"), "{}", line);
+        assert!(line.ends_with("error=-"), "{}", line);
+    }
+
+    #[tokio::test]
+    async fn target_limit_test() {
+        let echo = echo_server().await;
+        let state = test_state(ServerConfig { target_limit: 1, ..config() });
+        let targets = state.targets.clone();
+        let server = socks_server(Endpoint::Tcp("127.0.0.1:0".to_string()), state).await.to_string();
+        let mut first = TcpSocksClient::client_connect(&server, Proxy::new(Command::CONNECT, Address::Address(echo))).await.unwrap();
+        assert_echo(&mut first.stream).await;
+        match TcpSocksClient::client_connect(&server, Proxy::new(Command::CONNECT, Address::Address(echo))).await {
+            Err(Error::Socks(socket5::Error::Rejected(reply))) => assert_eq!(reply, Reply::RepConnNo),
+            _ => panic!("expected the second connection to the host to be refused"),
+        }
+        drop(first);
+        for _ in 0..100 {
+            if targets.open("127.0.0.1") == 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let mut client = TcpSocksClient::client_connect(&server, Proxy::new(Command::CONNECT, Address::Address(echo))).await.unwrap();
+        assert_echo(&mut client.stream).await;
+    }
+
+    #[tokio::test]
+    async fn source_limit_test() {
+        let echo = echo_server().await;
+        let state = test_state(ServerConfig { source_limit: 1, ..config() });
+        let server = socks_server(Endpoint::Tcp("127.0.0.1:0".to_string()), state).await.to_string();
+        let mut first = TcpSocksClient::client_connect(&server, Proxy::new(Command::CONNECT, Address::Address(echo))).await.unwrap();
+        let refused = TcpSocksClient::client_connect(&server, Proxy::new(Command::CONNECT, Address::Address(echo))).await;
+        assert!(matches!(refused, Err(Error::Socks(socket5::Error::MethodNo(METHOD_NO_ACCEPTABLE)))), "{:?}", refused.err());
+        assert_echo(&mut first.stream).await;
+    }
+
+    #[tokio::test]
+    async fn shed_test() {
+        let echo = echo_server().await;
+        let state = test_state(ServerConfig { shed: ShedConfig { connections: 1, ..ShedConfig::default() }, ..config() });
+        let stats = state.stats.clone();
+        let server = socks_server(Endpoint::Tcp("127.0.0.1:0".to_string()), state).await.to_string();
+        let mut first = TcpSocksClient::client_connect(&server, Proxy::new(Command::CONNECT, Address::Address(echo))).await.unwrap();
+        match TcpSocksClient::client_connect(&server, Proxy::new(Command::CONNECT, Address::Address(echo))).await {
+            Err(Error::Socks(socket5::Error::Rejected(reply))) => assert_eq!(reply, Reply::RepServerFail),
+            _ => panic!("expected the second request to be shed"),
+        }
+        // the relay already open isn't touched
+        assert_echo(&mut first.stream).await;
+        assert_eq!(stats.shed_requests(), 1);
+    }
+
+    #[tokio::test]
+    async fn sniff_test() {
+        let echo = echo_server().await;
+        let state = test_state(ServerConfig {
+            sniff: true,
+            blocklist: BlocklistConfig { domains: vec!["blocked.example".to_string()], ..BlocklistConfig::default() },
+            ..config()
+        });
+        let server = socks_server(Endpoint::Tcp("127.0.0.1:0".to_string()), state).await.to_string();
+        let hello = client_hello_record("ok.example");
+        let mut client = TcpSocksClient::client_connect(&server, Proxy::new(Command::CONNECT, Address::Address(echo))).await.unwrap();
+        client.stream.write_all(&hello).await.unwrap();
+        let mut buf = vec![0; hello.len()];
+        client.stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, hello);
+        assert_echo(&mut client.stream).await;
+
+        let mut client = TcpSocksClient::client_connect(&server, Proxy::new(Command::CONNECT, Address::Address(echo))).await.unwrap();
+        client.stream.write_all(&client_hello_record("www.blocked.example")).await.unwrap();
+        // closed without a byte reaching the target
+        assert_eq!(client.stream.read(&mut buf).await.unwrap(), 0);
+
+        let mut client = TcpSocksClient::client_connect(&server, Proxy::new(Command::CONNECT, Address::Address(echo))).await.unwrap();
+        client.stream.write_all(b"GET / HTTP/1.1\r\nHost: blocked.example\r\n\r\n").await.unwrap();
+        assert_eq!(client.stream.read(&mut buf).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn relay_buffer_test() {
+        let echo = echo_server().await;
+        let state = test_state(ServerConfig { relay: RelayConfig { up_buffer: 7, down_buffer: 3, ..RelayConfig::default() }, ..config() });
+        let server = socks_server(Endpoint::Tcp("127.0.0.1:0".to_string()), state).await.to_string();
+        let client = TcpSocksClient::client_connect(&server, Proxy::new(Command::CONNECT, Address::Address(echo))).await.unwrap();
+        let (mut read, mut write) = tokio::io::split(client.stream);
+        let data: Vec<u8> = (0..100_000).map(|i| i as u8).collect();
+        let sent = data.clone();
+        let writer = tokio::spawn(async move { write.write_all(&sent).await.unwrap() });
+        let mut received = vec![0; data.len()];
+        read.read_exact(&mut received).await.unwrap();
+        writer.await.unwrap();
+        assert!(received == data);
+    }
+
+    // panics on its nth write, just the once
+    struct Panicking {
+        stream: tokio::io::DuplexStream,
+        writes: usize,
+    }
+
+    impl tokio::io::AsyncRead for Panicking {
+        fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+            Pin::new(&mut self.stream).poll_read(cx, buf)
+        }
+    }
+
+    impl tokio::io::AsyncWrite for Panicking {
+        fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+            self.writes = self.writes.wrapping_sub(1);
+            if self.writes == 0 {
+                panic!("write went wrong");
+            }
+            Pin::new(&mut self.stream).poll_write(cx, buf)
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Pin::new(&mut self.stream).poll_flush(cx)
+        }
+
+        fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Pin::new(&mut self.stream).poll_shutdown(cx)
+        }
+    }
+
+    impl RawStream for Panicking {
+        type Raw = Self;
+
+        fn raw(&mut self) -> &mut Self {
+            self
+        }
+    }
+
+    #[tokio::test]
+    async fn panic_test() {
+        let echo = echo_server().await;
+        let state = test_state(config());
+        // the method selection goes out, the success reply panics
+        let (client, server) = duplex();
+        let served = tokio::spawn(TcpSocksClient::new(Panicking { stream: server, writes: 2 }).server_connect(state.clone()));
+        match TcpSocksClient::handshake(client, Proxy::new(Command::CONNECT, Address::Address(echo))).await {
+            Err(Error::Socks(socket5::Error::Rejected(reply))) => assert_eq!(reply, Reply::RepServerFail),
+            _ => panic!("expected the panic to be answered with a server failure"),
+        }
+        assert!(matches!(served.await.unwrap(), Err(Error::Panicked(message)) if message == "write went wrong"));
+        assert_eq!(state.stats.panics(), 1);
+        assert_eq!(state.stats.connections(), 0);
+
+        // once relaying nothing more is written, the connection just closes
+        let (client, server) = duplex();
+        let served = tokio::spawn(TcpSocksClient::new(Panicking { stream: server, writes: 3 }).server_connect(state.clone()));
+        let mut socks = TcpSocksClient::handshake(client, Proxy::new(Command::CONNECT, Address::Address(echo))).await.unwrap();
+        socks.stream.write_all(b"hello").await.unwrap();
+        let mut buf = Vec::new();
+        socks.stream.read_to_end(&mut buf).await.unwrap();
+        assert!(buf.is_empty());
+        assert!(matches!(served.await.unwrap(), Err(Error::Panicked(_))));
+        assert_eq!(state.stats.panics(), 2);
+    }
+
+    #[tokio::test]
+    async fn reject_reset_test() {
+        let state = test_state(ServerConfig {
+            reject: RejectMode::Reset,
+            blocklist: BlocklistConfig { domains: vec!["blocked.example".to_string()], ..BlocklistConfig::default() },
+            ..config()
+        });
+        let server = socks_server(Endpoint::Tcp("127.0.0.1:0".to_string()), state).await;
+        let mut stream = TcpStream::connect(server.to_string()).await.unwrap();
+        ShakeHands::new(vec![METHOD_NO_AUTHENTICATION]).write(&mut stream).await.unwrap();
+        let mut method = [0; 2];
+        stream.read_exact(&mut method).await.unwrap();
+        Proxy::new(Command::CONNECT, Address::DomainName("blocked.example".to_string(), 443)).write(&mut stream).await.unwrap();
+        // the rst may overtake the reply in the client's receive queue, either way it ends in a reset
+        let mut buf = [0; 64];
+        let err = loop {
+            match stream.read(&mut buf).await {
+                Ok(0) => panic!("expected a reset, not a fin"),
+                Ok(_) => continue,
+                Err(e) => break e,
+            }
+        };
+        assert_eq!(err.kind(), std::io::ErrorKind::ConnectionReset);
+    }
+
+    #[tokio::test]
+    async fn probe_drain_test() {
+        let state = test_state(ServerConfig {
+            probe: ProbeConfig { mode: ProbeMode::Drain, max_delay: 1, ..ProbeConfig::default() },
+            ..config()
+        });
+        let server = socks_server(Endpoint::Tcp("127.0.0.1:0".to_string()), state).await;
+        let mut stream = TcpStream::connect(server.to_string()).await.unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        let mut buf = [0; 16];
+        // neither an answer nor a close while the delay runs
+        let early = tokio::time::timeout(Duration::from_millis(300), stream.read(&mut buf)).await;
+        assert!(early.is_err());
+        stream.write_all(b"more garbage").await.unwrap();
+        let closed = tokio::time::timeout(Duration::from_secs(3), stream.read(&mut buf)).await.unwrap();
+        assert_eq!(closed.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn probe_drain_encrypted_test() {
+        let handle = start(ServerConfig {
+            password: "secret".to_string(),
+            encrypt: "aes-256-gcm".to_string(),
+            probe: ProbeConfig { mode: ProbeMode::Drain, max_delay: 1, ..ProbeConfig::default() },
+            ..config()
+        }).await.unwrap();
+        let server = handle.stats().listeners[0].endpoint.to_string();
+        let mut stream = TcpStream::connect(server).await.unwrap();
+        // a salt and a length chunk that can't authenticate
+        stream.write_all(&[0x42; 128]).await.unwrap();
+        let mut buf = [0; 16];
+        let early = tokio::time::timeout(Duration::from_millis(300), stream.read(&mut buf)).await;
+        assert!(early.is_err());
+        // read past the failed cipher, so the close is a fin rather than a reset over unread data
+        stream.write_all(b"more garbage").await.unwrap();
+        let closed = tokio::time::timeout(Duration::from_secs(3), stream.read(&mut buf)).await.unwrap();
+        assert_eq!(closed.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn relay_reset_recorded_test() {
+        let echo = echo_server().await;
+        let state = test_state(config());
+        let server = socks_server(Endpoint::Tcp("127.0.0.1:0".to_string()), state.clone()).await;
+        let mut client = TcpSocksClient::client_connect(
+            server.to_string(),
+            Proxy::new(Command::CONNECT, Address::Address(echo)),
+        ).await.unwrap();
+        assert_echo(&mut client.stream).await;
+        // a reset instead of a clean close still gets charged
+        client.stream.set_zero_linger().unwrap();
+        drop(client);
+        for _ in 0..100 {
+            if state.quota.used(None) == 10 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(state.quota.used(None), 10);
+        assert_eq!((state.stats.bytes_up(), state.stats.bytes_down()), (5, 5));
+    }
+
+    #[tokio::test]
+    async fn relay_quota_test() {
+        let echo = echo_server().await;
+        let state = test_state(ServerConfig {
+            quota: QuotaConfig { global: Some(4096), ..QuotaConfig::default() },
+            ..config()
+        });
+        let server = socks_server(Endpoint::Tcp("127.0.0.1:0".to_string()), state.clone()).await;
+        let mut client = TcpSocksClient::client_connect(
+            server.to_string(),
+            Proxy::new(Command::CONNECT, Address::Address(echo)),
+        ).await.unwrap();
+        let mut buf = [0; 1024];
+        let cut = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if client.stream.write_all(&buf).await.is_err() {
+                    break;
+                }
+                match client.stream.read_exact(&mut buf).await {
+                    Ok(_) => tokio::time::sleep(Duration::from_millis(50)).await,
+                    Err(_) => break,
+                }
+            }
+        }).await;
+        assert!(cut.is_ok());
+        assert!(state.quota.used(None) >= 4096);
+        assert!(!state.quota.check(None));
+    }
+
+    #[tokio::test]
+    async fn relay_transfer_test() {
+        let echo = echo_server().await;
+        let state = test_state(ServerConfig {
+            transfer: vec![TransferRule { pattern: None, ports: vec![echo.port()], asn: vec![64500], max_bytes: 4096, action: TransferAction::Close, rate: 0 }],
+            ..config()
+        });
+        // the echo server's address as one announced by as64500
+        state.asns.replace(AsnTable::parse("127.0.0.0\t127.255.255.255\t64500\n"));
+        let server = socks_server(Endpoint::Tcp("127.0.0.1:0".to_string()), state.clone()).await;
+        let mut client = TcpSocksClient::client_connect(
+            server.to_string(),
+            Proxy::new(Command::CONNECT, Address::Address(echo)),
+        ).await.unwrap();
+        let mut buf = [0; 1024];
+        let cut = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if client.stream.write_all(&buf).await.is_err() {
+                    break;
+                }
+                match client.stream.read_exact(&mut buf).await {
+                    Ok(_) => tokio::time::sleep(Duration::from_millis(50)).await,
+                    Err(_) => break,
+                }
+            }
+        }).await;
+        assert!(cut.is_ok());
+        // the connection is cut, not the server's quota
+        assert!(state.quota.check(None));
+        assert!(state.stats.closes().contains(&(Close::Policy, 1)));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn client_connect_unix_test() {
+        let echo = echo_server().await;
+        let path = std::env::temp_dir().join(format!("rust-ss5-test-{}.sock", std::process::id()));
+        let server = socks_server(Endpoint::Unix(path.clone()), test_state(config())).await;
+        let mut client = TcpSocksClient::client_connect_endpoint(
+            &server,
+            Proxy::new(Command::CONNECT, Address::Address(echo)),
+        ).await.unwrap();
+        assert_echo(&mut client.stream).await;
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -0,0 +1,98 @@
+// fixtures shared by the tests of every module
+use std::net::SocketAddr;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, UdpSocket};
+
+use crate::config::ServerConfig;
+use crate::server::ServerState;
+use crate::tcp::TcpSocksClient;
+use crate::transport::Endpoint;
+
+// a plain server on port 0 unless the test asks for more
+pub fn config() -> ServerConfig {
+    ServerConfig {
+        port: 0,
+        ..ServerConfig::default()
+    }
+}
+
+pub fn test_state(config: ServerConfig) -> ServerState {
+    ServerState::new(config).unwrap()
+}
+
+// echoes every connection back until it closes
+pub async fn echo_server() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let (mut read, mut write) = stream.split();
+                let _ = tokio::io::copy(&mut read, &mut write).await;
+            });
+        }
+    });
+    addr
+}
+
+pub async fn udp_echo_server() -> SocketAddr {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut buf = vec![0; 70000];
+        while let Ok((n, from)) = socket.recv_from(&mut buf).await {
+            let _ = socket.send_to(&buf[..n], from).await;
+        }
+    });
+    addr
+}
+
+// a plain socks server on the endpoint, without the listener machinery of server::start
+pub async fn socks_server(endpoint: Endpoint, state: ServerState) -> Endpoint {
+    let listener = endpoint.bind().await.unwrap();
+    let endpoint = listener.local_endpoint().unwrap();
+    if let Endpoint::Tcp(addr) = &endpoint {
+        state.listening.lock().unwrap().push(addr.parse().unwrap());
+    }
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let peer = stream.peer_ip();
+            tokio::spawn(TcpSocksClient::new(stream).with_peer(peer).server_connect(state.clone()));
+        }
+    });
+    endpoint
+}
+
+pub async fn assert_echo<S>(stream: &mut S)
+    where S: AsyncRead + AsyncWrite + Unpin
+{
+    stream.write_all(b"hello").await.unwrap();
+    let mut buf = [0; 5];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"hello");
+}
+
+// a tls 1.2 style client hello record naming host, with an extension before the name
+pub fn client_hello_record(host: &str) -> Vec<u8> {
+    let mut names = vec![0x00];
+    names.extend_from_slice(&(host.len() as u16).to_be_bytes());
+    names.extend_from_slice(host.as_bytes());
+    let mut sni = (names.len() as u16).to_be_bytes().to_vec();
+    sni.extend_from_slice(&names);
+    let mut extensions = vec![0x00, 0x17, 0x00, 0x00];
+    extensions.extend_from_slice(&[0x00, 0x00]);
+    extensions.extend_from_slice(&(sni.len() as u16).to_be_bytes());
+    extensions.extend_from_slice(&sni);
+    let mut hello = vec![0x03, 0x03];
+    hello.extend_from_slice(&[7; 32]);
+    hello.extend_from_slice(&[0, 0x00, 0x02, 0x13, 0x01, 0x01, 0x00]);
+    hello.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+    hello.extend_from_slice(&extensions);
+    let mut handshake = vec![0x01, 0, (hello.len() >> 8) as u8, hello.len() as u8];
+    handshake.extend_from_slice(&hello);
+    let mut record = vec![0x16, 0x03, 0x01];
+    record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+    record.extend_from_slice(&handshake);
+    record
+}
@@ -0,0 +1,230 @@
+// for crates built on this one to test against socks without sockets: in-memory streams, the
+// real server served over one, and a server and client that follow a script
+use bytes::BytesMut;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, DuplexStream};
+
+use crate::error::Error;
+use crate::server::ServerState;
+use crate::socket5::{Address, ConnectReply, MethodSelection, Proxy, Reply, ShakeHands, UserPassAuth};
+use crate::socket5::constant::{METHOD_NO_ACCEPTABLE, METHOD_NO_AUTHENTICATION, METHOD_USERNAME_PASSWORD};
+use crate::tcp::{Credentials, TcpSocksClient};
+use crate::transport::RawStream;
+
+// bytes either end can write before the other reads
+const DUPLEX_BUFFER: usize = 64 * 1024;
+
+impl RawStream for DuplexStream {
+    type Raw = DuplexStream;
+
+    fn raw(&mut self) -> &mut DuplexStream {
+        self
+    }
+}
+
+// two connected ends, what's written to one is read from the other
+pub fn duplex() -> (DuplexStream, DuplexStream) {
+    tokio::io::duplex(DUPLEX_BUFFER)
+}
+
+// the client end of a connection the server serves like any other, without a peer address;
+// needs a runtime, the server side is spawned
+pub fn memory_server(state: ServerState) -> DuplexStream {
+    let (client, server) = duplex();
+    tokio::spawn(TcpSocksClient::new(server).server_connect(state));
+    client
+}
+
+// what a fake server was sent
+#[derive(Debug, Clone, Default)]
+pub struct Seen {
+    pub methods: Vec<u8>,
+    pub credentials: Option<Credentials>,
+    pub proxy: Option<Proxy>,
+}
+
+// answers one handshake the way it's told, whatever the request
+#[derive(Debug, Clone)]
+pub struct FakeServer {
+    pub method: u8,
+    // the status for username/password auth
+    pub auth: bool,
+    pub reply: Reply,
+    pub bound: Address,
+}
+
+impl Default for FakeServer {
+    fn default() -> Self {
+        FakeServer {
+            method: METHOD_NO_AUTHENTICATION,
+            auth: true,
+            reply: Reply::RepSuccess,
+            bound: Address::Address("0.0.0.0:0".parse().unwrap()),
+        }
+    }
+}
+
+impl FakeServer {
+    pub fn new() -> Self {
+        FakeServer::default()
+    }
+
+    pub fn method(mut self, method: u8) -> Self {
+        self.method = method;
+        self
+    }
+
+    pub fn auth(mut self, auth: bool) -> Self {
+        self.auth = auth;
+        self
+    }
+
+    pub fn reply(mut self, reply: Reply, bound: Address) -> Self {
+        self.reply = reply;
+        self.bound = bound;
+        self
+    }
+
+    // the stream comes back where the script ended, after a success reply it's the tunnel
+    pub async fn accept<S>(&self, mut stream: S) -> Result<(Seen, S), Error>
+        where S: AsyncRead + AsyncWrite + Unpin
+    {
+        let mut seen = Seen { methods: ShakeHands::from(&mut stream).await?.methods, ..Seen::default() };
+        MethodSelection::new(self.method).write(&mut stream).await?;
+        if self.method == METHOD_NO_ACCEPTABLE {
+            return Ok((seen, stream));
+        }
+        if self.method == METHOD_USERNAME_PASSWORD {
+            let auth = UserPassAuth::from(&mut stream).await?;
+            seen.credentials = Some(Credentials::new(&auth.username, &auth.password));
+            UserPassAuth::write_status(&mut stream, self.auth).await?;
+            if !self.auth {
+                return Ok((seen, stream));
+            }
+        }
+        seen.proxy = Some(Proxy::from(&mut stream).await?);
+        ConnectReply::new(self.reply.clone(), self.bound.clone()).write(&mut stream).await?;
+        Ok((seen, stream))
+    }
+}
+
+// how far a fake client got and what it was told
+#[derive(Debug, Clone)]
+pub struct Answered {
+    pub method: u8,
+    pub auth: Option<bool>,
+    pub reply: Option<ConnectReply>,
+}
+
+// offers exactly the methods it's given and takes whatever answer comes, where a real client
+// would pick its own methods and turn a refusal into an error
+#[derive(Debug, Clone)]
+pub struct FakeClient {
+    pub methods: Vec<u8>,
+    pub credentials: Option<Credentials>,
+    pub proxy: Proxy,
+}
+
+impl FakeClient {
+    pub fn new(proxy: Proxy) -> Self {
+        FakeClient { methods: vec![METHOD_NO_AUTHENTICATION], credentials: None, proxy }
+    }
+
+    pub fn methods(mut self, methods: Vec<u8>) -> Self {
+        self.methods = methods;
+        self
+    }
+
+    // sent when the server picks username/password
+    pub fn credentials(mut self, username: &str, password: &str) -> Self {
+        self.credentials = Some(Credentials::new(username, password));
+        self
+    }
+
+    pub async fn connect<S>(&self, mut stream: S) -> Result<(Answered, S), Error>
+        where S: AsyncRead + AsyncWrite + Unpin
+    {
+        ShakeHands::new(self.methods.clone()).write(&mut stream).await?;
+        let method = MethodSelection::from(&mut stream).await?.method;
+        let mut answered = Answered { method, auth: None, reply: None };
+        match (method, &self.credentials) {
+            (METHOD_NO_AUTHENTICATION, _) => {}
+            (METHOD_USERNAME_PASSWORD, Some(credentials)) => {
+                UserPassAuth::new(credentials.username.clone(), credentials.password.clone()).write(&mut stream).await?;
+                let success = UserPassAuth::read_status(&mut stream).await?;
+                answered.auth = Some(success);
+                if !success {
+                    return Ok((answered, stream));
+                }
+            }
+            _ => return Ok((answered, stream)),
+        }
+        // unchecked, a test may well want to send what a server should refuse
+        let mut request = BytesMut::new();
+        self.proxy.command.encode(&mut request);
+        self.proxy.address.encode(&mut request)?;
+        stream.write_all(&request).await?;
+        answered.reply = Some(ConnectReply::from(&mut stream).await?);
+        Ok((answered, stream))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::config::{ServerConfig, UserConfig};
+    use crate::error::Error;
+    use crate::socket5::{self, Address, Command, Proxy, Reply};
+    use crate::socket5::constant::{METHOD_NO_ACCEPTABLE, METHOD_USERNAME_PASSWORD};
+    use crate::tcp::{ClientOptions, Credentials, TcpSocksClient};
+    use crate::test_util::{assert_echo, config, echo_server, test_state};
+    use crate::testing::{duplex, FakeClient, FakeServer, memory_server};
+
+    #[tokio::test]
+    async fn fake_server_test() {
+        let target = Address::DomainName("example.com".to_string(), 443);
+        let (client, server) = duplex();
+        let script = FakeServer::new().method(METHOD_USERNAME_PASSWORD).reply(Reply::RepSuccess, "192.0.2.1:1080".parse().unwrap());
+        let served = tokio::spawn(async move { script.accept(server).await.unwrap() });
+        let options = ClientOptions { credentials: Some(Credentials::new("alice", "secret")), ..ClientOptions::default() };
+        let mut socks = TcpSocksClient::handshake_with(client, Proxy::new(Command::CONNECT, target.clone()), &options).await.unwrap();
+        assert_eq!(socks.bound, "192.0.2.1:1080".parse().unwrap());
+        let (seen, mut tunnel) = served.await.unwrap();
+        assert_eq!(seen.methods, [0x00, METHOD_USERNAME_PASSWORD]);
+        assert_eq!(seen.credentials, options.credentials);
+        assert_eq!(seen.proxy.unwrap().address, target);
+        socks.stream.write_all(b"hello").await.unwrap();
+        let mut buf = [0; 5];
+        tunnel.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+
+        // a failure reply comes out of the real client as the refusal it is
+        let (client, server) = duplex();
+        tokio::spawn(async move { FakeServer::new().reply(Reply::RepConnRefused, target).accept(server).await });
+        match TcpSocksClient::handshake(client, Proxy::new(Command::CONNECT, "192.0.2.1:80".parse().unwrap())).await {
+            Err(Error::Socks(socket5::Error::Rejected(reply))) => assert_eq!(reply, Reply::RepConnRefused),
+            _ => panic!("expected the scripted refusal"),
+        }
+    }
+
+    #[tokio::test]
+    async fn memory_server_test() {
+        let echo = echo_server().await;
+        let state = test_state(ServerConfig {
+            users: vec![UserConfig { name: "alice".to_string(), password: Some("secret".to_string()), ..UserConfig::default() }],
+            ..config()
+        });
+        let proxy = Proxy::new(Command::CONNECT, Address::Address(echo));
+        let client = FakeClient::new(proxy.clone()).methods(vec![0x00, METHOD_USERNAME_PASSWORD]).credentials("alice", "secret");
+        let (answered, mut stream) = client.connect(memory_server(state.clone())).await.unwrap();
+        assert_eq!(answered.reply.unwrap().reply, Reply::RepSuccess);
+        assert_eq!(answered.auth, Some(true));
+        assert_echo(&mut stream).await;
+
+        // what the real server does with a client that won't authenticate
+        let (answered, _) = FakeClient::new(proxy).connect(memory_server(state)).await.unwrap();
+        assert_eq!(answered.method, METHOD_NO_ACCEPTABLE);
+        assert!(answered.reply.is_none());
+    }
+}
@@ -0,0 +1,289 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::warn;
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+
+use crate::config::TraceConfig;
+use crate::crypto::generate_key;
+
+// OTLP span kind for the receiving end of a request
+const SPAN_KIND_SERVER: u8 = 2;
+
+#[derive(Debug, Clone)]
+pub struct Span {
+    pub trace_id: [u8; 16],
+    pub span_id: [u8; 8],
+    pub parent: Option<[u8; 8]>,
+    pub name: String,
+    pub start: SystemTime,
+    pub end: SystemTime,
+    pub attributes: Vec<(String, String)>,
+}
+
+// hands finished spans to the exporter, a no-op unless `trace` is configured
+#[derive(Clone, Default)]
+pub struct Tracer {
+    sender: Option<mpsc::Sender<Span>>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl Tracer {
+    pub fn new(config: Option<TraceConfig>) -> Self {
+        match config {
+            None => Tracer::default(),
+            Some(config) => {
+                let (sender, receiver) = mpsc::channel(config.queue.max(1));
+                tokio::spawn(export(config, receiver));
+                Tracer { sender: Some(sender), dropped: Arc::default() }
+            }
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.sender.is_some()
+    }
+
+    // spans thrown away because the exporter was behind
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    // a root span covering the whole connection, ended when dropped
+    pub fn connection(&self) -> ConnectionTrace {
+        let random = generate_key(24).unwrap_or_else(|_| vec![0; 24]);
+        ConnectionTrace {
+            tracer: self.clone(),
+            trace_id: random[..16].try_into().unwrap(),
+            span_id: random[16..].try_into().unwrap(),
+            start: SystemTime::now(),
+            attributes: Vec::new(),
+        }
+    }
+
+    // never waits on the exporter, a slow collector costs spans rather than connections
+    fn send(&self, span: Span) {
+        if let Some(sender) = &self.sender {
+            if let Err(mpsc::error::TrySendError::Full(_)) = sender.try_send(span) {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+pub struct ConnectionTrace {
+    tracer: Tracer,
+    trace_id: [u8; 16],
+    span_id: [u8; 8],
+    start: SystemTime,
+    attributes: Vec<(String, String)>,
+}
+
+impl ConnectionTrace {
+    // short enough for log lines, and searchable in the tracing backend
+    pub fn id(&self) -> String {
+        hex(&self.span_id)
+    }
+
+    pub fn attribute<V: ToString>(&mut self, key: &str, value: V) {
+        self.attributes.push((key.to_string(), value.to_string()));
+    }
+
+    // a child span from start until now
+    pub fn span(&self, name: &str, start: SystemTime) {
+        if !self.tracer.is_enabled() {
+            return;
+        }
+        let span_id = generate_key(8).unwrap_or_else(|_| vec![0; 8]);
+        self.tracer.send(Span {
+            trace_id: self.trace_id,
+            span_id: span_id.try_into().unwrap(),
+            parent: Some(self.span_id),
+            name: name.to_string(),
+            start,
+            end: SystemTime::now(),
+            attributes: Vec::new(),
+        });
+    }
+}
+
+impl Drop for ConnectionTrace {
+    fn drop(&mut self) {
+        self.tracer.send(Span {
+            trace_id: self.trace_id,
+            span_id: self.span_id,
+            parent: None,
+            name: "connection".to_string(),
+            start: self.start,
+            end: SystemTime::now(),
+            attributes: std::mem::take(&mut self.attributes),
+        });
+    }
+}
+
+// batches spans and posts them as OTLP/HTTP json, a failed post drops its batch
+async fn export(config: TraceConfig, mut spans: mpsc::Receiver<Span>) {
+    let agent: ureq::Agent = ureq::Agent::config_builder()
+        .timeout_global(Some(Duration::from_secs(config.timeout.max(1))))
+        .build()
+        .into();
+    let mut batch = Vec::new();
+    let mut interval = tokio::time::interval(Duration::from_secs(config.interval.max(1)));
+    loop {
+        tokio::select! {
+            span = spans.recv() => match span {
+                Some(span) => {
+                    batch.push(span);
+                    if batch.len() < config.batch {
+                        continue;
+                    }
+                }
+                None => {
+                    post(&agent, &config, &mut batch).await;
+                    break;
+                }
+            },
+            _ = interval.tick() => {}
+        }
+        post(&agent, &config, &mut batch).await;
+    }
+}
+
+async fn post(agent: &ureq::Agent, config: &TraceConfig, batch: &mut Vec<Span>) {
+    if batch.is_empty() {
+        return;
+    }
+    let body = encode(&config.service, batch).to_string();
+    batch.clear();
+    let url = format!("{}/v1/traces", config.endpoint.trim_end_matches('/'));
+    let agent = agent.clone();
+    let posted = tokio::task::spawn_blocking(move || {
+        agent.post(&url).header("Content-Type", "application/json").send(&body).map(|_| ()).map_err(|e| e.to_string())
+    }).await;
+    match posted {
+        Ok(Err(e)) => warn!("export spans to {} fail : {}", config.endpoint, e),
+        Err(e) => warn!("export spans to {} fail : {}", config.endpoint, e),
+        Ok(Ok(())) => {}
+    }
+}
+
+// https://opentelemetry.io/docs/specs/otlp/#json-protobuf-encoding
+pub fn encode(service: &str, spans: &[Span]) -> Value {
+    let spans: Vec<Value> = spans.iter().map(|span| {
+        let mut value = json!({
+            "traceId": hex(&span.trace_id),
+            "spanId": hex(&span.span_id),
+            "name": span.name,
+            "kind": SPAN_KIND_SERVER,
+            "startTimeUnixNano": nanos(span.start),
+            "endTimeUnixNano": nanos(span.end),
+            "attributes": attributes(&span.attributes),
+        });
+        if let Some(parent) = &span.parent {
+            value["parentSpanId"] = json!(hex(parent));
+        }
+        value
+    }).collect();
+    json!({
+        "resourceSpans": [{
+            "resource": {"attributes": attributes(&[("service.name".to_string(), service.to_string())])},
+            "scopeSpans": [{"scope": {"name": "rust-ss5"}, "spans": spans}],
+        }]
+    })
+}
+
+fn attributes(attributes: &[(String, String)]) -> Value {
+    attributes.iter()
+        .map(|(key, value)| json!({"key": key, "value": {"stringValue": value}}))
+        .collect()
+}
+
+// uint64 goes as a string in OTLP json
+fn nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos().to_string()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use crate::config::TraceConfig;
+    use crate::trace::Tracer;
+
+    #[tokio::test]
+    async fn export_test() {
+        let collector = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}", collector.local_addr().unwrap());
+        let tracer = Tracer::new(Some(TraceConfig {
+            endpoint,
+            batch: 2,
+            ..TraceConfig::default()
+        }));
+        let mut trace = tracer.connection();
+        trace.attribute("socks.target", "example.com:443");
+        trace.span("dial", SystemTime::now());
+        let id = trace.id();
+        drop(trace);
+
+        let (mut stream, _) = collector.accept().await.unwrap();
+        let mut request = Vec::new();
+        let mut buf = [0; 4096];
+        while !String::from_utf8_lossy(&request).contains("\"connection\"") {
+            let n = stream.read(&mut buf).await.unwrap();
+            assert!(n > 0);
+            request.extend_from_slice(&buf[..n]);
+        }
+        stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").await.unwrap();
+        let request = String::from_utf8_lossy(&request);
+        assert!(request.starts_with("POST /v1/traces "));
+        let body: serde_json::Value = serde_json::from_str(&request[request.find("\r\n\r\n").unwrap() + 4..]).unwrap();
+        let spans = &body["resourceSpans"][0]["scopeSpans"][0]["spans"];
+        assert_eq!(spans[0]["name"], "dial");
+        assert_eq!(spans[0]["parentSpanId"], id.as_str());
+        assert_eq!(spans[1]["spanId"], id.as_str());
+        assert_eq!(spans[1]["attributes"][0]["value"]["stringValue"], "example.com:443");
+    }
+
+    #[tokio::test]
+    async fn full_queue_test() {
+        let tracer = Tracer::new(Some(TraceConfig {
+            endpoint: "http://127.0.0.1:1".to_string(),
+            queue: 1,
+            ..TraceConfig::default()
+        }));
+        // the exporter doesn't get to run before the test yields
+        let trace = tracer.connection();
+        for _ in 0..3 {
+            trace.span("dial", SystemTime::now());
+        }
+        assert_eq!(tracer.dropped(), 2);
+    }
+
+    #[tokio::test]
+    async fn post_timeout_test() {
+        let collector = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let tracer = Tracer::new(Some(TraceConfig {
+            endpoint: format!("http://{}", collector.local_addr().unwrap()),
+            batch: 1,
+            timeout: 1,
+            ..TraceConfig::default()
+        }));
+        let trace = tracer.connection();
+        trace.span("dial", SystemTime::now());
+        trace.span("relay", SystemTime::now());
+        // the first post never gets an answer, the second still goes out
+        let (_stalled, _) = collector.accept().await.unwrap();
+        let next = tokio::time::timeout(Duration::from_secs(5), collector.accept()).await;
+        assert!(next.is_ok());
+    }
+}
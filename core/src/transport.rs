@@ -0,0 +1,539 @@
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpSocket, TcpStream, ToSocketAddrs, UdpSocket};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+#[cfg(windows)]
+use tokio::net::windows::named_pipe::{NamedPipeServer, ServerOptions};
+
+use crate::crypto::generate_key;
+
+pub const UNIX_PREFIX: &str = "unix:";
+pub const PIPE_PREFIX: &str = "pipe:";
+
+// where a listener binds or a dialer connects, "127.0.0.1:9999", "unix:/run/ss5.sock"
+// or, listening only, the windows named pipe "pipe:\\.\pipe\ss5"
+#[derive(Debug, Clone, PartialEq)]
+pub enum Endpoint {
+    Tcp(String),
+    Unix(PathBuf),
+    Pipe(String),
+}
+
+impl FromStr for Endpoint {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix(UNIX_PREFIX) {
+            if path.is_empty() {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "empty unix socket path"));
+            }
+            return Ok(Endpoint::Unix(PathBuf::from(path)));
+        }
+        if let Some(name) = s.strip_prefix(PIPE_PREFIX) {
+            if name.is_empty() {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "empty pipe name"));
+            }
+            return Ok(Endpoint::Pipe(name.to_string()));
+        }
+        if s.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "empty endpoint"));
+        }
+        Ok(Endpoint::Tcp(s.to_string()))
+    }
+}
+
+impl Display for Endpoint {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Endpoint::Tcp(addr) => write!(f, "{}", addr),
+            Endpoint::Unix(path) => write!(f, "{}{}", UNIX_PREFIX, path.display()),
+            Endpoint::Pipe(name) => write!(f, "{}{}", PIPE_PREFIX, name),
+        }
+    }
+}
+
+impl Serialize for Endpoint {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Endpoint {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+// called on each outbound tcp socket before it connects, e.g. VpnService.protect on android
+// so the tunnel's own connections don't loop back into the vpn
+pub type Protect = Arc<dyn Fn(&TcpSocket) -> io::Result<()> + Send + Sync>;
+
+impl Endpoint {
+    pub async fn connect(&self) -> io::Result<Stream> {
+        self.connect_with(None).await
+    }
+
+    pub async fn connect_with(&self, protect: Option<&Protect>) -> io::Result<Stream> {
+        Ok(match self {
+            Endpoint::Tcp(addr) => Stream::Tcp(connect_tcp(addr.as_str(), protect).await?),
+            #[cfg(unix)]
+            Endpoint::Unix(path) => Stream::Unix(UnixStream::connect(path).await?),
+            #[cfg(not(unix))]
+            Endpoint::Unix(_) => return Err(unix_unsupported()),
+            // nothing dials the local listener's pipe but the apps it is there for
+            Endpoint::Pipe(_) => return Err(io::Error::new(io::ErrorKind::Unsupported, "named pipes are only listened on")),
+        })
+    }
+
+    pub async fn bind(&self) -> io::Result<Listener> {
+        self.bind_with(&ListenOptions::default()).await
+    }
+
+    pub async fn bind_with(&self, options: &ListenOptions) -> io::Result<Listener> {
+        Ok(match self {
+            Endpoint::Tcp(addr) => Listener::Tcp(bind_tcp(addr, options).await?),
+            #[cfg(unix)]
+            Endpoint::Unix(path) => {
+                remove_stale_socket(path)?;
+                Listener::Unix(UnixListener::bind(path)?)
+            }
+            #[cfg(not(unix))]
+            Endpoint::Unix(_) => return Err(unix_unsupported()),
+            #[cfg(windows)]
+            Endpoint::Pipe(name) => Listener::Pipe(PipeListener::bind(name)?),
+            #[cfg(not(windows))]
+            Endpoint::Pipe(_) => return Err(pipe_unsupported()),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ListenOptions {
+    pub backlog: u32,
+    // let several listeners share the port, the kernel spreads connections over them
+    pub reuse_port: bool,
+}
+
+impl Default for ListenOptions {
+    fn default() -> Self {
+        ListenOptions { backlog: 1024, reuse_port: false }
+    }
+}
+
+// an inclusive port range, "40000-40100" in config files
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl PortRange {
+    // every port once, from a random one on so concurrent binds don't all race for the first
+    pub fn ports(&self) -> impl Iterator<Item = u16> {
+        let len = (self.end - self.start) as u32 + 1;
+        let offset = match generate_key(4) {
+            Ok(random) => u32::from_be_bytes(random.try_into().unwrap()) % len,
+            Err(_) => 0,
+        };
+        let start = self.start;
+        (0..len).map(move |i| start + ((offset + i) % len) as u16)
+    }
+}
+
+impl FromStr for PortRange {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidInput, format!("invalid port range : {}", s));
+        let (start, end) = s.split_once('-').unwrap_or((s, s));
+        let start: u16 = start.trim().parse().map_err(|_| invalid())?;
+        let end: u16 = end.trim().parse().map_err(|_| invalid())?;
+        if start == 0 || start > end {
+            return Err(invalid());
+        }
+        Ok(PortRange { start, end })
+    }
+}
+
+impl Display for PortRange {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}-{}", self.start, self.end)
+    }
+}
+
+impl Serialize for PortRange {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for PortRange {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+// any port when no range is given, else the first free one in it
+pub async fn bind_udp(ip: IpAddr, range: Option<PortRange>) -> io::Result<UdpSocket> {
+    let Some(range) = range else {
+        return UdpSocket::bind((ip, 0)).await;
+    };
+    for port in range.ports() {
+        match UdpSocket::bind((ip, port)).await {
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse => continue,
+            bound => return bound,
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::AddrInUse, format!("no free port in {}", range)))
+}
+
+// each resolved address in turn, as TcpStream::connect does, with protect run on every socket
+pub async fn connect_tcp<A: ToSocketAddrs>(addr: A, protect: Option<&Protect>) -> io::Result<TcpStream> {
+    let Some(protect) = protect else {
+        return TcpStream::connect(addr).await;
+    };
+    let mut last = None;
+    for addr in tokio::net::lookup_host(addr).await? {
+        let socket = if addr.is_ipv4() { TcpSocket::new_v4()? } else { TcpSocket::new_v6()? };
+        protect(&socket)?;
+        match socket.connect(addr).await {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last = Some(e),
+        }
+    }
+    Err(last.unwrap_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no address to connect to")))
+}
+
+// like connect_tcp, leaving from bind and through the named interface when given; a bind address
+// only reaches addresses of its own family, the others resolved are skipped
+pub async fn connect_bound<A: ToSocketAddrs>(addr: A, bind: Option<IpAddr>, interface: Option<&str>) -> io::Result<TcpStream> {
+    let mut last = None;
+    for addr in tokio::net::lookup_host(addr).await? {
+        if bind.is_some_and(|ip| ip.is_ipv4() != addr.is_ipv4()) {
+            continue;
+        }
+        let socket = if addr.is_ipv4() { TcpSocket::new_v4()? } else { TcpSocket::new_v6()? };
+        if let Some(interface) = interface {
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            socket.bind_device(Some(interface.as_bytes()))?;
+            #[cfg(not(any(target_os = "linux", target_os = "android")))]
+            return Err(io::Error::new(io::ErrorKind::Unsupported, format!("binding to interface {} is only supported on linux", interface)));
+        }
+        if let Some(ip) = bind {
+            socket.bind(SocketAddr::new(ip, 0))?;
+        }
+        match socket.connect(addr).await {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last = Some(e),
+        }
+    }
+    Err(last.unwrap_or_else(|| io::Error::new(io::ErrorKind::AddrNotAvailable, "no address of the bind address's family to connect to")))
+}
+
+async fn bind_tcp(addr: &str, options: &ListenOptions) -> io::Result<TcpListener> {
+    let addr = match tokio::net::lookup_host(addr).await?.next() {
+        Some(addr) => addr,
+        None => return Err(io::Error::new(io::ErrorKind::AddrNotAvailable, addr.to_string())),
+    };
+    let socket = if addr.is_ipv4() { TcpSocket::new_v4()? } else { TcpSocket::new_v6()? };
+    // what TcpListener::bind does too, restarts don't wait for TIME_WAIT
+    #[cfg(unix)]
+    socket.set_reuseaddr(true)?;
+    if options.reuse_port {
+        #[cfg(target_os = "linux")]
+        socket.set_reuseport(true)?;
+        #[cfg(not(target_os = "linux"))]
+        return Err(io::Error::new(io::ErrorKind::Unsupported, "reuse_port is only supported on linux"));
+    }
+    socket.bind(addr)?;
+    socket.listen(options.backlog)
+}
+
+// a stale socket file from a previous run would make bind fail, anything else at the path is kept
+#[cfg(unix)]
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path),
+        Ok(_) => Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            format!("{} exists and is not a unix socket", path.display()),
+        )),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(not(unix))]
+fn unix_unsupported() -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported, "unix domain sockets are not supported on this platform")
+}
+
+#[cfg(not(windows))]
+fn pipe_unsupported() -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported, "named pipes are only supported on windows")
+}
+
+// a pipe instance serves one client, the next one is created as soon as it's taken so
+// there is always an instance waiting
+#[cfg(windows)]
+pub struct PipeListener {
+    name: String,
+    next: tokio::sync::Mutex<NamedPipeServer>,
+}
+
+#[cfg(windows)]
+impl PipeListener {
+    fn bind(name: &str) -> io::Result<Self> {
+        // fails when another process already serves the name rather than sharing it
+        let first = ServerOptions::new().first_pipe_instance(true).create(name)?;
+        Ok(PipeListener { name: name.to_string(), next: tokio::sync::Mutex::new(first) })
+    }
+
+    async fn accept(&self) -> io::Result<NamedPipeServer> {
+        let mut next = self.next.lock().await;
+        next.connect().await?;
+        let waiting = ServerOptions::new().create(&self.name)?;
+        Ok(std::mem::replace(&mut *next, waiting))
+    }
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+    #[cfg(windows)]
+    Pipe(PipeListener),
+}
+
+impl Listener {
+    // an already bound socket, e.g. one the embedding app opened and passed in by fd
+    pub fn from_std(listener: std::net::TcpListener) -> io::Result<Self> {
+        listener.set_nonblocking(true)?;
+        Ok(Listener::Tcp(TcpListener::from_std(listener)?))
+    }
+
+    #[cfg(unix)]
+    pub fn from_std_unix(listener: std::os::unix::net::UnixListener) -> io::Result<Self> {
+        listener.set_nonblocking(true)?;
+        Ok(Listener::Unix(UnixListener::from_std(listener)?))
+    }
+
+    // returns the accepted stream and a printable peer address
+    pub async fn accept(&self) -> io::Result<(Stream, String)> {
+        Ok(match self {
+            Listener::Tcp(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                (Stream::Tcp(stream), addr.to_string())
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                let peer = match addr.as_pathname() {
+                    None => format!("{}unnamed", UNIX_PREFIX),
+                    Some(path) => format!("{}{}", UNIX_PREFIX, path.display()),
+                };
+                (Stream::Unix(stream), peer)
+            }
+            #[cfg(windows)]
+            Listener::Pipe(listener) => (Stream::Pipe(listener.accept().await?), format!("{}{}", PIPE_PREFIX, listener.name)),
+        })
+    }
+
+    pub fn local_endpoint(&self) -> io::Result<Endpoint> {
+        Ok(match self {
+            Listener::Tcp(listener) => Endpoint::Tcp(listener.local_addr()?.to_string()),
+            #[cfg(unix)]
+            Listener::Unix(listener) => match listener.local_addr()?.as_pathname() {
+                None => return Err(io::Error::other("unnamed unix socket")),
+                Some(path) => Endpoint::Unix(path.to_path_buf()),
+            }
+            #[cfg(windows)]
+            Listener::Pipe(listener) => Endpoint::Pipe(listener.name.clone()),
+        })
+    }
+}
+
+// the socket under whatever wraps it, still readable after a wrapper gave up on the peer
+pub trait RawStream {
+    type Raw: AsyncRead + Unpin + Send;
+
+    fn raw(&mut self) -> &mut Self::Raw;
+
+    // end with a reset instead of a fin once dropped, where the transport has such a thing
+    fn abort_on_close(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+pub enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+    #[cfg(windows)]
+    Pipe(NamedPipeServer),
+}
+
+impl RawStream for Stream {
+    type Raw = Stream;
+
+    fn raw(&mut self) -> &mut Stream {
+        self
+    }
+
+    // a zero linger, so closing never blocks and the socket is freed at once
+    fn abort_on_close(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(s) => s.set_zero_linger(),
+            #[cfg(unix)]
+            Stream::Unix(_) => Ok(()),
+            #[cfg(windows)]
+            Stream::Pipe(_) => Ok(()),
+        }
+    }
+}
+
+impl Stream {
+    // None for unix sockets and pipes, their peers are on this host
+    pub fn peer_ip(&self) -> Option<IpAddr> {
+        match self {
+            Stream::Tcp(s) => s.peer_addr().ok().map(|addr| addr.ip()),
+            #[cfg(unix)]
+            Stream::Unix(_) => None,
+            #[cfg(windows)]
+            Stream::Pipe(_) => None,
+        }
+    }
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(s) => Pin::new(s).poll_read(cx, buf),
+            #[cfg(unix)]
+            Stream::Unix(s) => Pin::new(s).poll_read(cx, buf),
+            #[cfg(windows)]
+            Stream::Pipe(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Tcp(s) => Pin::new(s).poll_write(cx, buf),
+            #[cfg(unix)]
+            Stream::Unix(s) => Pin::new(s).poll_write(cx, buf),
+            #[cfg(windows)]
+            Stream::Pipe(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(s) => Pin::new(s).poll_flush(cx),
+            #[cfg(unix)]
+            Stream::Unix(s) => Pin::new(s).poll_flush(cx),
+            #[cfg(windows)]
+            Stream::Pipe(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(s) => Pin::new(s).poll_shutdown(cx),
+            #[cfg(unix)]
+            Stream::Unix(s) => Pin::new(s).poll_shutdown(cx),
+            #[cfg(windows)]
+            Stream::Pipe(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use crate::transport::{bind_udp, connect_bound, Endpoint, PortRange};
+
+    #[test]
+    fn port_range_test() {
+        let range: PortRange = "40000-40003".parse().unwrap();
+        let mut ports: Vec<u16> = range.ports().collect();
+        ports.sort();
+        assert_eq!(ports, vec![40000, 40001, 40002, 40003]);
+        assert_eq!("5000".parse::<PortRange>().unwrap(), PortRange { start: 5000, end: 5000 });
+        assert_eq!(range.to_string(), "40000-40003");
+        for invalid in ["0-10", "20-10", "a-b", ""] {
+            assert!(invalid.parse::<PortRange>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[tokio::test]
+    async fn bind_udp_range_test() {
+        let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let taken = bind_udp(ip, None).await.unwrap();
+        let port = taken.local_addr().unwrap().port();
+        let range = PortRange { start: port, end: port };
+        assert_eq!(bind_udp(ip, Some(range)).await.err().unwrap().kind(), std::io::ErrorKind::AddrInUse);
+        drop(taken);
+        assert_eq!(bind_udp(ip, Some(range)).await.unwrap().local_addr().unwrap().port(), port);
+    }
+
+    #[tokio::test]
+    async fn connect_bound_test() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let stream = connect_bound(addr, Some(ip), None).await.unwrap();
+        assert_eq!(stream.local_addr().unwrap().ip(), ip);
+        // an ipv6 source can't reach an ipv4 target
+        let err = connect_bound(addr, Some("::1".parse().unwrap()), None).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::AddrNotAvailable);
+    }
+
+    #[test]
+    fn pipe_endpoint_test() {
+        let endpoint: Endpoint = r"pipe:\\.\pipe\ss5".parse().unwrap();
+        assert_eq!(endpoint, Endpoint::Pipe(r"\\.\pipe\ss5".to_string()));
+        assert_eq!(endpoint.to_string(), r"pipe:\\.\pipe\ss5");
+        assert!("pipe:".parse::<Endpoint>().is_err());
+    }
+
+    #[cfg(not(windows))]
+    #[tokio::test]
+    async fn pipe_unsupported_test() {
+        let endpoint = Endpoint::Pipe(r"\\.\pipe\ss5".to_string());
+        assert_eq!(endpoint.bind().await.err().unwrap().kind(), std::io::ErrorKind::Unsupported);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn unix_bind_keeps_other_files_test() {
+        let path = std::env::temp_dir().join(format!("rust-ss5-bind-{}.sock", std::process::id()));
+        std::fs::write(&path, b"not a socket").unwrap();
+        let endpoint = Endpoint::Unix(path.clone());
+        assert_eq!(endpoint.bind().await.err().unwrap().kind(), std::io::ErrorKind::AddrInUse);
+        assert_eq!(std::fs::read(&path).unwrap(), b"not a socket");
+
+        // a socket left behind by an earlier listener is replaced
+        std::fs::remove_file(&path).unwrap();
+        drop(endpoint.bind().await.unwrap());
+        assert!(endpoint.bind().await.is_ok());
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -0,0 +1,473 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::ops::{Deref, DerefMut};
+
+use bytes::BytesMut;
+use log::{debug, info};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+use tokio::net::{lookup_host, TcpStream, UdpSocket};
+
+use crate::buffers::BufferPool;
+use crate::error::Error;
+use crate::mmsg;
+use crate::policy;
+use crate::relay::Traffic;
+use crate::server::ServerState;
+use crate::socket5::{self, Address, ConnectReply, Reply, UdpHeader};
+use crate::socket5::constant::ATYP_IPV6;
+use crate::transport::bind_udp;
+
+// ATYP, a 255 byte domain with its length, port and RSV FRAG
+pub const MAX_HEADER: usize = 3 + 1 + 1 + 255 + 2;
+
+// where the relay accepts client datagrams from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClientSource {
+    // the control connection's peer, loopback only when it came over a unix socket
+    pub ip: Option<IpAddr>,
+    // DST.ADDR of the request, None when the client sent zeros
+    pub requested: Option<SocketAddr>,
+    // DST.PORT, 0 when the client didn't know it yet
+    pub port: u16,
+}
+
+impl ClientSource {
+    pub fn new(peer: Option<IpAddr>, requested: &Address) -> Self {
+        let (requested, port) = match requested {
+            Address::Address(addr) if !addr.ip().is_unspecified() => (Some(*addr), addr.port()),
+            address => (None, address.port()),
+        };
+        ClientSource { ip: peer, requested, port }
+    }
+
+    pub fn allows(&self, from: SocketAddr) -> bool {
+        let ip = unmapped(from.ip());
+        let peer = match self.ip {
+            Some(peer) => unmapped(peer) == ip,
+            None => ip.is_loopback(),
+        };
+        let requested = self.requested.is_none_or(|requested| unmapped(requested.ip()) == ip);
+        peer && requested && (self.port == 0 || self.port == from.port())
+    }
+}
+
+fn unmapped(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        ip => ip,
+    }
+}
+
+// run a UDP ASSOCIATE until the control connection closes, payload bytes are added to traffic;
+// the relay and outbound sockets go with it however it ends, so no client mapping outlives the connection
+pub async fn associate<S>(control: &mut S, state: &ServerState, source: ClientSource, traffic: &Traffic) -> Result<(), Error>
+    where S: AsyncRead + AsyncWrite + Unpin
+{
+    let _association = state.stats.udp_association();
+    let ip: IpAddr = state.config.host.parse().unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+    let relay = bind_udp(ip, state.config.relay_ports).await?;
+    let outbound = Outbound {
+        v4: UdpSocket::bind((IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0)).await?,
+        // hosts without ipv6 still relay ipv4
+        v6: UdpSocket::bind((IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0)).await.ok(),
+    };
+    let bound = relay.local_addr()?;
+    ConnectReply::new(Reply::RepSuccess, Address::Address(bound)).write(control).await?;
+    info!("udp associate relay : {}", bound);
+
+    let limit = state.config.udp.buffer;
+    let batch = state.config.udp.batch.clamp(1, mmsg::MAX_BATCH);
+    // one byte over the limit tells a datagram that fit from one that was cut
+    let pool = BufferPool::global();
+    let mut relay_bufs: Vec<_> = (0..batch).map(|_| pool.get(limit + MAX_HEADER + 1)).collect();
+    let mut v4_bufs: Vec<_> = (0..batch).map(|_| pool.get(limit + 1)).collect();
+    let mut v6_bufs: Vec<_> = (0..batch).map(|_| pool.get(limit + 1)).collect();
+    let (mut from_client, mut from_v4, mut from_v6) = (Vec::with_capacity(batch), Vec::with_capacity(batch), Vec::with_capacity(batch));
+    // replies are wrapped here, they keep their capacity from one datagram to the next
+    let mut packets = vec![BytesMut::new(); batch];
+    let mut control_buf = [0; 1];
+    let mut client: Option<SocketAddr> = None;
+    loop {
+        tokio::select! {
+            read = control.read(&mut control_buf) => match read {
+                Ok(0) | Err(_) => {
+                    debug!("udp associate relay {} closed with its control connection", bound);
+                    break;
+                }
+                Ok(_) => continue,
+            },
+            result = mmsg::recv_batch(&relay, &mut relay_bufs, &mut from_client) => {
+                result?;
+                let (mut v4, mut v6) = (Vec::new(), Vec::new());
+                for (buf, &(n, from)) in relay_bufs.iter().zip(&from_client) {
+                    // the first allowed source owns the association, anybody else is ignored
+                    if client.is_some_and(|client| client != from) || !source.allows(from) {
+                        debug!("drop udp datagram from foreign source {}", from);
+                        continue;
+                    }
+                    client = Some(from);
+                    if n > limit + MAX_HEADER {
+                        state.stats.udp_datagram(true);
+                        continue;
+                    }
+                    match forward(&outbound, &buf[..n], limit, state).await {
+                        Ok(Forward::To(payload, target @ SocketAddr::V4(_))) => v4.push((payload, target)),
+                        Ok(Forward::To(payload, target)) => v6.push((payload, target)),
+                        Ok(Forward::Fragment) => state.stats.udp_datagram(false),
+                        Ok(Forward::Truncated) => state.stats.udp_datagram(true),
+                        Err(e) => debug!("drop udp datagram from {} : {:?}", from, e),
+                    }
+                }
+                for (socket, datagrams) in [(Some(&outbound.v4), &v4), (outbound.v6.as_ref(), &v6)] {
+                    let Some(socket) = socket.filter(|_| !datagrams.is_empty()) else {
+                        continue;
+                    };
+                    mmsg::send_batch(socket, datagrams, |i, sent| match sent {
+                        Ok(sent) => {
+                            state.stats.udp_datagram(false);
+                            traffic.add_up(sent as u64);
+                        }
+                        Err(e) => debug!("drop udp datagram to {} : {:?}", datagrams[i].1, e),
+                    }).await;
+                }
+            },
+            result = mmsg::recv_batch(&outbound.v4, &mut v4_bufs, &mut from_v4) => {
+                if unreachable(result, state)?.is_some() {
+                    traffic.add_down(reply(&relay, client, &v4_bufs, &from_v4, &mut packets, limit, state).await?);
+                }
+            },
+            result = recv_batch(&outbound.v6, &mut v6_bufs, &mut from_v6) => {
+                if unreachable(result, state)?.is_some() {
+                    traffic.add_down(reply(&relay, client, &v6_bufs, &from_v6, &mut packets, limit, state).await?);
+                }
+            },
+        }
+    }
+    Ok(())
+}
+
+struct Outbound {
+    v4: UdpSocket,
+    v6: Option<UdpSocket>,
+}
+
+// pending forever without an ipv6 socket
+async fn recv_batch<B: DerefMut<Target = [u8]>>(socket: &Option<UdpSocket>, bufs: &mut [B], received: &mut Vec<(usize, SocketAddr)>) -> io::Result<()> {
+    match socket {
+        Some(socket) => mmsg::recv_batch(socket, bufs, received).await,
+        None => std::future::pending().await,
+    }
+}
+
+// an icmp port or host unreachable for an earlier datagram comes back as the error of a later
+// receive, where the platform reports it at all (windows does for these unconnected sockets,
+// linux doesn't); it's counted and the association goes on rather than failing with it
+fn unreachable<T>(received: io::Result<T>, state: &ServerState) -> io::Result<Option<T>> {
+    match received {
+        Ok(received) => Ok(Some(received)),
+        Err(e) if matches!(e.kind(), io::ErrorKind::ConnectionRefused | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::HostUnreachable | io::ErrorKind::NetworkUnreachable) => {
+            debug!("udp destination unreachable : {}", e);
+            state.stats.udp_unreachable();
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+// what becomes of a client datagram
+enum Forward<'a> {
+    // its payload goes to the target
+    To(&'a [u8], SocketAddr),
+    // over the limit
+    Truncated,
+    // fragments are optional in RFC 1928, they're dropped
+    Fragment,
+}
+
+// strip the socks header and find where the payload goes
+async fn forward<'a>(outbound: &Outbound, packet: &'a [u8], limit: usize, state: &ServerState) -> Result<Forward<'a>, Error> {
+    let mut cursor = packet;
+    let header = UdpHeader::from(&mut cursor).await?;
+    if cursor.len() > limit {
+        return Ok(Forward::Truncated);
+    }
+    if header.frag != 0 {
+        return Ok(Forward::Fragment);
+    }
+    let target = match policy::permitted(&header.address, state.config.blocks_private(), &state.resolver, &state.nat64).await? {
+        Address::Address(target) => target,
+        // nothing to check or resolve through doh, the system resolver takes it
+        address => resolve(&address).await?,
+    };
+    let target = state.nat64.map(target).await;
+    if target.is_ipv6() && outbound.v6.is_none() {
+        return Err(Error::Socks(socket5::Error::AddressTypeNo(ATYP_IPV6)));
+    }
+    Ok(Forward::To(cursor, target))
+}
+
+// wrap the datagrams from destinations and hand them back to the client, the payload bytes it got
+async fn reply<B>(relay: &UdpSocket, client: Option<SocketAddr>, bufs: &[B], received: &[(usize, SocketAddr)], packets: &mut [BytesMut], limit: usize, state: &ServerState) -> Result<u64, Error>
+    where B: Deref<Target = [u8]>
+{
+    let mut replies = Vec::new();
+    let mut payloads = Vec::new();
+    for ((buf, &(n, from)), packet) in bufs.iter().zip(received).zip(packets.iter_mut()) {
+        let truncated = n > limit;
+        state.stats.udp_datagram(truncated);
+        if let (Some(client), false) = (client, truncated) {
+            packet.clear();
+            UdpHeader::new(Address::Address(from)).encode(packet)?;
+            packet.extend_from_slice(&buf[..n]);
+            replies.push((&packet[..], client));
+            payloads.push(n);
+        }
+    }
+    let (mut down, mut failed) = (0, None);
+    mmsg::send_batch(relay, &replies, |i, sent| match sent {
+        Ok(_) => down += payloads[i] as u64,
+        Err(e) => failed = failed.take().or(Some(e)),
+    }).await;
+    match failed {
+        Some(e) => Err(e.into()),
+        None => Ok(down),
+    }
+}
+
+// a udp socket whose datagrams go through a socks5 server's UDP ASSOCIATE relay
+pub struct SocksUdpSocket {
+    // the association lasts as long as this connection
+    control: TcpStream,
+    socket: UdpSocket,
+    relay: SocketAddr,
+}
+
+impl SocksUdpSocket {
+    pub(crate) async fn associate(control: TcpStream, relay: Address) -> Result<Self, Error> {
+        let mut relay = resolve(&relay).await?;
+        // servers bound to every interface answer with the unspecified address
+        if relay.ip().is_unspecified() {
+            relay.set_ip(control.peer_addr()?.ip());
+        }
+        let local: IpAddr = if relay.is_ipv4() { Ipv4Addr::UNSPECIFIED.into() } else { Ipv6Addr::UNSPECIFIED.into() };
+        let socket = UdpSocket::bind((local, 0)).await?;
+        socket.connect(relay).await?;
+        Ok(SocksUdpSocket { control, socket, relay })
+    }
+
+    pub fn relay_addr(&self) -> SocketAddr {
+        self.relay
+    }
+
+    pub fn control(&self) -> &TcpStream {
+        &self.control
+    }
+
+    pub async fn send_to(&self, buf: &[u8], target: &Address) -> Result<usize, Error> {
+        let mut packet = Vec::with_capacity(MAX_HEADER + buf.len());
+        UdpHeader::new(target.clone()).write(&mut packet).await?;
+        packet.extend_from_slice(buf);
+        self.socket.send(&packet).await?;
+        Ok(buf.len())
+    }
+
+    // fragments are dropped, like the server does
+    pub async fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, Address), Error> {
+        let mut packet = vec![0; buf.len() + MAX_HEADER];
+        loop {
+            let n = self.socket.recv(&mut packet).await?;
+            let mut cursor = &packet[..n];
+            let header = UdpHeader::from(&mut cursor).await?;
+            if header.frag != 0 {
+                continue;
+            }
+            let n = cursor.len().min(buf.len());
+            buf[..n].copy_from_slice(&cursor[..n]);
+            return Ok((n, header.address));
+        }
+    }
+}
+
+pub async fn encapsulate(from: SocketAddr, data: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut packet = Vec::with_capacity(MAX_HEADER + data.len());
+    UdpHeader::new(Address::Address(from)).write(&mut packet).await?;
+    packet.extend_from_slice(data);
+    Ok(packet)
+}
+
+pub async fn resolve(address: &Address) -> Result<SocketAddr, Error> {
+    match address {
+        Address::Address(addr) => Ok(*addr),
+        Address::DomainName(domain, port) => lookup_host((domain.as_str(), *port)).await?
+            .next()
+            .ok_or_else(|| Error::from(io::Error::new(io::ErrorKind::NotFound, domain.clone()))),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::{TcpStream, UdpSocket};
+
+    use crate::config::{ServerConfig, UdpConfig};
+    use crate::server::start;
+    use crate::socket5::{Address, Command, ConnectReply, Proxy, ShakeHands, UdpHeader};
+    use crate::socket5::constant::*;
+    use crate::tcp::TcpSocksClient;
+    use crate::test_util::udp_echo_server;
+    use crate::transport::PortRange;
+    use crate::test_util::{config, test_state};
+    use crate::udp::{ClientSource, encapsulate, unreachable};
+
+    #[test]
+    fn client_source_test() {
+        let peer = Some("10.0.0.1".parse().unwrap());
+        let any = ClientSource::new(peer, &"0.0.0.0:0".parse().unwrap());
+        assert!(any.allows("10.0.0.1:5000".parse().unwrap()));
+        assert!(any.allows("[::ffff:10.0.0.1]:5000".parse().unwrap()));
+        assert!(!any.allows("10.0.0.2:5000".parse().unwrap()));
+        let port = ClientSource::new(peer, &"0.0.0.0:5000".parse().unwrap());
+        assert!(port.allows("10.0.0.1:5000".parse().unwrap()));
+        assert!(!port.allows("10.0.0.1:5001".parse().unwrap()));
+        let named = ClientSource::new(peer, &"10.0.0.1:5000".parse().unwrap());
+        assert!(named.allows("10.0.0.1:5000".parse().unwrap()));
+        // the request can narrow the peer down, never point somewhere else
+        let elsewhere = ClientSource::new(peer, &"192.168.1.9:5000".parse().unwrap());
+        assert!(!elsewhere.allows("192.168.1.9:5000".parse().unwrap()));
+        assert!(!elsewhere.allows("10.0.0.1:5000".parse().unwrap()));
+        let unix = ClientSource::new(None, &"0.0.0.0:0".parse().unwrap());
+        assert!(unix.allows("127.0.0.1:5000".parse().unwrap()));
+        assert!(!unix.allows("10.0.0.1:5000".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn unreachable_test() {
+        let state = test_state(config());
+        let refused = std::io::Error::from(std::io::ErrorKind::ConnectionRefused);
+        assert!(unreachable::<()>(Err(refused), &state).unwrap().is_none());
+        assert_eq!(state.stats.udp_unreachable_count(), 1);
+        assert!(unreachable::<()>(Err(std::io::Error::other("x")), &state).is_err());
+        let from: SocketAddr = "10.0.0.1:53".parse().unwrap();
+        assert_eq!(unreachable(Ok((3, from)), &state).unwrap(), Some((3, from)));
+    }
+
+    #[tokio::test]
+    async fn udp_associate_test() {
+        let echo_addr = udp_echo_server().await;
+        let handle = start(ServerConfig {
+            port: 0,
+            udp: UdpConfig { buffer: 2048, ..UdpConfig::default() },
+            ..ServerConfig::default()
+        }).await.unwrap();
+        let server = handle.stats().listeners[0].endpoint.to_string();
+        let mut control = TcpStream::connect(server).await.unwrap();
+        ShakeHands::new(vec![METHOD_NO_AUTHENTICATION]).write(&mut control).await.unwrap();
+        let mut selection = [0; 2];
+        tokio::io::AsyncReadExt::read_exact(&mut control, &mut selection).await.unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let requested: SocketAddr = format!("0.0.0.0:{}", client.local_addr().unwrap().port()).parse().unwrap();
+        Proxy::new(Command::UDP, Address::Address(requested)).write(&mut control).await.unwrap();
+        let reply = ConnectReply::from(&mut control).await.unwrap().into_result().unwrap();
+        let relay = match reply.bound {
+            Address::Address(addr) => addr,
+            Address::DomainName(..) => panic!("relay address expected"),
+        };
+
+        // a socket the request didn't name can't take the association over
+        let foreign = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        foreign.send_to(&encapsulate(echo_addr, b"hijack").await.unwrap(), relay).await.unwrap();
+        let mut buf = vec![0; 70000];
+        let answer = tokio::time::timeout(Duration::from_millis(200), foreign.recv_from(&mut buf)).await;
+        assert!(answer.is_err());
+        for size in [1200, 2048] {
+            let data = vec![7; size];
+            client.send_to(&encapsulate(echo_addr, &data).await.unwrap(), relay).await.unwrap();
+            let (n, _) = client.recv_from(&mut buf).await.unwrap();
+            let mut cursor = &buf[..n];
+            let header = UdpHeader::from(&mut cursor).await.unwrap();
+            assert_eq!(header.frag, 0);
+            assert_eq!(cursor, &data[..]);
+        }
+        // over the configured buffer, counted and dropped, whether or not the header could have fit
+        for size in [2049, 4096] {
+            client.send_to(&encapsulate(echo_addr, &vec![7; size]).await.unwrap(), relay).await.unwrap();
+        }
+        for _ in 0..100 {
+            if handle.stats().udp_truncated == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(handle.stats().udp_truncated, 2);
+        control.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn udp_associate_batch_test() {
+        // datagrams that pile up while the relay is busy are taken and sent on a batch at a time
+        let echo_addr = udp_echo_server().await;
+        let handle = start(ServerConfig {
+            port: 0,
+            udp: UdpConfig { buffer: 2048, batch: if cfg!(target_os = "linux") { 8 } else { 1 } },
+            ..ServerConfig::default()
+        }).await.unwrap();
+        let server = handle.stats().listeners[0].endpoint.to_string();
+        let socket = TcpSocksClient::udp_associate(server).await.unwrap();
+        for i in 0..20u8 {
+            socket.send_to(&[i; 100], &Address::Address(echo_addr)).await.unwrap();
+        }
+        let mut got = Vec::new();
+        let mut buf = [0; 256];
+        while got.len() < 20 {
+            let (n, from) = tokio::time::timeout(Duration::from_secs(5), socket.recv_from(&mut buf)).await.unwrap().unwrap();
+            assert_eq!((n, from), (100, Address::Address(echo_addr)));
+            got.push(buf[0]);
+        }
+        got.sort();
+        assert_eq!(got, (0..20).collect::<Vec<_>>());
+        assert_eq!(handle.stats().udp_datagrams, 40);
+    }
+
+    #[tokio::test]
+    async fn udp_associate_client_test() {
+        let echo_addr = udp_echo_server().await;
+        let ports = PortRange { start: 41000, end: 41999 };
+        let handle = start(ServerConfig {
+            port: 0,
+            relay_ports: Some(ports),
+            ..ServerConfig::default()
+        }).await.unwrap();
+        let server = handle.stats().listeners[0].endpoint.to_string();
+        let socket = TcpSocksClient::udp_associate(server).await.unwrap();
+        assert!((ports.start..=ports.end).contains(&socket.relay_addr().port()));
+        socket.send_to(b"hello", &Address::Address(echo_addr)).await.unwrap();
+        let mut buf = [0; 16];
+        let (n, from) = socket.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"hello");
+        assert_eq!(from, Address::Address(echo_addr));
+    }
+
+    #[tokio::test]
+    async fn udp_associate_teardown_test() {
+        let handle = start(ServerConfig { port: 0, ..ServerConfig::default() }).await.unwrap();
+        let server = handle.stats().listeners[0].endpoint.to_string();
+        let socket = TcpSocksClient::udp_associate(server).await.unwrap();
+        let relay = socket.relay_addr();
+        assert_eq!(handle.stats().udp_associations, 1);
+        drop(socket);
+        for _ in 0..100 {
+            if handle.stats().udp_associations == 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(handle.stats().udp_associations, 0);
+        // the relay port was given back
+        UdpSocket::bind(relay).await.unwrap();
+    }
+}
@@ -0,0 +1,59 @@
+use std::any::Any;
+use std::future::Future;
+use std::panic::{AssertUnwindSafe, catch_unwind};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+// runs a connection's future and hands back the panic instead of letting it end the task
+// unseen; what the future held is dropped with it, which closes its sockets and releases its guards
+pub struct CatchUnwind<F> {
+    future: F,
+}
+
+impl<F: Future + Unpin> CatchUnwind<F> {
+    pub fn new(future: F) -> Self {
+        CatchUnwind { future }
+    }
+}
+
+impl<F: Future + Unpin> Future for CatchUnwind<F> {
+    type Output = Result<F::Output, String>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // nothing of the future is looked at again after a panic, so a broken invariant can't leak
+        match catch_unwind(AssertUnwindSafe(|| Pin::new(&mut self.future).poll(cx))) {
+            Ok(Poll::Ready(output)) => Poll::Ready(Ok(output)),
+            Ok(Poll::Pending) => Poll::Pending,
+            Err(panic) => Poll::Ready(Err(message(panic))),
+        }
+    }
+}
+
+// what was passed to panic!, for the log
+fn message(panic: Box<dyn Any + Send>) -> String {
+    match panic.downcast::<String>() {
+        Ok(message) => *message,
+        Err(panic) => panic.downcast_ref::<&str>().map_or("panic", |message| message).to_string(),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::unwind::CatchUnwind;
+
+    #[tokio::test]
+    async fn catch_test() {
+        assert_eq!(CatchUnwind::new(Box::pin(async { 7 })).await, Ok(7));
+        let n = 3;
+        let panicked = CatchUnwind::new(Box::pin(async move {
+            tokio::task::yield_now().await;
+            if n > 2 {
+                panic!("{} is too many", n);
+            }
+        })).await;
+        assert_eq!(panicked, Err("3 is too many".to_string()));
+        let panicked = CatchUnwind::new(Box::pin(async { panic!("static") })).await;
+        assert_eq!(panicked, Err::<(), _>("static".to_string()));
+    }
+}
@@ -0,0 +1,112 @@
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::crypto::Keyring;
+use crate::transport::Endpoint;
+
+// a remote rust-ss5 server the local client can forward through
+#[derive(Debug, Clone)]
+pub struct Upstream {
+    pub name: String,
+    pub endpoint: Endpoint,
+    pub keyring: Keyring,
+}
+
+// one upstream set and the connections relaying through it
+#[derive(Default)]
+struct Generation {
+    servers: Arc<Vec<Upstream>>,
+    active: Arc<AtomicUsize>,
+}
+
+// the current upstream set, swapped as a whole when a subscription refreshes
+#[derive(Clone, Default)]
+pub struct Upstreams {
+    current: Arc<RwLock<Generation>>,
+    // connections on any set, current or replaced
+    active: Arc<AtomicUsize>,
+    next: Arc<AtomicUsize>,
+}
+
+// held by a connection while it relays, counts it against the set it picked from
+pub struct Lease {
+    generation: Arc<AtomicUsize>,
+    total: Arc<AtomicUsize>,
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        self.generation.fetch_sub(1, Ordering::Relaxed);
+        self.total.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Upstreams {
+    pub fn new(servers: Vec<Upstream>) -> Self {
+        let upstreams = Upstreams::default();
+        upstreams.replace(servers);
+        upstreams
+    }
+
+    // round robin over the current set
+    pub fn pick(&self) -> Option<(Upstream, Lease)> {
+        let current = self.current.read().unwrap();
+        if current.servers.is_empty() {
+            return None;
+        }
+        let n = self.next.fetch_add(1, Ordering::Relaxed);
+        current.active.fetch_add(1, Ordering::Relaxed);
+        self.active.fetch_add(1, Ordering::Relaxed);
+        let lease = Lease { generation: current.active.clone(), total: self.active.clone() };
+        Some((current.servers[n % current.servers.len()].clone(), lease))
+    }
+
+    pub fn list(&self) -> Arc<Vec<Upstream>> {
+        self.current.read().unwrap().servers.clone()
+    }
+
+    // connections already relaying keep the upstream they picked, they drain off the old set
+    pub fn replace(&self, servers: Vec<Upstream>) {
+        *self.current.write().unwrap() = Generation { servers: Arc::new(servers), active: Arc::default() };
+    }
+
+    // connections relaying through any set
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    // connections still on a set that has since been replaced
+    pub fn draining(&self) -> usize {
+        let current = self.current.read().unwrap().active.load(Ordering::Relaxed);
+        self.active().saturating_sub(current)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::crypto::Keyring;
+    use crate::transport::Endpoint;
+    use crate::upstream::{Upstream, Upstreams};
+
+    fn upstream(name: &str) -> Upstream {
+        Upstream { name: name.to_string(), endpoint: Endpoint::Tcp(format!("{}:8388", name)), keyring: Keyring::new("", "", &[]).unwrap() }
+    }
+
+    #[test]
+    fn draining_test() {
+        let upstreams = Upstreams::new(vec![upstream("old")]);
+        let (picked, old) = upstreams.pick().unwrap();
+        assert_eq!(picked.name, "old");
+        upstreams.replace(vec![upstream("new")]);
+        let (picked, new) = upstreams.pick().unwrap();
+        assert_eq!(picked.name, "new");
+        assert_eq!((upstreams.active(), upstreams.draining()), (2, 1));
+        drop(old);
+        assert_eq!((upstreams.active(), upstreams.draining()), (1, 0));
+        drop(new);
+        assert_eq!(upstreams.active(), 0);
+        upstreams.replace(Vec::new());
+        assert!(upstreams.pick().is_none());
+    }
+}
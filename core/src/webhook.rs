@@ -0,0 +1,229 @@
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::warn;
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+
+use crate::access::Access;
+use crate::config::{WebhookConfig, WebhookEvent};
+use crate::error::Error;
+use crate::socket5::Reply;
+
+// the first retry waits this long, each one after twice the last
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+// hands connection events to the poster, a no-op unless `webhook` is configured
+#[derive(Clone, Default)]
+pub struct Webhook {
+    sender: Option<mpsc::Sender<Value>>,
+    events: Arc<Vec<WebhookEvent>>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl Webhook {
+    // needs a runtime when configured, the poster is spawned here
+    pub fn new(config: Option<WebhookConfig>) -> Self {
+        let Some(config) = config else {
+            return Webhook::default();
+        };
+        let (sender, receiver) = mpsc::channel(config.queue.max(1));
+        let events = Arc::new(config.events.clone());
+        tokio::spawn(post_events(config, receiver));
+        Webhook { sender: Some(sender), events, dropped: Arc::default() }
+    }
+
+    // events thrown away because the poster was behind
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    pub fn opened(&self, access: &Access) {
+        self.send(WebhookEvent::Opened, || connection(access))
+    }
+
+    pub fn closed(&self, access: &Access, error: Option<&Error>) {
+        self.send(WebhookEvent::Closed, || {
+            let mut event = connection(access);
+            event["duration"] = json!(access.start.elapsed().unwrap_or_default().as_secs_f64());
+            event["bytes_up"] = json!(access.traffic.up());
+            event["bytes_down"] = json!(access.traffic.down());
+            event["close"] = json!(access.traffic.close().map(|close| close.to_string()));
+            event["reply"] = json!(error.map_or(Reply::RepSuccess, |e| e.to_reply()).to_string());
+            event["error"] = json!(error.map(|e| e.to_string()));
+            event
+        })
+    }
+
+    pub fn auth_failed(&self, source: Option<IpAddr>, user: &str) {
+        self.send(WebhookEvent::AuthFailed, || json!({"source": source.map(|ip| ip.to_string()), "user": user}))
+    }
+
+    pub fn quota_exceeded(&self, access: &Access) {
+        self.send(WebhookEvent::QuotaExceeded, || connection(access))
+    }
+
+    // never waits on the poster, a slow receiver costs events rather than connections
+    fn send(&self, kind: WebhookEvent, event: impl FnOnce() -> Value) {
+        let Some(sender) = &self.sender else {
+            return;
+        };
+        if !self.events.is_empty() && !self.events.contains(&kind) {
+            return;
+        }
+        let mut event = event();
+        event["event"] = json!(kind);
+        event["time"] = json!(SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64());
+        if let Err(mpsc::error::TrySendError::Full(_)) = sender.try_send(event) {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+fn connection(access: &Access) -> Value {
+    json!({
+        "source": access.source.map(|ip| ip.to_string()),
+        "user": access.user,
+        "command": access.proxy.as_ref().map(|proxy| proxy.command.to_string()),
+        "target": access.proxy.as_ref().map(|proxy| proxy.address.to_string()),
+    })
+}
+
+// batches events and posts them, events arriving while a batch is retried wait in the queue
+async fn post_events(config: WebhookConfig, mut events: mpsc::Receiver<Value>) {
+    let agent: ureq::Agent = ureq::Agent::config_builder()
+        .timeout_global(Some(Duration::from_secs(config.timeout.max(1))))
+        .build()
+        .into();
+    let mut batch = Vec::new();
+    let mut interval = tokio::time::interval(Duration::from_secs(config.interval.max(1)));
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Some(event) => {
+                    batch.push(event);
+                    if batch.len() < config.batch {
+                        continue;
+                    }
+                }
+                None => {
+                    post(&agent, &config, &mut batch).await;
+                    break;
+                }
+            },
+            _ = interval.tick() => {}
+        }
+        post(&agent, &config, &mut batch).await;
+    }
+}
+
+async fn post(agent: &ureq::Agent, config: &WebhookConfig, batch: &mut Vec<Value>) {
+    if batch.is_empty() {
+        return;
+    }
+    let body = json!({"events": std::mem::take(batch)}).to_string();
+    let mut delay = RETRY_DELAY;
+    for attempt in 0..=config.retries {
+        if attempt > 0 {
+            tokio::time::sleep(delay).await;
+            delay *= 2;
+        }
+        let (agent, url, body) = (agent.clone(), config.url.clone(), body.clone());
+        let posted = tokio::task::spawn_blocking(move || {
+            agent.post(&url).header("Content-Type", "application/json").send(&body).map(|_| ())
+        }).await;
+        let e = match posted {
+            Ok(Ok(())) => return,
+            Ok(Err(e)) => e,
+            Err(e) => {
+                warn!("post events to {} fail : {}", config.url, e);
+                return;
+            }
+        };
+        // the receiver turned the events down, sending them again won't change its mind
+        let retry = !matches!(e, ureq::Error::StatusCode(code) if code < 500 && code != 429);
+        if !retry || attempt == config.retries {
+            warn!("post events to {} fail, dropping them : {}", config.url, e);
+            return;
+        }
+        warn!("post events to {} fail, retrying in {:?} : {}", config.url, delay, e);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+
+    use crate::access::Access;
+    use crate::config::{WebhookConfig, WebhookEvent};
+    use crate::error::Error;
+    use crate::socket5::{Address, Command, Proxy};
+    use crate::webhook::Webhook;
+
+    // answers each request with the next status, passing the bodies on
+    async fn receiver(statuses: Vec<u16>) -> (String, mpsc::UnboundedReceiver<Value>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/events", listener.local_addr().unwrap());
+        let (bodies, received) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            for status in statuses {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0; 4096];
+                let body = loop {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                    let text = String::from_utf8_lossy(&request).to_string();
+                    let Some((head, body)) = text.split_once("\r\n\r\n") else {
+                        continue;
+                    };
+                    let length: usize = head.lines()
+                        .find_map(|line| line.to_ascii_lowercase().strip_prefix("content-length:").map(|n| n.trim().parse().unwrap()))
+                        .unwrap();
+                    if body.len() >= length {
+                        break body.to_string();
+                    }
+                };
+                stream.write_all(format!("HTTP/1.1 {} X\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status).as_bytes()).await.unwrap();
+                bodies.send(serde_json::from_str(&body).unwrap()).unwrap();
+            }
+        });
+        (url, received)
+    }
+
+    #[tokio::test]
+    async fn webhook_test() {
+        let (url, mut received) = receiver(vec![503, 200]).await;
+        let webhook = Webhook::new(Some(WebhookConfig {
+            url,
+            events: vec![WebhookEvent::Opened, WebhookEvent::Closed, WebhookEvent::AuthFailed],
+            batch: 3,
+            ..WebhookConfig::default()
+        }));
+        let mut access = Access::new(Some("192.0.2.1".parse().unwrap()));
+        access.user = Some("alice".to_string());
+        access.proxy = Some(Proxy::new(Command::CONNECT, Address::DomainName("example.com".to_string(), 443)));
+        webhook.opened(&access);
+        // not asked for
+        webhook.quota_exceeded(&access);
+        webhook.auth_failed(access.source, "mallory");
+        webhook.closed(&access, Some(&Error::Blocked("example.com".to_string())));
+
+        // the batch turned away with a 503 comes again
+        let first = received.recv().await.unwrap();
+        assert_eq!(received.recv().await.unwrap(), first);
+        let events = first["events"].as_array().unwrap();
+        let kinds: Vec<&str> = events.iter().map(|event| event["event"].as_str().unwrap()).collect();
+        assert_eq!(kinds, ["opened", "auth_failed", "closed"]);
+        assert_eq!(events[0]["target"], "example.com:443");
+        assert_eq!(events[1]["user"], "mallory");
+        assert_eq!(events[2]["reply"], "host-unreachable");
+        assert_eq!(webhook.dropped(), 0);
+    }
+}
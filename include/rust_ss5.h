@@ -0,0 +1,66 @@
+/* generated by cbindgen --config cbindgen.toml --output include/rust_ss5.h, do not edit */
+
+#ifndef RUST_SS5_H
+#define RUST_SS5_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+typedef struct Ss5Local Ss5Local;
+
+typedef struct Ss5LocalStats {
+  uint64_t servers;
+  uint64_t active;
+  uint64_t draining;
+  uint64_t listeners;
+} Ss5LocalStats;
+
+typedef struct Ss5StreamStats {
+  uint32_t id;
+  uint64_t bytes_sent;
+  uint64_t bytes_received;
+  uint64_t rtt_us;
+  uint64_t stalls;
+} Ss5StreamStats;
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/**
+ * Starts a local client from the toml text of a local config, null on failure.
+ */
+Ss5Local *ss5_local_start(const char *config);
+
+/**
+ * Restarts the client with a new config, 0 on success. The old listeners are closed first so
+ * the new ones can take their ports; on failure the client stays stopped until the next update.
+ */
+int ss5_local_update(Ss5Local *local, const char *config);
+
+/**
+ * Fills `stats`, 0 on success.
+ */
+int ss5_local_stats(const Ss5Local *local, Ss5LocalStats *stats);
+
+/**
+ * Fills up to `len` entries of `streams` with the open mux streams and returns how many are
+ * open, which may be more than `len`; -1 on failure. Without `mux = true` there are none.
+ */
+int ss5_local_streams(const Ss5Local *local, Ss5StreamStats *streams, size_t len);
+
+/**
+ * Stops the client and frees it, waiting for its listeners to close.
+ */
+void ss5_local_stop(Ss5Local *local);
+
+/**
+ * Why the last call on this thread failed, null if none did; valid until the next failing call.
+ */
+const char *ss5_last_error(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* RUST_SS5_H */
@@ -0,0 +1,142 @@
+// every socks5 message against bytes written out from RFC 1928 and RFC 1929, decoded and
+// encoded both ways, so a field that goes missing or moves shows up as a byte diff
+
+use bytes::BytesMut;
+
+use crate::socket5::{Address, Command, ConnectReply, Decoded, Error, MethodSelection, Proxy, Reply, ShakeHands, UdpHeader, UserPassAuth};
+
+fn encoded(encode: impl FnOnce(&mut BytesMut)) -> Vec<u8> {
+    let mut buf = BytesMut::new();
+    encode(&mut buf);
+    buf.to_vec()
+}
+
+// the whole message and nothing after it
+fn decoded<T>(decode: fn(&[u8]) -> Result<Decoded<T>, Error>, bytes: &[u8]) -> T {
+    let mut more = bytes.to_vec();
+    more.push(0xee);
+    match decode(&more).unwrap() {
+        Decoded::Done(value, n) => {
+            assert_eq!(n, bytes.len(), "{:02x?}", bytes);
+            value
+        }
+        Decoded::Needs(n) => panic!("{:02x?} needs {}", bytes, n),
+    }
+}
+
+// DST.ADDR DST.PORT of every ATYP, as they follow a request, reply or udp header
+fn addresses() -> Vec<(Address, Vec<u8>)> {
+    vec![
+        (Address::Address("192.0.2.1:80".parse().unwrap()), vec![0x01, 192, 0, 2, 1, 0x00, 0x50]),
+        (Address::DomainName("example.com".to_string(), 443), [&[0x03, 11][..], b"example.com", &[0x01, 0xbb]].concat()),
+        (
+            Address::Address("[2001:db8::1]:8080".parse().unwrap()),
+            vec![0x04, 0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x01, 0x1f, 0x90],
+        ),
+    ]
+}
+
+#[test]
+fn method_negotiation_test() {
+    // VER NMETHODS METHODS
+    let hello = [0x05, 0x02, 0x00, 0x02];
+    assert_eq!(decoded(ShakeHands::decode, &hello).methods, vec![0x00, 0x02]);
+    assert_eq!(encoded(|buf| ShakeHands::new(vec![0x00, 0x02]).encode(buf).unwrap()), hello);
+    assert_eq!(decoded(ShakeHands::decode, &[0x05, 0x00]).methods, Vec::<u8>::new());
+
+    // VER METHOD
+    for method in [0x00, 0x02, 0xff] {
+        let selection = [0x05, method];
+        assert_eq!(decoded(MethodSelection::decode, &selection), MethodSelection::new(method));
+        assert_eq!(encoded(|buf| MethodSelection::new(method).encode(buf)), selection);
+    }
+}
+
+#[test]
+fn username_password_test() {
+    // VER ULEN UNAME PLEN PASSWD, with VER 1 for the sub-negotiation
+    let auth = [&[0x01, 5][..], b"alice", &[6], b"secret"].concat();
+    let message = UserPassAuth::new("alice".to_string(), "secret".to_string());
+    assert_eq!(decoded(UserPassAuth::decode, &auth), message);
+    assert_eq!(encoded(|buf| message.encode(buf).unwrap()), auth);
+
+    // VER STATUS, anything but 0 is a failure
+    assert!(decoded(UserPassAuth::decode_status, &[0x01, 0x00]));
+    assert!(!decoded(UserPassAuth::decode_status, &[0x01, 0x01]));
+    assert_eq!(encoded(|buf| UserPassAuth::encode_status(true, buf)), [0x01, 0x00]);
+    assert_eq!(encoded(|buf| UserPassAuth::encode_status(false, buf)), [0x01, 0x01]);
+}
+
+#[test]
+fn request_test() {
+    // VER CMD RSV ATYP DST.ADDR DST.PORT
+    for (command, code) in [(Command::CONNECT, 0x01), (Command::BIND, 0x02), (Command::UDP, 0x03)] {
+        for (address, bytes) in addresses() {
+            let request = [&[0x05, code, 0x00][..], &bytes].concat();
+            let proxy = decoded(Proxy::decode, &request);
+            assert_eq!((&proxy.command, &proxy.address), (&command, &address));
+            assert_eq!(encoded(|buf| Proxy::new(command.clone(), address).encode(buf).unwrap()), request);
+        }
+    }
+}
+
+#[test]
+fn reply_test() {
+    // VER REP RSV ATYP BND.ADDR BND.PORT, the REP codes of section 6 and whatever else comes
+    let names = [
+        Reply::RepSuccess, Reply::RepServerFail, Reply::RepConnNo, Reply::RepNetworkNo, Reply::RepHostNo,
+        Reply::RepConnRefused, Reply::RepTtlExp, Reply::RepCmdNo, Reply::RepAddressNo, Reply::RepNo,
+    ];
+    for code in 0..=u8::MAX {
+        let reply = names.get(code as usize).cloned().unwrap_or(Reply::Other(code));
+        for (address, bytes) in addresses() {
+            let message = [&[0x05, code, 0x00][..], &bytes].concat();
+            let decoded = decoded(ConnectReply::decode, &message);
+            assert_eq!((&decoded.reply, &decoded.bound), (&reply, &address));
+            assert_eq!(encoded(|buf| ConnectReply::new(reply.clone(), address).encode(buf).unwrap()), message);
+        }
+    }
+}
+
+#[test]
+fn udp_header_test() {
+    // RSV(2) FRAG ATYP DST.ADDR DST.PORT
+    for frag in [0x00, 0x01, 0x80] {
+        for (address, bytes) in addresses() {
+            let message = [&[0x00, 0x00, frag][..], &bytes].concat();
+            let header = decoded(UdpHeader::decode, &message);
+            assert_eq!((header.frag, &header.address), (frag, &address));
+            let mut header = UdpHeader::new(address);
+            header.frag = frag;
+            assert_eq!(encoded(|buf| header.encode(buf).unwrap()), message);
+        }
+    }
+}
+
+#[test]
+fn malformed_test() {
+    // another socks version anywhere VER is
+    assert!(matches!(ShakeHands::decode(&[0x04, 0x01, 0x00]), Err(Error::VersionNo(0x04))));
+    assert!(matches!(MethodSelection::decode(&[0x04, 0x00]), Err(Error::VersionNo(0x04))));
+    assert!(matches!(Proxy::decode(&[0x04, 0x01, 0x00, 0x01, 1, 2, 3, 4, 0, 80]), Err(Error::VersionNo(0x04))));
+    assert!(matches!(ConnectReply::decode(&[0x04, 0x00, 0x00, 0x01, 1, 2, 3, 4, 0, 80]), Err(Error::VersionNo(0x04))));
+    assert!(matches!(UserPassAuth::decode(&[0x05, 0x00, 0x00]), Err(Error::VersionNo(0x05))));
+    // commands and address types past the assigned ones
+    assert!(matches!(Proxy::decode(&[0x05, 0x04, 0x00, 0x01, 1, 2, 3, 4, 0, 80]), Err(Error::CommandNo(0x04))));
+    assert!(matches!(Proxy::decode(&[0x05, 0x01, 0x00, 0x02, 1, 2, 3, 4, 0, 80]), Err(Error::AddressTypeNo(0x02))));
+    assert!(matches!(UdpHeader::decode(&[0x00, 0x00, 0x00, 0x05]), Err(Error::AddressTypeNo(0x05))));
+    // a DOMAINNAME of length 0, which no encoder writes
+    assert!(matches!(Proxy::decode(&[0x05, 0x01, 0x00, 0x03, 0x00, 0, 80]), Err(Error::DomainLength(0))));
+    assert!(matches!(UdpHeader::decode(&[0x00, 0x00, 0x00, 0x03, 0x00, 0, 53]), Err(Error::DomainLength(0))));
+    // a domain that doesn't fit its one byte length isn't written
+    let long = Address::DomainName("a".repeat(256), 80);
+    assert!(matches!(Proxy::new(Command::CONNECT, long).encode(&mut BytesMut::new()), Err(Error::DomainLength(256))));
+    // nor are credentials or method lists that don't fit theirs
+    let auth = |username: &str, password: &str| UserPassAuth::new(username.to_string(), password.to_string()).encode(&mut BytesMut::new());
+    assert!(matches!(auth("alice", &"p".repeat(256)), Err(Error::PasswordLength(256))));
+    assert!(matches!(auth("alice", ""), Err(Error::PasswordLength(0))));
+    assert!(matches!(auth(&"u".repeat(256), "secret"), Err(Error::UsernameLength(256))));
+    assert!(auth(&"u".repeat(255), &"p".repeat(255)).is_ok());
+    assert!(matches!(ShakeHands::new(vec![0x00; 256]).encode(&mut BytesMut::new()), Err(Error::MethodsLength(256))));
+    assert!(matches!(ShakeHands::new(vec![]).encode(&mut BytesMut::new()), Err(Error::MethodsLength(0))));
+}
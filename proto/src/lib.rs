@@ -0,0 +1,4 @@
+// the protocol alone, no sockets, runtime or crypto; rust-ss5 builds its servers and clients on it
+pub mod socket5;
+#[cfg(test)]
+mod conformance;
@@ -0,0 +1,1018 @@
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::str::FromStr;
+use std::string::FromUtf8Error;
+
+use bytes::{BufMut, BytesMut};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::socket5::constant::*;
+
+// socket5 https://www.ietf.org/rfc/rfc1928.txt
+pub mod constant {
+    pub const SOCKET5_VERSION: u8 = 0x05;
+    pub const METHOD_NO_AUTHENTICATION: u8 = 0x00;
+    pub const METHOD_USERNAME_PASSWORD: u8 = 0x02;
+    // from the private range, the connection becomes a mux session once selected
+    pub const METHOD_MUX: u8 = 0x80;
+    pub const METHOD_NO_ACCEPTABLE: u8 = 0xFF;
+    pub const RSV: u8 = 0x00;
+    pub const CMD_CONNECT: u8 = 0x01;
+    pub const CMD_BIND: u8 = 0x02;
+    pub const CMD_UDP: u8 = 0x03;
+    pub const ATYP_IPV4: u8 = 0x01;
+    pub const ATYP_DOMAINNAME: u8 = 0x03;
+    pub const ATYP_IPV6: u8 = 0x04;
+    pub const REP_SUCESS: u8 = 0x00;
+    pub const REP_SERVER_FAIL: u8 = 0x01;
+    pub const REP_CONN_NO: u8 = 0x02;
+    pub const REP_NETWORK_NO: u8 = 0x03;
+    pub const REP_HOST_NO: u8 = 0x04;
+    pub const REP_CONN_REFUSED: u8 = 0x05;
+    pub const REP_TTL_EXP: u8 = 0x06;
+    pub const REP_CMD_NO: u8 = 0x07;
+    pub const REP_ADDRESS_NO: u8 = 0x08;
+    pub const REP_NO: u8 = 0x09;
+    // username/password sub-negotiation https://www.ietf.org/rfc/rfc1929.txt
+    pub const AUTH_VERSION: u8 = 0x01;
+    pub const AUTH_SUCCESS: u8 = 0x00;
+    pub const AUTH_FAILURE: u8 = 0x01;
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    CONNECT,
+    BIND,
+    UDP,
+}
+
+// what decoding and encoding can fail with; more may come, so matches need a catch-all
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    IoError(io::Error),
+    AddressTypeNo(u8),
+    AddressDomainNo,
+    VersionNo(u8),
+    CommandNo(u8),
+    MethodNo(u8),
+    // the upstream server answered the request with a non success reply
+    Rejected(Reply),
+    AuthFailed(String),
+    // domains go on the wire with a one byte length, 1 to 255
+    DomainLength(usize),
+    // so do the username and password of RFC 1929
+    UsernameLength(usize),
+    PasswordLength(usize),
+    // and the methods a client offers
+    MethodsLength(usize),
+    // port 0 is only meaningful in UDP ASSOCIATE and BIND requests, the client may not know it yet
+    PortNo,
+    // a string form that names no command or reply
+    ValueNo(String),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::IoError(e) => write!(f, "{}", e),
+            Error::AddressTypeNo(atyp) => write!(f, "unsupported address type {}", atyp),
+            Error::AddressDomainNo => write!(f, "invalid address"),
+            Error::VersionNo(version) => write!(f, "unsupported socks version {}", version),
+            Error::CommandNo(command) => write!(f, "unsupported command {}", command),
+            Error::MethodNo(method) => write!(f, "unsupported method {}", method),
+            Error::Rejected(reply) => write!(f, "rejected : {}", reply),
+            Error::AuthFailed(user) => write!(f, "authentication failed for {}", user),
+            Error::DomainLength(len) => write!(f, "domain length {} not in 1..=255", len),
+            Error::UsernameLength(len) => write!(f, "username length {} not in 1..=255", len),
+            Error::PasswordLength(len) => write!(f, "password length {} not in 1..=255", len),
+            Error::MethodsLength(len) => write!(f, "{} methods not in 1..=255", len),
+            Error::PortNo => write!(f, "port 0"),
+            Error::ValueNo(value) => write!(f, "unknown value {}", value),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::IoError(err)
+    }
+}
+
+impl From<FromUtf8Error> for Error {
+    fn from(_: FromUtf8Error) -> Self {
+        Error::AddressDomainNo
+    }
+}
+
+// codes past the assigned ones come and go as Other, more may be named later
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum Reply {
+    RepSuccess,
+    RepServerFail,
+    RepConnNo,
+    RepNetworkNo,
+    RepHostNo,
+    RepConnRefused,
+    RepTtlExp,
+    RepCmdNo,
+    RepAddressNo,
+    RepNo,
+    Other(u8),
+}
+
+// string forms for config files and admin payloads
+impl Display for Command {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Command::CONNECT => "connect",
+            Command::BIND => "bind",
+            Command::UDP => "udp",
+        })
+    }
+}
+
+impl FromStr for Command {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "connect" => Ok(Command::CONNECT),
+            "bind" => Ok(Command::BIND),
+            "udp" | "udp-associate" => Ok(Command::UDP),
+            _ => Err(Error::ValueNo(s.to_string())),
+        }
+    }
+}
+
+// unassigned codes are written as their number
+impl Display for Reply {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Reply::RepSuccess => "success",
+            Reply::RepServerFail => "server-fail",
+            Reply::RepConnNo => "not-allowed",
+            Reply::RepNetworkNo => "network-unreachable",
+            Reply::RepHostNo => "host-unreachable",
+            Reply::RepConnRefused => "connection-refused",
+            Reply::RepTtlExp => "ttl-expired",
+            Reply::RepCmdNo => "command-not-supported",
+            Reply::RepAddressNo => "address-not-supported",
+            Reply::RepNo => "unassigned",
+            Reply::Other(u) => return write!(f, "{}", u),
+        })
+    }
+}
+
+impl FromStr for Reply {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(u) = s.parse::<u8>() {
+            return Ok(Reply::from_u8(u));
+        }
+        (0..=REP_NO).map(Reply::from_u8)
+            .find(|reply| reply.to_string() == s)
+            .ok_or_else(|| Error::ValueNo(s.to_string()))
+    }
+}
+
+// every byte is some reply, so the TryFrom<u8> this gives can't fail; `u.into()` rather than
+// Reply::from, which reads one off a stream
+impl From<u8> for Reply {
+    fn from(u: u8) -> Self {
+        Reply::from_u8(u)
+    }
+}
+
+impl From<Reply> for u8 {
+    fn from(reply: Reply) -> Self {
+        reply.to_u8()
+    }
+}
+
+impl Display for Address {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Address::Address(addr) => write!(f, "{}", addr),
+            Address::DomainName(name, port) => write!(f, "{}:{}", name, port),
+        }
+    }
+}
+
+macro_rules! serde_string {
+    ($($ty:ty),*) => {$(
+        impl Serialize for $ty {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.collect_str(self)
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $ty {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                let s = String::deserialize(deserializer)?;
+                s.parse().map_err(|e: Error| serde::de::Error::custom(format!("{} : {}", s, e)))
+            }
+        }
+    )*};
+}
+
+serde_string!(Address, Command, Reply);
+
+impl Error {
+    pub fn to_reply(&self) -> Reply {
+        Reply::from_u8(
+            match self {
+                Error::IoError(e) => match e.kind() {
+                    io::ErrorKind::ConnectionRefused => REP_CONN_REFUSED,
+                    io::ErrorKind::NetworkUnreachable => REP_NETWORK_NO,
+                    io::ErrorKind::HostUnreachable => REP_HOST_NO,
+                    io::ErrorKind::TimedOut => REP_TTL_EXP,
+                    _ => REP_SERVER_FAIL,
+                },
+                Error::AddressTypeNo(_) => REP_ADDRESS_NO,
+                Error::AddressDomainNo => REP_HOST_NO,
+                Error::VersionNo(_) => REP_NO,
+                Error::CommandNo(_) => REP_CMD_NO,
+                Error::MethodNo(_) => REP_SERVER_FAIL,
+                Error::Rejected(reply) => reply.to_u8(),
+                Error::AuthFailed(_) => REP_CONN_NO,
+                Error::DomainLength(_) => REP_ADDRESS_NO,
+                Error::UsernameLength(_) | Error::PasswordLength(_) | Error::MethodsLength(_) => REP_SERVER_FAIL,
+                Error::PortNo => REP_ADDRESS_NO,
+                Error::ValueNo(_) => REP_SERVER_FAIL,
+            }
+        )
+    }
+}
+
+
+// sans-io decoding: a message's decode takes the bytes received so far and either finds the whole
+// message at their start or says how long they must be to get further, so the async readers below
+// read exactly that much and never into whatever follows the message
+#[derive(Debug, Clone, PartialEq)]
+pub enum Decoded<T> {
+    // the message and how many bytes it took
+    Done(T, usize),
+    // the total length needed before decoding can go on
+    Needs(usize),
+}
+
+// the inner message's value and where it ends, or its Needs moved past offset
+macro_rules! take {
+    ($decoded:expr, $offset:expr) => {
+        match $decoded? {
+            Decoded::Done(value, n) => (value, $offset + n),
+            Decoded::Needs(total) => return Ok(Decoded::Needs($offset + total)),
+        }
+    };
+}
+
+async fn read_decoded<T, R, D>(read: &mut R, decode: D) -> Result<T, Error>
+    where R: AsyncRead + Unpin, D: Fn(&[u8]) -> Result<Decoded<T>, Error>
+{
+    let mut buf = Vec::new();
+    loop {
+        match decode(&buf)? {
+            Decoded::Done(value, _) => return Ok(value),
+            Decoded::Needs(total) => {
+                let start = buf.len();
+                buf.resize(total, 0);
+                read.read_exact(&mut buf[start..]).await?;
+            }
+        }
+    }
+}
+
+// one write per message, encoded in full first so nothing is written when encoding fails
+async fn write_buf<W: AsyncWrite + Unpin>(write: &mut W, buf: BytesMut) -> Result<(), Error> {
+    write.write_all(&buf).await?;
+    Ok(())
+}
+
+impl Reply {
+    pub fn from_u8(u: u8) -> Self {
+        match u {
+            REP_SUCESS => Reply::RepSuccess,
+            REP_SERVER_FAIL => Reply::RepServerFail,
+            REP_CONN_NO => Reply::RepConnNo,
+            REP_NETWORK_NO => Reply::RepNetworkNo,
+            REP_HOST_NO => Reply::RepHostNo,
+            REP_CONN_REFUSED => Reply::RepConnRefused,
+            REP_TTL_EXP => Reply::RepTtlExp,
+            REP_CMD_NO => Reply::RepCmdNo,
+            REP_ADDRESS_NO => Reply::RepAddressNo,
+            REP_NO => Reply::RepNo,
+            _ => Reply::Other(u),
+        }
+    }
+
+    pub fn to_u8(&self) -> u8 {
+        match self {
+            Reply::RepSuccess => REP_SUCESS,
+            Reply::RepServerFail => REP_SERVER_FAIL,
+            Reply::RepConnNo => REP_CONN_NO,
+            Reply::RepNetworkNo => REP_NETWORK_NO,
+            Reply::RepHostNo => REP_HOST_NO,
+            Reply::RepConnRefused => REP_CONN_REFUSED,
+            Reply::RepTtlExp => REP_TTL_EXP,
+            Reply::RepCmdNo => REP_CMD_NO,
+            Reply::RepAddressNo => REP_ADDRESS_NO,
+            Reply::RepNo => REP_NO,
+            Reply::Other(u) => *u
+        }
+    }
+
+    // VER REP RSV
+    pub fn decode(buf: &[u8]) -> Result<Decoded<Self>, Error> {
+        if buf.len() < 3 {
+            return Ok(Decoded::Needs(3));
+        }
+        if buf[0] != SOCKET5_VERSION {
+            return Err(Error::VersionNo(buf[0]));
+        }
+        Ok(Decoded::Done(Reply::from_u8(buf[1]), 3))
+    }
+
+    pub fn encode(&self, buf: &mut BytesMut) {
+        buf.put_slice(&[SOCKET5_VERSION, self.to_u8(), RSV]);
+    }
+
+    pub async fn from<T>(read: &mut T) -> Result<Self, Error>
+        where T: AsyncRead + Unpin
+    {
+        read_decoded(read, Reply::decode).await
+    }
+
+    pub async fn write<T>(&self, write: &mut T) -> Result<(), Error>
+        where T: AsyncWrite + Unpin
+    {
+        let mut buf = BytesMut::new();
+        self.encode(&mut buf);
+        write_buf(write, buf).await
+    }
+}
+
+impl Command {
+    pub fn to_u8(&self) -> u8 {
+        match self {
+            Command::CONNECT => CMD_CONNECT,
+            Command::BIND => CMD_BIND,
+            Command::UDP => CMD_UDP,
+        }
+    }
+
+    pub fn from_u8(u: u8) -> Result<Self, Error> {
+        match u {
+            CMD_CONNECT => Ok(Command::CONNECT),
+            CMD_BIND => Ok(Command::BIND),
+            CMD_UDP => Ok(Command::UDP),
+            _ => Err(Error::CommandNo(u))
+        }
+    }
+
+    // VER CMD RSV
+    pub fn decode(buf: &[u8]) -> Result<Decoded<Self>, Error> {
+        if buf.len() < 3 {
+            return Ok(Decoded::Needs(3));
+        }
+        if buf[0] != SOCKET5_VERSION {
+            return Err(Error::VersionNo(buf[0]));
+        }
+        Ok(Decoded::Done(Command::from_u8(buf[1])?, 3))
+    }
+
+    pub fn encode(&self, buf: &mut BytesMut) {
+        buf.put_slice(&[SOCKET5_VERSION, self.to_u8(), RSV]);
+    }
+
+    pub async fn from<T>(read: &mut T) -> Result<Self, Error>
+        where T: AsyncRead + Unpin
+    {
+        read_decoded(read, Command::decode).await
+    }
+
+    pub async fn write<T>(&self, write: &mut T) -> Result<(), Error>
+        where T: AsyncWrite + Unpin
+    {
+        let mut buf = BytesMut::new();
+        self.encode(&mut buf);
+        write_buf(write, buf).await
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ShakeHands {
+    pub methods: Vec<u8>,
+}
+
+impl ShakeHands {
+    pub fn new(methods: Vec<u8>) -> Self {
+        ShakeHands { methods }
+    }
+
+    // VER NMETHODS METHODS
+    pub fn decode(buf: &[u8]) -> Result<Decoded<Self>, Error> {
+        if buf.len() < 2 {
+            return Ok(Decoded::Needs(2));
+        }
+        if buf[0] != SOCKET5_VERSION {
+            return Err(Error::VersionNo(buf[0]));
+        }
+        let len = 2 + buf[1] as usize;
+        if buf.len() < len {
+            return Ok(Decoded::Needs(len));
+        }
+        Ok(Decoded::Done(ShakeHands { methods: buf[2..len].to_vec() }, len))
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        match self.methods.len() {
+            1..=255 => Ok(()),
+            len => Err(Error::MethodsLength(len)),
+        }
+    }
+
+    pub fn encode(&self, buf: &mut BytesMut) -> Result<(), Error> {
+        self.validate()?;
+        buf.put_u8(SOCKET5_VERSION);
+        buf.put_u8(self.methods.len() as u8);
+        buf.put_slice(&self.methods);
+        Ok(())
+    }
+
+    pub async fn from<T>(read: &mut T) -> Result<Self, Error>
+        where T: AsyncRead + Unpin
+    {
+        read_decoded(read, ShakeHands::decode).await
+    }
+
+    pub async fn write<T>(&self, write: &mut T) -> Result<(), Error>
+        where T: AsyncWrite + Unpin
+    {
+        let mut buf = BytesMut::new();
+        self.encode(&mut buf)?;
+        write_buf(write, buf).await
+    }
+}
+
+// the server's answer to ShakeHands, METHOD_NO_ACCEPTABLE when none of the offered methods fits
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MethodSelection {
+    pub method: u8,
+}
+
+impl MethodSelection {
+    pub fn new(method: u8) -> Self {
+        MethodSelection { method }
+    }
+
+    // VER METHOD
+    pub fn decode(buf: &[u8]) -> Result<Decoded<Self>, Error> {
+        if buf.len() < 2 {
+            return Ok(Decoded::Needs(2));
+        }
+        if buf[0] != SOCKET5_VERSION {
+            return Err(Error::VersionNo(buf[0]));
+        }
+        Ok(Decoded::Done(MethodSelection { method: buf[1] }, 2))
+    }
+
+    pub fn encode(&self, buf: &mut BytesMut) {
+        buf.put_u8(SOCKET5_VERSION);
+        buf.put_u8(self.method);
+    }
+
+    pub async fn from<T>(read: &mut T) -> Result<Self, Error>
+        where T: AsyncRead + Unpin
+    {
+        read_decoded(read, MethodSelection::decode).await
+    }
+
+    pub async fn write<T>(&self, write: &mut T) -> Result<(), Error>
+        where T: AsyncWrite + Unpin
+    {
+        let mut buf = BytesMut::new();
+        self.encode(&mut buf);
+        write_buf(write, buf).await
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct UserPassAuth {
+    pub username: String,
+    pub password: String,
+}
+
+impl UserPassAuth {
+    pub fn new(username: String, password: String) -> Self {
+        UserPassAuth { username, password }
+    }
+
+    // VER ULEN UNAME PLEN PASSWD
+    pub fn decode(buf: &[u8]) -> Result<Decoded<Self>, Error> {
+        if buf.len() < 2 {
+            return Ok(Decoded::Needs(2));
+        }
+        if buf[0] != AUTH_VERSION {
+            return Err(Error::VersionNo(buf[0]));
+        }
+        let username_end = 2 + buf[1] as usize;
+        let Some(&plen) = buf.get(username_end) else {
+            return Ok(Decoded::Needs(username_end + 1));
+        };
+        let len = username_end + 1 + plen as usize;
+        if buf.len() < len {
+            return Ok(Decoded::Needs(len));
+        }
+        let text = |bytes: &[u8]| String::from_utf8(bytes.to_vec()).map_err(|_| Error::AuthFailed("".to_string()));
+        Ok(Decoded::Done(UserPassAuth {
+            username: text(&buf[2..username_end])?,
+            password: text(&buf[username_end + 1..len])?,
+        }, len))
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        if !(1..=255).contains(&self.username.len()) {
+            return Err(Error::UsernameLength(self.username.len()));
+        }
+        if !(1..=255).contains(&self.password.len()) {
+            return Err(Error::PasswordLength(self.password.len()));
+        }
+        Ok(())
+    }
+
+    pub fn encode(&self, buf: &mut BytesMut) -> Result<(), Error> {
+        self.validate()?;
+        buf.put_u8(AUTH_VERSION);
+        buf.put_u8(self.username.len() as u8);
+        buf.put_slice(self.username.as_bytes());
+        buf.put_u8(self.password.len() as u8);
+        buf.put_slice(self.password.as_bytes());
+        Ok(())
+    }
+
+    // VER STATUS, true for success
+    pub fn decode_status(buf: &[u8]) -> Result<Decoded<bool>, Error> {
+        if buf.len() < 2 {
+            return Ok(Decoded::Needs(2));
+        }
+        Ok(Decoded::Done(buf[1] == AUTH_SUCCESS, 2))
+    }
+
+    pub fn encode_status(success: bool, buf: &mut BytesMut) {
+        buf.put_slice(&[AUTH_VERSION, if success { AUTH_SUCCESS } else { AUTH_FAILURE }]);
+    }
+
+    pub async fn from<T>(read: &mut T) -> Result<Self, Error>
+        where T: AsyncRead + Unpin
+    {
+        read_decoded(read, UserPassAuth::decode).await
+    }
+
+    pub async fn write<T>(&self, write: &mut T) -> Result<(), Error>
+        where T: AsyncWrite + Unpin
+    {
+        let mut buf = BytesMut::new();
+        self.encode(&mut buf)?;
+        write_buf(write, buf).await
+    }
+
+    pub async fn read_status<T>(read: &mut T) -> Result<bool, Error>
+        where T: AsyncRead + Unpin
+    {
+        read_decoded(read, UserPassAuth::decode_status).await
+    }
+
+    pub async fn write_status<T>(write: &mut T, success: bool) -> Result<(), Error>
+        where T: AsyncWrite + Unpin
+    {
+        let mut buf = BytesMut::new();
+        UserPassAuth::encode_status(success, &mut buf);
+        write_buf(write, buf).await
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Address {
+    Address(SocketAddr),
+    DomainName(String, u16),
+}
+
+// "1.2.3.4:80", "[::1]:80" or "example.com:443"
+impl FromStr for Address {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(addr) = s.parse::<SocketAddr>() {
+            return Ok(Address::Address(addr));
+        }
+        match s.rsplit_once(':') {
+            Some((host, port)) if !host.is_empty() => match port.parse::<u16>() {
+                Ok(port) => Address::domain(host, port),
+                Err(_) => Err(Error::AddressDomainNo),
+            },
+            _ => Err(Error::AddressDomainNo),
+        }
+    }
+}
+
+impl Address {
+    pub fn domain(name: &str, port: u16) -> Result<Self, Error> {
+        let address = Address::DomainName(name.to_string(), port);
+        address.validate()?;
+        Ok(address)
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        match self {
+            Address::DomainName(name, _) if name.is_empty() || name.len() > 255 => Err(Error::DomainLength(name.len())),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn port(&self) -> u16 {
+        match self {
+            Address::Address(addr) => addr.port(),
+            Address::DomainName(_, port) => *port,
+        }
+    }
+
+    // ATYP then 4 or 16 address bytes, or a length and the domain, then the port
+    pub fn decode(buf: &[u8]) -> Result<Decoded<Self>, Error> {
+        let Some(&atyp) = buf.first() else {
+            return Ok(Decoded::Needs(1));
+        };
+        let len = match atyp {
+            ATYP_IPV4 => 1 + 4 + 2,
+            ATYP_IPV6 => 1 + 16 + 2,
+            ATYP_DOMAINNAME => match buf.get(1) {
+                None => return Ok(Decoded::Needs(2)),
+                // nothing could encode an empty domain, nor is there a host to dial
+                Some(0) => return Err(Error::DomainLength(0)),
+                Some(&domain_len) => 2 + domain_len as usize + 2,
+            },
+            u => return Err(Error::AddressTypeNo(u)),
+        };
+        if buf.len() < len {
+            return Ok(Decoded::Needs(len));
+        }
+        let port = u16::from_be_bytes([buf[len - 2], buf[len - 1]]);
+        let address = match atyp {
+            ATYP_IPV4 => Address::Address(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(buf[1], buf[2], buf[3], buf[4]), port))),
+            ATYP_IPV6 => {
+                let octets: [u8; 16] = buf[1..17].try_into().unwrap();
+                Address::Address(SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::from(octets), port, 0, 0)))
+            }
+            _ => Address::DomainName(String::from_utf8(buf[2..len - 2].to_vec())?, port),
+        };
+        Ok(Decoded::Done(address, len))
+    }
+
+    pub fn encode(&self, buf: &mut BytesMut) -> Result<(), Error> {
+        self.validate()?;
+        match self {
+            Address::Address(SocketAddr::V4(v4)) => {
+                buf.put_u8(ATYP_IPV4);
+                buf.put_slice(&v4.ip().octets());
+                buf.put_u16(v4.port());
+            }
+            Address::Address(SocketAddr::V6(v6)) => {
+                buf.put_u8(ATYP_IPV6);
+                buf.put_slice(&v6.ip().octets());
+                buf.put_u16(v6.port());
+            }
+            Address::DomainName(addr, port) => {
+                buf.put_u8(ATYP_DOMAINNAME);
+                buf.put_u8(addr.len() as u8);
+                buf.put_slice(addr.as_bytes());
+                buf.put_u16(*port);
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn from<T>(read: &mut T) -> Result<Self, Error>
+        where T: AsyncRead + Unpin
+    {
+        read_decoded(read, Address::decode).await
+    }
+
+    pub async fn write<T>(&self, write: &mut T) -> Result<(), Error>
+        where T: AsyncWrite + Unpin
+    {
+        let mut buf = BytesMut::new();
+        self.encode(&mut buf)?;
+        write_buf(write, buf).await
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Proxy {
+    pub command: Command,
+    pub address: Address,
+}
+
+impl Proxy {
+    pub fn new(command: Command, address: Address) -> Self {
+        Proxy { command, address }
+    }
+
+    // like new, but refuses a request no server could honour
+    pub fn build(command: Command, address: Address) -> Result<Self, Error> {
+        let proxy = Proxy::new(command, address);
+        proxy.validate()?;
+        Ok(proxy)
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        self.address.validate()?;
+        if self.address.port() == 0 && self.command == Command::CONNECT {
+            return Err(Error::PortNo);
+        }
+        Ok(())
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Decoded<Self>, Error> {
+        let (command, n) = take!(Command::decode(buf), 0);
+        let (address, n) = take!(Address::decode(&buf[n..]), n);
+        Ok(Decoded::Done(Proxy { command, address }, n))
+    }
+
+    pub fn encode(&self, buf: &mut BytesMut) -> Result<(), Error> {
+        self.validate()?;
+        self.command.encode(buf);
+        self.address.encode(buf)
+    }
+
+    pub async fn from<T>(read: &mut T) -> Result<Self, Error>
+        where T: AsyncRead + Unpin
+    {
+        read_decoded(read, Proxy::decode).await
+    }
+
+    pub async fn write<T>(&self, write: &mut T) -> Result<(), Error>
+        where T: AsyncWrite + Unpin
+    {
+        let mut buf = BytesMut::new();
+        self.encode(&mut buf)?;
+        write_buf(write, buf).await
+    }
+}
+
+// the reply to a request, VER REP RSV followed by BND.ADDR and BND.PORT
+#[derive(Debug, Clone)]
+pub struct ConnectReply {
+    pub reply: Reply,
+    pub bound: Address,
+}
+
+impl ConnectReply {
+    pub fn new(reply: Reply, bound: Address) -> Self {
+        ConnectReply { reply, bound }
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Decoded<Self>, Error> {
+        let (reply, n) = take!(Reply::decode(buf), 0);
+        let (bound, n) = take!(Address::decode(&buf[n..]), n);
+        Ok(Decoded::Done(ConnectReply { reply, bound }, n))
+    }
+
+    pub fn encode(&self, buf: &mut BytesMut) -> Result<(), Error> {
+        self.reply.encode(buf);
+        self.bound.encode(buf)
+    }
+
+    pub async fn from<T>(read: &mut T) -> Result<Self, Error>
+        where T: AsyncRead + Unpin
+    {
+        read_decoded(read, ConnectReply::decode).await
+    }
+
+    pub async fn write<T>(&self, write: &mut T) -> Result<(), Error>
+        where T: AsyncWrite + Unpin
+    {
+        let mut buf = BytesMut::new();
+        self.encode(&mut buf)?;
+        write_buf(write, buf).await
+    }
+
+    // anything but success becomes Error::Rejected
+    pub fn into_result(self) -> Result<Self, Error> {
+        match self.reply {
+            Reply::RepSuccess => Ok(self),
+            reply => Err(Error::Rejected(reply)),
+        }
+    }
+}
+
+// RSV(2) FRAG ATYP DST.ADDR DST.PORT ahead of every relayed udp datagram
+#[derive(Debug, Clone)]
+pub struct UdpHeader {
+    pub frag: u8,
+    pub address: Address,
+}
+
+impl UdpHeader {
+    pub fn new(address: Address) -> Self {
+        UdpHeader { frag: 0, address }
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Decoded<Self>, Error> {
+        if buf.len() < 3 {
+            return Ok(Decoded::Needs(3));
+        }
+        let (address, n) = take!(Address::decode(&buf[3..]), 3);
+        Ok(Decoded::Done(UdpHeader { frag: buf[2], address }, n))
+    }
+
+    pub fn encode(&self, buf: &mut BytesMut) -> Result<(), Error> {
+        buf.put_slice(&[RSV, RSV, self.frag]);
+        self.address.encode(buf)
+    }
+
+    pub async fn from<T>(read: &mut T) -> Result<Self, Error>
+        where T: AsyncRead + Unpin
+    {
+        read_decoded(read, UdpHeader::decode).await
+    }
+
+    pub async fn write<T>(&self, write: &mut T) -> Result<(), Error>
+        where T: AsyncWrite + Unpin
+    {
+        let mut buf = BytesMut::new();
+        self.encode(&mut buf)?;
+        write_buf(write, buf).await
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+    use bytes::BytesMut;
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+
+    use crate::socket5::{Address, Command, ConnectReply, Decoded, Error, MethodSelection, Proxy, Reply, ShakeHands, UdpHeader, UserPassAuth};
+    use crate::socket5::constant::{ATYP_DOMAINNAME, ATYP_IPV4, ATYP_IPV6, METHOD_USERNAME_PASSWORD, SOCKET5_VERSION};
+
+    type Decode = fn(&[u8]) -> usize;
+
+    // where the message ends, or the length it asks for
+    fn length<T>(decoded: Result<Decoded<T>, Error>) -> usize {
+        match decoded.unwrap() {
+            Decoded::Done(_, n) | Decoded::Needs(n) => n,
+        }
+    }
+
+    #[test]
+    fn decode_test() {
+        let encoded = |encode: &dyn Fn(&mut BytesMut)| {
+            let mut buf = BytesMut::new();
+            encode(&mut buf);
+            buf.to_vec()
+        };
+        let proxy = encoded(&|buf| Proxy::new(Command::CONNECT, Address::domain("example.com", 443).unwrap()).encode(buf).unwrap());
+        let reply = encoded(&|buf| ConnectReply::new(Reply::RepSuccess, "[::1]:80".parse().unwrap()).encode(buf).unwrap());
+        let auth = encoded(&|buf| UserPassAuth::new("alice".to_string(), "secret".to_string()).encode(buf).unwrap());
+        let selection = encoded(&|buf| MethodSelection::new(METHOD_USERNAME_PASSWORD).encode(buf));
+        assert_eq!(selection, [SOCKET5_VERSION, METHOD_USERNAME_PASSWORD]);
+        let messages: [(&[u8], Decode); 4] = [
+            (&proxy, |buf| length(Proxy::decode(buf))),
+            (&reply, |buf| length(ConnectReply::decode(buf))),
+            (&auth, |buf| length(UserPassAuth::decode(buf))),
+            (&selection, |buf| length(MethodSelection::decode(buf))),
+        ];
+        for (message, decode) in messages {
+            // every cut asks for more, never for more than the message has
+            for cut in 0..message.len() {
+                let needs = decode(&message[..cut]);
+                assert!(needs > cut && needs <= message.len(), "{:?} cut at {} needs {}", message, cut, needs);
+            }
+            let mut more = message.to_vec();
+            more.extend_from_slice(b"payload");
+            assert_eq!(decode(&more), message.len());
+        }
+        match Proxy::decode(&proxy).unwrap() {
+            Decoded::Done(proxy, _) => assert_eq!(proxy.address, Address::DomainName("example.com".to_string(), 443)),
+            Decoded::Needs(_) => panic!("incomplete"),
+        }
+        assert!(matches!(ShakeHands::decode(&[4, 1]), Err(Error::VersionNo(4))));
+        assert!(matches!(MethodSelection::decode(&[4, 0]), Err(Error::VersionNo(4))));
+        assert!(matches!(Address::decode(&[9]), Err(Error::AddressTypeNo(9))));
+    }
+
+    proptest! {
+        // whatever arrives, parsing gives an answer and doesn't panic
+        #[test]
+        fn decode_any_test(bytes in vec(any::<u8>(), 0..300)) {
+            let _ = (Proxy::decode(&bytes), ConnectReply::decode(&bytes), UdpHeader::decode(&bytes), ShakeHands::decode(&bytes));
+            let _ = (UserPassAuth::decode(&bytes), MethodSelection::decode(&bytes), Address::decode(&bytes));
+        }
+
+        // bytes that start like an address and then go anywhere, short streams and bad utf-8
+        // included, are an error or an address
+        #[test]
+        fn decode_address_test(atyp in prop_oneof![Just(ATYP_IPV4), Just(ATYP_IPV6), Just(ATYP_DOMAINNAME), any::<u8>()], rest in vec(any::<u8>(), 0..300)) {
+            let _ = Address::decode(&[&[atyp][..], &rest].concat());
+        }
+    }
+
+    // ports at the edges come up more often than chance would have them
+    fn port() -> impl Strategy<Value = u16> {
+        prop_oneof![Just(0), Just(u16::MAX), any::<u16>()]
+    }
+
+    // 1 to 255 bytes of any utf-8, not only hostnames; a char that doesn't fit is left off
+    fn domain() -> impl Strategy<Value = String> {
+        let any_text = vec(any::<char>(), 1..=255).prop_map(|chars| {
+            let mut name = String::new();
+            for c in chars {
+                if name.len() + c.len_utf8() > 255 {
+                    break;
+                }
+                name.push(c);
+            }
+            name
+        });
+        prop_oneof!["[a-z0-9.-]{1,255}", any_text]
+    }
+
+    fn address() -> impl Strategy<Value = Address> {
+        prop_oneof![
+            (any::<Ipv4Addr>(), port()).prop_map(|(ip, port)| Address::Address(SocketAddr::new(IpAddr::V4(ip), port))),
+            (any::<Ipv6Addr>(), port()).prop_map(|(ip, port)| Address::Address(SocketAddr::new(IpAddr::V6(ip), port))),
+            (domain(), port()).prop_map(|(name, port)| Address::DomainName(name, port)),
+        ]
+    }
+
+    #[test]
+    fn address_roundtrip_test() {
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        proptest!(|(address in address())| {
+            let (wire, back, left) = runtime.block_on(async {
+                let mut wire = Vec::new();
+                address.write(&mut wire).await.unwrap();
+                let mut read = &wire[..];
+                let back = Address::from(&mut read).await;
+                let left = read.len();
+                (wire, back, left)
+            });
+            prop_assert_eq!(back.map_err(|e| e.to_string()), Ok(address), "{:02x?}", wire);
+            prop_assert_eq!(left, 0);
+        });
+    }
+
+    #[tokio::test]
+    async fn unwritable_address_test() {
+        // addresses that can't be written don't leave half of themselves on the wire
+        for name in [String::new(), "a".repeat(256), "é".repeat(200)] {
+            let mut wire = Vec::new();
+            assert!(matches!(Address::DomainName(name, 80).write(&mut wire).await, Err(Error::DomainLength(_))));
+            assert!(wire.is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn proxy_validation_test() {
+        assert!(matches!(Address::domain("", 80), Err(Error::DomainLength(0))));
+        assert!(matches!(Address::domain(&"a".repeat(256), 80), Err(Error::DomainLength(256))));
+        assert!(matches!("example.com:0".parse::<Address>(), Ok(Address::DomainName(_, 0))));
+        let address = Address::domain(&"a".repeat(255), 443).unwrap();
+        assert!(Proxy::build(Command::CONNECT, address).is_ok());
+        let any: Address = "0.0.0.0:0".parse().unwrap();
+        assert!(matches!(Proxy::build(Command::CONNECT, any.clone()), Err(Error::PortNo)));
+        assert!(Proxy::build(Command::BIND, any.clone()).is_ok());
+        assert!(Proxy::build(Command::UDP, any).is_ok());
+
+        // nothing reaches the wire for an invalid request
+        let mut buf = Vec::new();
+        let proxy = Proxy::new(Command::CONNECT, Address::DomainName("a".repeat(300), 80));
+        assert!(proxy.write(&mut buf).await.is_err());
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn string_forms_test() {
+        for s in ["1.2.3.4:80", "[::1]:443", "example.com:443"] {
+            let address: Address = s.parse().unwrap();
+            assert_eq!(address.to_string(), s);
+            assert_eq!(serde_json::to_string(&address).unwrap(), format!("\"{}\"", s));
+            assert_eq!(serde_json::from_str::<Address>(&format!("\"{}\"", s)).unwrap(), address);
+        }
+        for reply in [Reply::RepSuccess, Reply::RepConnRefused, Reply::RepNo, Reply::Other(42)] {
+            assert_eq!(reply.to_string().parse::<Reply>().unwrap(), reply);
+        }
+        for u in 0..=u8::MAX {
+            let reply: Reply = u.into();
+            assert_eq!(u8::from(reply), u);
+        }
+        assert_eq!(serde_json::from_str::<Command>("\"udp\"").unwrap(), Command::UDP);
+        assert!(serde_json::from_str::<Address>("\"example.com\"").is_err());
+    }
+}
@@ -1,2 +0,0 @@
-#[tokio::main]
-async fn main() {}
\ No newline at end of file
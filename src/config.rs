@@ -1,8 +0,0 @@
-#[derive(Clone)]
-pub struct ServerConfig {
-    pub port: u16,
-    pub password: String,
-    pub encrypt: String,
-}
-
-
@@ -0,0 +1,216 @@
+use std::cell::RefCell;
+use std::ffi::{c_char, c_int, CStr, CString};
+use std::fmt::Display;
+use std::ptr;
+
+use tokio::runtime::Runtime;
+
+use crate::config::{self, LocalConfig};
+use crate::local::{self, LocalHandle};
+
+// the c api for gui frontends, declared in include/rust_ss5.h; configs are passed as the toml
+// text a local.toml holds, failures return null or -1 with the reason in ss5_last_error
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+// a running local client with the runtime its tasks are on
+pub struct Ss5Local {
+    runtime: Runtime,
+    handle: Option<LocalHandle>,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Ss5LocalStats {
+    pub servers: u64,
+    // connections to the upstreams, open and still draining after a server list change
+    pub active: u64,
+    pub draining: u64,
+    pub listeners: u64,
+}
+
+// one open mux stream, as StreamStats
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Ss5StreamStats {
+    pub id: u32,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    // microseconds, 0 until the first frame came back
+    pub rtt_us: u64,
+    pub stalls: u64,
+}
+
+fn fail<E: Display, T>(err: E, value: T) -> T {
+    let message = CString::new(err.to_string().replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+    value
+}
+
+unsafe fn parse(config: *const c_char) -> Result<LocalConfig, String> {
+    if config.is_null() {
+        return Err("config is null".to_string());
+    }
+    let text = CStr::from_ptr(config).to_str().map_err(|e| format!("config isn't utf-8 : {}", e))?;
+    config::from_profile(text, None).map_err(|e| e.to_string())
+}
+
+/// Starts a local client from the toml text of a local config, null on failure.
+///
+/// # Safety
+/// `config` is a nul terminated string or null.
+#[no_mangle]
+pub unsafe extern "C" fn ss5_local_start(config: *const c_char) -> *mut Ss5Local {
+    let config = match parse(config) {
+        Ok(config) => config,
+        Err(e) => return fail(e, ptr::null_mut()),
+    };
+    let runtime = match tokio::runtime::Builder::new_multi_thread().enable_all().build() {
+        Ok(runtime) => runtime,
+        Err(e) => return fail(e, ptr::null_mut()),
+    };
+    match runtime.block_on(local::start(config)) {
+        Ok(handle) => Box::into_raw(Box::new(Ss5Local { runtime, handle: Some(handle) })),
+        Err(e) => fail(e, ptr::null_mut()),
+    }
+}
+
+/// Restarts the client with a new config, 0 on success. The old listeners are closed first so
+/// the new ones can take their ports; on failure the client stays stopped until the next update.
+///
+/// # Safety
+/// `local` comes from `ss5_local_start` and wasn't stopped, `config` is as for `ss5_local_start`.
+#[no_mangle]
+pub unsafe extern "C" fn ss5_local_update(local: *mut Ss5Local, config: *const c_char) -> c_int {
+    let Some(local) = local.as_mut() else {
+        return fail("local is null", -1);
+    };
+    let config = match parse(config) {
+        Ok(config) => config,
+        Err(e) => return fail(e, -1),
+    };
+    if let Some(handle) = local.handle.take() {
+        local.runtime.block_on(handle.shutdown());
+    }
+    match local.runtime.block_on(local::start(config)) {
+        Ok(handle) => {
+            local.handle = Some(handle);
+            0
+        }
+        Err(e) => fail(e, -1),
+    }
+}
+
+/// Fills `stats`, 0 on success.
+///
+/// # Safety
+/// `local` is as for `ss5_local_update`, `stats` points to writable memory for one `Ss5LocalStats`.
+#[no_mangle]
+pub unsafe extern "C" fn ss5_local_stats(local: *const Ss5Local, stats: *mut Ss5LocalStats) -> c_int {
+    let (Some(local), false) = (local.as_ref(), stats.is_null()) else {
+        return fail("local or stats is null", -1);
+    };
+    *stats = match &local.handle {
+        None => Ss5LocalStats::default(),
+        Some(handle) => {
+            let stats = handle.stats();
+            Ss5LocalStats {
+                servers: stats.servers as u64,
+                active: stats.active as u64,
+                draining: stats.draining as u64,
+                listeners: stats.listeners as u64,
+            }
+        }
+    };
+    0
+}
+
+/// Fills up to `len` entries of `streams` with the open mux streams and returns how many are
+/// open, which may be more than `len`; -1 on failure. Without `mux = true` there are none.
+///
+/// # Safety
+/// `local` is as for `ss5_local_update`, `streams` points to writable memory for `len`
+/// `Ss5StreamStats` or is null with `len` 0.
+#[no_mangle]
+pub unsafe extern "C" fn ss5_local_streams(local: *const Ss5Local, streams: *mut Ss5StreamStats, len: usize) -> c_int {
+    let Some(local) = local.as_ref() else {
+        return fail("local is null", -1);
+    };
+    if streams.is_null() && len > 0 {
+        return fail("streams is null", -1);
+    }
+    let open = match &local.handle {
+        None => Vec::new(),
+        Some(handle) => local.runtime.block_on(handle.streams()),
+    };
+    for (i, (_, stats)) in open.iter().take(len).enumerate() {
+        *streams.add(i) = Ss5StreamStats {
+            id: stats.id,
+            bytes_sent: stats.bytes_sent,
+            bytes_received: stats.bytes_received,
+            rtt_us: stats.rtt.map_or(0, |rtt| rtt.as_micros() as u64),
+            stalls: stats.stalls,
+        };
+    }
+    open.len().min(c_int::MAX as usize) as c_int
+}
+
+/// Stops the client and frees it, waiting for its listeners to close.
+///
+/// # Safety
+/// `local` comes from `ss5_local_start` and isn't used afterwards, or is null.
+#[no_mangle]
+pub unsafe extern "C" fn ss5_local_stop(local: *mut Ss5Local) {
+    if local.is_null() {
+        return;
+    }
+    let mut local = Box::from_raw(local);
+    if let Some(handle) = local.handle.take() {
+        local.runtime.block_on(handle.shutdown());
+    }
+}
+
+/// Why the last call on this thread failed, null if none did; valid until the next failing call.
+#[no_mangle]
+pub extern "C" fn ss5_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ref().map_or(ptr::null(), |e| e.as_ptr()))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::{CStr, CString};
+
+    use crate::ffi::{ss5_last_error, ss5_local_start, ss5_local_stats, ss5_local_stop, ss5_local_streams, ss5_local_update, Ss5LocalStats};
+
+    #[test]
+    fn local_test() {
+        let config = CString::new("port = 0\nserver = \"127.0.0.1:1\"\n").unwrap();
+        unsafe {
+            let local = ss5_local_start(config.as_ptr());
+            assert!(!local.is_null());
+            let mut stats = Ss5LocalStats::default();
+            assert_eq!(ss5_local_stats(local, &mut stats), 0);
+            assert_eq!(stats, Ss5LocalStats { servers: 1, active: 0, draining: 0, listeners: 1 });
+            assert_eq!(ss5_local_streams(local, std::ptr::null_mut(), 0), 0);
+
+            let invalid = CString::new("port = \"x\"").unwrap();
+            assert_eq!(ss5_local_update(local, invalid.as_ptr()), -1);
+            let error = CStr::from_ptr(ss5_last_error()).to_str().unwrap();
+            assert!(error.starts_with("parse config fail"), "{}", error);
+            // a config that doesn't parse leaves the running client alone
+            assert_eq!(ss5_local_stats(local, &mut stats), 0);
+            assert_eq!(stats.listeners, 1);
+
+            let updated = CString::new("port = 0\nunix = \"/nonexistent/ss5.sock\"\n").unwrap();
+            assert_eq!(ss5_local_update(local, updated.as_ptr()), -1);
+            assert_eq!(ss5_local_stats(local, &mut stats), 0);
+            assert_eq!(stats, Ss5LocalStats::default());
+            assert_eq!(ss5_local_update(local, config.as_ptr()), 0);
+            ss5_local_stop(local);
+            assert!(ss5_local_start(invalid.as_ptr()).is_null());
+        }
+    }
+}
@@ -1,4 +1,9 @@
+// the command line, json logger and c api of the rust-ss5 binary and library; everything else is
+// ss5-core's, re-exported here so rust_ss5::server, rust_ss5::socket5 and the rest keep their paths
+pub use ss5_core::*;
+#[cfg(feature = "runtime")]
 pub mod opt;
-pub mod config;
-pub mod socket5;
-pub mod tcp;
\ No newline at end of file
+#[cfg(feature = "runtime")]
+pub mod logger;
+#[cfg(feature = "runtime")]
+pub mod ffi;
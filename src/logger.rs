@@ -0,0 +1,70 @@
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{LevelFilter, Log, Metadata, Record, SetLoggerError};
+use serde_json::json;
+
+// one json object per line on stdout, for log collectors reading a container's output
+pub struct JsonLogger {
+    level: LevelFilter,
+}
+
+impl JsonLogger {
+    pub fn init(level: LevelFilter) -> Result<(), SetLoggerError> {
+        log::set_boxed_logger(Box::new(JsonLogger { level }))?;
+        log::set_max_level(level);
+        Ok(())
+    }
+}
+
+impl Log for JsonLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            let line = format(record, SystemTime::now());
+            // a closed stdout has nowhere to report to
+            let _ = writeln!(std::io::stdout().lock(), "{}", line);
+        }
+    }
+
+    fn flush(&self) {
+        let _ = std::io::stdout().flush();
+    }
+}
+
+fn format(record: &Record, now: SystemTime) -> String {
+    let ts = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64();
+    json!({
+        "ts": ts,
+        "level": record.level().as_str(),
+        "target": record.target(),
+        "msg": record.args().to_string(),
+    }).to_string()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use log::{Level, Record};
+
+    use crate::logger::format;
+
+    #[test]
+    fn format_test() {
+        let line = format(
+            &Record::builder().level(Level::Warn).target("rust_ss5::server").args(format_args!("a \"quoted\" {}", 1)).build(),
+            UNIX_EPOCH + Duration::from_millis(1500),
+        );
+        let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(value["ts"], 1.5);
+        assert_eq!(value["level"], "WARN");
+        assert_eq!(value["target"], "rust_ss5::server");
+        assert_eq!(value["msg"], "a \"quoted\" 1");
+        assert!(!line.contains('\n'));
+    }
+}
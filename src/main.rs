@@ -0,0 +1,142 @@
+use std::process::exit;
+use std::time::Duration;
+
+use simple_logger::SimpleLogger;
+use structopt::StructOpt;
+use rust_ss5::bench::{bench, ping};
+use rust_ss5::config::{LocalConfig, ServerConfig, Spans};
+use rust_ss5::crypto::{encode_key, generate_key, Method};
+use rust_ss5::logger::JsonLogger;
+use rust_ss5::opt::{Opt, SubCommand};
+use rust_ss5::{local, metrics, nat, selfcheck, server};
+use log::{LevelFilter, info, error};
+
+#[tokio::main]
+async fn main() {
+    let opt = Opt::from_args();
+    match &opt.command {
+        SubCommand::Server(server) if server.zero_config() => JsonLogger::init(LevelFilter::Info).unwrap(),
+        _ => SimpleLogger::new().with_level(LevelFilter::Info).init().unwrap(),
+    }
+    match opt.command {
+        SubCommand::Server(opt) => {
+            let config = opt.config().unwrap_or_else(fail);
+            self_check(selfcheck::server(&config).await);
+            let drain = Duration::from_secs(config.drain_timeout);
+            let handle = server::start(config).await.unwrap_or_else(fail);
+            stop_signal().await;
+            info!("shutdown socks5 server, {:?}", handle.stats());
+            if !drain.is_zero() {
+                info!("draining {} connections for up to {:?}", handle.stats().connections, drain);
+                let left = handle.drain(drain).await;
+                info!("drained, {} connections cut", left);
+            } else {
+                handle.shutdown().await;
+            }
+        }
+        SubCommand::Local(opt) => {
+            let config = opt.config().unwrap_or_else(fail);
+            self_check(selfcheck::local(&config).await);
+            let handle = local::start(config).await.unwrap_or_else(fail);
+            let _ = tokio::signal::ctrl_c().await;
+            let upstreams = handle.upstreams();
+            info!("shutdown socks5 local, {} connections, {} draining", upstreams.active(), upstreams.draining());
+            handle.shutdown().await;
+        }
+        SubCommand::Genkey { method } => {
+            if method == Method::None {
+                fail::<_, ()>("method none takes no key");
+            }
+            let key = generate_key(method.key_len()).unwrap_or_else(fail);
+            println!("{}", encode_key(&key));
+        }
+        SubCommand::CheckConfig { conf, local, profile } => {
+            let checked = if local {
+                LocalConfig::load_profile(&conf, profile.as_deref()).map(|c| c.check())
+            } else {
+                ServerConfig::load_profile(&conf, profile.as_deref()).map(|c| c.check())
+            };
+            match checked {
+                Ok(problems) if problems.is_empty() => println!("{} : ok", conf.display()),
+                Ok(problems) => {
+                    // the file parsed just now, so it parses again for the spans
+                    let content = std::fs::read_to_string(&conf).unwrap_or_default();
+                    let spans = Spans::parse(&content, profile.as_deref()).ok();
+                    for problem in &problems {
+                        match spans.as_ref().and_then(|spans| spans.locate(problem)) {
+                            Some((line, column)) => println!("{}:{}:{} : {}", conf.display(), line, column, problem),
+                            None => println!("{} : {}", conf.display(), problem),
+                        }
+                    }
+                    exit(1);
+                }
+                Err(e) => fail(format!("{} : {}", conf.display(), e)),
+            }
+        }
+        SubCommand::Bench { server, target, bytes, pull } => {
+            let report = bench(&server, target, bytes, pull).await.unwrap_or_else(|e| fail(format!("{:?}", e)));
+            println!("connect : {:?}", report.connect);
+            println!("sent : {} bytes, received : {} bytes, in {:?}", report.sent, report.received, report.elapsed);
+            println!("throughput : {:.2} MB/s", report.throughput() / 1024.0 / 1024.0);
+        }
+        SubCommand::Ping { server, target, count } => {
+            let report = ping(&server, target, count).await.unwrap_or_else(|e| fail(format!("{:?}", e)));
+            println!("{} ok, {} failed", report.samples.len(), report.failed);
+            for (name, p) in [("p50", 50.0), ("p95", 95.0), ("p99", 99.0)] {
+                match report.percentile(p) {
+                    Some(latency) => println!("{} : {:?}", name, latency),
+                    None => println!("{} : -", name),
+                }
+            }
+        }
+        SubCommand::Stats { metrics, top } => {
+            let report = metrics::traffic(&metrics).await.unwrap_or_else(|e| fail(format!("{} : {}", metrics, e)));
+            print!("{}", report.table(top));
+        }
+        SubCommand::Maintenance { metrics, token, mode } => {
+            let token = token.or_else(|| std::env::var("SS5_METRICS_TOKEN").ok());
+            let on = metrics::maintenance(&metrics, mode.map(|mode| mode == "on"), token.as_deref()).await.unwrap_or_else(|e| fail(format!("{} : {}", metrics, e)));
+            println!("maintenance : {}", if on { "on" } else { "off" });
+        }
+        SubCommand::Nat { server, stun, timeout } => {
+            let report = nat::detect(&server, &stun, Duration::from_secs(timeout)).await.unwrap_or_else(|e| fail(format!("{:?}", e)));
+            for (stun, mapped) in &report.mapped {
+                println!("mapped : {} seen by {}", mapped, stun);
+            }
+            println!("mapping : {}", report.mapping);
+            println!("filtering : {}", report.filtering);
+            println!("nat : {}", report.kind());
+        }
+    }
+}
+
+// every check in the log, and no start with any of them failed
+fn self_check(report: selfcheck::Report) {
+    for line in report.to_string().lines() {
+        info!("self-check {}", line);
+    }
+    if let Some(e) = report.error() {
+        fail::<_, ()>(e);
+    }
+}
+
+// ctrl-c, or SIGTERM as sent by service managers and the upgrade that replaces this process
+async fn stop_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        if let Ok(mut term) = signal(SignalKind::terminate()) {
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                _ = term.recv() => {}
+            }
+            return;
+        }
+    }
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+fn fail<E: std::fmt::Display, T>(err: E) -> T {
+    error!("{}", err);
+    exit(1)
+}
@@ -1,19 +1,220 @@
+use std::path::PathBuf;
+
 use structopt::StructOpt;
 
+use crate::config::{ConfigError, LocalConfig, QuotaPeriod, ServerConfig};
+use crate::crypto::Method;
+use crate::socket5::Address;
+use crate::transport::Endpoint;
+
 #[derive(StructOpt, Debug)]
 #[structopt(name = "rust-ss5")]
 pub struct Opt {
-    #[structopt(short = "c", parse(from_str))]
-    conf: Option<String>,
+    #[structopt(subcommand)]
+    pub command: SubCommand,
+}
+
+#[derive(StructOpt, Debug)]
+pub enum SubCommand {
+    /// run the remote socks5 server
+    Server(ServerOpt),
+    /// run the local socks5 listener forwarding to a server
+    Local(LocalOpt),
+    /// print a random key for the config file
+    Genkey {
+        /// the key fits this encrypt method
+        #[structopt(short = "m", long = "method", default_value = "chacha20-ietf-poly1305")]
+        method: Method,
+    },
+    /// parse a config file and report errors without starting anything
+    CheckConfig {
+        #[structopt(short = "c", parse(from_os_str))]
+        conf: PathBuf,
+        /// the file is a local config rather than a server one
+        #[structopt(long = "local")]
+        local: bool,
+        #[structopt(long = "profile")]
+        profile: Option<String>,
+    },
+    /// push data through a server to a target and report throughput
+    Bench {
+        #[structopt(short = "s", long = "server")]
+        server: Endpoint,
+        /// a host:port that discards or echoes what it receives, rust-ss5.invalid:7, :9 or :19
+        /// for the echo, discard or chargen of a server running with bench on
+        #[structopt(short = "t", long = "target", parse(try_from_str = parse_address))]
+        target: Address,
+        /// bytes pushed to the target
+        #[structopt(short = "b", long = "bytes", default_value = "104857600")]
+        bytes: u64,
+        /// stop reading once this many bytes came back, 0 reads until eof
+        #[structopt(long = "pull", default_value = "0")]
+        pull: u64,
+    },
+    /// time handshakes and small echoes through a server, reporting p50/p95/p99
+    Ping {
+        #[structopt(short = "s", long = "server")]
+        server: Endpoint,
+        /// a host:port that echoes what it receives, by default the echo of a server running
+        /// with diag or bench on
+        #[structopt(short = "t", long = "target", default_value = "rust-ss5.invalid:7", parse(try_from_str = parse_address))]
+        target: Address,
+        #[structopt(short = "n", long = "count", default_value = "20")]
+        count: usize,
+    },
+    /// print the traffic per user and destination over the last hour and day
+    Stats {
+        /// the server's metrics listener, host:port
+        #[structopt(short = "m", long = "metrics")]
+        metrics: String,
+        /// destinations listed, the busiest first
+        #[structopt(short = "n", long = "top", default_value = "20")]
+        top: usize,
+    },
+    /// turn a server's maintenance mode on or off, refusing new requests while relays carry on;
+    /// without either print whether it's on
+    Maintenance {
+        /// the server's metrics listener, host:port
+        #[structopt(short = "m", long = "metrics")]
+        metrics: String,
+        /// the server's metrics_token, needed to turn it on or off; SS5_METRICS_TOKEN otherwise
+        #[structopt(long = "token")]
+        token: Option<String>,
+        #[structopt(possible_values = &["on", "off"])]
+        mode: Option<String>,
+    },
+    /// probe the NAT behavior of a server's UDP relay with stun servers
+    Nat {
+        /// the server's tcp address, the association is made through it
+        #[structopt(short = "s", long = "server")]
+        server: String,
+        /// two or more servers tell the mapping apart, the first should support change requests
+        #[structopt(long = "stun", parse(try_from_str = parse_address), default_value = "stun.l.google.com:19302")]
+        stun: Vec<Address>,
+        /// seconds to wait for each answer
+        #[structopt(long = "timeout", default_value = "2")]
+        timeout: u64,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+pub struct ServerOpt {
+    #[structopt(short = "c", parse(from_os_str))]
+    conf: Option<PathBuf>,
+    /// merge the file's [profile.<name>] table over its top-level settings
+    #[structopt(long = "profile")]
+    profile: Option<String>,
+    #[structopt(short = "p", long = "port")]
+    port: Option<u16>,
+    /// without -c, serve on 0.0.0.0 with the default cipher and log json lines to stdout
+    #[structopt(long = "password")]
+    password: Option<String>,
+    /// listen on a unix domain socket as well, e.g. --unix /run/ss5.sock
+    #[structopt(long = "unix", parse(from_os_str))]
+    unix: Option<PathBuf>,
+    /// global byte quota per --quota-period, both directions counted
+    #[structopt(long = "quota")]
+    quota: Option<u64>,
+    #[structopt(long = "quota-period", parse(try_from_str = parse_period))]
+    quota_period: Option<QuotaPeriod>,
+    #[structopt(long = "quota-file", parse(from_os_str))]
+    quota_file: Option<PathBuf>,
 }
 
-// const CONF: String = "~/conf/ss5-server.conf".parse().unwrap();
+#[derive(StructOpt, Debug)]
+pub struct LocalOpt {
+    #[structopt(short = "c", parse(from_os_str))]
+    conf: Option<PathBuf>,
+    /// merge the file's [profile.<name>] table over its top-level settings, e.g. --profile work
+    #[structopt(long = "profile")]
+    profile: Option<String>,
+    #[structopt(short = "p", long = "port")]
+    port: Option<u16>,
+    #[structopt(long = "unix", parse(from_os_str))]
+    unix: Option<PathBuf>,
+    /// listen on a windows named pipe as well, e.g. --pipe \\.\pipe\ss5
+    #[structopt(long = "pipe")]
+    pipe: Option<String>,
+    /// set the os proxy settings to this client until it exits
+    #[structopt(long = "system-proxy")]
+    system_proxy: bool,
+    /// remote server, "host:port" or "unix:/path"
+    #[structopt(short = "s", long = "server")]
+    server: Option<Endpoint>,
+}
+
+fn parse_period(s: &str) -> Result<QuotaPeriod, String> {
+    match s {
+        "day" => Ok(QuotaPeriod::Day),
+        "month" => Ok(QuotaPeriod::Month),
+        _ => Err(format!("unknown quota period : {}", s)),
+    }
+}
+
+fn parse_address(s: &str) -> Result<Address, String> {
+    s.parse().map_err(|_| format!("invalid address : {}", s))
+}
 
-impl Opt {
-    pub fn conf(&self) -> String {
-        match self.conf.clone() {
-            None => "1".to_string(),
-            Some(c) => c
+impl ServerOpt {
+    // the config file if given, then SS5_* environment variables, with command line flags taking precedence
+    pub fn config(&self) -> Result<ServerConfig, ConfigError> {
+        let mut config = match (&self.conf, self.zero_config()) {
+            (Some(path), _) => ServerConfig::load_profile(path, self.profile.as_deref())?,
+            (None, true) => ServerConfig::zero_config(""),
+            (None, false) => ServerConfig::default(),
+        };
+        config.apply_env(|name| std::env::var(name).ok())?;
+        if let Some(password) = self.password.clone() {
+            config.password = password;
+        }
+        if let Some(port) = self.port {
+            config.port = port;
         }
+        if let Some(path) = self.unix.clone() {
+            config.unix = Some(path);
+        }
+        let quota = &mut config.quota;
+        if let Some(bytes) = self.quota {
+            quota.global = Some(bytes);
+        }
+        if let Some(period) = self.quota_period {
+            quota.period = period;
+        }
+        if let Some(path) = self.quota_file.clone() {
+            quota.path = Some(path);
+        }
+        Ok(config)
     }
-}
\ No newline at end of file
+
+    // no file and a password from --password or SS5_PASSWORD, as a container entrypoint runs it
+    pub fn zero_config(&self) -> bool {
+        self.conf.is_none()
+            && (self.password.is_some() || std::env::var("SS5_PASSWORD").is_ok_and(|p| !p.is_empty()))
+    }
+}
+
+impl LocalOpt {
+    pub fn config(&self) -> Result<LocalConfig, ConfigError> {
+        let mut config = match &self.conf {
+            None => LocalConfig::default(),
+            Some(path) => LocalConfig::load_profile(path, self.profile.as_deref())?,
+        };
+        config.apply_env(|name| std::env::var(name).ok())?;
+        if let Some(port) = self.port {
+            config.port = port;
+        }
+        if let Some(path) = self.unix.clone() {
+            config.unix = Some(path);
+        }
+        if let Some(name) = self.pipe.clone() {
+            config.pipe = Some(name);
+        }
+        if self.system_proxy {
+            config.system_proxy = true;
+        }
+        if let Some(server) = self.server.clone() {
+            config.server = server;
+        }
+        Ok(config)
+    }
+}